@@ -0,0 +1,133 @@
+//! Benchmarks of the work done for every MIDI event between the input callback and the output.
+//!
+//! Latency budget for one event, on a laptop from the last few years:
+//!
+//! | stage                              | budget  |
+//! |------------------------------------|---------|
+//! | sequencing and batching            | 1 µs    |
+//! | encoding a batch of up to 16       | 5 µs    |
+//! | decoding a batch of up to 16       | 5 µs    |
+//! | routing (channel map + transpose)  | 200 ns  |
+//!
+//! Together this is well under 1% of the 5 to 10 ms a player notices, leaving the rest to the
+//! network. A change that pushes any stage over its budget needs a good reason.
+//!
+//! Run with `cargo bench`, criterion reports the change since the last run.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use p2pmidi::midi::MessageKind;
+use p2pmidi::p2p::protocol::{
+    decode_frames, encode_frames, FrameSequencer, MidiFrame, WIRE_VERSION,
+};
+use p2pmidi::routing::{ChannelMapping, PeerConfig, PeerRoute};
+
+/// Batch sizes seen in practice: a single key press, a chord with pedal, a busy controller.
+const BATCH_SIZES: [usize; 3] = [1, 16, 256];
+
+fn messages(count: usize) -> Vec<Vec<u8>> {
+    (0..count)
+        .map(|i| match i % 4 {
+            0 => vec![0x90 | (i % 16) as u8, 60 + (i % 24) as u8, 100],
+            1 => vec![0x80 | (i % 16) as u8, 60 + (i % 24) as u8, 0],
+            2 => vec![0xB0 | (i % 16) as u8, 1, (i % 128) as u8],
+            _ => vec![0xE0 | (i % 16) as u8, 0, 64],
+        })
+        .collect()
+}
+
+fn frames(count: usize) -> Vec<MidiFrame> {
+    let mut sequencer = FrameSequencer::default();
+    messages(count)
+        .into_iter()
+        .map(|m| sequencer.frame(m))
+        .collect()
+}
+
+fn busy_route() -> PeerRoute {
+    PeerRoute::new(
+        "bench",
+        &PeerConfig {
+            channel_map: (1..=16)
+                .map(|from| ChannelMapping {
+                    from,
+                    to: 17 - from,
+                })
+                .collect(),
+            transpose: 12,
+            filters: vec![MessageKind::Clock],
+            ..Default::default()
+        },
+    )
+}
+
+fn batching(c: &mut Criterion) {
+    let mut group = c.benchmark_group("batching");
+    for size in BATCH_SIZES {
+        let batch = messages(size);
+        group.throughput(Throughput::Elements(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &batch, |b, batch| {
+            let mut sequencer = FrameSequencer::default();
+            b.iter(|| {
+                batch
+                    .iter()
+                    .map(|m| sequencer.frame(m.clone()))
+                    .collect::<Vec<MidiFrame>>()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn encoding(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode");
+    for size in BATCH_SIZES {
+        let batch = frames(size);
+        group.throughput(Throughput::Elements(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &batch, |b, batch| {
+            b.iter(|| encode_frames(black_box(batch), WIRE_VERSION).unwrap())
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("decode");
+    for size in BATCH_SIZES {
+        let bytes = encode_frames(&frames(size), WIRE_VERSION).unwrap();
+        group.throughput(Throughput::Elements(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &bytes, |b, bytes| {
+            b.iter(|| decode_frames(black_box(bytes.clone())).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn routing(c: &mut Criterion) {
+    let message = [0x93, 60, 100];
+    let untouched = PeerRoute::new("bench", &PeerConfig::default());
+    let busy = busy_route();
+    c.bench_function("route/untouched", |b| {
+        b.iter(|| untouched.apply(black_box(&message)))
+    });
+    c.bench_function("route/mapped", |b| {
+        b.iter(|| busy.apply(black_box(&message)))
+    });
+
+    // What a receiver does with every batch: decode it and route each message
+    let mut group = c.benchmark_group("receive");
+    for size in BATCH_SIZES {
+        let bytes = encode_frames(&frames(size), WIRE_VERSION).unwrap();
+        group.throughput(Throughput::Elements(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &bytes, |b, bytes| {
+            b.iter(|| {
+                decode_frames(black_box(bytes.clone()))
+                    .unwrap()
+                    .iter()
+                    .filter_map(|frame| busy.apply(&frame.message))
+                    .count()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, batching, encoding, routing);
+criterion_main!(benches);
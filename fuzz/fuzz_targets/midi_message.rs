@@ -0,0 +1,32 @@
+//! MIDI messages received from a peer, through parsing and a route that changes everything it can.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use p2pmidi::midi::{self, MessageKind};
+use p2pmidi::routing::{ChannelMapping, PeerConfig, PeerRoute};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = MessageKind::of(data);
+    let _ = midi::channel(data);
+    let _ = midi::is_silencing(data);
+
+    for transpose in [-128, -12, 0, 12, 127] {
+        let route = PeerRoute::new(
+            "fuzz",
+            &PeerConfig {
+                channel_map: (1..=16)
+                    .map(|from| ChannelMapping {
+                        from,
+                        to: 17 - from,
+                    })
+                    .collect(),
+                transpose,
+                filters: vec![MessageKind::Clock, MessageKind::SysEx],
+                ..Default::default()
+            },
+        );
+        if let Some(message) = route.apply(data) {
+            assert_eq!(message.len(), data.len());
+        }
+    }
+});
@@ -0,0 +1,22 @@
+//! Bytes received from a peer, both as a bare batch and through the length prefixed codec.
+#![no_main]
+
+use bytes::Bytes;
+use futures::{executor::block_on, io::Cursor};
+use libfuzzer_sys::fuzz_target;
+use libp2p::request_response::Codec;
+use p2pmidi::p2p::protocol::{decode_frames, encode_frames, MidiCodec, PROTOCOL, TRACKS_PROTOCOL};
+
+fuzz_target!(|data: &[u8]| {
+    // Anything that decodes must encode back to the same bytes in its version
+    if let Ok(frames) = decode_frames(Bytes::copy_from_slice(data)) {
+        assert_eq!(&encode_frames(&frames, data[0]).unwrap()[..], data);
+    }
+
+    let mut io = Cursor::new(data);
+    let _ = block_on(MidiCodec::default().read_request(&PROTOCOL, &mut io));
+    let mut io = Cursor::new(data);
+    let _ = block_on(MidiCodec::default().read_request(&TRACKS_PROTOCOL, &mut io));
+    let mut io = Cursor::new(data);
+    let _ = block_on(MidiCodec::default().read_response(&PROTOCOL, &mut io));
+});
@@ -0,0 +1,255 @@
+//! Arpeggios of the notes a peer holds, played here on the beat grid of the session transport.
+//! Only which notes are held comes over the network, so the steps stay locked to the session tempo
+//! however much the MIDI of the peer jitters on the way.
+
+use serde::{Deserialize, Serialize};
+
+use super::midi::{self, MessageKind};
+use super::transport::{unix_micros, TransportState};
+
+/// Length of each step, as a note value.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ArpRate {
+    #[serde(rename = "1/4")]
+    Quarter,
+    #[serde(rename = "1/8")]
+    Eighth,
+    #[serde(rename = "1/8t")]
+    EighthTriplet,
+    #[default]
+    #[serde(rename = "1/16")]
+    Sixteenth,
+    #[serde(rename = "1/16t")]
+    SixteenthTriplet,
+    #[serde(rename = "1/32")]
+    ThirtySecond,
+}
+
+impl ArpRate {
+    pub fn steps_per_beat(&self) -> f64 {
+        match self {
+            ArpRate::Quarter => 1.0,
+            ArpRate::Eighth => 2.0,
+            ArpRate::EighthTriplet => 3.0,
+            ArpRate::Sixteenth => 4.0,
+            ArpRate::SixteenthTriplet => 6.0,
+            ArpRate::ThirtySecond => 8.0,
+        }
+    }
+}
+
+/// Order the held notes are played in.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ArpPattern {
+    #[default]
+    Up,
+    Down,
+    /// Up then back down, without playing the top and bottom notes twice.
+    UpDown,
+    /// In the order the notes were pressed.
+    AsPlayed,
+    Random,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct ArpeggiatorConfig {
+    pub rate: ArpRate,
+    pub pattern: ArpPattern,
+    /// Fraction of a step each note sounds for, from 0 to 1.
+    pub gate: f64,
+    /// Octaves the pattern goes up through.
+    pub octaves: u8,
+}
+
+impl Default for ArpeggiatorConfig {
+    fn default() -> Self {
+        ArpeggiatorConfig {
+            rate: ArpRate::default(),
+            pattern: ArpPattern::default(),
+            gate: 0.5,
+            octaves: 1,
+        }
+    }
+}
+
+/// A note of an arpeggio, with when it starts and ends in microseconds since the Unix epoch.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ArpNote {
+    pub on_us: u64,
+    pub off_us: u64,
+    pub channel: u8,
+    pub note: u8,
+    pub velocity: u8,
+}
+
+impl ArpNote {
+    pub fn note_on(&self) -> [u8; 3] {
+        [0x90 | self.channel, self.note, self.velocity]
+    }
+
+    pub fn note_off(&self) -> [u8; 3] {
+        [0x80 | self.channel, self.note, 0]
+    }
+}
+
+/// Plays the notes held by one peer as an arpeggio.
+#[derive(Debug, Clone)]
+pub struct Arpeggiator {
+    config: ArpeggiatorConfig,
+    /// Channel, note and velocity of the held notes, in the order they were pressed.
+    held: Vec<(u8, u8, u8)>,
+    /// Steps played since the first note was pressed.
+    step: usize,
+    /// The next step to play, counted on the beat grid.
+    next_step: Option<i64>,
+    /// State of the xorshift generator of the random pattern.
+    random: u64,
+}
+
+impl Arpeggiator {
+    pub fn new(config: ArpeggiatorConfig) -> Self {
+        Arpeggiator {
+            config,
+            held: Vec::new(),
+            step: 0,
+            next_step: None,
+            random: unix_micros() | 1,
+        }
+    }
+
+    pub fn set_config(&mut self, config: &ArpeggiatorConfig) {
+        if self.config != *config {
+            self.config = config.clone();
+        }
+    }
+
+    /// Take a note of the peer to arpeggiate. Returns whether it was taken, messages that are not
+    /// are played as they are.
+    pub fn take(&mut self, message: &[u8]) -> bool {
+        let (channel, note) = match (midi::channel(message), message.get(1)) {
+            (Some(channel), Some(note)) => (channel, *note),
+            _ => return false,
+        };
+        match MessageKind::of(message) {
+            // Notes pressed before the arpeggiator was on end as they would have
+            Some(MessageKind::NoteOn | MessageKind::NoteOff) if midi::is_silencing(message) => {
+                let held = self.held.len();
+                self.held.retain(|(c, n, _)| (*c, *n) != (channel, note));
+                if self.held.is_empty() {
+                    self.stop();
+                }
+                self.held.len() != held
+            }
+            Some(MessageKind::NoteOn) => {
+                self.held.retain(|(c, n, _)| (*c, *n) != (channel, note));
+                self.held.push((channel, note, message[2]));
+                true
+            }
+            Some(MessageKind::ControlChange) if matches!(note, 120 | 123) => {
+                self.held.retain(|(c, _, _)| *c != channel);
+                if self.held.is_empty() {
+                    self.stop();
+                }
+                false
+            }
+            _ => false,
+        }
+    }
+
+    fn stop(&mut self) {
+        self.step = 0;
+        self.next_step = None;
+    }
+
+    /// The notes of the steps starting before `until_us`, on the beat grid of `transport`. While
+    /// the transport is stopped the steps go on at its tempo.
+    pub fn steps(
+        &mut self,
+        transport: &TransportState,
+        now_us: u64,
+        until_us: u64,
+    ) -> Vec<ArpNote> {
+        if self.held.is_empty() {
+            return Vec::new();
+        }
+        let grid = TransportState {
+            playing: true,
+            ..*transport
+        };
+        let per_beat = self.config.rate.steps_per_beat();
+        let now_step = grid.beat_at(now_us) * per_beat;
+        // Start on the next step, or again from now when the beat moved since the last one
+        let mut step = match self.next_step {
+            Some(step)
+                if (now_step.floor() as i64..=now_step.ceil() as i64 + 1).contains(&step) =>
+            {
+                step
+            }
+            _ => now_step.ceil() as i64,
+        };
+        let step_us = 60_000_000.0 / grid.tempo / per_beat;
+        let gate_us = (step_us * self.config.gate.clamp(0.0, 1.0)).max(1.0) as u64;
+        let mut notes = Vec::new();
+        loop {
+            let on_us = grid.time_of_beat(step as f64 / per_beat);
+            if on_us >= until_us {
+                break;
+            }
+            if let Some((channel, note, velocity)) = self.next_note() {
+                notes.push(ArpNote {
+                    on_us,
+                    off_us: on_us + gate_us,
+                    channel,
+                    note,
+                    velocity,
+                });
+            }
+            step += 1;
+        }
+        self.next_step = Some(step);
+        notes
+    }
+
+    /// The note of the next step of the pattern.
+    fn next_note(&mut self) -> Option<(u8, u8, u8)> {
+        let mut notes = self.held.clone();
+        if self.config.pattern != ArpPattern::AsPlayed {
+            notes.sort_by_key(|(_, note, _)| *note);
+        }
+        let notes: Vec<(u8, u8, u8)> = (0..self.config.octaves.max(1))
+            .flat_map(|octave| {
+                notes.iter().filter_map(move |(channel, note, velocity)| {
+                    let note = *note as u16 + 12 * octave as u16;
+                    (note <= 127).then_some((*channel, note as u8, *velocity))
+                })
+            })
+            .collect();
+        if notes.is_empty() {
+            return None;
+        }
+        let step = self.step;
+        self.step += 1;
+        let len = notes.len();
+        let i = match self.config.pattern {
+            ArpPattern::Up | ArpPattern::AsPlayed => step % len,
+            ArpPattern::Down => len - 1 - step % len,
+            ArpPattern::UpDown if len < 2 => 0,
+            ArpPattern::UpDown => {
+                let i = step % (2 * len - 2);
+                match i < len {
+                    true => i,
+                    false => 2 * len - 2 - i,
+                }
+            }
+            ArpPattern::Random => {
+                self.random ^= self.random << 13;
+                self.random ^= self.random >> 7;
+                self.random ^= self.random << 17;
+                self.random as usize % len
+            }
+        };
+        Some(notes[i])
+    }
+}
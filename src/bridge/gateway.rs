@@ -0,0 +1,308 @@
+//! A web page bridging WebMIDI in the browser to the session over a WebSocket, so someone can
+//! join from a machine with nothing installed. Every browser is its own client of this node: it
+//! hears every peer, and plays to everyone or to the one peer it picked.
+//!
+//! The page and its WebSocket are only served with the secret token of the URL logged when the
+//! gateway starts, and the WebSocket only to pages of the gateway itself. Browsers get the route
+//! and rate limit of the `browser` entry of the peer configs, each one its own.
+
+use futures::channel::mpsc::{UnboundedReceiver, UnboundedSender};
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{debug, info, info_span, warn, Instrument};
+
+use super::{BridgeEvent, BridgeMidi};
+use crate::midi::MessageKind;
+use crate::p2p::ratelimit::{RateLimitPolicy, RateLimiter, Verdict};
+use crate::routing::{PeerConfig, PeerRoute};
+use crate::runtime;
+
+const PAGE: &str = include_str!("gateway.html");
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+struct PeerInfo {
+    peer_id: String,
+    name: String,
+}
+
+/// What the page is told.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage {
+    Welcome { id: String },
+    Peers { peers: Vec<PeerInfo> },
+    Midi { from: String, data: Vec<u8> },
+}
+
+/// What the page sends.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    Hello {
+        name: String,
+    },
+    Midi {
+        data: Vec<u8>,
+        /// PeerId to play to instead of everyone.
+        to: Option<String>,
+    },
+}
+
+/// What browsers may do, like a peer would.
+#[derive(Clone, Debug, Default)]
+pub struct BrowserLimits {
+    /// The `browser` entry of the peer configs.
+    pub config: PeerConfig,
+    pub max_inbound_rate: Option<u32>,
+    pub rate_limit_policy: RateLimitPolicy,
+}
+
+/// The browsers connected and the peers they can see.
+struct Gateway {
+    token: String,
+    limits: BrowserLimits,
+    next_id: AtomicU64,
+    clients: Mutex<HashMap<u64, UnboundedSender<ServerMessage>>>,
+    peers: Mutex<BTreeMap<String, String>>,
+}
+
+impl Gateway {
+    fn peer_list(&self) -> ServerMessage {
+        ServerMessage::Peers {
+            peers: self
+                .peers
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(peer_id, name)| PeerInfo {
+                    peer_id: peer_id.clone(),
+                    name: name.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Whether `path` carries the token of the gateway.
+    fn admits(&self, path: &str) -> bool {
+        let query = path.split_once('?').map_or("", |(_, query)| query);
+        query
+            .split('&')
+            .any(|pair| pair.strip_prefix("token=") == Some(self.token.as_str()))
+    }
+
+    fn broadcast(&self, message: ServerMessage) {
+        self.clients
+            .lock()
+            .unwrap()
+            .retain(|_, client| client.unbounded_send(message.clone()).is_ok());
+    }
+}
+
+/// Serve the page, or switch to a WebSocket when the request asks for one.
+async fn handle_connection(
+    stream: TcpStream,
+    from: SocketAddr,
+    gateway: Arc<Gateway>,
+    input: UnboundedSender<BridgeMidi>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut head = [0u8; 2048];
+    let len = stream.peek(&mut head).await?;
+    let head = String::from_utf8_lossy(&head[..len]).to_string();
+    if !head.to_ascii_lowercase().contains("upgrade: websocket") {
+        let mut stream = stream;
+        let path = head.split_whitespace().nth(1).unwrap_or_default();
+        let page = path.split('?').next().unwrap_or_default();
+        let response = match page {
+            "/" | "/index.html" if !gateway.admits(path) => {
+                "HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+            }
+            "/" | "/index.html" => format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                PAGE.len(),
+                PAGE
+            ),
+            _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+        };
+        stream.write_all(response.as_bytes()).await?;
+        return Ok(());
+    }
+
+    let check = |request: &Request, response: Response| -> Result<Response, ErrorResponse> {
+        let header = |name: &str| request.headers().get(name).and_then(|v| v.to_str().ok());
+        // Pages elsewhere could otherwise have browsers play into the session
+        let same_origin = match (header("origin"), header("host")) {
+            (Some(origin), Some(host)) => {
+                origin == format!("http://{}", host) || origin == format!("https://{}", host)
+            }
+            _ => false,
+        };
+        let path = request.uri().path_and_query().map_or("", |p| p.as_str());
+        match (same_origin, gateway.admits(path)) {
+            (true, true) => Ok(response),
+            _ => {
+                debug!("Refused a WebSocket from {}", from);
+                let mut refused = ErrorResponse::new(None);
+                *refused.status_mut() = StatusCode::FORBIDDEN;
+                Err(refused)
+            }
+        }
+    };
+    let socket = tokio_tungstenite::accept_hdr_async(stream, check).await?;
+    let (mut sink, mut messages) = socket.split();
+    let id = gateway.next_id.fetch_add(1, Ordering::Relaxed);
+    let (sender, mut outgoing) = futures::channel::mpsc::unbounded();
+    let _ = sender.unbounded_send(ServerMessage::Welcome {
+        id: format!("browser-{}", id),
+    });
+    let _ = sender.unbounded_send(gateway.peer_list());
+    gateway.clients.lock().unwrap().insert(id, sender);
+
+    let writer = runtime::spawn(async move {
+        while let Some(message) = outgoing.next().await {
+            let text = match serde_json::to_string(&message) {
+                Ok(text) => text,
+                Err(e) => {
+                    warn!("Error serializing message for the browser: {}", e);
+                    continue;
+                }
+            };
+            if sink.send(Message::Text(text)).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut name = format!("browser-{}", id);
+    let route = PeerRoute::new(&name, &gateway.limits.config);
+    let rate = route.max_rate.or(gateway.limits.max_inbound_rate);
+    let mut limiter = RateLimiter::new(gateway.limits.rate_limit_policy, Instant::now());
+    while let Some(message) = messages.next().await {
+        let text = match message? {
+            Message::Text(text) => text,
+            Message::Close(_) => break,
+            _ => continue,
+        };
+        match serde_json::from_str::<ClientMessage>(&text) {
+            Ok(ClientMessage::Hello { name: given }) => {
+                if !given.trim().is_empty() {
+                    name = given.trim().to_string();
+                }
+                info!("{} joined from {}", name, from);
+            }
+            // Only whole messages with a valid status byte go on to the peers
+            Ok(ClientMessage::Midi { data, to }) if MessageKind::of(&data).is_some() => {
+                if let Some(rate) = rate {
+                    match limiter.check(&data, rate, Instant::now()) {
+                        Verdict::Pass => {}
+                        Verdict::Drop => continue,
+                        Verdict::Alert => {
+                            warn!(
+                                "{} is over its rate limit of {} events a second",
+                                name, rate
+                            );
+                            continue;
+                        }
+                    }
+                }
+                let message = match route.apply(&data) {
+                    Some(message) => message,
+                    None => continue,
+                };
+                if input.unbounded_send(BridgeMidi { to, message }).is_err() {
+                    break;
+                }
+            }
+            Ok(ClientMessage::Midi { .. }) => debug!("Invalid MIDI from {}", name),
+            Err(e) => debug!("Invalid message from {}: {}", name, e),
+        }
+    }
+    info!("{} left", name);
+    gateway.clients.lock().unwrap().remove(&id);
+    writer.abort();
+    Ok(())
+}
+
+/// Serve the browser gateway on `address`, at `http://<address>/?token=<token>` with a token made
+/// up for this session.
+pub fn start(
+    address: SocketAddr,
+    limits: BrowserLimits,
+    mut events: UnboundedReceiver<BridgeEvent>,
+    input: UnboundedSender<BridgeMidi>,
+) -> Result<(), Box<dyn Error>> {
+    let listener = std::net::TcpListener::bind(address)?;
+    listener.set_nonblocking(true)?;
+    let _runtime = runtime::enter();
+    let listener = TcpListener::from_std(listener)?;
+    let token = format!("{:032x}", rand::random::<u128>());
+    info!("Browser gateway at http://{}/?token={}", address, token);
+    let receive = limits.config.permissions.receive_midi;
+    let gateway = Arc::new(Gateway {
+        token,
+        limits,
+        next_id: AtomicU64::new(0),
+        clients: Mutex::default(),
+        peers: Mutex::default(),
+    });
+
+    let accepting = gateway.clone();
+    runtime::spawn(
+        async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, from)) => {
+                        let gateway = accepting.clone();
+                        let input = input.clone();
+                        runtime::spawn(
+                            async move {
+                                if let Err(e) =
+                                    handle_connection(stream, from, gateway, input).await
+                                {
+                                    debug!("Error serving {}: {}", from, e);
+                                }
+                            }
+                            .in_current_span(),
+                        );
+                    }
+                    Err(e) => warn!("Error accepting gateway connection: {}", e),
+                }
+            }
+        }
+        .instrument(info_span!("gateway")),
+    );
+
+    runtime::spawn(async move {
+        while let Some(event) = events.next().await {
+            match event {
+                BridgeEvent::PeerJoined { peer_id, name } => {
+                    gateway.peers.lock().unwrap().insert(peer_id, name);
+                    gateway.broadcast(gateway.peer_list());
+                }
+                BridgeEvent::PeerLeft { peer_id } => {
+                    gateway.peers.lock().unwrap().remove(&peer_id);
+                    gateway.broadcast(gateway.peer_list());
+                }
+                BridgeEvent::PeerWaiting { .. } | BridgeEvent::CountIn { .. } => {}
+                BridgeEvent::Midi { .. } if !receive => {}
+                BridgeEvent::Midi {
+                    peer_id, message, ..
+                } => gateway.broadcast(ServerMessage::Midi {
+                    from: peer_id,
+                    data: message.to_vec(),
+                }),
+            }
+        }
+    });
+    Ok(())
+}
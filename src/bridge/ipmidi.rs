@@ -0,0 +1,214 @@
+//! ipMIDI style MIDI over multicast UDP, as used by studio rigs on the LAN. Each packet carries
+//! plain MIDI bytes, and each multicast port is one MIDI port.
+
+use futures::channel::mpsc::{UnboundedReceiver, UnboundedSender};
+use futures::stream::StreamExt;
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tracing::{debug, info, info_span, trace, warn, Instrument};
+
+use super::{BridgeEvent, BridgeMidi};
+use crate::midi::data_len;
+use crate::runtime;
+
+/// Multicast group ipMIDI uses by default.
+pub const DEFAULT_GROUP: Ipv4Addr = Ipv4Addr::new(225, 0, 0, 37);
+
+/// Which way MIDI goes through an ipMIDI port.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// Play what the port carries into the session.
+    In,
+    /// Send what peers play to the port.
+    Out,
+    #[default]
+    Both,
+}
+
+fn default_group() -> Ipv4Addr {
+    DEFAULT_GROUP
+}
+
+/// An ipMIDI port bridged into the session, from the `ipmidi:` section of the config file.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct IpMidiMapping {
+    #[serde(default = "default_group")]
+    pub group: Ipv4Addr,
+    /// UDP port, 21928 is the first ipMIDI port.
+    pub port: u16,
+    #[serde(default)]
+    pub direction: Direction,
+    /// Only bridge this peer, by PeerId or name: what the port carries goes to it alone and only
+    /// what it plays goes out.
+    pub peer: Option<String>,
+}
+
+/// Split the plain MIDI bytes of a packet into messages, following running status.
+fn split_messages(bytes: &[u8]) -> Vec<Vec<u8>> {
+    let mut messages = Vec::new();
+    let mut running_status = None;
+    let mut i = 0;
+    while i < bytes.len() {
+        let status = match bytes[i] {
+            status if status & 0x80 != 0 => {
+                i += 1;
+                status
+            }
+            _ => match running_status {
+                Some(status) => status,
+                None => {
+                    // Data bytes without a status to go with them
+                    i += 1;
+                    continue;
+                }
+            },
+        };
+        if status == 0xF0 {
+            match bytes[i..].iter().position(|b| *b == 0xF7) {
+                Some(end) => {
+                    let mut message = vec![0xF0];
+                    message.extend_from_slice(&bytes[i..=i + end]);
+                    messages.push(message);
+                    i += end + 1;
+                }
+                None => {
+                    trace!("Dropping unterminated SysEx");
+                    break;
+                }
+            }
+            running_status = None;
+            continue;
+        }
+        let len = data_len(status);
+        match bytes.get(i..i + len) {
+            Some(data) => {
+                let mut message = vec![status];
+                message.extend_from_slice(data);
+                messages.push(message);
+            }
+            None => break,
+        }
+        i += len;
+        match status {
+            0x80..=0xEF => running_status = Some(status),
+            0xF0..=0xF7 => running_status = None,
+            _ => {}
+        }
+    }
+    messages
+}
+
+/// A socket listening on the multicast group, shared with other ipMIDI software on this machine.
+fn multicast_socket(group: Ipv4Addr, port: u16) -> Result<UdpSocket, Box<dyn Error>> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port).into())?;
+    socket.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+/// Bridge every mapped ipMIDI port into the session.
+pub fn start(
+    mappings: Vec<IpMidiMapping>,
+    mut events: UnboundedReceiver<BridgeEvent>,
+    input: UnboundedSender<BridgeMidi>,
+) -> Result<(), Box<dyn Error>> {
+    let _runtime = runtime::enter();
+    // Sending from its own port tells our packets apart when they loop back
+    let sender = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    sender.set_multicast_loop_v4(true)?;
+    sender.set_nonblocking(true)?;
+    let sender = Arc::new(UdpSocket::from_std(sender)?);
+    let own_port = sender.local_addr()?.port();
+
+    for mapping in mappings.iter().filter(|m| m.direction != Direction::Out) {
+        let socket = multicast_socket(mapping.group, mapping.port).map_err(|e| {
+            format!(
+                "Could not join ipMIDI group {}:{}: {}",
+                mapping.group, mapping.port, e
+            )
+        })?;
+        info!("Listening for ipMIDI on {}:{}", mapping.group, mapping.port);
+        let input = input.clone();
+        let to = mapping.peer.clone();
+        let span = info_span!("ipmidi", group = %mapping.group, port = mapping.port);
+        runtime::spawn(
+            async move {
+                let mut buffer = [0u8; 1500];
+                loop {
+                    let (len, from) = match socket.recv_from(&mut buffer).await {
+                        Ok(received) => received,
+                        Err(e) => {
+                            warn!("Error receiving ipMIDI: {}", e);
+                            continue;
+                        }
+                    };
+                    if from.port() == own_port {
+                        continue;
+                    }
+                    for message in split_messages(&buffer[..len]) {
+                        let midi = BridgeMidi {
+                            to: to.clone(),
+                            message,
+                        };
+                        if input.unbounded_send(midi).is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+            .instrument(span),
+        );
+    }
+
+    let outputs: Vec<IpMidiMapping> = mappings
+        .into_iter()
+        .filter(|m| m.direction != Direction::In)
+        .collect();
+    if outputs.is_empty() {
+        return Ok(());
+    }
+    runtime::spawn(
+        async move {
+            let mut names: HashMap<String, String> = HashMap::new();
+            while let Some(event) = events.next().await {
+                let (peer_id, message) = match event {
+                    BridgeEvent::PeerJoined { peer_id, name } => {
+                        names.insert(peer_id, name);
+                        continue;
+                    }
+                    BridgeEvent::PeerLeft { peer_id } => {
+                        names.remove(&peer_id);
+                        continue;
+                    }
+                    BridgeEvent::Midi {
+                        peer_id, message, ..
+                    } => (peer_id, message),
+                    BridgeEvent::PeerWaiting { .. } | BridgeEvent::CountIn { .. } => continue,
+                };
+                for output in &outputs {
+                    let wanted = output.peer.as_ref().map_or(true, |peer| {
+                        *peer == peer_id || names.get(&peer_id) == Some(peer)
+                    });
+                    if !wanted {
+                        continue;
+                    }
+                    let to = SocketAddr::from((output.group, output.port));
+                    if let Err(e) = sender.send_to(&message, to).await {
+                        debug!("Error sending ipMIDI to {}: {}", to, e);
+                    }
+                }
+            }
+        }
+        .instrument(info_span!("ipmidi")),
+    );
+    Ok(())
+}
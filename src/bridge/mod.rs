@@ -0,0 +1,136 @@
+//! Bridges between the session and other MIDI protocols, so gear and apps that don't run p2pmidi
+//! can take part. Bridges hear what the peers play after routing, and what they play themselves
+//! is sent on to the peers like local input.
+
+use bytes::Bytes;
+use futures::channel::mpsc::{UnboundedReceiver, UnboundedSender};
+use std::error::Error;
+use std::net::SocketAddr;
+
+pub mod gateway;
+pub mod ipmidi;
+pub mod netmidi2;
+pub mod osc;
+pub mod ports;
+pub mod rtpmidi;
+
+/// What happens in the session, as far as bridges care.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BridgeEvent {
+    PeerJoined {
+        peer_id: String,
+        name: String,
+    },
+    PeerLeft {
+        peer_id: String,
+    },
+    /// A new peer asking to join, let in or turned away with `PeerHandle::accept` or `reject`.
+    PeerWaiting {
+        peer_id: String,
+        name: Option<String>,
+    },
+    /// MIDI received from a peer, after its route was applied.
+    Midi {
+        peer_id: String,
+        message: Bytes,
+        /// Track the peer labeled the message with.
+        track: Option<String>,
+    },
+    /// Beats left before a start scheduled with the peers, 0 when it starts.
+    CountIn {
+        beats_left: u32,
+    },
+}
+
+/// MIDI a bridge plays into the session.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BridgeMidi {
+    /// Only send to this peer, by PeerId or name, instead of every connected one.
+    pub to: Option<String>,
+    pub message: Vec<u8>,
+}
+
+/// Which bridges to run along with a session.
+#[derive(Clone, Debug, Default)]
+pub struct BridgeOptions {
+    /// Serve RTP-MIDI sessions starting at this control port.
+    pub rtp_midi_port: Option<u16>,
+    /// RTP-MIDI sessions to join, as `host:port` of their control port.
+    pub rtp_midi_invite: Vec<String>,
+    /// Listen for OSC on this port.
+    pub osc_port: Option<u16>,
+    /// Send what peers play as OSC to these `host:port` addresses.
+    pub osc_send: Vec<String>,
+    pub osc_map: Vec<osc::OscMapping>,
+    /// Serve the browser gateway on this address.
+    pub gateway_address: Option<SocketAddr>,
+    pub ipmidi: Vec<ipmidi::IpMidiMapping>,
+    /// Serve a Network MIDI 2.0 endpoint on this UDP port.
+    pub network_midi2_port: Option<u16>,
+    /// Open a "To" and a "From" virtual MIDI port for each peer.
+    pub virtual_ports: bool,
+}
+
+/// Hands session events to every running bridge.
+#[derive(Default)]
+pub struct Bridges {
+    subscribers: Vec<UnboundedSender<BridgeEvent>>,
+}
+
+impl Bridges {
+    pub fn subscribe(&mut self) -> UnboundedReceiver<BridgeEvent> {
+        let (sender, receiver) = futures::channel::mpsc::unbounded();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    /// Hand events to `subscriber` too.
+    pub fn add(&mut self, subscriber: UnboundedSender<BridgeEvent>) {
+        self.subscribers.push(subscriber);
+    }
+
+    /// Send an event to the bridges, forgetting the ones that stopped.
+    pub fn send(&mut self, event: BridgeEvent) {
+        self.subscribers
+            .retain(|subscriber| subscriber.unbounded_send(event.clone()).is_ok());
+    }
+}
+
+/// Start the bridges enabled in `options`. They run on the shared runtime until the session ends.
+pub fn start(
+    options: &BridgeOptions,
+    browsers: gateway::BrowserLimits,
+    bridges: &mut Bridges,
+    input: UnboundedSender<BridgeMidi>,
+) -> Result<(), Box<dyn Error>> {
+    if options.rtp_midi_port.is_some() || !options.rtp_midi_invite.is_empty() {
+        rtpmidi::start(
+            options.rtp_midi_port,
+            &options.rtp_midi_invite,
+            bridges.subscribe(),
+            input.clone(),
+        )?;
+    }
+    if options.osc_port.is_some() || !options.osc_send.is_empty() {
+        osc::start(
+            options.osc_port,
+            &options.osc_send,
+            options.osc_map.clone(),
+            bridges.subscribe(),
+            input.clone(),
+        )?;
+    }
+    if let Some(address) = options.gateway_address {
+        gateway::start(address, browsers, bridges.subscribe(), input.clone())?;
+    }
+    if !options.ipmidi.is_empty() {
+        ipmidi::start(options.ipmidi.clone(), bridges.subscribe(), input.clone())?;
+    }
+    if options.virtual_ports {
+        ports::start(bridges.subscribe(), input.clone())?;
+    }
+    if let Some(port) = options.network_midi2_port {
+        netmidi2::start(port, bridges.subscribe(), input)?;
+    }
+    Ok(())
+}
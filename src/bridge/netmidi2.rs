@@ -0,0 +1,457 @@
+//! A Network MIDI 2.0 (UDP) host, so operating systems and devices speaking UMP over the network
+//! can join the session natively.
+//!
+//! Group 1 carries every peer, and each connected peer gets one of the following groups to itself,
+//! up to the 16 a UMP endpoint has. The endpoint is not announced over mDNS, add it by address.
+//! Only sessions without authentication are accepted.
+
+use futures::channel::mpsc::{UnboundedReceiver, UnboundedSender};
+use futures::{future::FutureExt, stream::StreamExt};
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tracing::{debug, info, info_span, trace, warn, Instrument};
+
+use super::{BridgeEvent, BridgeMidi};
+use crate::runtime;
+use crate::ump::{self, Decoder};
+
+/// Every packet starts with "MIDI".
+const SIGNATURE: [u8; 4] = *b"MIDI";
+
+const INVITATION: u8 = 0x01;
+const INVITATION_ACCEPTED: u8 = 0x10;
+const PING: u8 = 0x20;
+const PING_REPLY: u8 = 0x21;
+const RETRANSMIT_REQUEST: u8 = 0x80;
+const RETRANSMIT_ERROR: u8 = 0x81;
+const SESSION_RESET: u8 = 0x82;
+const SESSION_RESET_REPLY: u8 = 0x83;
+const NAK: u8 = 0x8F;
+const BYE: u8 = 0xF0;
+const BYE_REPLY: u8 = 0xF1;
+const UMP_DATA: u8 = 0xFF;
+
+/// NAK reason for commands we don't implement.
+const NAK_NOT_SUPPORTED: u8 = 0x01;
+/// Retransmit error reason when the data asked for is gone.
+const RETRANSMIT_UNAVAILABLE: u8 = 0x01;
+/// Bye reason for a host shutting down.
+const BYE_SHUTDOWN: u8 = 0x02;
+
+const ENDPOINT_NAME: &str = "p2pmidi";
+const PRODUCT_INSTANCE_ID: &str = "p2pmidi";
+
+/// Earlier UMP Data commands repeated in every packet, so a lost packet costs nothing.
+const REDUNDANCY: usize = 2;
+
+/// UMP Data commands kept per client to answer retransmit requests.
+const HISTORY: usize = 64;
+
+/// How often clients are pinged.
+const PING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Clients silent for this long are considered gone.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Words of UMP per UMP Data command, the most its length byte allows.
+const MAX_UMP_WORDS: usize = 255;
+
+/// A command of a Network MIDI 2.0 packet.
+#[derive(Debug, Clone, PartialEq)]
+struct Command {
+    code: u8,
+    /// The two command specific bytes of the header.
+    data: [u8; 2],
+    payload: Vec<u32>,
+}
+
+impl Command {
+    fn new(code: u8, data: [u8; 2], payload: Vec<u32>) -> Self {
+        Command {
+            code,
+            data,
+            payload,
+        }
+    }
+
+    fn ump_data(seq: u16, words: Vec<u32>) -> Self {
+        Command::new(UMP_DATA, seq.to_be_bytes(), words)
+    }
+
+    fn encode(&self, packet: &mut Vec<u8>) {
+        packet.extend_from_slice(&[self.code, self.payload.len() as u8]);
+        packet.extend_from_slice(&self.data);
+        for word in &self.payload {
+            packet.extend_from_slice(&word.to_be_bytes());
+        }
+    }
+}
+
+/// Commands of a packet, or None when it isn't Network MIDI 2.0.
+fn decode_packet(packet: &[u8]) -> Option<Vec<Command>> {
+    if packet.get(0..4)? != SIGNATURE {
+        return None;
+    }
+    let mut commands = Vec::new();
+    let mut rest = &packet[4..];
+    while rest.len() >= 4 {
+        let len = rest[1] as usize;
+        let payload = rest.get(4..4 + len * 4)?;
+        commands.push(Command {
+            code: rest[0],
+            data: [rest[2], rest[3]],
+            payload: payload
+                .chunks_exact(4)
+                .map(|word| u32::from_be_bytes([word[0], word[1], word[2], word[3]]))
+                .collect(),
+        });
+        rest = &rest[4 + len * 4..];
+    }
+    Some(commands)
+}
+
+fn encode_packet(commands: &[Command]) -> Vec<u8> {
+    let mut packet = SIGNATURE.to_vec();
+    for command in commands {
+        command.encode(&mut packet);
+    }
+    packet
+}
+
+/// UTF-8 text padded with zeros to whole words.
+fn text_words(text: &str) -> Vec<u32> {
+    text.as_bytes()
+        .chunks(4)
+        .map(|chunk| {
+            let mut word = [0u8; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            u32::from_be_bytes(word)
+        })
+        .collect()
+}
+
+fn words_text(words: &[u32]) -> String {
+    let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_be_bytes()).collect();
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+/// Whether sequence number `a` comes after `b`, allowing for wrap around.
+fn is_after(a: u16, b: u16) -> bool {
+    a != b && a.wrapping_sub(b) < 0x8000
+}
+
+/// A UMP endpoint connected to us.
+struct Client {
+    name: String,
+    last_seen: Instant,
+    /// Sequence number of the next UMP Data command sent.
+    seq: u16,
+    /// UMP Data commands sent, newest last.
+    sent: VecDeque<Command>,
+    /// Sequence number of the last UMP Data command received.
+    received: Option<u16>,
+    decoder: Decoder,
+}
+
+impl Client {
+    fn new(name: String) -> Self {
+        Client {
+            name,
+            last_seen: Instant::now(),
+            seq: 0,
+            sent: VecDeque::new(),
+            received: None,
+            decoder: Decoder::default(),
+        }
+    }
+}
+
+struct Host {
+    socket: Arc<UdpSocket>,
+    clients: HashMap<SocketAddr, Client>,
+    /// PeerId on each group after the first.
+    groups: [Option<String>; 16],
+    input: UnboundedSender<BridgeMidi>,
+}
+
+impl Host {
+    async fn send(&self, commands: &[Command], to: SocketAddr) {
+        if let Err(e) = self.socket.send_to(&encode_packet(commands), to).await {
+            warn!("Error sending to {}: {}", to, e);
+        }
+    }
+
+    async fn receive(&mut self, packet: &[u8], from: SocketAddr) {
+        let commands = match decode_packet(packet) {
+            Some(commands) => commands,
+            None => {
+                debug!("Unknown packet from {}", from);
+                return;
+            }
+        };
+        let mut replies = Vec::new();
+        for command in commands {
+            trace!("Command {:02x} from {}", command.code, from);
+            if let Some(client) = self.clients.get_mut(&from) {
+                client.last_seen = Instant::now();
+            }
+            match command.code {
+                INVITATION => {
+                    let name_len = command.data[0] as usize;
+                    let name = words_text(command.payload.get(..name_len).unwrap_or_default());
+                    info!("{} joined from {}", name, from);
+                    self.clients.insert(from, Client::new(name));
+                    let mut payload = text_words(ENDPOINT_NAME);
+                    let name_len = payload.len() as u8;
+                    payload.extend(text_words(PRODUCT_INSTANCE_ID));
+                    replies.push(Command::new(INVITATION_ACCEPTED, [name_len, 0], payload));
+                }
+                PING => replies.push(Command::new(PING_REPLY, [0, 0], command.payload)),
+                PING_REPLY => {}
+                UMP_DATA => self.receive_ump(&command, from),
+                RETRANSMIT_REQUEST => {
+                    let first = u16::from_be_bytes(command.data);
+                    let count = command.payload.first().map_or(1, |word| word >> 16) as u16;
+                    let client = match self.clients.get(&from) {
+                        Some(client) => client,
+                        None => continue,
+                    };
+                    let wanted: Vec<Command> = client
+                        .sent
+                        .iter()
+                        .filter(|sent| {
+                            let seq = u16::from_be_bytes(sent.data);
+                            seq.wrapping_sub(first) < count.max(1)
+                        })
+                        .cloned()
+                        .collect();
+                    match wanted.first() {
+                        Some(oldest) if oldest.data == command.data => replies.extend(wanted),
+                        _ => {
+                            debug!("Could not retransmit to {}", client.name);
+                            let oldest = client
+                                .sent
+                                .front()
+                                .map_or(client.seq, |sent| u16::from_be_bytes(sent.data));
+                            replies.push(Command::new(
+                                RETRANSMIT_ERROR,
+                                [0, RETRANSMIT_UNAVAILABLE],
+                                vec![(oldest as u32) << 16],
+                            ));
+                        }
+                    }
+                }
+                SESSION_RESET => {
+                    if let Some(client) = self.clients.get_mut(&from) {
+                        client.seq = 0;
+                        client.sent.clear();
+                        client.received = None;
+                        client.decoder = Decoder::default();
+                    }
+                    replies.push(Command::new(SESSION_RESET_REPLY, [0, 0], Vec::new()));
+                }
+                BYE => {
+                    if let Some(client) = self.clients.remove(&from) {
+                        info!("{} left", client.name);
+                    }
+                    replies.push(Command::new(BYE_REPLY, [0, 0], Vec::new()));
+                }
+                BYE_REPLY | NAK | RETRANSMIT_ERROR | SESSION_RESET_REPLY => {}
+                code => {
+                    debug!("Unsupported command {:02x} from {}", code, from);
+                    let header = u32::from_be_bytes([
+                        code,
+                        command.payload.len() as u8,
+                        command.data[0],
+                        command.data[1],
+                    ]);
+                    replies.push(Command::new(NAK, [NAK_NOT_SUPPORTED, 0], vec![header]));
+                }
+            }
+        }
+        if !replies.is_empty() {
+            self.send(&replies, from).await;
+        }
+    }
+
+    fn receive_ump(&mut self, command: &Command, from: SocketAddr) {
+        let client = match self.clients.get_mut(&from) {
+            Some(client) => client,
+            None => {
+                debug!("UMP from {} before its invitation", from);
+                return;
+            }
+        };
+        // Repeated commands from the redundancy of earlier packets
+        let seq = u16::from_be_bytes(command.data);
+        if let Some(received) = client.received {
+            if !is_after(seq, received) {
+                return;
+            }
+            let lost = seq.wrapping_sub(received).wrapping_sub(1);
+            if lost > 0 {
+                debug!("Lost {} UMP Data commands from {}", lost, client.name);
+            }
+        }
+        client.received = Some(seq);
+
+        let mut words = &command.payload[..];
+        while let Some(word) = words.first() {
+            let len = ump::packet_len(*word).min(words.len());
+            let group = Decoder::group(*word).unwrap_or(0) as usize;
+            let to = match group {
+                0 => None,
+                group => match &self.groups[group] {
+                    Some(peer_id) => Some(peer_id.clone()),
+                    None => {
+                        words = &words[len..];
+                        continue;
+                    }
+                },
+            };
+            for message in client.decoder.decode(&words[..len]) {
+                let _ = self.input.unbounded_send(BridgeMidi {
+                    to: to.clone(),
+                    message,
+                });
+            }
+            words = &words[len..];
+        }
+    }
+
+    /// Send UMP to every client, repeating the last few commands for the ones lost on the way.
+    async fn play(&mut self, words: Vec<u32>) {
+        let mut packets = Vec::new();
+        for (address, client) in self.clients.iter_mut() {
+            let mut commands: Vec<Command> = Vec::new();
+            for chunk in words.chunks(MAX_UMP_WORDS) {
+                let command = Command::ump_data(client.seq, chunk.to_vec());
+                client.seq = client.seq.wrapping_add(1);
+                client.sent.push_back(command.clone());
+                if client.sent.len() > HISTORY {
+                    client.sent.pop_front();
+                }
+                commands.push(command);
+            }
+            let repeated = client
+                .sent
+                .len()
+                .saturating_sub(commands.len() + REDUNDANCY);
+            let packet: Vec<Command> = client.sent.iter().skip(repeated).cloned().collect();
+            trace!("{} UMP Data commands to {}", packet.len(), client.name);
+            packets.push((*address, packet));
+        }
+        for (address, packet) in packets {
+            self.send(&packet, address).await;
+        }
+    }
+
+    async fn run(mut self, mut outgoing: UnboundedReceiver<BridgeEvent>) {
+        let (packet_sender, mut packets) = futures::channel::mpsc::unbounded();
+        let socket = self.socket.clone();
+        let reader = runtime::spawn(async move {
+            let mut buffer = [0u8; 1500];
+            loop {
+                match socket.recv_from(&mut buffer).await {
+                    Ok((len, from)) => {
+                        if packet_sender
+                            .unbounded_send((buffer[..len].to_vec(), from))
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                    Err(e) => warn!("Error receiving Network MIDI 2.0: {}", e),
+                }
+            }
+        });
+
+        let mut ping_timer = futures_timer::Delay::new(PING_INTERVAL).fuse();
+        loop {
+            futures::select! {
+                (packet, from) = packets.select_next_some() => {
+                    self.receive(&packet, from).await;
+                },
+                event = outgoing.next() => match event {
+                    Some(BridgeEvent::PeerJoined { peer_id, name }) => {
+                        match (1..16).find(|group| self.groups[*group].is_none()) {
+                            Some(group) => {
+                                info!("{} on group {}", name, group + 1);
+                                self.groups[group] = Some(peer_id);
+                            }
+                            None => debug!("No group left for {}", name),
+                        }
+                    }
+                    Some(BridgeEvent::PeerLeft { peer_id }) => {
+                        for group in self.groups.iter_mut() {
+                            if group.as_ref() == Some(&peer_id) {
+                                *group = None;
+                            }
+                        }
+                    }
+                    Some(BridgeEvent::PeerWaiting { .. } | BridgeEvent::CountIn { .. }) => {}
+                    Some(BridgeEvent::Midi { peer_id, message, .. }) => {
+                        let mut words = ump::from_midi1(&message, 0);
+                        if let Some(group) = self
+                            .groups
+                            .iter()
+                            .position(|group| group.as_ref() == Some(&peer_id))
+                        {
+                            words.extend(ump::from_midi1(&message, group as u8));
+                        }
+                        self.play(words).await;
+                    }
+                    None => break,
+                },
+                _ = ping_timer => {
+                    ping_timer = futures_timer::Delay::new(PING_INTERVAL).fuse();
+                    self.clients.retain(|_, client| {
+                        let alive = client.last_seen.elapsed() < CLIENT_TIMEOUT;
+                        if !alive {
+                            info!("{} timed out", client.name);
+                        }
+                        alive
+                    });
+                    let addresses: Vec<SocketAddr> = self.clients.keys().copied().collect();
+                    for address in addresses {
+                        let ping = Command::new(PING, [0, 0], vec![rand::random()]);
+                        self.send(&[ping], address).await;
+                    }
+                },
+            }
+        }
+
+        let addresses: Vec<SocketAddr> = self.clients.keys().copied().collect();
+        for address in addresses {
+            let bye = Command::new(BYE, [BYE_SHUTDOWN, 0], Vec::new());
+            self.send(&[bye], address).await;
+        }
+        reader.abort();
+    }
+}
+
+/// Serve a Network MIDI 2.0 endpoint on UDP `port`.
+pub fn start(
+    port: u16,
+    events: UnboundedReceiver<BridgeEvent>,
+    input: UnboundedSender<BridgeMidi>,
+) -> Result<(), Box<dyn Error>> {
+    let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))?;
+    socket.set_nonblocking(true)?;
+    let _runtime = runtime::enter();
+    let socket = Arc::new(UdpSocket::from_std(socket)?);
+    info!("Serving Network MIDI 2.0 on port {}", port);
+    let host = Host {
+        socket,
+        clients: HashMap::new(),
+        groups: Default::default(),
+        input,
+    };
+    runtime::spawn(host.run(events).instrument(info_span!("network_midi2")));
+    Ok(())
+}
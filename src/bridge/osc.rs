@@ -0,0 +1,297 @@
+//! OSC for controllers like TouchOSC or Lemur and for visual software. OSC values from 0 to 1 are
+//! turned into controllers, notes and pitch bends and back, through the addresses in the
+//! `osc_map:` section of the config file or else the default ones like `/midi/cc/1/74`.
+
+use futures::channel::mpsc::{UnboundedReceiver, UnboundedSender};
+use futures::stream::StreamExt;
+use rosc::{OscMessage, OscPacket, OscType};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::net::{Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tracing::{debug, info, info_span, trace, warn, Instrument};
+
+use super::{BridgeEvent, BridgeMidi};
+use crate::midi::{self, MessageKind};
+use crate::runtime;
+
+/// MIDI an OSC address stands for. Channels are numbered 1 to 16.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OscTarget {
+    ControlChange {
+        channel: u8,
+        controller: u8,
+    },
+    /// Values above 0 play the note with that velocity, 0 ends it.
+    Note {
+        channel: u8,
+        note: u8,
+    },
+    PitchBend {
+        channel: u8,
+    },
+}
+
+impl OscTarget {
+    /// The address used when the map has none for this target.
+    fn default_address(&self) -> String {
+        match self {
+            OscTarget::ControlChange {
+                channel,
+                controller,
+            } => format!("/midi/cc/{}/{}", channel, controller),
+            OscTarget::Note { channel, note } => format!("/midi/note/{}/{}", channel, note),
+            OscTarget::PitchBend { channel } => format!("/midi/pitchbend/{}", channel),
+        }
+    }
+
+    fn from_default_address(address: &str) -> Option<Self> {
+        let parts: Vec<&str> = address.trim_start_matches('/').split('/').collect();
+        let number = |i: usize| parts.get(i).and_then(|p| p.parse::<u8>().ok());
+        let target = match (parts.first(), parts.get(1)) {
+            (Some(&"midi"), Some(&"cc")) if parts.len() == 4 => OscTarget::ControlChange {
+                channel: number(2)?,
+                controller: number(3)?,
+            },
+            (Some(&"midi"), Some(&"note")) if parts.len() == 4 => OscTarget::Note {
+                channel: number(2)?,
+                note: number(3)?,
+            },
+            (Some(&"midi"), Some(&"pitchbend")) if parts.len() == 3 => OscTarget::PitchBend {
+                channel: number(2)?,
+            },
+            _ => return None,
+        };
+        target.is_valid().then_some(target)
+    }
+
+    fn is_valid(&self) -> bool {
+        let (channel, data) = match self {
+            OscTarget::ControlChange {
+                channel,
+                controller,
+            } => (*channel, *controller),
+            OscTarget::Note { channel, note } => (*channel, *note),
+            OscTarget::PitchBend { channel } => (*channel, 0),
+        };
+        (1..=16).contains(&channel) && data <= 127
+    }
+
+    /// The MIDI message setting this target to `value`, from 0 to 1.
+    fn to_midi(self, value: f32) -> Vec<u8> {
+        let value = value.clamp(0.0, 1.0);
+        match self {
+            OscTarget::ControlChange {
+                channel,
+                controller,
+            } => vec![
+                0xB0 | (channel - 1),
+                controller,
+                (value * 127.0).round() as u8,
+            ],
+            OscTarget::Note { channel, note } if value > 0.0 => {
+                vec![
+                    0x90 | (channel - 1),
+                    note,
+                    ((value * 127.0).round() as u8).max(1),
+                ]
+            }
+            OscTarget::Note { channel, note } => vec![0x80 | (channel - 1), note, 0],
+            OscTarget::PitchBend { channel } => {
+                let bend = (value * 16383.0).round() as u16;
+                vec![0xE0 | (channel - 1), (bend & 0x7F) as u8, (bend >> 7) as u8]
+            }
+        }
+    }
+
+    /// The target a MIDI message sets and the value it sets it to, from 0 to 1.
+    fn of_midi(message: &[u8]) -> Option<(Self, f32)> {
+        let channel = midi::channel(message)? + 1;
+        let data = |i: usize| message.get(i).copied();
+        match MessageKind::of(message)? {
+            MessageKind::ControlChange => Some((
+                OscTarget::ControlChange {
+                    channel,
+                    controller: data(1)?,
+                },
+                data(2)? as f32 / 127.0,
+            )),
+            MessageKind::NoteOn | MessageKind::NoteOff => {
+                let note = OscTarget::Note {
+                    channel,
+                    note: data(1)?,
+                };
+                match midi::is_silencing(message) {
+                    true => Some((note, 0.0)),
+                    false => Some((note, data(2)? as f32 / 127.0)),
+                }
+            }
+            MessageKind::PitchBend => {
+                let bend = (data(1)? as u16) | ((data(2)? as u16) << 7);
+                Some((OscTarget::PitchBend { channel }, bend as f32 / 16383.0))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Maps an OSC address to MIDI, from the `osc_map:` section of the config file.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct OscMapping {
+    /// OSC address, like `/1/fader1`.
+    pub address: String,
+    #[serde(flatten)]
+    pub target: OscTarget,
+}
+
+/// The first argument of an OSC message as a value from 0 to 1. Integers are read as MIDI values
+/// from 0 to 127.
+fn osc_value(args: &[OscType]) -> Option<f32> {
+    match args.first()? {
+        OscType::Float(value) => Some(*value),
+        OscType::Double(value) => Some(*value as f32),
+        OscType::Int(value) => Some(*value as f32 / 127.0),
+        OscType::Long(value) => Some(*value as f32 / 127.0),
+        OscType::Bool(value) => Some(if *value { 1.0 } else { 0.0 }),
+        _ => None,
+    }
+}
+
+/// The MIDI messages of an OSC packet, looking into bundles.
+fn packet_to_midi(map: &[OscMapping], packet: OscPacket, midi: &mut Vec<Vec<u8>>) {
+    match packet {
+        OscPacket::Message(message) => {
+            let target = map
+                .iter()
+                .find(|m| m.address == message.addr)
+                .map(|m| m.target)
+                .or_else(|| OscTarget::from_default_address(&message.addr));
+            match (target, osc_value(&message.args)) {
+                (Some(target), Some(value)) => midi.push(target.to_midi(value)),
+                _ => trace!("Ignoring OSC message {}", message.addr),
+            }
+        }
+        OscPacket::Bundle(bundle) => {
+            for packet in bundle.content {
+                packet_to_midi(map, packet, midi);
+            }
+        }
+    }
+}
+
+fn midi_to_osc(map: &[OscMapping], message: &[u8]) -> Option<OscMessage> {
+    let (target, value) = OscTarget::of_midi(message)?;
+    let addr = map
+        .iter()
+        .find(|m| m.target == target)
+        .map(|m| m.address.clone())
+        .unwrap_or_else(|| target.default_address());
+    Some(OscMessage {
+        addr,
+        args: vec![OscType::Float(value)],
+    })
+}
+
+/// Listen for OSC on `port` and send what peers play as OSC to every address in `send`.
+pub fn start(
+    port: Option<u16>,
+    send: &[String],
+    map: Vec<OscMapping>,
+    mut events: UnboundedReceiver<BridgeEvent>,
+    input: UnboundedSender<BridgeMidi>,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(mapping) = map.iter().find(|m| !m.target.is_valid()) {
+        return Err(format!(
+            "Invalid OSC mapping for {}: channels go from 1 to 16, notes and controllers from 0 to 127",
+            mapping.address
+        )
+        .into());
+    }
+    let targets = send
+        .iter()
+        .map(|address| {
+            address
+                .to_socket_addrs()
+                .ok()
+                .and_then(|mut a| a.next())
+                .ok_or_else(|| format!("Invalid OSC address {}", address))
+        })
+        .collect::<Result<Vec<SocketAddr>, String>>()?;
+
+    let _runtime = runtime::enter();
+    let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port.unwrap_or(0)))?;
+    socket.set_nonblocking(true)?;
+    let socket = Arc::new(UdpSocket::from_std(socket)?);
+    let map = Arc::new(map);
+
+    if port.is_some() {
+        info!("Listening for OSC on {}", socket.local_addr()?);
+        let socket = socket.clone();
+        let map = map.clone();
+        runtime::spawn(
+            async move {
+                let mut buffer = [0u8; rosc::decoder::MTU];
+                loop {
+                    let (len, from) = match socket.recv_from(&mut buffer).await {
+                        Ok(received) => received,
+                        Err(e) => {
+                            warn!("Error receiving OSC: {}", e);
+                            continue;
+                        }
+                    };
+                    let packet = match rosc::decoder::decode_udp(&buffer[..len]) {
+                        Ok((_, packet)) => packet,
+                        Err(e) => {
+                            debug!("Invalid OSC packet from {}: {:?}", from, e);
+                            continue;
+                        }
+                    };
+                    let mut midi = Vec::new();
+                    packet_to_midi(&map, packet, &mut midi);
+                    for message in midi {
+                        if input
+                            .unbounded_send(BridgeMidi { to: None, message })
+                            .is_err()
+                        {
+                            return;
+                        }
+                    }
+                }
+            }
+            .instrument(info_span!("osc")),
+        );
+    }
+
+    if !targets.is_empty() {
+        runtime::spawn(
+            async move {
+                while let Some(event) = events.next().await {
+                    let message = match event {
+                        BridgeEvent::Midi { message, .. } => message,
+                        _ => continue,
+                    };
+                    let osc = match midi_to_osc(&map, &message) {
+                        Some(osc) => osc,
+                        None => continue,
+                    };
+                    let packet = match rosc::encoder::encode(&OscPacket::Message(osc)) {
+                        Ok(packet) => packet,
+                        Err(e) => {
+                            warn!("Error encoding OSC: {:?}", e);
+                            continue;
+                        }
+                    };
+                    for target in &targets {
+                        if let Err(e) = socket.send_to(&packet, target).await {
+                            warn!("Error sending OSC to {}: {}", target, e);
+                        }
+                    }
+                }
+            }
+            .instrument(info_span!("osc")),
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,150 @@
+//! A pair of virtual MIDI ports for each connected peer, so apps can route to and from peers one
+//! by one: what a peer plays comes out of "From <name>" and what is played into "To <name>" goes
+//! to that peer alone. MIDI a peer labels with a track comes out of a port of its own for each
+//! track, opened the first time the track is heard.
+
+use futures::channel::mpsc::{UnboundedReceiver, UnboundedSender};
+#[cfg(all(feature = "midi", unix))]
+use midir::os::unix::{VirtualInput, VirtualOutput};
+#[cfg(all(feature = "midi", unix))]
+use midir::{Ignore, MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+#[cfg(all(feature = "midi", unix))]
+use std::collections::{hash_map::Entry, HashMap};
+use std::error::Error;
+#[cfg(all(feature = "midi", unix))]
+use tracing::{info, warn};
+
+use super::{BridgeEvent, BridgeMidi};
+#[cfg(all(feature = "midi", unix))]
+use crate::routing::{port_names, track_port_name};
+
+/// The ports of one peer, closed when dropped.
+#[cfg(all(feature = "midi", unix))]
+struct PeerPorts {
+    name: String,
+    from: MidiOutputConnection,
+    _to: MidiInputConnection<()>,
+    /// Ports of the tracks heard so far, by label.
+    tracks: HashMap<String, MidiOutputConnection>,
+}
+
+#[cfg(all(feature = "midi", unix))]
+impl PeerPorts {
+    /// The port MIDI on `track` comes out of, opening it the first time.
+    fn output(
+        &mut self,
+        track: Option<String>,
+    ) -> Result<&mut MidiOutputConnection, Box<dyn Error>> {
+        let track = match track {
+            Some(track) => track,
+            None => return Ok(&mut self.from),
+        };
+        match self.tracks.entry(track) {
+            Entry::Occupied(port) => Ok(port.into_mut()),
+            Entry::Vacant(entry) => {
+                let port = MidiOutput::new("p2pmidi")?
+                    .create_virtual(&track_port_name(&self.name, entry.key()))
+                    .map_err(|e| e.to_string())?;
+                info!(
+                    "Opened a virtual MIDI port for track {} of {}",
+                    entry.key(),
+                    self.name
+                );
+                Ok(entry.insert(port))
+            }
+        }
+    }
+}
+
+#[cfg(all(feature = "midi", unix))]
+fn open(
+    peer_id: &str,
+    name: &str,
+    input: UnboundedSender<BridgeMidi>,
+) -> Result<PeerPorts, Box<dyn Error>> {
+    let (to, from) = port_names(name);
+    let from = MidiOutput::new("p2pmidi")?
+        .create_virtual(&from)
+        .map_err(|e| e.to_string())?;
+    let mut midi_in = MidiInput::new("p2pmidi")?;
+    midi_in.ignore(Ignore::None);
+    let peer_id = peer_id.to_string();
+    let to = midi_in
+        .create_virtual(
+            &to,
+            move |_, message, _| {
+                let _ = input.unbounded_send(BridgeMidi {
+                    to: Some(peer_id.clone()),
+                    message: message.to_vec(),
+                });
+            },
+            (),
+        )
+        .map_err(|e| e.to_string())?;
+    Ok(PeerPorts {
+        name: name.to_string(),
+        from,
+        _to: to,
+        tracks: HashMap::new(),
+    })
+}
+
+/// Open the ports of each peer as it joins and close them when it leaves.
+#[cfg(all(feature = "midi", unix))]
+pub fn start(
+    events: UnboundedReceiver<BridgeEvent>,
+    input: UnboundedSender<BridgeMidi>,
+) -> Result<(), Box<dyn Error>> {
+    // MIDI connections stay on the thread that opened them
+    std::thread::Builder::new()
+        .name("virtual ports".to_string())
+        .spawn(move || {
+            let mut peers: HashMap<String, PeerPorts> = HashMap::new();
+            for event in futures::executor::block_on_stream(events) {
+                match event {
+                    BridgeEvent::PeerJoined { peer_id, name } => {
+                        match open(&peer_id, &name, input.clone()) {
+                            Ok(ports) => {
+                                info!("Opened virtual MIDI ports for {}", name);
+                                peers.insert(peer_id, ports);
+                            }
+                            Err(e) => warn!("No virtual MIDI ports for {}: {}", name, e),
+                        }
+                    }
+                    BridgeEvent::PeerLeft { peer_id } => {
+                        peers.remove(&peer_id);
+                    }
+                    BridgeEvent::PeerWaiting { .. } | BridgeEvent::CountIn { .. } => {}
+                    BridgeEvent::Midi {
+                        peer_id,
+                        message,
+                        track,
+                    } => {
+                        let ports = match peers.get_mut(&peer_id) {
+                            Some(ports) => ports,
+                            None => continue,
+                        };
+                        let sent = ports
+                            .output(track)
+                            .and_then(|port| port.send(&message).map_err(|e| e.into()));
+                        if let Err(e) = sent {
+                            warn!("Error playing MIDI from {}: {}", peer_id, e);
+                        }
+                    }
+                }
+            }
+        })?;
+    Ok(())
+}
+
+#[cfg(not(all(feature = "midi", unix)))]
+pub fn start(
+    _events: UnboundedReceiver<BridgeEvent>,
+    _input: UnboundedSender<BridgeMidi>,
+) -> Result<(), Box<dyn Error>> {
+    Err(crate::failure::Failure::Config(
+        "This build has no virtual MIDI ports, they need --features midi on Linux or macOS"
+            .to_string(),
+    )
+    .into())
+}
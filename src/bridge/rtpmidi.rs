@@ -0,0 +1,711 @@
+//! RTP-MIDI (AppleMIDI) sessions, as used by macOS Network MIDI, rtpMIDI on Windows and network
+//! MIDI interfaces.
+//!
+//! Every connected peer gets its own session, on the port pairs following the one configured, so
+//! they show up as separate devices. The configured port itself carries everyone at once. Sessions
+//! are not announced over Bonjour, add them by address in the directory of Audio MIDI Setup.
+
+use bytes::Bytes;
+use futures::channel::mpsc::{UnboundedReceiver, UnboundedSender};
+use futures::{future::FutureExt, stream::StreamExt};
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::{Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, info_span, trace, warn, Instrument};
+
+use super::{BridgeEvent, BridgeMidi};
+use crate::midi::data_len;
+use crate::runtime;
+
+const PROTOCOL_VERSION: u32 = 2;
+
+/// RTP payload type of MIDI, as used by Apple.
+const PAYLOAD_TYPE: u8 = 0x61;
+
+/// How often sessions we joined synchronize clocks.
+const SYNC_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often an unanswered invitation is sent again.
+const INVITE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Participants silent for this long are considered gone.
+const PARTICIPANT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Bytes of MIDI per packet, well below the MTU.
+const MAX_MIDI_LIST: usize = 1000;
+
+/// AppleMIDI session commands, sent on both the control and the data port.
+#[derive(Debug, Clone, PartialEq)]
+enum SessionCommand {
+    Invitation {
+        token: u32,
+        ssrc: u32,
+        name: String,
+    },
+    Accepted {
+        token: u32,
+        ssrc: u32,
+        name: String,
+    },
+    Rejected {
+        token: u32,
+        ssrc: u32,
+    },
+    End {
+        token: u32,
+        ssrc: u32,
+    },
+    /// Clock synchronization, timestamps in units of 100 µs.
+    Sync {
+        ssrc: u32,
+        count: u8,
+        timestamps: [u64; 3],
+    },
+    /// The last sequence number received, so the sender can trim its recovery journal.
+    ReceiverFeedback {
+        ssrc: u32,
+        seq: u16,
+    },
+}
+
+impl SessionCommand {
+    fn encode(&self) -> Vec<u8> {
+        let exchange = |command: &[u8; 2], token: u32, ssrc: u32, name: Option<&str>| {
+            let mut packet = vec![0xFF, 0xFF, command[0], command[1]];
+            packet.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+            packet.extend_from_slice(&token.to_be_bytes());
+            packet.extend_from_slice(&ssrc.to_be_bytes());
+            if let Some(name) = name {
+                packet.extend_from_slice(name.as_bytes());
+                packet.push(0);
+            }
+            packet
+        };
+        match self {
+            SessionCommand::Invitation { token, ssrc, name } => {
+                exchange(b"IN", *token, *ssrc, Some(name))
+            }
+            SessionCommand::Accepted { token, ssrc, name } => {
+                exchange(b"OK", *token, *ssrc, Some(name))
+            }
+            SessionCommand::Rejected { token, ssrc } => exchange(b"NO", *token, *ssrc, None),
+            SessionCommand::End { token, ssrc } => exchange(b"BY", *token, *ssrc, None),
+            SessionCommand::Sync {
+                ssrc,
+                count,
+                timestamps,
+            } => {
+                let mut packet = vec![0xFF, 0xFF, b'C', b'K'];
+                packet.extend_from_slice(&ssrc.to_be_bytes());
+                packet.extend_from_slice(&[*count, 0, 0, 0]);
+                for timestamp in timestamps {
+                    packet.extend_from_slice(&timestamp.to_be_bytes());
+                }
+                packet
+            }
+            SessionCommand::ReceiverFeedback { ssrc, seq } => {
+                let mut packet = vec![0xFF, 0xFF, b'R', b'S'];
+                packet.extend_from_slice(&ssrc.to_be_bytes());
+                packet.extend_from_slice(&((*seq as u32) << 16).to_be_bytes());
+                packet
+            }
+        }
+    }
+
+    fn decode(packet: &[u8]) -> Option<Self> {
+        if packet.get(0..2)? != [0xFF, 0xFF] {
+            return None;
+        }
+        let u32_at = |i: usize| Some(u32::from_be_bytes(packet.get(i..i + 4)?.try_into().ok()?));
+        let u64_at = |i: usize| Some(u64::from_be_bytes(packet.get(i..i + 8)?.try_into().ok()?));
+        let name = || {
+            let name = packet.get(16..).unwrap_or_default();
+            let end = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+            String::from_utf8_lossy(&name[..end]).into_owned()
+        };
+        match packet.get(2..4)? {
+            b"IN" => Some(SessionCommand::Invitation {
+                token: u32_at(8)?,
+                ssrc: u32_at(12)?,
+                name: name(),
+            }),
+            b"OK" => Some(SessionCommand::Accepted {
+                token: u32_at(8)?,
+                ssrc: u32_at(12)?,
+                name: name(),
+            }),
+            b"NO" => Some(SessionCommand::Rejected {
+                token: u32_at(8)?,
+                ssrc: u32_at(12)?,
+            }),
+            b"BY" => Some(SessionCommand::End {
+                token: u32_at(8)?,
+                ssrc: u32_at(12)?,
+            }),
+            b"CK" => Some(SessionCommand::Sync {
+                ssrc: u32_at(4)?,
+                count: *packet.get(8)?,
+                timestamps: [u64_at(12)?, u64_at(20)?, u64_at(28)?],
+            }),
+            b"RS" => Some(SessionCommand::ReceiverFeedback {
+                ssrc: u32_at(4)?,
+                seq: (u32_at(8)? >> 16) as u16,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// An RTP-MIDI packet playing all of `messages` at its timestamp, without a recovery journal.
+fn encode_midi(seq: u16, timestamp: u32, ssrc: u32, messages: &[Bytes]) -> Vec<u8> {
+    // Each message but the first has a delta time before it
+    let list_len: usize = messages
+        .iter()
+        .map(|m| m.len() + 1)
+        .sum::<usize>()
+        .saturating_sub(1);
+    let mut packet = Vec::with_capacity(14 + list_len);
+    packet.extend_from_slice(&[0x80, PAYLOAD_TYPE]);
+    packet.extend_from_slice(&seq.to_be_bytes());
+    packet.extend_from_slice(&timestamp.to_be_bytes());
+    packet.extend_from_slice(&ssrc.to_be_bytes());
+    match list_len {
+        0..=15 => packet.push(list_len as u8),
+        _ => packet.extend_from_slice(&[0x80 | ((list_len >> 8) as u8 & 0x0F), list_len as u8]),
+    }
+    for (i, message) in messages.iter().enumerate() {
+        // No delta time before the first message, zero before the others
+        if i > 0 {
+            packet.push(0);
+        }
+        packet.extend_from_slice(message);
+    }
+    packet
+}
+
+/// Sequence number and MIDI messages of an RTP-MIDI packet. Delta times and the recovery journal
+/// are ignored, and so are SysEx messages split over several packets.
+fn decode_midi(packet: &[u8]) -> Option<(u16, Vec<Vec<u8>>)> {
+    if packet.len() < 13 || packet[0] >> 6 != 2 || packet[1] & 0x7F != PAYLOAD_TYPE {
+        return None;
+    }
+    let seq = u16::from_be_bytes([packet[2], packet[3]]);
+    let header = packet[12];
+    let (len, start) = match header & 0x80 {
+        0 => ((header & 0x0F) as usize, 13),
+        _ => (
+            (((header & 0x0F) as usize) << 8) | *packet.get(13)? as usize,
+            14,
+        ),
+    };
+    let list = packet.get(start..start + len)?;
+    let has_first_delta = header & 0x20 != 0;
+
+    let mut messages = Vec::new();
+    let mut running_status = None;
+    let mut i = 0;
+    while i < list.len() {
+        if i > 0 || has_first_delta {
+            // Delta times take up to four bytes, all but the last with the high bit set
+            while *list.get(i)? & 0x80 != 0 {
+                i += 1;
+            }
+            i += 1;
+        }
+        let status = match list.get(i)? {
+            status if status & 0x80 != 0 => {
+                i += 1;
+                *status
+            }
+            _ => running_status?,
+        };
+        match status {
+            0xF0 => {
+                let end = i + list[i..].iter().position(|b| b & 0x80 != 0)?;
+                if list[end] == 0xF7 {
+                    let mut message = vec![0xF0];
+                    message.extend_from_slice(&list[i..=end]);
+                    messages.push(message);
+                } else {
+                    trace!("Skipping segmented SysEx");
+                }
+                running_status = None;
+                i = end + 1;
+            }
+            _ => {
+                let len = data_len(status);
+                let mut message = vec![status];
+                message.extend_from_slice(list.get(i..i + len)?);
+                messages.push(message);
+                i += len;
+                match status {
+                    0x80..=0xEF => running_status = Some(status),
+                    0xF0..=0xF7 => running_status = None,
+                    // Real time messages leave the running status alone
+                    _ => {}
+                }
+            }
+        }
+    }
+    Some((seq, messages))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Port {
+    Control,
+    Data,
+}
+
+#[derive(Debug)]
+struct Participant {
+    name: String,
+    control: SocketAddr,
+    data: Option<SocketAddr>,
+    last_seen: Instant,
+}
+
+/// One RTP-MIDI session, on a control port and the data port right after it.
+struct Endpoint {
+    name: String,
+    ssrc: u32,
+    token: u32,
+    control: Arc<UdpSocket>,
+    data: Arc<UdpSocket>,
+    start: Instant,
+    seq: u16,
+    participants: HashMap<u32, Participant>,
+    /// Peer the MIDI played into this session goes to, or every peer.
+    to: Option<String>,
+    input: UnboundedSender<BridgeMidi>,
+}
+
+impl Endpoint {
+    /// Bind the session's ports. Port 0 picks free ports, for sessions that only invite others.
+    fn bind(
+        port: u16,
+        name: String,
+        to: Option<String>,
+        input: UnboundedSender<BridgeMidi>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let bind = |port: u16| -> Result<Arc<UdpSocket>, Box<dyn Error>> {
+            let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))?;
+            socket.set_nonblocking(true)?;
+            Ok(Arc::new(UdpSocket::from_std(socket)?))
+        };
+        let control = bind(port)?;
+        let data = bind(match port {
+            0 => 0,
+            port => port
+                .checked_add(1)
+                .ok_or("RTP-MIDI needs the port after the control port")?,
+        })?;
+        Ok(Endpoint {
+            name,
+            ssrc: rand::random(),
+            token: rand::random(),
+            control,
+            data,
+            start: Instant::now(),
+            seq: rand::random(),
+            participants: HashMap::new(),
+            to,
+            input,
+        })
+    }
+
+    /// Run the session until the returned sender is dropped, playing what is sent to it.
+    fn spawn(self, invite: Option<SocketAddr>) -> UnboundedSender<Bytes> {
+        let (sender, receiver) = futures::channel::mpsc::unbounded();
+        let span = info_span!("rtp_midi", session = %self.name);
+        runtime::spawn(self.run(receiver, invite).instrument(span));
+        sender
+    }
+
+    /// Time in the units of RTP-MIDI timestamps, 100 µs.
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_micros() as u64 / 100
+    }
+
+    async fn send(&self, port: Port, command: SessionCommand, to: SocketAddr) {
+        let socket = match port {
+            Port::Control => &self.control,
+            Port::Data => &self.data,
+        };
+        if let Err(e) = socket.send_to(&command.encode(), to).await {
+            warn!("Error sending to {}: {}", to, e);
+        }
+    }
+
+    async fn run(mut self, mut outgoing: UnboundedReceiver<Bytes>, invite: Option<SocketAddr>) {
+        let (packet_sender, mut packets) = futures::channel::mpsc::unbounded();
+        let readers = [
+            read_packets(self.control.clone(), Port::Control, packet_sender.clone()),
+            read_packets(self.data.clone(), Port::Data, packet_sender),
+        ];
+        match self.control.local_addr() {
+            Ok(address) => info!("Serving RTP-MIDI on {}", address),
+            Err(e) => warn!("Serving RTP-MIDI on an unknown address: {}", e),
+        }
+
+        let mut invite_timer = futures_timer::Delay::new(Duration::ZERO).fuse();
+        let mut sync_timer = futures_timer::Delay::new(SYNC_INTERVAL).fuse();
+        loop {
+            futures::select! {
+                (port, packet, from) = packets.select_next_some() => {
+                    self.receive(port, &packet, from).await;
+                },
+                message = outgoing.next() => match message {
+                    Some(message) => {
+                        let mut messages = vec![message];
+                        while let Ok(Some(message)) = outgoing.try_next() {
+                            messages.push(message);
+                        }
+                        self.play(messages).await;
+                    }
+                    None => break,
+                },
+                _ = invite_timer => {
+                    invite_timer = futures_timer::Delay::new(INVITE_INTERVAL).fuse();
+                    if let Some(remote) = invite {
+                        self.invite(remote).await;
+                    }
+                },
+                _ = sync_timer => {
+                    sync_timer = futures_timer::Delay::new(SYNC_INTERVAL).fuse();
+                    self.participants.retain(|_, p| {
+                        let alive = p.last_seen.elapsed() < PARTICIPANT_TIMEOUT;
+                        if !alive {
+                            info!("{} timed out", p.name);
+                        }
+                        alive
+                    });
+                    // Whoever sent the invitation keeps the clocks in sync
+                    if let Some(data) = invite.and_then(|remote| self.data_address(remote)) {
+                        let sync = SessionCommand::Sync {
+                            ssrc: self.ssrc,
+                            count: 0,
+                            timestamps: [self.now(), 0, 0],
+                        };
+                        self.send(Port::Data, sync, data).await;
+                    }
+                },
+            }
+        }
+
+        for participant in self.participants.values() {
+            let end = SessionCommand::End {
+                token: self.token,
+                ssrc: self.ssrc,
+            };
+            self.send(Port::Control, end, participant.control).await;
+        }
+        for reader in readers {
+            reader.abort();
+        }
+    }
+
+    /// Data address of the participant reached on `control`, once it accepted both invitations.
+    fn data_address(&self, control: SocketAddr) -> Option<SocketAddr> {
+        self.participants
+            .values()
+            .find(|p| p.control == control)
+            .and_then(|p| p.data)
+    }
+
+    /// Invite the session at `remote` on whichever port did not accept yet.
+    async fn invite(&self, remote: SocketAddr) {
+        let invitation = SessionCommand::Invitation {
+            token: self.token,
+            ssrc: self.ssrc,
+            name: self.name.clone(),
+        };
+        match self.participants.values().find(|p| p.control == remote) {
+            None => self.send(Port::Control, invitation, remote).await,
+            Some(Participant { data: None, .. }) => {
+                let data = SocketAddr::new(remote.ip(), remote.port().wrapping_add(1));
+                self.send(Port::Data, invitation, data).await
+            }
+            Some(_) => {}
+        }
+    }
+
+    async fn receive(&mut self, port: Port, packet: &[u8], from: SocketAddr) {
+        let command = match SessionCommand::decode(packet) {
+            Some(command) => command,
+            None if port == Port::Data => {
+                self.receive_midi(packet);
+                return;
+            }
+            None => {
+                debug!("Unknown packet from {}", from);
+                return;
+            }
+        };
+        trace!("{:?} on {:?} port from {}", command, port, from);
+        match command {
+            SessionCommand::Invitation { token, ssrc, name } => {
+                let accepted = match port {
+                    Port::Control => {
+                        info!("{} joined", name);
+                        self.participants.insert(
+                            ssrc,
+                            Participant {
+                                name,
+                                control: from,
+                                data: None,
+                                last_seen: Instant::now(),
+                            },
+                        );
+                        true
+                    }
+                    Port::Data => match self.participants.get_mut(&ssrc) {
+                        Some(participant) => {
+                            participant.data = Some(from);
+                            true
+                        }
+                        None => false,
+                    },
+                };
+                let reply = match accepted {
+                    true => SessionCommand::Accepted {
+                        token,
+                        ssrc: self.ssrc,
+                        name: self.name.clone(),
+                    },
+                    false => SessionCommand::Rejected {
+                        token,
+                        ssrc: self.ssrc,
+                    },
+                };
+                self.send(port, reply, from).await;
+            }
+            SessionCommand::Accepted { ssrc, name, .. } => match port {
+                Port::Control => {
+                    info!("Joined {}", name);
+                    self.participants.insert(
+                        ssrc,
+                        Participant {
+                            name,
+                            control: from,
+                            data: None,
+                            last_seen: Instant::now(),
+                        },
+                    );
+                    self.invite(from).await;
+                }
+                Port::Data => {
+                    if let Some(participant) = self.participants.get_mut(&ssrc) {
+                        participant.data = Some(from);
+                    }
+                }
+            },
+            SessionCommand::Rejected { .. } => warn!("{} rejected the invitation", from),
+            SessionCommand::End { ssrc, .. } => {
+                if let Some(participant) = self.participants.remove(&ssrc) {
+                    info!("{} left", participant.name);
+                }
+            }
+            SessionCommand::Sync {
+                ssrc,
+                count,
+                mut timestamps,
+            } => {
+                if let Some(participant) = self.participants.get_mut(&ssrc) {
+                    participant.last_seen = Instant::now();
+                }
+                if count < 2 {
+                    timestamps[count as usize + 1] = self.now();
+                    let reply = SessionCommand::Sync {
+                        ssrc: self.ssrc,
+                        count: count + 1,
+                        timestamps,
+                    };
+                    self.send(Port::Data, reply, from).await;
+                } else {
+                    let rtt = timestamps[2].saturating_sub(timestamps[0]);
+                    debug!("Clock sync with {}, RTT {} µs", from, rtt * 100);
+                }
+            }
+            SessionCommand::ReceiverFeedback { .. } => {}
+        }
+    }
+
+    fn receive_midi(&mut self, packet: &[u8]) {
+        let ssrc = match packet.get(8..12) {
+            Some(ssrc) => u32::from_be_bytes([ssrc[0], ssrc[1], ssrc[2], ssrc[3]]),
+            None => return,
+        };
+        let participant = match self.participants.get_mut(&ssrc) {
+            Some(participant) => participant,
+            None => {
+                debug!("MIDI from unknown participant {:08x}", ssrc);
+                return;
+            }
+        };
+        participant.last_seen = Instant::now();
+        let (seq, messages) = match decode_midi(packet) {
+            Some(decoded) => decoded,
+            None => {
+                debug!("Invalid RTP-MIDI packet from {}", participant.name);
+                return;
+            }
+        };
+        trace!(
+            seq,
+            "{} MIDI messages from {}",
+            messages.len(),
+            participant.name
+        );
+        for message in messages {
+            let _ = self.input.unbounded_send(BridgeMidi {
+                to: self.to.clone(),
+                message,
+            });
+        }
+    }
+
+    /// Send MIDI to every participant, in as few packets as fit.
+    async fn play(&mut self, messages: Vec<Bytes>) {
+        let mut packets = Vec::new();
+        let mut batch: Vec<Bytes> = Vec::new();
+        let mut batch_len = 0;
+        let timestamp = self.now() as u32;
+        for message in messages {
+            if message.len() > MAX_MIDI_LIST {
+                warn!(
+                    "Dropping {} bytes of SysEx, too long for one packet",
+                    message.len()
+                );
+                continue;
+            }
+            if !batch.is_empty() && batch_len + message.len() + 1 > MAX_MIDI_LIST {
+                self.seq = self.seq.wrapping_add(1);
+                packets.push(encode_midi(self.seq, timestamp, self.ssrc, &batch));
+                batch.clear();
+                batch_len = 0;
+            }
+            batch_len += message.len() + 1;
+            batch.push(message);
+        }
+        if !batch.is_empty() {
+            self.seq = self.seq.wrapping_add(1);
+            packets.push(encode_midi(self.seq, timestamp, self.ssrc, &batch));
+        }
+
+        for participant in self.participants.values() {
+            if let Some(data) = participant.data {
+                for packet in &packets {
+                    if let Err(e) = self.data.send_to(packet, data).await {
+                        warn!("Error sending MIDI to {}: {}", participant.name, e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Forward every packet received on `socket`, until aborted.
+fn read_packets(
+    socket: Arc<UdpSocket>,
+    port: Port,
+    packets: UnboundedSender<(Port, Vec<u8>, SocketAddr)>,
+) -> JoinHandle<()> {
+    runtime::spawn(async move {
+        let mut buffer = [0u8; 1500];
+        loop {
+            match socket.recv_from(&mut buffer).await {
+                Ok((len, from)) => {
+                    if packets
+                        .unbounded_send((port, buffer[..len].to_vec(), from))
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+                Err(e) => warn!("Error receiving RTP-MIDI: {}", e),
+            }
+        }
+    })
+}
+
+/// Serve RTP-MIDI sessions from `port`, one carrying every peer and one more per connected peer,
+/// and join the sessions at `invite`.
+pub fn start(
+    port: Option<u16>,
+    invite: &[String],
+    mut events: UnboundedReceiver<BridgeEvent>,
+    input: UnboundedSender<BridgeMidi>,
+) -> Result<(), Box<dyn Error>> {
+    let _runtime = runtime::enter();
+    // Sessions hearing every peer
+    let mut mixes = Vec::new();
+    if let Some(port) = port {
+        let endpoint = Endpoint::bind(port, "p2pmidi".to_string(), None, input.clone())?;
+        mixes.push(endpoint.spawn(None));
+    }
+    for address in invite {
+        let remote = address
+            .to_socket_addrs()
+            .map_err(|e| format!("Invalid RTP-MIDI session address {}: {}", address, e))?
+            .next()
+            .ok_or_else(|| format!("RTP-MIDI session {} not found", address))?;
+        let endpoint = Endpoint::bind(0, "p2pmidi".to_string(), None, input.clone())?;
+        mixes.push(endpoint.spawn(Some(remote)));
+    }
+
+    runtime::spawn(async move {
+        // Session of each peer and the slot its ports come from
+        let mut peers: HashMap<String, (u16, UnboundedSender<Bytes>)> = HashMap::new();
+        while let Some(event) = events.next().await {
+            match event {
+                BridgeEvent::PeerJoined { peer_id, name } => {
+                    let port = match port {
+                        Some(port) => port,
+                        None => continue,
+                    };
+                    let slot = (1..)
+                        .find(|slot| !peers.values().any(|(used, _)| used == slot))
+                        .unwrap_or(1);
+                    let bound = slot
+                        .checked_mul(2)
+                        .and_then(|offset| port.checked_add(offset))
+                        .ok_or_else(|| "No ports left".into())
+                        .and_then(|port| {
+                            Endpoint::bind(
+                                port,
+                                format!("p2pmidi {}", name),
+                                Some(peer_id.clone()),
+                                input.clone(),
+                            )
+                        });
+                    match bound {
+                        Ok(endpoint) => {
+                            peers.insert(peer_id, (slot, endpoint.spawn(None)));
+                        }
+                        Err(e) => warn!("No RTP-MIDI session for {}: {}", name, e),
+                    }
+                }
+                // Dropping its sender ends the session
+                BridgeEvent::PeerLeft { peer_id } => {
+                    peers.remove(&peer_id);
+                }
+                BridgeEvent::PeerWaiting { .. } | BridgeEvent::CountIn { .. } => {}
+                BridgeEvent::Midi {
+                    peer_id, message, ..
+                } => {
+                    if let Some((_, session)) = peers.get(&peer_id) {
+                        let _ = session.unbounded_send(message.clone());
+                    }
+                    for mix in &mixes {
+                        let _ = mix.unbounded_send(message.clone());
+                    }
+                }
+            }
+        }
+    });
+    Ok(())
+}
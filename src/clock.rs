@@ -0,0 +1,220 @@
+//! Peer timestamps mapped to our clock. The clocks of two machines drift apart by tens of
+//! microseconds a second, which adds up over a session running all night, so their offset and skew
+//! are estimated all along and the mapping slews towards them instead of jumping.
+
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
+
+/// Samples are reduced to the fastest transit of each window, the one least delayed by queues.
+const WINDOW_US: i64 = 5_000_000;
+
+/// Windows the estimate is made from, five minutes of them.
+const WINDOWS: usize = 60;
+
+/// How fast the mapping may move away from the estimated skew to catch up, 0.5ms a second.
+const MAX_SLEW: f64 = 0.0005;
+
+/// Windows the estimate needs before how far off it may be is known.
+const MIN_QUALITY_WINDOWS: usize = 3;
+
+/// Uncertainty of the offset past which what is scheduled by the clock of a peer may be off.
+const POOR_UNCERTAINTY_US: f64 = 2_000.0;
+
+/// Offset further from the one applied than this, ten seconds, is a bogus timestamp rather than
+/// a clock that moved.
+const MAX_OFFSET_JUMP_US: f64 = 10_000_000.0;
+
+/// Time without a new window past which the estimate may have drifted away.
+const STALE_SECS: f64 = 60.0;
+
+/// How well the clock of a peer is known.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct ClockQuality {
+    /// Half the 95% confidence interval of the offset, unknown until a few windows are in.
+    pub uncertainty_us: Option<f64>,
+    pub skew_ppm: f64,
+    /// Seconds since the estimate last took in a window, unknown before the first one.
+    pub resync_age_secs: Option<f64>,
+    /// Windows the estimate is made from.
+    pub windows: usize,
+}
+
+impl ClockQuality {
+    /// Whether MIDI scheduled by the clock of the peer may play at the wrong time.
+    pub fn is_poor(&self) -> bool {
+        self.uncertainty_us
+            .map_or(true, |u| u > POOR_UNCERTAINTY_US)
+            || self.resync_age_secs.map_or(true, |age| age > STALE_SECS)
+    }
+}
+
+impl fmt::Display for ClockQuality {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.uncertainty_us, self.resync_age_secs) {
+            (Some(uncertainty), Some(age)) => write!(
+                f,
+                "clock ±{:.1} ms, {:.1} ppm, synced {:.0}s ago",
+                uncertainty / 1000.0,
+                self.skew_ppm,
+                age
+            ),
+            _ => write!(f, "clock syncing"),
+        }
+    }
+}
+
+/// Our clock minus the peer's, at a time on our clock.
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    local_us: i64,
+    offset_us: i64,
+}
+
+/// Maps the timestamps of one peer to our clock.
+#[derive(Debug, Clone, Default)]
+pub struct PeerClock {
+    /// Fastest transit of each past window.
+    windows: VecDeque<Sample>,
+    /// Fastest transit of the window in progress.
+    current: Option<Sample>,
+    window_start_us: i64,
+    /// Line through the windows: mean time, mean offset and skew.
+    fit: Option<(f64, f64, f64)>,
+    /// The offset applied, at the time it was last moved.
+    applied: Option<(i64, f64)>,
+    /// When the last window was taken into the fit.
+    fitted_at_us: Option<i64>,
+}
+
+impl PeerClock {
+    /// A frame sent at `remote_us` on the peer's clock arrived at `local_us` on ours. Returns false
+    /// and leaves the estimate alone when the timestamp is too far off to be believed.
+    pub fn observe(&mut self, local_us: i64, remote_us: i64) -> bool {
+        let sample = Sample {
+            local_us,
+            offset_us: local_us.saturating_sub(remote_us),
+        };
+        if let Some((_, offset)) = self.applied {
+            if (sample.offset_us as f64 - offset).abs() > MAX_OFFSET_JUMP_US {
+                return false;
+            }
+        }
+        match self.current {
+            None => {
+                self.current = Some(sample);
+                self.window_start_us = local_us;
+            }
+            Some(fastest) if sample.offset_us < fastest.offset_us => self.current = Some(sample),
+            Some(_) => {}
+        }
+        if local_us - self.window_start_us >= WINDOW_US {
+            if let Some(fastest) = self.current.take() {
+                if self.windows.len() == WINDOWS {
+                    self.windows.pop_front();
+                }
+                self.windows.push_back(fastest);
+                self.fit = fit(&self.windows);
+                self.fitted_at_us = Some(local_us);
+            }
+        }
+
+        self.applied = Some(match (self.applied, self.fit) {
+            // Until the first window closes follow the fastest transit seen
+            (None, _) | (Some(_), None) => {
+                let fastest = self.current.map_or(sample.offset_us, |c| c.offset_us);
+                (local_us, fastest as f64)
+            }
+            (Some((at, offset)), Some((mean_us, mean_offset, skew))) => {
+                let elapsed = (local_us - at).max(0) as f64;
+                let predicted = offset + skew * elapsed;
+                let target = mean_offset + skew * (local_us as f64 - mean_us);
+                let step = MAX_SLEW * elapsed;
+                (
+                    local_us,
+                    predicted + (target - predicted).clamp(-step, step),
+                )
+            }
+        });
+        true
+    }
+
+    /// When a frame sent at `remote_us` would have arrived without any delay on the way.
+    pub fn to_local(&self, remote_us: i64) -> i64 {
+        match self.applied {
+            Some((at, offset)) => {
+                let local_us = remote_us as f64 + offset;
+                (local_us + self.skew() * (local_us - at as f64)) as i64
+            }
+            None => remote_us,
+        }
+    }
+
+    /// How much faster our clock runs than the peer's.
+    pub fn skew(&self) -> f64 {
+        self.fit.map_or(0.0, |(_, _, skew)| skew)
+    }
+
+    pub fn skew_ppm(&self) -> f64 {
+        self.skew() * 1e6
+    }
+
+    /// How well the clock is known at `local_us` on ours.
+    pub fn quality(&self, local_us: i64) -> ClockQuality {
+        ClockQuality {
+            uncertainty_us: self
+                .fit
+                .and_then(|fit| uncertainty(&self.windows, fit, local_us)),
+            skew_ppm: self.skew_ppm(),
+            resync_age_secs: self
+                .fitted_at_us
+                .map(|at| (local_us - at).max(0) as f64 / 1e6),
+            windows: self.windows.len(),
+        }
+    }
+}
+
+/// Half the 95% confidence interval of the offset the fit gives at `local_us`, from how far the
+/// windows are off the line.
+fn uncertainty(
+    samples: &VecDeque<Sample>,
+    (mean_us, mean_offset, skew): (f64, f64, f64),
+    local_us: i64,
+) -> Option<f64> {
+    let n = samples.len();
+    if n < MIN_QUALITY_WINDOWS {
+        return None;
+    }
+    let (squares, spread) = samples.iter().fold((0.0, 0.0), |(squares, spread), s| {
+        let dx = s.local_us as f64 - mean_us;
+        let residual = s.offset_us as f64 - (mean_offset + skew * dx);
+        (squares + residual * residual, spread + dx * dx)
+    });
+    let error = (squares / (n - 2) as f64).sqrt();
+    let dx = local_us as f64 - mean_us;
+    let leverage = 1.0 / n as f64
+        + match spread > 0.0 {
+            true => dx * dx / spread,
+            false => 0.0,
+        };
+    Some(1.96 * error * leverage.sqrt())
+}
+
+/// Least squares line through the samples.
+fn fit(samples: &VecDeque<Sample>) -> Option<(f64, f64, f64)> {
+    let n = samples.len() as f64;
+    if n == 0.0 {
+        return None;
+    }
+    let mean_us = samples.iter().map(|s| s.local_us as f64).sum::<f64>() / n;
+    let mean_offset = samples.iter().map(|s| s.offset_us as f64).sum::<f64>() / n;
+    let (covariance, variance) = samples.iter().fold((0.0, 0.0), |(cov, var), s| {
+        let dx = s.local_us as f64 - mean_us;
+        (cov + dx * (s.offset_us as f64 - mean_offset), var + dx * dx)
+    });
+    let skew = match variance > 0.0 {
+        true => covariance / variance,
+        false => 0.0,
+    };
+    Some((mean_us, mean_offset, skew))
+}
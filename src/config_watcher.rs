@@ -0,0 +1,237 @@
+use futures::channel::mpsc::{unbounded, UnboundedReceiver};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use super::settings::Settings;
+
+/// What changed in the config file since it was last loaded.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConfigChange {
+    /// Settings that are applied to the running session.
+    pub applied: Vec<&'static str>,
+    /// Settings that only take effect after reconnecting.
+    pub needs_reconnect: Vec<&'static str>,
+}
+
+impl ConfigChange {
+    pub fn is_empty(&self) -> bool {
+        self.applied.is_empty() && self.needs_reconnect.is_empty()
+    }
+
+    /// Copy the settings that can change live from the reloaded file into the running settings.
+    pub fn apply(&self, settings: &mut Settings, reloaded: &Settings) {
+        settings.theme = reloaded.theme;
+        settings.performance_mode = reloaded.performance_mode;
+        settings.peers = reloaded.peers.clone();
+        settings.harmony = reloaded.harmony.clone();
+        settings.velocity_curve = reloaded.velocity_curve.clone();
+        settings.keybindings = reloaded.keybindings.clone();
+        settings.midi_bindings = reloaded.midi_bindings.clone();
+        settings.control_device = reloaded.control_device.clone();
+    }
+}
+
+/// Reloads a config file and tells which settings changed, comparing only with what was last read
+/// from the file so values given on the command line are not reported as changes.
+pub struct ConfigReloader {
+    path: PathBuf,
+    last: Settings,
+}
+
+impl ConfigReloader {
+    pub fn new(path: &Path) -> Self {
+        ConfigReloader {
+            path: path.to_path_buf(),
+            last: Settings::load(path).unwrap_or_default(),
+        }
+    }
+
+    pub fn reload(&mut self) -> Result<(Settings, ConfigChange), Box<dyn Error>> {
+        let reloaded = Settings::load(&self.path)?;
+        let old = &self.last;
+        let mut change = ConfigChange::default();
+
+        if old.theme != reloaded.theme {
+            change.applied.push("theme");
+        }
+        if old.performance_mode != reloaded.performance_mode {
+            change.applied.push("performance_mode");
+        }
+        if old.peers != reloaded.peers {
+            change.applied.push("peers");
+        }
+        if old.harmony != reloaded.harmony {
+            change.applied.push("harmony");
+        }
+        if old.velocity_curve != reloaded.velocity_curve {
+            change.applied.push("velocity_curve");
+        }
+        if old.keybindings != reloaded.keybindings {
+            change.applied.push("keybindings");
+        }
+        if old.midi_bindings != reloaded.midi_bindings {
+            change.applied.push("midi_bindings");
+        }
+        if old.control_device != reloaded.control_device {
+            change.applied.push("control_device");
+        }
+
+        if old.name != reloaded.name {
+            change.needs_reconnect.push("name");
+        }
+        if old.ip_addresses != reloaded.ip_addresses {
+            change.needs_reconnect.push("ip_addresses");
+        }
+        if old.port != reloaded.port {
+            change.needs_reconnect.push("port");
+        }
+        if old.bind_address != reloaded.bind_address {
+            change.needs_reconnect.push("bind_address");
+        }
+        if old.midi_device != reloaded.midi_device {
+            change.needs_reconnect.push("midi_device");
+        }
+        if old.track != reloaded.track {
+            change.needs_reconnect.push("track");
+        }
+        if old.midi_output != reloaded.midi_output {
+            change.needs_reconnect.push("midi_output");
+        }
+        if old.backpressure != reloaded.backpressure {
+            change.needs_reconnect.push("backpressure");
+        }
+        if old.priority_classes != reloaded.priority_classes {
+            change.needs_reconnect.push("priority_classes");
+        }
+        if old.max_inbound_rate != reloaded.max_inbound_rate {
+            change.needs_reconnect.push("max_inbound_rate");
+        }
+        if old.rate_limit_policy != reloaded.rate_limit_policy {
+            change.needs_reconnect.push("rate_limit_policy");
+        }
+        if old.archive != reloaded.archive {
+            change.needs_reconnect.push("archive");
+        }
+        if old.archive_share != reloaded.archive_share {
+            change.needs_reconnect.push("archive_share");
+        }
+        if old.publish != reloaded.publish {
+            change.needs_reconnect.push("publish");
+        }
+        if old.relay_token != reloaded.relay_token {
+            change.needs_reconnect.push("relay_token");
+        }
+        if old.thru != reloaded.thru {
+            change.needs_reconnect.push("thru");
+        }
+        if old.latency_mode != reloaded.latency_mode {
+            change.needs_reconnect.push("latency_mode");
+        }
+        if old.delay_bars != reloaded.delay_bars {
+            change.needs_reconnect.push("delay_bars");
+        }
+        if old.auto_accept != reloaded.auto_accept {
+            change.needs_reconnect.push("auto_accept");
+        }
+        if old.virtual_ports != reloaded.virtual_ports {
+            change.needs_reconnect.push("virtual_ports");
+        }
+        if old.rtp_midi_port != reloaded.rtp_midi_port {
+            change.needs_reconnect.push("rtp_midi_port");
+        }
+        if old.rtp_midi_invite != reloaded.rtp_midi_invite {
+            change.needs_reconnect.push("rtp_midi_invite");
+        }
+        if old.osc_port != reloaded.osc_port {
+            change.needs_reconnect.push("osc_port");
+        }
+        if old.osc_send != reloaded.osc_send {
+            change.needs_reconnect.push("osc_send");
+        }
+        if old.osc_map != reloaded.osc_map {
+            change.needs_reconnect.push("osc_map");
+        }
+        if old.gateway_address != reloaded.gateway_address {
+            change.needs_reconnect.push("gateway_address");
+        }
+        if old.ipmidi != reloaded.ipmidi {
+            change.needs_reconnect.push("ipmidi");
+        }
+        if old.network_midi2_port != reloaded.network_midi2_port {
+            change.needs_reconnect.push("network_midi2_port");
+        }
+        if old.status_mqtt != reloaded.status_mqtt {
+            change.needs_reconnect.push("status_mqtt");
+        }
+        if old.status_websocket != reloaded.status_websocket {
+            change.needs_reconnect.push("status_websocket");
+        }
+        if old.latency_alarm_ms != reloaded.latency_alarm_ms {
+            change.needs_reconnect.push("latency_alarm_ms");
+        }
+        if old.jack_transport != reloaded.jack_transport {
+            change.needs_reconnect.push("jack_transport");
+        }
+        if old.clock_output != reloaded.clock_output {
+            change.needs_reconnect.push("clock_output");
+        }
+        if old.auto_record != reloaded.auto_record {
+            change.needs_reconnect.push("auto_record");
+        }
+        if old.auto_record_keep != reloaded.auto_record_keep {
+            change.needs_reconnect.push("auto_record_keep");
+        }
+        if old.auto_record_days != reloaded.auto_record_days {
+            change.needs_reconnect.push("auto_record_days");
+        }
+        if old.swarm_key != reloaded.swarm_key {
+            change.needs_reconnect.push("swarm_key");
+        }
+        if old.metrics_address != reloaded.metrics_address {
+            change.needs_reconnect.push("metrics_address");
+        }
+        if old.relay_address != reloaded.relay_address {
+            change.needs_reconnect.push("relay_address");
+        }
+        if old.relay_port != reloaded.relay_port {
+            change.needs_reconnect.push("relay_port");
+        }
+        if old.relay_peer_id != reloaded.relay_peer_id {
+            change.needs_reconnect.push("relay_peer_id");
+        }
+        if old.relays != reloaded.relays {
+            change.needs_reconnect.push("relays");
+        }
+
+        self.last = reloaded.clone();
+        Ok((reloaded, change))
+    }
+}
+
+/// Watch a config file for modifications. The watcher stops when dropped.
+///
+/// The parent directory is watched since editors often replace the file instead of writing to it.
+pub fn watch_config(
+    path: &Path,
+) -> Result<(RecommendedWatcher, UnboundedReceiver<()>), Box<dyn Error>> {
+    let (sender, receiver) = unbounded();
+    let file_name = path.file_name().map(|n| n.to_os_string());
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+        if let Ok(event) = res {
+            let touches_config = event
+                .paths
+                .iter()
+                .any(|p| p.file_name().map(|n| n.to_os_string()) == file_name);
+            if touches_config && (event.kind.is_modify() || event.kind.is_create()) {
+                let _ = sender.unbounded_send(());
+            }
+        }
+    })?;
+    let dir = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    Ok((watcher, receiver))
+}
@@ -2,5 +2,8 @@ pub const RELAY_ADDRESS: &str = "p2pmidirelay.fly.dev";
 pub const RELAY_PORT: u16 = 8040;
 pub const DEFAULT_PORT: u16 = 8040;
 pub const DEFAULT_CONFIG_PATH: &str = "~/.config/p2pmidi/config.yml";
+pub const DATA_DIR: &str = "~/.local/share/p2pmidi";
+pub const PROFILES_DIR: &str = "~/.config/p2pmidi/profiles";
+pub const DEFAULT_PROFILE: &str = "default";
 pub const MAX_PORT_NUMBER: u16 = 65535;
 pub const USE_IPV6: bool = false;
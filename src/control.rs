@@ -0,0 +1,362 @@
+use futures::channel::mpsc::UnboundedSender;
+use futures::channel::oneshot;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+#[cfg(unix)]
+use std::io::{BufRead, BufReader, Write};
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+#[cfg(unix)]
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+#[cfg(unix)]
+use tracing::{debug, info, warn};
+
+#[cfg(unix)]
+use super::runtime;
+
+use super::p2p::playout::Timing;
+use super::settings::{CtlAction, FileAction, PresetAction, RecordAction};
+
+/// A command sent to a running daemon through its control socket, one JSON object per line.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "method", content = "params", rename_all = "snake_case")]
+pub enum ControlRequest {
+    Status,
+    /// Loss and late frame counters of the connected peers.
+    Stats,
+    /// Dial a multiaddr, or a PeerId through the relay.
+    Dial {
+        address: String,
+    },
+    Disconnect {
+        peer_id: String,
+    },
+    /// Drop the connections to a peer and dial it again through the relay, picking its transport
+    /// anew.
+    Renegotiate {
+        peer_id: String,
+    },
+    /// Let in a peer waiting to be accepted, remembering it.
+    Accept {
+        peer_id: String,
+    },
+    Reject {
+        peer_id: String,
+    },
+    /// Short authentication string to compare with a peer.
+    Sas {
+        peer_id: String,
+    },
+    /// Mark a peer verified once its short authentication string matched.
+    Verify {
+        peer_id: String,
+    },
+    /// An invite to hand out, with a token when it should expire or only work once.
+    Invite {
+        expires_in_secs: Option<u64>,
+        once: bool,
+    },
+    /// The last `limit` connection attempts, oldest first.
+    History {
+        limit: usize,
+    },
+    Panic,
+    /// Change the tempo from the next bar line on.
+    SetTempo {
+        tempo: f64,
+    },
+    /// Count a tap of tap tempo, changing the tempo once there are enough.
+    TapTempo,
+    /// Start every node at once after counting in this many beats.
+    StartTogether {
+        count_in: u32,
+    },
+    /// Record to a new file, saving the one recorded to until now.
+    RecordStart {
+        path: PathBuf,
+    },
+    RecordStop,
+    /// Save the routing of every peer as a preset, replacing one with the same name.
+    PresetSave {
+        name: String,
+    },
+    /// Switch the routing of every peer to a saved preset.
+    PresetLoad {
+        name: String,
+    },
+    PresetList,
+    PresetDelete {
+        name: String,
+    },
+    /// Offer a file to a peer, sending it once accepted.
+    FileSend {
+        peer_id: String,
+        path: PathBuf,
+    },
+    FileAccept {
+        peer_id: String,
+        id: u64,
+    },
+    FileDecline {
+        peer_id: String,
+        id: u64,
+    },
+    /// Files peers offered, waiting for an answer.
+    FileList,
+    /// Change the volume, mute or timing of a connected peer, by PeerId or name, until the config
+    /// file is reloaded.
+    PeerSet {
+        peer_id: String,
+        volume: Option<u8>,
+        muted: Option<bool>,
+        timing: Option<Timing>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ControlResponse {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "serde_json::Value::is_null")]
+    pub result: serde_json::Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ControlResponse {
+    pub fn ok(result: serde_json::Value) -> Self {
+        ControlResponse {
+            ok: true,
+            result,
+            error: None,
+        }
+    }
+
+    pub fn error(message: impl Into<String>) -> Self {
+        ControlResponse {
+            ok: false,
+            result: serde_json::Value::Null,
+            error: Some(message.into()),
+        }
+    }
+}
+
+/// A request waiting for the session to answer it.
+pub type PendingRequest = (ControlRequest, oneshot::Sender<ControlResponse>);
+
+/// Socket used when none is given, in the user's runtime directory when there is one or else in
+/// a directory of the user's own in the temporary directory.
+pub fn default_socket_path() -> PathBuf {
+    match std::env::var("XDG_RUNTIME_DIR") {
+        Ok(dir) if !dir.is_empty() => Path::new(&dir).join("p2pmidi.sock"),
+        _ => {
+            let user = std::env::var("USER").unwrap_or_else(|_| "default".to_string());
+            std::env::temp_dir()
+                .join(format!("p2pmidi-{}", user))
+                .join("p2pmidi.sock")
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn handle_connection(
+    stream: tokio::net::UnixStream,
+    requests: UnboundedSender<PendingRequest>,
+) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = tokio::io::BufReader::new(reader).lines();
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(l)) if l.trim().is_empty() => continue,
+            Ok(Some(l)) => l,
+            Ok(None) | Err(_) => break,
+        };
+        debug!("Control request: {}", line);
+        let response = match serde_json::from_str::<ControlRequest>(&line) {
+            Ok(request) => {
+                let (sender, receiver) = oneshot::channel();
+                match requests.unbounded_send((request, sender)) {
+                    Ok(_) => receiver
+                        .await
+                        .unwrap_or_else(|_| ControlResponse::error("Session is shutting down")),
+                    Err(_) => ControlResponse::error("Session is shutting down"),
+                }
+            }
+            Err(e) => ControlResponse::error(format!("Invalid request: {}", e)),
+        };
+        let line = serde_json::to_string(&response).unwrap_or_default() + "\n";
+        if writer.write_all(line.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Listen for control connections on a Unix socket in a background task, forwarding requests to
+/// the session.
+#[cfg(unix)]
+pub fn serve(
+    socket_path: &Path,
+    requests: UnboundedSender<PendingRequest>,
+) -> Result<(), Box<dyn Error>> {
+    // Only we may reach the socket, through a directory of ours when it needs one
+    if let Some(dir) = socket_path.parent().filter(|dir| !dir.exists()) {
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)?;
+    }
+    // A socket left behind by a previous run would make binding fail
+    if socket_path.exists() {
+        if UnixStream::connect(socket_path).is_ok() {
+            return Err(format!("{} is already in use", socket_path.display()).into());
+        }
+        std::fs::remove_file(socket_path)?;
+    }
+    let _runtime = runtime::enter();
+    let listener = tokio::net::UnixListener::bind(socket_path)?;
+    std::fs::set_permissions(socket_path, std::fs::Permissions::from_mode(0o600))?;
+    info!("Control socket listening on {}", socket_path.display());
+
+    runtime::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    runtime::spawn(handle_connection(stream, requests.clone()));
+                }
+                Err(e) => warn!("Error accepting control connection: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn serve(
+    _socket_path: &Path,
+    _requests: UnboundedSender<PendingRequest>,
+) -> Result<(), Box<dyn Error>> {
+    Err("Control sockets are only supported on Unix".into())
+}
+
+/// Send one request to a running daemon and wait for its response.
+#[cfg(unix)]
+pub fn send_request(
+    socket_path: &Path,
+    request: &ControlRequest,
+) -> Result<ControlResponse, Box<dyn Error>> {
+    let mut stream = UnixStream::connect(socket_path).map_err(|e| {
+        format!(
+            "Could not connect to the daemon at {}: {}",
+            socket_path.display(),
+            e
+        )
+    })?;
+    writeln!(stream, "{}", serde_json::to_string(request)?)?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    Ok(serde_json::from_str(&line)?)
+}
+
+#[cfg(not(unix))]
+pub fn send_request(
+    _socket_path: &Path,
+    _request: &ControlRequest,
+) -> Result<ControlResponse, Box<dyn Error>> {
+    Err("Control sockets are only supported on Unix".into())
+}
+
+/// Run a `p2pmidi ctl` subcommand, printing the daemon response as JSON.
+pub fn run_ctl_command(socket: Option<PathBuf>, action: &CtlAction) -> Result<(), Box<dyn Error>> {
+    let request = match action {
+        CtlAction::Status => ControlRequest::Status,
+        CtlAction::Stats => ControlRequest::Stats,
+        CtlAction::Dial { address } => ControlRequest::Dial {
+            address: address.clone(),
+        },
+        CtlAction::Disconnect { peer_id } => ControlRequest::Disconnect {
+            peer_id: peer_id.clone(),
+        },
+        CtlAction::Renegotiate { peer_id } => ControlRequest::Renegotiate {
+            peer_id: peer_id.clone(),
+        },
+        CtlAction::Accept { peer_id } => ControlRequest::Accept {
+            peer_id: peer_id.clone(),
+        },
+        CtlAction::Reject { peer_id } => ControlRequest::Reject {
+            peer_id: peer_id.clone(),
+        },
+        CtlAction::Sas { peer_id } => ControlRequest::Sas {
+            peer_id: peer_id.clone(),
+        },
+        CtlAction::Verify { peer_id } => ControlRequest::Verify {
+            peer_id: peer_id.clone(),
+        },
+        CtlAction::Invite { expires, once } => ControlRequest::Invite {
+            expires_in_secs: expires.map(|e| e.as_secs()),
+            once: *once,
+        },
+        CtlAction::History { limit } => ControlRequest::History { limit: *limit },
+        CtlAction::Panic => ControlRequest::Panic,
+        CtlAction::Tempo { bpm, tap } => match bpm {
+            Some(tempo) if !tap => ControlRequest::SetTempo { tempo: *tempo },
+            _ => ControlRequest::TapTempo,
+        },
+        CtlAction::Start { count_in } => ControlRequest::StartTogether {
+            count_in: *count_in,
+        },
+        CtlAction::Record {
+            action: RecordAction::Start { path },
+        } => ControlRequest::RecordStart { path: path.clone() },
+        CtlAction::Record {
+            action: RecordAction::Stop,
+        } => ControlRequest::RecordStop,
+        CtlAction::Preset { action } => match action {
+            PresetAction::Save { name } => ControlRequest::PresetSave { name: name.clone() },
+            PresetAction::Load { name } => ControlRequest::PresetLoad { name: name.clone() },
+            PresetAction::List => ControlRequest::PresetList,
+            PresetAction::Delete { name } => ControlRequest::PresetDelete { name: name.clone() },
+        },
+        CtlAction::File { action } => match action {
+            // The daemon may run from another directory
+            FileAction::Send { peer_id, path } => ControlRequest::FileSend {
+                peer_id: peer_id.clone(),
+                path: std::fs::canonicalize(path)
+                    .map_err(|e| format!("Error reading {}: {}", path.display(), e))?,
+            },
+            FileAction::Accept { peer_id, id } => ControlRequest::FileAccept {
+                peer_id: peer_id.clone(),
+                id: *id,
+            },
+            FileAction::Decline { peer_id, id } => ControlRequest::FileDecline {
+                peer_id: peer_id.clone(),
+                id: *id,
+            },
+            FileAction::List => ControlRequest::FileList,
+        },
+        CtlAction::Peer {
+            peer_id,
+            volume,
+            mute,
+            unmute,
+            timing,
+        } => ControlRequest::PeerSet {
+            peer_id: peer_id.clone(),
+            volume: *volume,
+            muted: match (mute, unmute) {
+                (true, _) => Some(true),
+                (_, true) => Some(false),
+                _ => None,
+            },
+            timing: *timing,
+        },
+    };
+    let socket = socket.unwrap_or_else(default_socket_path);
+    let response = send_request(&socket, &request)?;
+    println!("{}", serde_json::to_string_pretty(&response)?);
+    match response.error {
+        Some(e) => Err(e.into()),
+        None => Ok(()),
+    }
+}
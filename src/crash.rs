@@ -0,0 +1,95 @@
+use serde_json::Value;
+use std::backtrace::Backtrace;
+use std::fmt::Write as _;
+use std::panic::PanicHookInfo;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::logging;
+use super::settings::Settings;
+use super::storage::Storage;
+
+/// Settings whose name contains one of these are left out of crash reports.
+const SECRET_WORDS: [&str; 5] = ["secret", "password", "passphrase", "token", "key"];
+
+/// Name of the file pointing at the crash report to show on the next GUI launch.
+const LAST_CRASH_FILE: &str = "last_crash";
+
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (name, value) in map.iter_mut() {
+                let name = name.to_lowercase();
+                // Keybindings are shortcuts, not keys
+                if name != "keybindings" && SECRET_WORDS.iter().any(|w| name.contains(w)) {
+                    *value = Value::String("<redacted>".to_string());
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+fn crash_report(info: &PanicHookInfo, settings: &Value) -> String {
+    let mut report = String::new();
+    let _ = writeln!(report, "p2pmidi {} crashed", env!("CARGO_PKG_VERSION"));
+    let _ = writeln!(
+        report,
+        "Platform: {} {}",
+        std::env::consts::OS,
+        std::env::consts::ARCH
+    );
+    let _ = writeln!(report, "\n{}", info);
+    let _ = writeln!(report, "\nBacktrace:\n{}", Backtrace::force_capture());
+    let _ = writeln!(
+        report,
+        "Settings:\n{}",
+        serde_json::to_string_pretty(settings).unwrap_or_default()
+    );
+    let _ = writeln!(report, "\nRecent log:");
+    for line in logging::recent_lines() {
+        let _ = writeln!(report, "{}", line);
+    }
+    report
+}
+
+fn write_report(dir: &Path, report: &str) -> std::io::Result<PathBuf> {
+    let crashes = dir.join("crashes");
+    std::fs::create_dir_all(&crashes)?;
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let path = crashes.join(format!("crash-{}.txt", timestamp));
+    std::fs::write(&path, report)?;
+    std::fs::write(dir.join(LAST_CRASH_FILE), path.display().to_string())?;
+    Ok(path)
+}
+
+/// Write a crash report to the data directory whenever the program panics, before the default
+/// panic message is printed.
+pub fn install_panic_hook(storage: &Storage, settings: &Settings) {
+    let dir = storage.dir().to_path_buf();
+    let mut settings = serde_json::to_value(settings).unwrap_or_default();
+    redact(&mut settings);
+
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        match write_report(&dir, &crash_report(info, &settings)) {
+            Ok(path) => eprintln!("Crash report saved to {}", path.display()),
+            Err(e) => eprintln!("Could not save a crash report: {}", e),
+        }
+        default_hook(info);
+    }));
+}
+
+/// The crash report of a previous run that was not shown yet, if any.
+pub fn take_last_crash(storage: &Storage) -> Option<PathBuf> {
+    let marker = storage.dir().join(LAST_CRASH_FILE);
+    let path = std::fs::read_to_string(&marker).ok()?;
+    let _ = std::fs::remove_file(marker);
+    Some(PathBuf::from(path.trim()))
+}
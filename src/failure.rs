@@ -0,0 +1,64 @@
+use std::error::Error;
+use std::fmt;
+
+use super::output::{Report, Reporter};
+
+/// Exit codes listed in `--help`, matching [`Failure::exit_code`].
+pub const EXIT_CODES_HELP: &str = "Exit codes:
+  1  runtime failure
+  2  configuration error
+  3  relay unreachable
+  4  peer unreachable
+  5  MIDI device missing";
+
+/// Why the program stopped early. Each kind has its own exit code so scripts can tell them apart.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Failure {
+    Config(String),
+    RelayUnreachable(String),
+    PeerUnreachable(String),
+    MidiDeviceMissing(String),
+    Runtime(String),
+}
+
+impl Failure {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Failure::Runtime(_) => 1,
+            Failure::Config(_) => 2,
+            Failure::RelayUnreachable(_) => 3,
+            Failure::PeerUnreachable(_) => 4,
+            Failure::MidiDeviceMissing(_) => 5,
+        }
+    }
+
+    /// Keep the kind of errors that already are failures, anything else is a runtime failure.
+    pub fn from_error(error: Box<dyn Error>) -> Self {
+        match error.downcast::<Failure>() {
+            Ok(failure) => *failure,
+            Err(error) => Failure::Runtime(error.to_string()),
+        }
+    }
+
+    /// Report the failure and exit with its code.
+    pub fn exit(self, reporter: &Reporter) -> ! {
+        reporter.report(Report::Error {
+            message: self.to_string(),
+        });
+        std::process::exit(self.exit_code())
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Failure::Config(e) => write!(f, "Configuration error: {}", e),
+            Failure::RelayUnreachable(e) => write!(f, "Relay unreachable: {}", e),
+            Failure::PeerUnreachable(e) => write!(f, "Peer unreachable: {}", e),
+            Failure::MidiDeviceMissing(e) => write!(f, "MIDI device missing: {}", e),
+            Failure::Runtime(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for Failure {}
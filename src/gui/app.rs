@@ -0,0 +1,1040 @@
+use crate::config_watcher::ConfigReloader;
+use crate::constants;
+use crate::keybindings::{Action, ActionTable, KeyBinding, MidiControl, MidiTable, MidiTarget};
+use crate::logging::LogLine;
+use crate::midi::{connect_input, get_midi_list, InputConnection};
+use crate::p2p::client::{ClientOptions, Mode};
+use crate::p2p::directory::{self, ListOptions, OpenSession};
+use crate::p2p::troubleshoot::{self, CheckResult, TroubleshootOptions};
+use crate::ring;
+use crate::routing::MidiRouter;
+use crate::session::{Session, SessionEvent};
+use crate::smf::{self, FilePlayer};
+use crate::storage::{RecentSession, Storage};
+use crate::transport::DEFAULT_COUNT_IN;
+use crate::validation::describe_errors;
+use libp2p::identity::Keypair;
+use libp2p::PeerId;
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{info, warn};
+
+use super::components::{
+    AddressList, AddressListMessage, ChannelActivity, ControllerGraphs, ControllerGraphsMessage,
+    FileQueue, LogPanel, Notices, PeerPanel, PeerPanelMessage, SaveAs, SaveAsMessage,
+    PERFORMANCE_REFRESH_INTERVAL,
+};
+use super::monitor_window::{DetachedMonitor, MonitorLine};
+use super::screens::{self, Screen};
+use super::subscription::{self, ControlEvents, SessionBatch, SessionEvents};
+use super::theme;
+use crate::settings;
+use iced::{executor, Application, Command, Theme};
+use midir::MidiOutput;
+
+/// How often the peers panel asks the session how each peer is reached.
+const SESSION_STATUS_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How long each connection check waits for an answer.
+const TROUBLESHOOT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Session events shown in the timeline.
+const TIMELINE_EVENTS: usize = 500;
+
+/// How often the monitor redraws, turning off the lights of channels gone quiet.
+pub(super) const MONITOR_REFRESH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often the progress of a dropped MIDI file is redrawn.
+const PLAYBACK_REFRESH_INTERVAL: Duration = Duration::from_millis(200);
+
+/// MIDI messages of the control device queued for the window.
+const CONTROL_QUEUE_CAPACITY: usize = 256;
+
+pub(super) struct AppFlags {
+    pub(super) settings: settings::Settings,
+    pub(super) config_path: PathBuf,
+    /// Crash report of the previous run, shown on start.
+    pub(super) last_crash: Option<PathBuf>,
+    pub(super) storage: Storage,
+}
+
+impl std::default::Default for AppFlags {
+    fn default() -> Self {
+        Self {
+            settings: settings::Settings::default(),
+            config_path: PathBuf::from(constants::DEFAULT_CONFIG_PATH),
+            last_crash: None,
+            storage: Storage::new(None),
+        }
+    }
+}
+
+/// The MIDI devices to pick from, or why the MIDI backend could not be opened.
+fn midi_devices() -> Result<Vec<String>, String> {
+    let midi_output = MidiOutput::new("midir test output").map_err(|e| e.to_string())?;
+    Ok(get_midi_list(&midi_output))
+}
+
+#[derive(Debug, Clone)]
+pub(super) enum Message {
+    SettingsChanged(settings::Settings),
+    RelayPortChanged(u16),
+    AppPortChanged(u16),
+    DelayBarsChanged(u32),
+    Connect,
+    /// Open the MIDI backend again and list its devices.
+    ReloadMidiDevices,
+    /// Go back to the default relay when none is set.
+    UseDefaultRelay,
+    SaveSettings,
+    SaveAs(SaveAsMessage),
+    Addresses(AddressListMessage),
+    ResetSettings,
+    ConfigFileChanged,
+    KeyPressed(KeyBinding),
+    Panic,
+    ToggleMute,
+    /// Let in a peer waiting to join.
+    AcceptPeer(String),
+    /// Turn away a peer waiting to join.
+    RejectPeer(String),
+    Log(LogLine),
+    Session(SessionEvent),
+    /// Session events gathered since the last redraw, in performance mode.
+    SessionBatch(Vec<SessionEvent>),
+    SessionEnded,
+    /// Time to ask the session how its peers are doing.
+    SessionTick,
+    PeerPanel(PeerPanelMessage),
+    ControllerGraphs(ControllerGraphsMessage),
+    ShowHistory,
+    HideHistory,
+    ShowMonitor,
+    HideMonitor,
+    /// Time to redraw the monitor.
+    MonitorTick,
+    /// Move the monitor to a window of its own.
+    DetachMonitor,
+    /// Close the monitor window and show the monitor here again.
+    AttachMonitor,
+    ShowTimeline,
+    HideTimeline,
+    BrowseSessions,
+    SessionsListed(Result<Vec<OpenSession>, String>),
+    JoinSession(OpenSession),
+    HideSessions,
+    ShowRecent,
+    HideRecent,
+    /// Join a recent session again, with the settings it was joined with.
+    Rejoin(RecentSession),
+    Troubleshoot,
+    Troubleshot(Result<Vec<CheckResult>, String>),
+    HideTroubleshoot,
+    TapTempo,
+    /// Start every peer at once after counting in.
+    StartTogether,
+    ControlDeviceChanged(String),
+    /// MIDI played on the control device.
+    ControlMidi(Vec<u8>),
+    /// A file was dropped on the window.
+    FileDropped(PathBuf),
+    /// Time to redraw the progress of the MIDI file playing and start the next one.
+    PlaybackTick,
+    CancelPlayback,
+    LearnChoice(MidiTarget),
+    /// Bind the next control moved on the control device to a target.
+    Learn(MidiTarget),
+}
+
+pub(super) struct App {
+    initial_settings: settings::Settings,
+    pub(super) app_flags: AppFlags,
+    pub(super) notices: Notices,
+    pub(super) midi_devices: Vec<String>,
+    /// Why the MIDI backend could not be opened, until a reload opens it.
+    pub(super) midi_error: Option<String>,
+    pub(super) addresses: AddressList,
+    pub(super) save_as: SaveAs,
+    pub(super) log: LogPanel,
+    pub(super) peers: PeerPanel,
+    pub(super) channels: ChannelActivity,
+    pub(super) graphs: ControllerGraphs,
+    pub(super) files: FileQueue,
+    /// The monitor popped out into a window of its own.
+    pub(super) monitor_window: Option<DetachedMonitor>,
+    pub(super) screen: Screen,
+    pub(super) session: Option<Session>,
+    session_events: SessionEvents,
+    session_batch: SessionBatch,
+    /// Sessions started so far, telling their subscriptions apart.
+    sessions: u64,
+    config_reloader: ConfigReloader,
+    actions: ActionTable,
+    muted: bool,
+    midi_table: MidiTable,
+    /// Target the next control moved is bound to.
+    pub(super) learning: Option<MidiTarget>,
+    pub(super) learn_choice: Option<MidiTarget>,
+    pub(super) presets: Vec<String>,
+    /// Peers waiting to be let in, by PeerId with the name they gave.
+    pub(super) waiting: Vec<(String, Option<String>)>,
+    control_input: Option<InputConnection>,
+    control_events: ControlEvents,
+    /// Control devices opened so far, telling their subscriptions apart.
+    control_devices: u64,
+}
+
+/// Options for a session with the settings in the window, dialing the configured addresses or
+/// waiting for peers to join.
+fn session_options(flags: &AppFlags) -> Result<ClientOptions, Box<dyn Error>> {
+    let local_key = flags
+        .storage
+        .load_identity(&flags.storage.identity_path())?;
+    ClientOptions::from_settings(
+        &flags.settings,
+        flags.config_path.clone(),
+        local_key,
+        flags.storage.clone(),
+    )
+}
+
+/// Options to list the sessions open on the relay in the settings.
+fn list_options(flags: &AppFlags) -> Result<(ListOptions, Keypair), Box<dyn Error>> {
+    let settings = &flags.settings;
+    let options = ListOptions {
+        relay_address: settings
+            .relay_address
+            .clone()
+            .unwrap_or_else(|| constants::RELAY_ADDRESS.to_string()),
+        relay_port: settings.relay_port.unwrap_or(constants::RELAY_PORT),
+        relay_peer_id: match &settings.relay_peer_id {
+            Some(peer_id) => Some(peer_id.parse()?),
+            None => None,
+        },
+        use_ipv6: constants::USE_IPV6,
+        bind_address: settings.bind_address,
+        swarm_key: match &settings.swarm_key {
+            Some(path) => Some(crate::p2p::swarm_key::load(path)?),
+            None => None,
+        },
+        token: settings.relay_token.clone(),
+    };
+    let local_key = flags
+        .storage
+        .load_identity(&flags.storage.identity_path())?;
+    Ok((options, local_key))
+}
+
+/// List the sessions open on the relay on a thread of its own, it takes connecting to the relay.
+async fn list_sessions(
+    options: ListOptions,
+    local_key: Keypair,
+) -> Result<Vec<OpenSession>, String> {
+    let (sender, receiver) = futures::channel::oneshot::channel();
+    std::thread::spawn(move || {
+        let _ =
+            sender.send(directory::list_sessions(options, local_key).map_err(|e| e.to_string()));
+    });
+    receiver
+        .await
+        .unwrap_or_else(|_| Err("Listing sessions stopped".to_string()))
+}
+
+/// Options to check the way to the relay in the settings, hole punching to the first peer to dial
+/// given by PeerId.
+fn troubleshoot_options(flags: &AppFlags) -> Result<TroubleshootOptions, Box<dyn Error>> {
+    let settings = &flags.settings;
+    let book = flags.storage.address_book()?;
+    Ok(TroubleshootOptions {
+        relay_address: settings
+            .relay_address
+            .clone()
+            .unwrap_or_else(|| constants::RELAY_ADDRESS.to_string()),
+        relay_port: settings.relay_port.unwrap_or(constants::RELAY_PORT),
+        relay_peer_id: match &settings.relay_peer_id {
+            Some(peer_id) => Some(peer_id.parse()?),
+            None => None,
+        },
+        use_ipv6: constants::USE_IPV6,
+        bind_address: settings.bind_address,
+        swarm_key: match &settings.swarm_key {
+            Some(path) => Some(crate::p2p::swarm_key::load(path)?),
+            None => None,
+        },
+        peer: settings
+            .ip_addresses
+            .iter()
+            .find_map(|a| book.get(a).unwrap_or(a).parse::<PeerId>().ok()),
+        timeout: TROUBLESHOOT_TIMEOUT,
+    })
+}
+
+/// Run the connection checks on a thread of their own, they take connecting to the relay.
+async fn run_troubleshoot(options: TroubleshootOptions) -> Result<Vec<CheckResult>, String> {
+    let (sender, receiver) = futures::channel::oneshot::channel();
+    std::thread::spawn(move || {
+        let _ = sender
+            .send(troubleshoot::troubleshoot(&options, &mut |_| {}).map_err(|e| e.to_string()));
+    });
+    receiver
+        .await
+        .unwrap_or_else(|_| Err("Troubleshooting stopped".to_string()))
+}
+
+/// What a shortcut or MIDI control bound to `action` does.
+fn action_message(action: Action) -> Message {
+    match action {
+        Action::Panic => Message::Panic,
+        Action::Mute => Message::ToggleMute,
+        Action::Connect => Message::Connect,
+        Action::SaveSettings => Message::SaveSettings,
+        Action::ReloadMidiDevices => Message::ReloadMidiDevices,
+        Action::TapTempo => Message::TapTempo,
+    }
+}
+
+impl App {
+    /// Targets MIDI learn can bind in the settings screen, peers having theirs in the peer panel.
+    pub(super) fn learn_choices(&self) -> Vec<MidiTarget> {
+        Action::ALL
+            .into_iter()
+            .map(MidiTarget::Action)
+            .chain(self.presets.iter().cloned().map(MidiTarget::Preset))
+            .collect()
+    }
+
+    /// Open the control device in the settings, closing the one open.
+    fn open_control_device(&mut self) {
+        self.control_input = None;
+        let device = match &self.app_flags.settings.control_device {
+            Some(device) => device.clone(),
+            None => return,
+        };
+        let (producer, consumer) = ring::ring_buffer(CONTROL_QUEUE_CAPACITY);
+        match connect_input(&device, producer) {
+            Ok(connection) => {
+                info!("Taking controls from {}", device);
+                self.control_input = Some(connection);
+                self.control_devices += 1;
+                *self.control_events.lock().unwrap() = Some(consumer);
+            }
+            Err(e) => {
+                self.notices.error = Some(format!("Error opening control device: {}", e));
+            }
+        }
+    }
+
+    /// Bind a control to the target being learned, saving it to the config file.
+    fn learn(&mut self, control: MidiControl, target: MidiTarget) {
+        let bindings = &mut self.app_flags.settings.midi_bindings;
+        let target_name = target.to_string();
+        bindings.retain(|_, bound| *bound != target_name);
+        bindings.insert(control.to_string(), target_name);
+        self.midi_table = MidiTable::from_config(bindings).0;
+        self.save(self.app_flags.config_path.clone());
+        if self.notices.error.is_none() {
+            self.notices.info = Some(format!("Bound {} to {}", control, target));
+        }
+    }
+
+    /// Operate a target with the value of the control bound to it.
+    fn operate(&mut self, target: MidiTarget, value: u8) -> Command<Message> {
+        match target {
+            MidiTarget::Action(action) => return self.update(action_message(action)),
+            MidiTarget::PeerVolume(peer) => {
+                let volume = (value as u32 * 100 / 127) as u8;
+                return self.update(Message::PeerPanel(PeerPanelMessage::Volume(peer, volume)));
+            }
+            MidiTarget::PeerMute(peer) => {
+                let muted = self
+                    .peers
+                    .resolve(&peer)
+                    .map_or(false, |peer_id| self.peers.is_muted(&peer_id));
+                return self.update(Message::PeerPanel(PeerPanelMessage::Mute(peer, !muted)));
+            }
+            MidiTarget::Preset(name) => {
+                if let Some(session) = &self.session {
+                    match session.load_preset(name.clone()) {
+                        Ok(_) => self.notices.info = Some(format!("Loaded preset {}", name)),
+                        Err(e) => self.notices.error = Some(format!("Error loading preset: {}", e)),
+                    }
+                }
+            }
+        }
+        Command::none()
+    }
+
+    /// Start the next dropped MIDI file once the one playing finished, sending it to the peers
+    /// selected in the peers panel.
+    fn play_next_file(&mut self) {
+        let targets: Vec<_> = match &self.session {
+            Some(session) => self
+                .peers
+                .file_targets()
+                .into_iter()
+                .map(|peer_id| session.peer(peer_id))
+                .collect(),
+            None => return,
+        };
+        while let Some(path) = self.files.next() {
+            let messages = match smf::load_events(&path) {
+                Ok(messages) => messages,
+                Err(e) => {
+                    self.notices.error = Some(format!("Error reading {}: {}", path.display(), e));
+                    continue;
+                }
+            };
+            let targets = targets.clone();
+            let send = move |message: &[u8]| {
+                for peer in &targets {
+                    if let Err(e) = peer.send_midi(message) {
+                        warn!("Error playing file to {}: {}", peer.id(), e);
+                    }
+                }
+            };
+            match FilePlayer::start(messages, send) {
+                Ok(player) => self.files.play(path, player),
+                Err(e) => {
+                    self.notices.error = Some(format!("Error playing {}: {}", path.display(), e))
+                }
+            }
+        }
+    }
+
+    fn performance_mode(&self) -> bool {
+        self.app_flags.settings.performance_mode.unwrap_or(false)
+    }
+
+    /// Batch the events of the session in performance mode, from the next batch on.
+    fn batch_session_events(&self) {
+        *self.session_batch.lock().unwrap() = self
+            .performance_mode()
+            .then_some(PERFORMANCE_REFRESH_INTERVAL);
+    }
+
+    /// How often meters redraw, `normal` unless in performance mode.
+    pub(super) fn refresh_interval(&self, normal: Duration) -> Duration {
+        match self.performance_mode() {
+            true => normal.max(PERFORMANCE_REFRESH_INTERVAL),
+            false => normal,
+        }
+    }
+
+    /// Pass a line on to the monitor window, forgetting it once closed.
+    fn feed_monitor_window(&mut self, line: MonitorLine) {
+        if let Some(window) = &mut self.monitor_window {
+            if window.send(&line).is_err() {
+                self.monitor_window = None;
+            }
+        }
+    }
+
+    /// Start a session, or end the running one.
+    fn toggle_session(&mut self) {
+        if let Some(session) = self.session.take() {
+            if let Err(e) = session.stop() {
+                self.notices.error = Some(format!("Session ended with an error: {}", e));
+            }
+            self.peers.clear();
+            self.channels.clear();
+            self.graphs.clear();
+            self.files.cancel();
+            self.waiting.clear();
+            self.feed_monitor_window(MonitorLine::Clear);
+            return;
+        }
+        self.start_session(None);
+    }
+
+    /// Start a session with the settings in the window, or joining an open session of the relay.
+    fn start_session(&mut self, join: Option<OpenSession>) {
+        let router = MidiRouter::new(self.app_flags.settings.peers.clone());
+        let options = session_options(&self.app_flags).map(|mut options| {
+            if let Some(open) = join {
+                options.mode = Mode::Dial;
+                options.addresses = open.peers;
+                options.publish = Some(open.session);
+            }
+            options
+        });
+        match options.and_then(|options| Session::start(router, options)) {
+            Ok((session, events)) => {
+                self.session = Some(session);
+                self.sessions += 1;
+                *self.session_events.lock().unwrap() = Some(events);
+                self.notices.error = None;
+                self.notices.info = Some("Connecting".to_string());
+            }
+            Err(e) => self.notices.error = Some(format!("Error starting the session: {}", e)),
+        }
+    }
+
+    /// Validate the settings and save them to `path`, saving there from now on.
+    fn save(&mut self, path: PathBuf) {
+        if let Err(errors) = self.app_flags.settings.validate() {
+            self.notices.error = Some(format!("Invalid settings:\n{}", describe_errors(&errors)));
+            return;
+        }
+        self.notices.error = None;
+        self.notices.info = match self.app_flags.settings.save(&path) {
+            Ok(s) => {
+                self.app_flags.config_path = PathBuf::from(&s);
+                info!("Saved settings to {:?}", s);
+                Some(format!("Saved settings to {:?}", s))
+            }
+            Err(e) => {
+                self.notices.error = Some(format!("Error saving settings: {}", e));
+                None
+            }
+        };
+    }
+}
+
+impl Application for App {
+    type Executor = executor::Default;
+    type Message = Message;
+    type Theme = Theme;
+    type Flags = AppFlags;
+
+    fn new(_flags: Self::Flags) -> (Self, Command<Message>) {
+        let (midi_devices, midi_error) = match midi_devices() {
+            Ok(devices) => (devices, None),
+            Err(e) => (Vec::new(), Some(e)),
+        };
+        let mut error_message = match _flags.settings.validate() {
+            Ok(_) => None,
+            Err(errors) => Some(format!("Invalid settings:\n{}", describe_errors(&errors))),
+        };
+        let (actions, keybinding_errors) = ActionTable::from_config(&_flags.settings.keybindings);
+        if !keybinding_errors.is_empty() {
+            error_message = Some(format!("Keybindings: {}", keybinding_errors.join(", ")));
+        }
+        let (midi_table, midi_binding_errors) =
+            MidiTable::from_config(&_flags.settings.midi_bindings);
+        if !midi_binding_errors.is_empty() {
+            error_message = Some(format!("MIDI bindings: {}", midi_binding_errors.join(", ")));
+        }
+        let presets = _flags
+            .storage
+            .presets()
+            .map(|presets| presets.into_keys().collect())
+            .unwrap_or_default();
+        if let Some(path) = &_flags.last_crash {
+            error_message = Some(format!(
+                "p2pmidi crashed last time, a report was saved to {}",
+                path.display()
+            ));
+        }
+        // Start on the recent sessions to join one again in a click, unless something needs
+        // fixing in the settings first
+        let screen = match _flags.storage.recent_sessions() {
+            Ok(sessions)
+                if !sessions.is_empty()
+                    && midi_error.is_none()
+                    && _flags.settings.relay_address.is_some() =>
+            {
+                Screen::Recent(sessions)
+            }
+            _ => Screen::Settings,
+        };
+        let mut app = App {
+            config_reloader: ConfigReloader::new(&_flags.config_path),
+            actions,
+            muted: false,
+            midi_table,
+            learning: None,
+            learn_choice: None,
+            presets,
+            control_input: None,
+            control_events: Arc::new(Mutex::new(None)),
+            control_devices: 0,
+            initial_settings: _flags.settings.clone(),
+            app_flags: _flags,
+            midi_devices,
+            midi_error,
+            notices: Notices {
+                error: error_message,
+                info: None,
+            },
+            addresses: AddressList::default(),
+            save_as: SaveAs::default(),
+            log: LogPanel::default(),
+            peers: PeerPanel::default(),
+            channels: ChannelActivity::default(),
+            graphs: ControllerGraphs::default(),
+            files: FileQueue::default(),
+            monitor_window: None,
+            screen,
+            session: None,
+            waiting: Vec::new(),
+            session_events: Arc::new(Mutex::new(None)),
+            session_batch: Arc::new(Mutex::new(None)),
+            sessions: 0,
+        };
+        app.batch_session_events();
+        app.open_control_device();
+        (app, Command::none())
+    }
+
+    fn title(&self) -> String {
+        String::from("App Settings")
+    }
+
+    fn update(&mut self, message: Message) -> Command<Message> {
+        match message {
+            Message::Connect => self.toggle_session(),
+            Message::ReloadMidiDevices => {
+                match midi_devices() {
+                    Ok(devices) => {
+                        self.midi_devices = devices;
+                        self.midi_error = None;
+                    }
+                    Err(e) => self.midi_error = Some(e),
+                }
+                if let Ok(presets) = self.app_flags.storage.presets() {
+                    self.presets = presets.into_keys().collect();
+                }
+            }
+            Message::SettingsChanged(settings) => {
+                self.app_flags.settings = settings;
+                self.batch_session_events();
+            }
+            Message::UseDefaultRelay => {
+                let settings = &mut self.app_flags.settings;
+                settings.relay_address = Some(constants::RELAY_ADDRESS.to_string());
+                settings.relay_port = settings.relay_port.or(Some(constants::RELAY_PORT));
+            }
+            Message::RelayPortChanged(i) => {
+                self.app_flags.settings.relay_port = Some(i);
+            }
+            Message::Addresses(message) => {
+                self.addresses
+                    .update(message, &mut self.app_flags.settings.ip_addresses);
+            }
+            Message::AppPortChanged(p) => {
+                self.app_flags.settings.port = Some(p);
+            }
+            Message::DelayBarsChanged(bars) => {
+                self.app_flags.settings.delay_bars = Some(bars);
+            }
+            Message::SaveSettings => {
+                self.save(self.app_flags.config_path.clone());
+            }
+            Message::SaveAs(message) => {
+                if let Some(path) = self.save_as.update(message) {
+                    if path.is_empty() {
+                        self.notices.error =
+                            Some("Enter a file path to save settings as".to_string());
+                        return Command::none();
+                    }
+                    self.save(PathBuf::from(path));
+                    if self.notices.error.is_none() {
+                        self.save_as.clear();
+                    }
+                }
+            }
+            Message::ResetSettings => {
+                self.app_flags.settings = self.initial_settings.clone();
+                self.batch_session_events();
+            }
+            Message::ConfigFileChanged => match self.config_reloader.reload() {
+                Ok((reloaded, change)) if !change.is_empty() => {
+                    let control_device = self.app_flags.settings.control_device.clone();
+                    change.apply(&mut self.app_flags.settings, &reloaded);
+                    self.batch_session_events();
+                    self.actions = ActionTable::from_config(&reloaded.keybindings).0;
+                    self.midi_table = MidiTable::from_config(&reloaded.midi_bindings).0;
+                    if self.app_flags.settings.control_device != control_device {
+                        self.open_control_device();
+                    }
+                    self.notices.info = match change.needs_reconnect.is_empty() {
+                        true => Some(format!("Applied changes: {}", change.applied.join(", "))),
+                        false => Some(format!(
+                            "Config file changed, reconnect to apply: {}",
+                            change.needs_reconnect.join(", ")
+                        )),
+                    };
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("Error reloading config file: {}", e);
+                    self.notices.error = Some(format!("Error reloading config file: {}", e));
+                }
+            },
+            Message::KeyPressed(binding) => {
+                if let Some(action) = self.actions.action_for(&binding) {
+                    return self.update(action_message(action));
+                }
+            }
+            Message::Panic => {
+                if let Some(session) = &self.session {
+                    if let Err(e) = session.panic() {
+                        warn!("Error sending all notes off: {}", e);
+                    }
+                }
+            }
+            Message::Log(line) => {
+                self.feed_monitor_window(MonitorLine::of_log(&line));
+                self.log.push(line);
+            }
+            Message::Session(SessionEvent::CountIn { beats_left }) => {
+                self.notices.info = Some(match beats_left {
+                    0 => "Go!".to_string(),
+                    beats_left => format!("Starting in {}", beats_left),
+                });
+            }
+            Message::Session(SessionEvent::PeerWaiting { peer_id, name }) => {
+                if !self.waiting.iter().any(|(waiting, _)| *waiting == peer_id) {
+                    self.waiting.push((peer_id, name));
+                }
+            }
+            Message::AcceptPeer(peer_id) => {
+                self.waiting.retain(|(waiting, _)| *waiting != peer_id);
+                if let Some(session) = &self.session {
+                    if let Err(e) = session.peer(peer_id).accept() {
+                        self.notices.error = Some(format!("Error letting the peer in: {}", e));
+                    }
+                }
+            }
+            Message::RejectPeer(peer_id) => {
+                self.waiting.retain(|(waiting, _)| *waiting != peer_id);
+                if let Some(session) = &self.session {
+                    if let Err(e) = session.peer(peer_id).reject() {
+                        self.notices.error = Some(format!("Error turning the peer away: {}", e));
+                    }
+                }
+            }
+            Message::Session(event) => {
+                // Peers let in or gone another way are no longer waiting
+                if let SessionEvent::PeerJoined { peer_id, .. }
+                | SessionEvent::PeerLeft { peer_id } = &event
+                {
+                    self.waiting.retain(|(waiting, _)| waiting != peer_id);
+                }
+                if let Some(line) = MonitorLine::of_event(&event) {
+                    self.feed_monitor_window(line);
+                }
+                self.channels.update(&event);
+                self.graphs.push(&event);
+                self.peers.update(event);
+            }
+            Message::SessionBatch(events) => {
+                return Command::batch(
+                    events
+                        .into_iter()
+                        .map(|event| self.update(Message::Session(event))),
+                );
+            }
+            Message::SessionTick => {
+                if let Some(session) = &self.session {
+                    match session.connections() {
+                        Ok(connections) => self.peers.set_connections(connections),
+                        Err(e) => warn!("Error getting the session status: {}", e),
+                    }
+                    match session.clocks() {
+                        Ok(clocks) => self.peers.set_clocks(clocks),
+                        Err(e) => warn!("Error getting the session status: {}", e),
+                    }
+                }
+                // Follow the session on the timeline
+                if matches!(self.screen, Screen::Timeline(_)) {
+                    return self.update(Message::ShowTimeline);
+                }
+            }
+            Message::PeerPanel(PeerPanelMessage::Volume(peer, volume)) => {
+                if let (Some(session), Some(peer_id)) = (&self.session, self.peers.resolve(&peer)) {
+                    match session.peer(peer_id.clone()).set_volume(volume) {
+                        Ok(_) => self.peers.set_volume(&peer_id, volume),
+                        Err(e) => self.notices.error = Some(format!("Error setting volume: {}", e)),
+                    }
+                }
+            }
+            Message::PeerPanel(PeerPanelMessage::Mute(peer, muted)) => {
+                if let (Some(session), Some(peer_id)) = (&self.session, self.peers.resolve(&peer)) {
+                    match session.peer(peer_id.clone()).set_muted(muted) {
+                        Ok(_) => self.peers.set_muted(&peer_id, muted),
+                        Err(e) => self.notices.error = Some(format!("Error muting: {}", e)),
+                    }
+                }
+            }
+            Message::PeerPanel(PeerPanelMessage::Timing(peer_id, timing)) => {
+                if let Some(session) = &self.session {
+                    match session.peer(peer_id.clone()).set_timing(timing) {
+                        Ok(_) => self.peers.set_timing(&peer_id, timing),
+                        Err(e) => {
+                            self.notices.error = Some(format!("Error changing timing: {}", e))
+                        }
+                    }
+                }
+            }
+            Message::PeerPanel(PeerPanelMessage::Learn(target)) | Message::Learn(target) => {
+                self.notices.info = Some(match &self.control_input {
+                    Some(_) => format!("Move a control to bind it to {}", target),
+                    None => "Pick a control device to learn from first".to_string(),
+                });
+                self.learning = self.control_input.as_ref().map(|_| target);
+            }
+            Message::TapTempo => {
+                if let Some(session) = &self.session {
+                    match session.tap_tempo() {
+                        Ok(Some(tempo)) => {
+                            self.notices.info = Some(format!("Tempo {:.1} BPM", tempo))
+                        }
+                        Ok(None) => {}
+                        Err(e) => self.notices.error = Some(format!("Error tapping tempo: {}", e)),
+                    }
+                }
+            }
+            Message::StartTogether => {
+                if let Some(session) = &self.session {
+                    if let Err(e) = session.start_together(DEFAULT_COUNT_IN) {
+                        self.notices.error = Some(format!("Error starting: {}", e));
+                    }
+                }
+            }
+            Message::LearnChoice(target) => {
+                self.learn_choice = Some(target);
+            }
+            Message::ControlDeviceChanged(device) => {
+                self.app_flags.settings.control_device = Some(device);
+                self.open_control_device();
+            }
+            Message::ControlMidi(message) => {
+                if let Some((control, value)) = MidiControl::of(&message) {
+                    // Learning takes the first control pressed or turned up
+                    match self.learning.take() {
+                        Some(target) if value > 0 => self.learn(control, target),
+                        Some(target) => self.learning = Some(target),
+                        None => {
+                            if let Some(target) = self.midi_table.target_for(&control, value) {
+                                let target = target.clone();
+                                return self.operate(target, value);
+                            }
+                        }
+                    }
+                }
+            }
+            Message::PeerPanel(PeerPanelMessage::Renegotiate(peer_id)) => {
+                if let Some(session) = &self.session {
+                    if let Err(e) = session.peer(peer_id).renegotiate() {
+                        self.notices.error = Some(format!("Error renegotiating: {}", e));
+                    }
+                }
+            }
+            Message::SessionEnded => {
+                // The session stopped on its own, its thread has the reason
+                if let Some(session) = self.session.take() {
+                    if let Err(e) = session.stop() {
+                        self.notices.error = Some(format!("Session ended: {}", e));
+                    }
+                }
+                self.peers.clear();
+                self.channels.clear();
+                self.graphs.clear();
+                self.files.cancel();
+                self.waiting.clear();
+                self.feed_monitor_window(MonitorLine::Clear);
+            }
+            Message::ShowHistory => match self.app_flags.storage.history() {
+                Ok(mut records) => {
+                    records.reverse();
+                    self.screen = Screen::History(records);
+                }
+                Err(e) => {
+                    self.notices.error = Some(format!("Error reading connection history: {}", e));
+                }
+            },
+            Message::HideHistory => {
+                self.screen = Screen::Settings;
+            }
+            Message::ShowMonitor => {
+                self.screen = Screen::Monitor;
+            }
+            Message::HideMonitor => {
+                self.screen = Screen::Settings;
+            }
+            Message::MonitorTick => {}
+            Message::DetachMonitor => match DetachedMonitor::open(&self.app_flags.config_path) {
+                Ok(window) => {
+                    self.monitor_window = Some(window);
+                    // Name the peers already there
+                    for (peer_id, name) in self.peers.names() {
+                        self.feed_monitor_window(MonitorLine::PeerJoined { peer_id, name });
+                    }
+                }
+                Err(e) => {
+                    self.notices.error = Some(format!("Error opening the monitor window: {}", e))
+                }
+            },
+            Message::AttachMonitor => {
+                self.monitor_window = None;
+            }
+            Message::ShowTimeline => match self.app_flags.storage.events() {
+                Ok(records) => {
+                    let skip = records.len().saturating_sub(TIMELINE_EVENTS);
+                    self.screen = Screen::Timeline(records.into_iter().skip(skip).rev().collect());
+                }
+                Err(e) => {
+                    self.notices.error = Some(format!("Error reading the event log: {}", e));
+                }
+            },
+            Message::HideTimeline => {
+                self.screen = Screen::Settings;
+            }
+            Message::ControllerGraphs(message) => self.graphs.update(message),
+            Message::PeerPanel(PeerPanelMessage::Select(peer_id, selected)) => {
+                self.peers.set_selected(&peer_id, selected);
+            }
+            Message::FileDropped(path) => {
+                let is_midi = path.extension().map_or(false, |extension| {
+                    extension.eq_ignore_ascii_case("mid") || extension.eq_ignore_ascii_case("midi")
+                });
+                if !is_midi {
+                    self.notices.error = Some(format!("{} is not a MIDI file", path.display()));
+                } else if self.session.is_none() {
+                    self.notices.error = Some("Connect first to play a MIDI file".to_string());
+                } else {
+                    self.files.push(path);
+                    self.play_next_file();
+                }
+            }
+            Message::PlaybackTick => self.play_next_file(),
+            Message::CancelPlayback => self.files.cancel(),
+            Message::BrowseSessions => match list_options(&self.app_flags) {
+                Ok((options, local_key)) => {
+                    self.notices.info = Some("Asking the relay for open sessions".to_string());
+                    return Command::perform(
+                        list_sessions(options, local_key),
+                        Message::SessionsListed,
+                    );
+                }
+                Err(e) => self.notices.error = Some(format!("Error listing sessions: {}", e)),
+            },
+            Message::SessionsListed(result) => match result {
+                Ok(sessions) => {
+                    self.notices.info = None;
+                    self.screen = Screen::Sessions(sessions);
+                }
+                Err(e) => self.notices.error = Some(format!("Error listing sessions: {}", e)),
+            },
+            Message::JoinSession(open) => {
+                self.screen = Screen::Settings;
+                match self.session {
+                    Some(_) => self.notices.error = Some("Disconnect first".to_string()),
+                    None => self.start_session(Some(open)),
+                }
+            }
+            Message::HideSessions => {
+                self.screen = Screen::Settings;
+            }
+            Message::ShowRecent => match self.app_flags.storage.recent_sessions() {
+                Ok(sessions) => self.screen = Screen::Recent(sessions),
+                Err(e) => {
+                    self.notices.error = Some(format!("Error reading the recent sessions: {}", e));
+                }
+            },
+            Message::HideRecent => {
+                self.screen = Screen::Settings;
+            }
+            Message::Rejoin(recent) => {
+                self.screen = Screen::Settings;
+                match self.session {
+                    Some(_) => self.notices.error = Some("Disconnect first".to_string()),
+                    None => {
+                        recent.apply_to(&mut self.app_flags.settings);
+                        self.start_session(None);
+                    }
+                }
+            }
+            Message::Troubleshoot => match troubleshoot_options(&self.app_flags) {
+                Ok(options) => {
+                    self.notices.info = Some("Checking the connection to the relay".to_string());
+                    return Command::perform(run_troubleshoot(options), Message::Troubleshot);
+                }
+                Err(e) => self.notices.error = Some(format!("Error troubleshooting: {}", e)),
+            },
+            Message::Troubleshot(result) => match result {
+                Ok(results) => {
+                    self.notices.info = None;
+                    self.screen = Screen::Troubleshoot(results);
+                }
+                Err(e) => self.notices.error = Some(format!("Error troubleshooting: {}", e)),
+            },
+            Message::HideTroubleshoot => {
+                self.screen = Screen::Settings;
+            }
+            Message::ToggleMute => {
+                self.muted = !self.muted;
+                info!("Muted: {}", self.muted);
+                self.notices.info = Some(match self.muted {
+                    true => "Muted".to_string(),
+                    false => "Unmuted".to_string(),
+                });
+            }
+        };
+        Command::none()
+    }
+
+    fn view(&self) -> iced::Element<Self::Message> {
+        match &self.screen {
+            Screen::Settings => screens::settings(self),
+            Screen::History(records) => screens::history(records),
+            Screen::Sessions(sessions) => screens::sessions(sessions),
+            Screen::Recent(sessions) => screens::recent(sessions),
+            Screen::Troubleshoot(results) => screens::troubleshoot(results),
+            Screen::Monitor => screens::monitor(self),
+            Screen::Timeline(records) => screens::timeline(records),
+        }
+    }
+
+    fn theme(&self) -> Self::Theme {
+        theme::iced_theme(self.app_flags.settings.theme)
+    }
+
+    fn style(&self) -> <Self::Theme as iced::application::StyleSheet>::Style {
+        iced::theme::Application::default()
+    }
+
+    fn subscription(&self) -> iced::Subscription<Self::Message> {
+        let mut subscriptions = vec![
+            subscription::keys(),
+            subscription::config_changes(self.app_flags.config_path.clone()),
+            subscription::log(),
+            subscription::dropped_files(),
+        ];
+        if self.control_input.is_some() {
+            subscriptions.push(subscription::control_midi(
+                self.control_devices,
+                self.control_events.clone(),
+            ));
+        }
+        if self.session.is_some() {
+            subscriptions.push(subscription::session(
+                self.sessions,
+                self.session_events.clone(),
+                self.session_batch.clone(),
+            ));
+            subscriptions
+                .push(iced::time::every(SESSION_STATUS_INTERVAL).map(|_| Message::SessionTick));
+            if matches!(self.screen, Screen::Monitor) {
+                subscriptions.push(
+                    iced::time::every(self.refresh_interval(MONITOR_REFRESH_INTERVAL))
+                        .map(|_| Message::MonitorTick),
+                );
+            }
+            if self.files.is_active() {
+                subscriptions.push(
+                    iced::time::every(self.refresh_interval(PLAYBACK_REFRESH_INTERVAL))
+                        .map(|_| Message::PlaybackTick),
+                );
+            }
+        }
+        iced::Subscription::batch(subscriptions)
+    }
+
+    fn scale_factor(&self) -> f64 {
+        1.0
+    }
+}
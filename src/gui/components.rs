@@ -0,0 +1,1024 @@
+use iced::widget::{
+    Button, Checkbox, Column, PickList, ProgressBar, Row, Rule, Scrollable, Slider, Space, Text,
+    TextInput,
+};
+use iced::{Element, Font, Length};
+use iced_aw::NumberInput;
+use std::collections::{BTreeMap, VecDeque};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tracing::Level;
+
+use super::theme;
+use crate::clock::ClockQuality;
+use crate::keybindings::MidiTarget;
+use crate::logging::LogLine;
+use crate::midi::{self, MessageKind};
+use crate::p2p::paths::ConnectionPath;
+use crate::p2p::playout::Timing;
+use crate::p2p::troubleshoot::{CheckResult, Outcome};
+use crate::session::SessionEvent;
+use crate::smf::FilePlayer;
+use crate::status::StatusEvent;
+use crate::storage::{
+    format_utc, format_utc_time, ConnectionOutcome, ConnectionRecord, EventRecord, RecentSession,
+};
+
+/// Lines kept in the log panel.
+const LOG_PANEL_LINES: usize = 200;
+
+/// How long a channel light of the monitor stays on after a message.
+const ACTIVITY_LIGHT: Duration = Duration::from_millis(300);
+
+/// How often meters redraw and session events are handed to the window in performance mode.
+pub const PERFORMANCE_REFRESH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Seconds of controller values graphed unless picked otherwise, and at most.
+const DEFAULT_GRAPH_SECS: u64 = 10;
+const MAX_GRAPH_SECS: u64 = 120;
+
+/// Columns of each controller graph.
+const GRAPH_COLUMNS: usize = 60;
+
+/// Bars of a graph column, from lowest to highest value.
+const GRAPH_BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// Error and info lines at the top of a screen.
+#[derive(Debug, Default)]
+pub struct Notices {
+    pub error: Option<String>,
+    pub info: Option<String>,
+}
+
+impl Notices {
+    pub fn view<'a, M: 'a>(&self) -> Element<'a, M> {
+        Column::new()
+            .spacing(20)
+            .push(match &self.error {
+                Some(s) => Text::new(s.clone()).style(theme::ERROR),
+                None => Text::new(""),
+            })
+            .push(
+                match &self.info {
+                    Some(s) => Text::new(s.clone()),
+                    None => Text::new(""),
+                }
+                .horizontal_alignment(iced::alignment::Horizontal::Left),
+            )
+            .into()
+    }
+}
+
+/// Something keeping the app from working, with a button to fix or retry it.
+pub fn problem<'a, M: Clone + 'a>(
+    description: String,
+    action: &'a str,
+    on_press: M,
+) -> Element<'a, M> {
+    Row::new()
+        .spacing(10)
+        .align_items(iced::Alignment::Center)
+        .push(Text::new(description).style(theme::ERROR))
+        .push(Button::new(Text::new(action)).on_press(on_press))
+        .into()
+}
+
+/// A peer asking to join the session, with buttons to let it in or turn it away.
+pub fn join_request<'a, M: Clone + 'a>(
+    peer_id: &str,
+    name: Option<&str>,
+    accept: M,
+    reject: M,
+) -> Element<'a, M> {
+    let who = match name {
+        Some(name) => format!("{} ({})", name, peer_id),
+        None => peer_id.to_string(),
+    };
+    Row::new()
+        .spacing(10)
+        .align_items(iced::Alignment::Center)
+        .push(Text::new(format!("{} asks to join", who)))
+        .push(Button::new(Text::new("Accept")).on_press(accept))
+        .push(Button::new(Text::new("Deny")).on_press(reject))
+        .into()
+}
+
+#[derive(Debug, Clone)]
+pub enum AddressListMessage {
+    InputChanged(String),
+    Add,
+    Remove(String),
+}
+
+/// Device addresses, with an input to add more.
+#[derive(Debug, Default)]
+pub struct AddressList {
+    input: String,
+}
+
+impl AddressList {
+    pub fn update(&mut self, message: AddressListMessage, addresses: &mut Vec<String>) {
+        match message {
+            AddressListMessage::InputChanged(input) => self.input = input,
+            AddressListMessage::Add => {
+                let address = std::mem::take(&mut self.input);
+                if !address.trim().is_empty() {
+                    addresses.push(address);
+                }
+            }
+            AddressListMessage::Remove(address) => addresses.retain(|a| *a != address),
+        }
+    }
+
+    pub fn view<'a>(&'a self, addresses: &'a [String]) -> Element<'a, AddressListMessage> {
+        let input = Column::new().push(Text::new("Device addresses:")).push(
+            Row::new()
+                .spacing(20)
+                .align_items(iced::Alignment::End)
+                .push(
+                    TextInput::new("Device address", self.input.as_str())
+                        .on_input(AddressListMessage::InputChanged)
+                        .on_submit(AddressListMessage::Add)
+                        .padding(15)
+                        .size(20),
+                )
+                .push(
+                    Button::new(Text::new("Add"))
+                        .on_press(AddressListMessage::Add)
+                        .padding(15),
+                ),
+        );
+        let list = addresses
+            .iter()
+            .fold(Column::new().spacing(10), |col, address| {
+                col.push(
+                    Row::new()
+                        .spacing(20)
+                        .align_items(iced::Alignment::End)
+                        .push(Text::new(address))
+                        .push(Space::with_width(Length::Fill))
+                        .push(
+                            Button::new(Text::new("Remove"))
+                                .on_press(AddressListMessage::Remove(address.clone())),
+                        )
+                        .push(Space::with_width(20)),
+                )
+            });
+        Column::new()
+            .spacing(20)
+            .push(input)
+            .push(
+                Column::new()
+                    .push(Rule::horizontal(10))
+                    .push(Scrollable::new(list).height(150).width(Length::Fill))
+                    .push(Rule::horizontal(10)),
+            )
+            .into()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum SaveAsMessage {
+    PathChanged(String),
+    Save,
+}
+
+/// A path to save the settings to instead of the current config file.
+#[derive(Debug, Default)]
+pub struct SaveAs {
+    path: String,
+}
+
+impl SaveAs {
+    /// The path entered, once saving was asked for.
+    pub fn update(&mut self, message: SaveAsMessage) -> Option<String> {
+        match message {
+            SaveAsMessage::PathChanged(path) => {
+                self.path = path;
+                None
+            }
+            SaveAsMessage::Save => Some(self.path.clone()),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.path.clear();
+    }
+
+    pub fn view(&self, placeholder: &str) -> Element<SaveAsMessage> {
+        Row::new()
+            .spacing(20)
+            .align_items(iced::Alignment::End)
+            .push(
+                TextInput::new(placeholder, self.path.as_str())
+                    .on_input(SaveAsMessage::PathChanged)
+                    .on_submit(SaveAsMessage::Save)
+                    .padding(15)
+                    .size(20),
+            )
+            .push(
+                Button::new("Save As")
+                    .on_press(SaveAsMessage::Save)
+                    .padding(15),
+            )
+            .into()
+    }
+}
+
+/// The last log lines, warnings and errors in color.
+#[derive(Debug, Default)]
+pub struct LogPanel {
+    lines: VecDeque<LogLine>,
+}
+
+impl LogPanel {
+    pub fn push(&mut self, line: LogLine) {
+        if self.lines.len() == LOG_PANEL_LINES {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line);
+    }
+
+    pub fn view<'a, M: 'a>(&self) -> Element<'a, M> {
+        let lines =
+            self.lines
+                .iter()
+                .fold(Column::new().spacing(2).width(Length::Fill), |col, line| {
+                    let text = Text::new(line.to_string()).size(14);
+                    col.push(match line.level {
+                        Level::ERROR => text.style(theme::ERROR),
+                        Level::WARN => text.style(theme::WARNING),
+                        _ => text,
+                    })
+                });
+        Scrollable::new(lines).height(150).into()
+    }
+}
+
+/// A connected peer, how it is reached and how many MIDI messages it played.
+#[derive(Debug)]
+struct PeerActivity {
+    name: String,
+    paths: Vec<ConnectionPath>,
+    clock: Option<ClockQuality>,
+    messages: u64,
+    /// Percent the velocity of its notes is scaled to.
+    volume: u8,
+    muted: bool,
+    timing: Timing,
+    /// Dropped MIDI files play to it.
+    selected: bool,
+}
+
+#[derive(Debug, Clone)]
+pub enum PeerPanelMessage {
+    /// Drop the connections to a peer and dial it again.
+    Renegotiate(String),
+    Volume(String, u8),
+    Mute(String, bool),
+    Timing(String, Timing),
+    /// Choose whether dropped MIDI files play to a peer.
+    Select(String, bool),
+    /// Bind the next control moved on the control device.
+    Learn(MidiTarget),
+}
+
+/// The peers of the running session, updated as they come and go and play.
+#[derive(Debug, Default)]
+pub struct PeerPanel {
+    peers: BTreeMap<String, PeerActivity>,
+}
+
+impl PeerPanel {
+    pub fn update(&mut self, event: SessionEvent) {
+        match event {
+            SessionEvent::PeerJoined { peer_id, name } => {
+                self.peers
+                    .entry(peer_id)
+                    .or_insert(PeerActivity {
+                        name: String::new(),
+                        paths: Vec::new(),
+                        clock: None,
+                        messages: 0,
+                        volume: 100,
+                        muted: false,
+                        timing: Timing::default(),
+                        selected: false,
+                    })
+                    .name = name;
+            }
+            SessionEvent::PeerLeft { peer_id } => {
+                self.peers.remove(&peer_id);
+            }
+            SessionEvent::Midi { peer_id, .. } => {
+                if let Some(peer) = self.peers.get_mut(&peer_id) {
+                    peer.messages += 1;
+                }
+            }
+            SessionEvent::PeerWaiting { .. } | SessionEvent::CountIn { .. } => {}
+        }
+    }
+
+    /// Update how the peers are reached, from the connections of the session.
+    pub fn set_connections(&mut self, mut connections: BTreeMap<String, Vec<ConnectionPath>>) {
+        for (peer_id, peer) in self.peers.iter_mut() {
+            peer.paths = connections.remove(peer_id).unwrap_or_default();
+        }
+    }
+
+    /// Update how well the clock of each peer is known.
+    pub fn set_clocks(&mut self, mut clocks: BTreeMap<String, ClockQuality>) {
+        for (peer_id, peer) in self.peers.iter_mut() {
+            peer.clock = clocks.remove(peer_id);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.peers.clear();
+    }
+
+    /// The PeerId of a peer given by PeerId or name.
+    pub fn resolve(&self, peer: &str) -> Option<String> {
+        self.peers
+            .iter()
+            .find(|(peer_id, activity)| *peer_id == peer || activity.name == peer)
+            .map(|(peer_id, _)| peer_id.clone())
+    }
+
+    pub fn set_volume(&mut self, peer_id: &str, volume: u8) {
+        if let Some(peer) = self.peers.get_mut(peer_id) {
+            peer.volume = volume;
+        }
+    }
+
+    pub fn set_muted(&mut self, peer_id: &str, muted: bool) {
+        if let Some(peer) = self.peers.get_mut(peer_id) {
+            peer.muted = muted;
+        }
+    }
+
+    pub fn set_timing(&mut self, peer_id: &str, timing: Timing) {
+        if let Some(peer) = self.peers.get_mut(peer_id) {
+            peer.timing = timing;
+        }
+    }
+
+    /// The name of every peer by PeerId.
+    pub fn names(&self) -> BTreeMap<String, String> {
+        self.peers
+            .iter()
+            .map(|(peer_id, peer)| (peer_id.clone(), peer.name.clone()))
+            .collect()
+    }
+
+    pub fn is_muted(&self, peer_id: &str) -> bool {
+        self.peers.get(peer_id).map_or(false, |peer| peer.muted)
+    }
+
+    pub fn set_selected(&mut self, peer_id: &str, selected: bool) {
+        if let Some(peer) = self.peers.get_mut(peer_id) {
+            peer.selected = selected;
+        }
+    }
+
+    /// The PeerIds dropped MIDI files play to, every peer when none is selected.
+    pub fn file_targets(&self) -> Vec<String> {
+        let selected: Vec<String> = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| peer.selected)
+            .map(|(peer_id, _)| peer_id.clone())
+            .collect();
+        match selected.is_empty() {
+            true => self.peers.keys().cloned().collect(),
+            false => selected,
+        }
+    }
+
+    pub fn view(&self) -> Element<PeerPanelMessage> {
+        let title = match self.peers.len() {
+            0 => "No peers connected".to_string(),
+            1 => "1 peer connected:".to_string(),
+            n => format!("{} peers connected:", n),
+        };
+        self.peers
+            .iter()
+            .fold(
+                Column::new().spacing(5).push(Text::new(title)),
+                |col, (peer_id, peer)| {
+                    col.push(
+                        Row::new()
+                            .spacing(10)
+                            .align_items(iced::Alignment::Center)
+                            .push(
+                                Text::new(format!(
+                                    "{}  {}  {} messages",
+                                    peer.name,
+                                    match peer.paths.is_empty() {
+                                        true => "-".to_string(),
+                                        false => peer
+                                            .paths
+                                            .iter()
+                                            .map(|p| p.to_string())
+                                            .collect::<Vec<String>>()
+                                            .join(" + "),
+                                    },
+                                    peer.messages
+                                ))
+                                .size(14),
+                            )
+                            .push(match &peer.clock {
+                                // Notes may play at the wrong time, late or early
+                                Some(clock) if clock.is_poor() => {
+                                    Text::new(clock.to_string()).size(14).style(theme::WARNING)
+                                }
+                                Some(clock) => Text::new(clock.to_string()).size(14),
+                                None => Text::new(""),
+                            })
+                            .push(
+                                Slider::new(0..=100, peer.volume, |volume| {
+                                    PeerPanelMessage::Volume(peer_id.clone(), volume)
+                                })
+                                .width(Length::Fixed(100.0)),
+                            )
+                            .push(Button::new(Text::new("Learn").size(14)).on_press(
+                                PeerPanelMessage::Learn(MidiTarget::PeerVolume(peer_id.clone())),
+                            ))
+                            .push(
+                                Checkbox::new("Mute", peer.muted, |muted| {
+                                    PeerPanelMessage::Mute(peer_id.clone(), muted)
+                                })
+                                .size(14),
+                            )
+                            .push(Button::new(Text::new("Learn").size(14)).on_press(
+                                PeerPanelMessage::Learn(MidiTarget::PeerMute(peer_id.clone())),
+                            ))
+                            .push(
+                                Checkbox::new(
+                                    "Forgiving timing",
+                                    peer.timing == Timing::Forgiving,
+                                    |forgiving| {
+                                        PeerPanelMessage::Timing(
+                                            peer_id.clone(),
+                                            match forgiving {
+                                                true => Timing::Forgiving,
+                                                false => Timing::Tight,
+                                            },
+                                        )
+                                    },
+                                )
+                                .size(14),
+                            )
+                            .push(
+                                Checkbox::new("Play files", peer.selected, |selected| {
+                                    PeerPanelMessage::Select(peer_id.clone(), selected)
+                                })
+                                .size(14),
+                            )
+                            .push(
+                                Button::new(Text::new("Renegotiate").size(14))
+                                    .on_press(PeerPanelMessage::Renegotiate(peer_id.clone())),
+                            ),
+                    )
+                },
+            )
+            .into()
+    }
+}
+
+/// MIDI files dropped on the window, played one after the other.
+#[derive(Debug, Default)]
+pub struct FileQueue {
+    playing: Option<(PathBuf, FilePlayer)>,
+    queued: VecDeque<PathBuf>,
+}
+
+impl FileQueue {
+    pub fn push(&mut self, path: PathBuf) {
+        self.queued.push_back(path);
+    }
+
+    /// The file to play next, once the one playing finished.
+    pub fn next(&mut self) -> Option<PathBuf> {
+        if matches!(&self.playing, Some((_, player)) if player.is_finished()) {
+            self.playing = None;
+        }
+        match self.playing {
+            Some(_) => None,
+            None => self.queued.pop_front(),
+        }
+    }
+
+    pub fn play(&mut self, path: PathBuf, player: FilePlayer) {
+        self.playing = Some((path, player));
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.playing.is_some() || !self.queued.is_empty()
+    }
+
+    /// Stop the file playing and forget the queued ones.
+    pub fn cancel(&mut self) {
+        if let Some((_, player)) = self.playing.take() {
+            player.cancel();
+        }
+        self.queued.clear();
+    }
+
+    pub fn view<'a, M: Clone + 'a>(&self, on_cancel: M) -> Element<'a, M> {
+        let file_name = |path: &PathBuf| {
+            path.file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default()
+        };
+        let mut col = Column::new().spacing(5);
+        match &self.playing {
+            Some((path, player)) => {
+                col = col.push(
+                    Row::new()
+                        .spacing(10)
+                        .align_items(iced::Alignment::Center)
+                        .push(Text::new(format!("Playing {}", file_name(path))).size(14))
+                        .push(
+                            ProgressBar::new(0.0..=1.0, player.progress())
+                                .height(Length::Fixed(10.0))
+                                .width(Length::Fixed(200.0)),
+                        )
+                        .push(Button::new(Text::new("Cancel").size(14)).on_press(on_cancel)),
+                );
+            }
+            None => {
+                col = col.push(
+                    Text::new("Drop a MIDI file on the window to play it to the peers").size(14),
+                );
+            }
+        }
+        if !self.queued.is_empty() {
+            col = col.push(
+                Text::new(format!(
+                    "Next: {}",
+                    self.queued
+                        .iter()
+                        .map(file_name)
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                ))
+                .size(14),
+            );
+        }
+        col.into()
+    }
+}
+
+/// When a peer last played notes and controllers on each channel.
+#[derive(Debug, Default)]
+struct PeerChannels {
+    name: String,
+    notes: [Option<Instant>; 16],
+    controls: [Option<Instant>; 16],
+}
+
+/// Lights for the 16 channels of every peer, showing at a glance which channels its notes and
+/// controllers arrive on after routing.
+#[derive(Debug, Default)]
+pub struct ChannelActivity {
+    peers: BTreeMap<String, PeerChannels>,
+}
+
+impl ChannelActivity {
+    pub fn update(&mut self, event: &SessionEvent) {
+        match event {
+            SessionEvent::PeerJoined { peer_id, name } => {
+                self.peers.entry(peer_id.clone()).or_default().name = name.clone();
+            }
+            SessionEvent::PeerLeft { peer_id } => {
+                self.peers.remove(peer_id);
+            }
+            SessionEvent::Midi {
+                peer_id, message, ..
+            } => {
+                let (peer, channel) = match (self.peers.get_mut(peer_id), midi::channel(message)) {
+                    (Some(peer), Some(channel)) => (peer, channel as usize),
+                    _ => return,
+                };
+                match MessageKind::of(message) {
+                    Some(
+                        MessageKind::NoteOn | MessageKind::NoteOff | MessageKind::PolyAftertouch,
+                    ) => peer.notes[channel] = Some(Instant::now()),
+                    Some(_) => peer.controls[channel] = Some(Instant::now()),
+                    None => {}
+                }
+            }
+            SessionEvent::PeerWaiting { .. } | SessionEvent::CountIn { .. } => {}
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.peers.clear();
+    }
+
+    /// The lights stay on until the next redraw, `refresh` from now, at least.
+    pub fn view<'a, M: 'a>(&self, refresh: Duration) -> Element<'a, M> {
+        let light_for = ACTIVITY_LIGHT.max(refresh);
+        let lit = |at: Option<Instant>| at.map_or(false, |at| at.elapsed() < light_for);
+        let light = |on: bool, color| {
+            Text::new("●")
+                .size(14)
+                .width(Length::Fixed(24.0))
+                .style(match on {
+                    true => color,
+                    false => theme::LIGHT_OFF,
+                })
+        };
+        let label = |text: String| Text::new(text).size(14).width(Length::Fixed(160.0));
+        let header = (1..=16).fold(Row::new().push(label("Channel".to_string())), |row, c| {
+            row.push(Text::new(c.to_string()).size(14).width(Length::Fixed(24.0)))
+        });
+        self.peers
+            .values()
+            .fold(Column::new().spacing(5).push(header), |col, peer| {
+                let notes = (0..16).fold(
+                    Row::new().push(label(format!("{} notes", peer.name))),
+                    |row, c| row.push(light(lit(peer.notes[c]), theme::NOTE_LIGHT)),
+                );
+                let controls = (0..16).fold(
+                    Row::new().push(label(format!("{} controls", peer.name))),
+                    |row, c| row.push(light(lit(peer.controls[c]), theme::CONTROL_LIGHT)),
+                );
+                col.push(Rule::horizontal(5)).push(notes).push(controls)
+            })
+            .into()
+    }
+}
+
+/// A controller whose values can be graphed in the monitor, on any channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Controller {
+    PitchBend,
+    ChannelPressure,
+    ControlChange(u8),
+}
+
+impl Controller {
+    fn all() -> Vec<Controller> {
+        [Controller::PitchBend, Controller::ChannelPressure]
+            .into_iter()
+            .chain((0..128).map(Controller::ControlChange))
+            .collect()
+    }
+
+    /// The value `message` sets the controller to.
+    fn value(&self, message: &[u8]) -> Option<i32> {
+        match (self, MessageKind::of(message), message) {
+            (Controller::PitchBend, Some(MessageKind::PitchBend), [_, lsb, msb]) => {
+                Some(((*msb as i32) << 7 | *lsb as i32) - 8192)
+            }
+            (Controller::ChannelPressure, Some(MessageKind::ChannelAftertouch), [_, value]) => {
+                Some(*value as i32)
+            }
+            (
+                Controller::ControlChange(controller),
+                Some(MessageKind::ControlChange),
+                [_, number, value],
+            ) if number == controller => Some(*value as i32),
+            _ => None,
+        }
+    }
+
+    /// Lowest and highest values.
+    fn range(&self) -> (i32, i32) {
+        match self {
+            Controller::PitchBend => (-8192, 8191),
+            _ => (0, 127),
+        }
+    }
+}
+
+impl std::fmt::Display for Controller {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Controller::PitchBend => write!(f, "Pitch bend"),
+            Controller::ChannelPressure => write!(f, "Channel pressure"),
+            Controller::ControlChange(controller) => write!(f, "CC{}", controller),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ControllerGraphsMessage {
+    /// Graph a controller of a peer, by PeerId.
+    Pin(String, Controller),
+    Unpin(String, Controller),
+    /// Seconds graphed.
+    Window(u64),
+}
+
+/// Values a peer set its pinned controllers to lately.
+#[derive(Debug, Default)]
+struct PeerGraphs {
+    name: String,
+    pinned: BTreeMap<Controller, VecDeque<(Instant, i32)>>,
+}
+
+/// Graphs of the controllers pinned for each peer, to see what thinning and smoothing leave of
+/// them.
+#[derive(Debug)]
+pub struct ControllerGraphs {
+    peers: BTreeMap<String, PeerGraphs>,
+    window_secs: u64,
+}
+
+impl Default for ControllerGraphs {
+    fn default() -> Self {
+        ControllerGraphs {
+            peers: BTreeMap::new(),
+            window_secs: DEFAULT_GRAPH_SECS,
+        }
+    }
+}
+
+impl ControllerGraphs {
+    pub fn update(&mut self, message: ControllerGraphsMessage) {
+        match message {
+            ControllerGraphsMessage::Pin(peer_id, controller) => {
+                if let Some(peer) = self.peers.get_mut(&peer_id) {
+                    peer.pinned.entry(controller).or_default();
+                }
+            }
+            ControllerGraphsMessage::Unpin(peer_id, controller) => {
+                if let Some(peer) = self.peers.get_mut(&peer_id) {
+                    peer.pinned.remove(&controller);
+                }
+            }
+            ControllerGraphsMessage::Window(secs) => self.window_secs = secs.max(1),
+        }
+    }
+
+    /// Follow the peers joining and leaving, and keep the values of the pinned controllers.
+    pub fn push(&mut self, event: &SessionEvent) {
+        match event {
+            SessionEvent::PeerJoined { peer_id, name } => {
+                self.peers.entry(peer_id.clone()).or_default().name = name.clone();
+            }
+            SessionEvent::PeerLeft { peer_id } => {
+                self.peers.remove(peer_id);
+            }
+            SessionEvent::Midi {
+                peer_id, message, ..
+            } => {
+                let window = Duration::from_secs(self.window_secs);
+                let peer = match self.peers.get_mut(peer_id) {
+                    Some(peer) => peer,
+                    None => return,
+                };
+                for (controller, values) in peer.pinned.iter_mut() {
+                    if let Some(value) = controller.value(message) {
+                        values.push_back((Instant::now(), value));
+                        // The last value before the window is where the graph starts from
+                        while values.get(1).map_or(false, |(at, _)| at.elapsed() > window) {
+                            values.pop_front();
+                        }
+                    }
+                }
+            }
+            SessionEvent::PeerWaiting { .. } | SessionEvent::CountIn { .. } => {}
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.peers.clear();
+    }
+
+    /// One bar per column of the last value set by its end, blank before the first.
+    fn graph(&self, controller: &Controller, values: &VecDeque<(Instant, i32)>) -> String {
+        let window = Duration::from_secs(self.window_secs);
+        let (lowest, highest) = controller.range();
+        let now = Instant::now();
+        (1..=GRAPH_COLUMNS)
+            .map(|column| {
+                let end = window * (GRAPH_COLUMNS - column) as u32 / GRAPH_COLUMNS as u32;
+                values
+                    .iter()
+                    .rev()
+                    .find(|(at, _)| now.duration_since(*at) >= end)
+                    .map_or(' ', |(_, value)| {
+                        let level = (value - lowest) as usize * GRAPH_BARS.len()
+                            / (highest - lowest + 1) as usize;
+                        GRAPH_BARS[level.min(GRAPH_BARS.len() - 1)]
+                    })
+            })
+            .collect()
+    }
+
+    pub fn view(&self) -> Element<ControllerGraphsMessage> {
+        let window = Row::new()
+            .spacing(10)
+            .align_items(iced::Alignment::Center)
+            .push(Text::new("Graph the last").size(14))
+            .push(
+                NumberInput::new(
+                    self.window_secs,
+                    MAX_GRAPH_SECS,
+                    ControllerGraphsMessage::Window,
+                )
+                .size(14.0),
+            )
+            .push(Text::new("seconds of the pinned controllers").size(14));
+        self.peers
+            .iter()
+            .fold(
+                Column::new().spacing(5).push(window),
+                |col, (peer_id, peer)| {
+                    let pick = Row::new()
+                        .spacing(10)
+                        .align_items(iced::Alignment::Center)
+                        .push(Text::new(peer.name.clone()).size(14))
+                        .push(
+                            PickList::new(Controller::all(), None, |controller| {
+                                ControllerGraphsMessage::Pin(peer_id.clone(), controller)
+                            })
+                            .placeholder("Pin a controller")
+                            .text_size(14),
+                        );
+                    peer.pinned.iter().fold(
+                        col.push(Rule::horizontal(5)).push(pick),
+                        |col, (controller, values)| {
+                            col.push(
+                                Row::new()
+                                    .spacing(10)
+                                    .align_items(iced::Alignment::Center)
+                                    .push(
+                                        Text::new(controller.to_string())
+                                            .size(14)
+                                            .width(Length::Fixed(130.0)),
+                                    )
+                                    .push(
+                                        Text::new(self.graph(controller, values))
+                                            .size(14)
+                                            .font(Font::MONOSPACE),
+                                    )
+                                    .push(
+                                        Text::new(match values.back() {
+                                            Some((_, value)) => value.to_string(),
+                                            None => "-".to_string(),
+                                        })
+                                        .size(14)
+                                        .width(Length::Fixed(50.0)),
+                                    )
+                                    .push(Button::new(Text::new("Unpin").size(14)).on_press(
+                                        ControllerGraphsMessage::Unpin(
+                                            peer_id.clone(),
+                                            *controller,
+                                        ),
+                                    )),
+                            )
+                        },
+                    )
+                },
+            )
+            .into()
+    }
+}
+
+/// One line of the event timeline, with peers called by the name in `names` when known.
+pub fn event_record<'a, M: 'a>(
+    record: &EventRecord,
+    names: &BTreeMap<String, String>,
+) -> Element<'a, M> {
+    let name = |peer_id: &String| names.get(peer_id).unwrap_or(peer_id).clone();
+    let description = match &record.event {
+        StatusEvent::PeerJoined { peer_id, name } => format!("{} joined ({})", name, peer_id),
+        StatusEvent::PeerLeft { peer_id } => format!("{} left", name(peer_id)),
+        StatusEvent::Transport { tempo, playing } => format!(
+            "{} at {:.1} BPM",
+            match playing {
+                true => "Playing",
+                false => "Stopped",
+            },
+            tempo
+        ),
+        StatusEvent::Recording {
+            recording: true,
+            path,
+        } => match path {
+            Some(path) => format!("Recording to {}", path.display()),
+            None => "Recording".to_string(),
+        },
+        StatusEvent::Recording { .. } => "Recording stopped".to_string(),
+        StatusEvent::RateLimited { peer_id, rate } => {
+            format!("{} went over {} messages a second", name(peer_id), rate)
+        }
+        StatusEvent::LatencyMode {
+            peer_id,
+            mode,
+            delay_bars,
+        } => match delay_bars {
+            Some(bars) => format!("{} played {}, {} bars late", name(peer_id), mode, bars),
+            None => format!("{} played {}", name(peer_id), mode),
+        },
+        StatusEvent::Latency {
+            peer_id,
+            rtt_ms,
+            alarm,
+        } => match alarm {
+            true => format!("{} is {:.0} ms away", name(peer_id), rtt_ms),
+            false => format!("{} is back to {:.0} ms", name(peer_id), rtt_ms),
+        },
+        StatusEvent::DirectConnection {
+            peer_id,
+            error: None,
+        } => format!("{} connected directly", name(peer_id)),
+        StatusEvent::DirectConnection {
+            peer_id,
+            error: Some(error),
+        } => format!("{} stays relayed: {}", name(peer_id), error),
+        StatusEvent::Dropout { peer_id, lost } => {
+            format!("{} lost {} messages on the way", name(peer_id), lost)
+        }
+        StatusEvent::Panic => "Panic, all notes off".to_string(),
+    };
+    let text = Text::new(format!("{}  {}", format_utc_time(record.at), description)).size(14);
+    match &record.event {
+        StatusEvent::Dropout { .. }
+        | StatusEvent::Panic
+        | StatusEvent::Latency { alarm: true, .. }
+        | StatusEvent::DirectConnection { error: Some(_), .. } => text.style(theme::WARNING).into(),
+        _ => text.into(),
+    }
+}
+
+/// Who a recent session was with, when and how it was joined.
+pub fn recent_session<'a, M: 'a>(recent: &RecentSession) -> Element<'a, M> {
+    let title = match (recent.peers.is_empty(), recent.targets.is_empty()) {
+        (false, _) => recent.peers.join(", "),
+        (true, false) => recent.targets.join(", "),
+        (true, true) => "Waiting for peers".to_string(),
+    };
+    let settings = &recent.settings;
+    let mut details = vec![format!(
+        "relay {}:{}",
+        settings.relay_address, settings.relay_port
+    )];
+    if let Some(device) = &settings.midi_device {
+        details.push(format!("from {}", device));
+    }
+    if let Some(output) = &settings.midi_output {
+        details.push(format!("to {}", output));
+    }
+    if let Some(track) = &settings.track {
+        details.push(format!("playing {}", track));
+    }
+    Column::new()
+        .spacing(5)
+        .push(Text::new(title).size(18))
+        .push(Text::new(format!("Last joined {} UTC", format_utc(recent.at))).size(14))
+        .push(Text::new(details.join(", ")).size(14))
+        .into()
+}
+
+/// A connection check, with advice under it when it failed.
+pub fn check_result<'a, M: 'a>(result: &CheckResult) -> Element<'a, M> {
+    let line = |text: String| Text::new(text).size(14);
+    match &result.outcome {
+        Outcome::Passed { detail } => line(format!("{}: {}", result.check, detail)).into(),
+        Outcome::Warning { error, advice } => Column::new()
+            .push(line(format!("{}: {}", result.check, error)).style(theme::WARNING))
+            .push(line(advice.clone()))
+            .into(),
+        Outcome::Failed { error, advice } => Column::new()
+            .push(line(format!("{}: {}", result.check, error)).style(theme::ERROR))
+            .push(line(advice.clone()))
+            .into(),
+        Outcome::Skipped { reason } => line(format!("{}: skipped, {}", result.check, reason))
+            .style(theme::LIGHT_OFF)
+            .into(),
+    }
+}
+
+/// One line of the connection history.
+pub fn connection_record<'a, M: 'a>(record: &ConnectionRecord) -> Element<'a, M> {
+    let outcome = match &record.outcome {
+        ConnectionOutcome::Connected => match (record.duration_secs, record.rtt_ms) {
+            (Some(secs), Some(rtt)) => {
+                format!("connected for {:.0}s, {:.0} ms round trip", secs, rtt)
+            }
+            (Some(secs), None) => format!("connected for {:.0}s", secs),
+            (None, _) => "connected".to_string(),
+        },
+        ConnectionOutcome::Failed { error } => format!("failed: {}", error),
+    };
+    let text = Text::new(format!(
+        "{}  {:?}  {}  {}  {}  {}",
+        record.at,
+        record.direction,
+        record.peer_id.as_deref().unwrap_or("unknown peer"),
+        record.transport,
+        record.address.as_deref().unwrap_or(""),
+        outcome
+    ))
+    .size(14);
+    match record.outcome {
+        ConnectionOutcome::Failed { .. } => text.style(theme::ERROR).into(),
+        ConnectionOutcome::Connected => text.into(),
+    }
+}
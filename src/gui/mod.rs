@@ -0,0 +1,45 @@
+//! The settings window, which can also run a session with them. `app` keeps the state and handles
+//! messages, `screens` lays out what is shown, built from the pieces in `components`,
+//! `subscription` brings in events from outside, the running session's among them, and `theme`
+//! holds the look. `monitor_window` is the monitor popped out into a window of its own.
+
+mod app;
+mod components;
+mod monitor_window;
+mod screens;
+mod subscription;
+mod theme;
+
+use iced::{Application, Settings};
+use std::path::PathBuf;
+
+use crate::settings;
+use crate::storage::Storage;
+use app::{App, AppFlags};
+use monitor_window::MonitorWindow;
+
+pub fn run_app(
+    settings: settings::Settings,
+    config_path: PathBuf,
+    last_crash: Option<PathBuf>,
+    storage: Storage,
+) -> Result<(), iced::Error> {
+    App::run(Settings {
+        flags: AppFlags {
+            settings,
+            config_path,
+            last_crash,
+            storage,
+            ..AppFlags::default()
+        },
+        ..Default::default()
+    })
+}
+
+/// Show the monitor of a running GUI in a window of its own, fed on stdin.
+pub fn run_monitor_window(settings: settings::Settings) -> Result<(), iced::Error> {
+    MonitorWindow::run(Settings {
+        flags: settings,
+        ..Default::default()
+    })
+}
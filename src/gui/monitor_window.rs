@@ -0,0 +1,270 @@
+//! The monitor popped out of the GUI, to keep it on a second display. iced runs one window per
+//! process, so the GUI starts itself again with `monitor-window` and writes what the session plays
+//! and logs to its stdin, one JSON line at a time.
+
+use futures::channel::mpsc;
+use iced::futures::{SinkExt, StreamExt};
+use iced::widget::{Column, Container, Scrollable, Text};
+use iced::{executor, Application, Command, Element, Length, Subscription, Theme};
+use serde::{Deserialize, Serialize};
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::{Child, ChildStdin, Stdio};
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::{warn, Level};
+
+use super::components::{
+    ChannelActivity, ControllerGraphs, ControllerGraphsMessage, LogPanel,
+    PERFORMANCE_REFRESH_INTERVAL,
+};
+use super::theme;
+use crate::logging::LogLine;
+use crate::session::SessionEvent;
+use crate::settings::{Settings, ThemeType};
+
+/// How often the monitor window redraws, turning off the lights of channels gone quiet.
+const REFRESH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What the GUI tells the monitor window.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub(super) enum MonitorLine {
+    PeerJoined {
+        peer_id: String,
+        name: String,
+    },
+    PeerLeft {
+        peer_id: String,
+    },
+    Midi {
+        peer_id: String,
+        message: Vec<u8>,
+    },
+    Log {
+        level: String,
+        target: String,
+        message: String,
+    },
+    /// The session ended.
+    Clear,
+}
+
+impl MonitorLine {
+    pub(super) fn of_event(event: &SessionEvent) -> Option<Self> {
+        match event {
+            SessionEvent::PeerJoined { peer_id, name } => Some(MonitorLine::PeerJoined {
+                peer_id: peer_id.clone(),
+                name: name.clone(),
+            }),
+            SessionEvent::PeerLeft { peer_id } => Some(MonitorLine::PeerLeft {
+                peer_id: peer_id.clone(),
+            }),
+            SessionEvent::Midi {
+                peer_id, message, ..
+            } => Some(MonitorLine::Midi {
+                peer_id: peer_id.clone(),
+                message: message.to_vec(),
+            }),
+            SessionEvent::PeerWaiting { .. } | SessionEvent::CountIn { .. } => None,
+        }
+    }
+
+    pub(super) fn of_log(line: &LogLine) -> Self {
+        MonitorLine::Log {
+            level: line.level.to_string(),
+            target: line.target.clone(),
+            message: line.message.clone(),
+        }
+    }
+
+    fn into_event(self) -> Option<SessionEvent> {
+        match self {
+            MonitorLine::PeerJoined { peer_id, name } => {
+                Some(SessionEvent::PeerJoined { peer_id, name })
+            }
+            MonitorLine::PeerLeft { peer_id } => Some(SessionEvent::PeerLeft { peer_id }),
+            MonitorLine::Midi { peer_id, message } => Some(SessionEvent::Midi {
+                peer_id,
+                message: message.into(),
+                track: None,
+            }),
+            MonitorLine::Log { .. } | MonitorLine::Clear => None,
+        }
+    }
+}
+
+/// The monitor window started by the GUI, closed when dropped.
+pub(super) struct DetachedMonitor {
+    child: Child,
+    stdin: ChildStdin,
+}
+
+impl DetachedMonitor {
+    /// Start the monitor window, with the theme and performance mode of the config file at
+    /// `config_path`.
+    pub(super) fn open(config_path: &Path) -> io::Result<Self> {
+        let mut child = std::process::Command::new(std::env::current_exe()?)
+            .arg("--config")
+            .arg(config_path)
+            .arg("monitor-window")
+            .stdin(Stdio::piped())
+            .spawn()?;
+        let stdin = child
+            .stdin
+            .take()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Monitor window has no stdin"))?;
+        Ok(DetachedMonitor { child, stdin })
+    }
+
+    /// Fails once the window was closed.
+    pub(super) fn send(&mut self, line: &MonitorLine) -> io::Result<()> {
+        let mut json = serde_json::to_string(line)?;
+        json.push('\n');
+        self.stdin.write_all(json.as_bytes())
+    }
+}
+
+impl Drop for DetachedMonitor {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(super) enum Message {
+    Line(MonitorLine),
+    /// The GUI quit or took the monitor back.
+    Closed,
+    /// Time to redraw the channel lights.
+    Tick,
+    ControllerGraphs(ControllerGraphsMessage),
+}
+
+pub(super) struct MonitorWindow {
+    theme: Option<ThemeType>,
+    refresh: Duration,
+    channels: ChannelActivity,
+    graphs: ControllerGraphs,
+    log: LogPanel,
+}
+
+impl Application for MonitorWindow {
+    type Executor = executor::Default;
+    type Message = Message;
+    type Theme = Theme;
+    type Flags = Settings;
+
+    fn new(settings: Self::Flags) -> (Self, Command<Message>) {
+        let window = MonitorWindow {
+            theme: settings.theme,
+            refresh: match settings.performance_mode {
+                Some(true) => PERFORMANCE_REFRESH_INTERVAL,
+                _ => REFRESH_INTERVAL,
+            },
+            channels: ChannelActivity::default(),
+            graphs: ControllerGraphs::default(),
+            log: LogPanel::default(),
+        };
+        (window, Command::none())
+    }
+
+    fn title(&self) -> String {
+        String::from("Monitor")
+    }
+
+    fn update(&mut self, message: Message) -> Command<Message> {
+        match message {
+            Message::Line(MonitorLine::Log {
+                level,
+                target,
+                message,
+            }) => self.log.push(LogLine {
+                level: Level::from_str(&level).unwrap_or(Level::INFO),
+                target,
+                message,
+            }),
+            Message::Line(MonitorLine::Clear) => {
+                self.channels.clear();
+                self.graphs.clear();
+            }
+            Message::Line(line) => {
+                if let Some(event) = line.into_event() {
+                    self.channels.update(&event);
+                    self.graphs.push(&event);
+                }
+            }
+            Message::Closed => return iced::window::close(),
+            Message::Tick => {}
+            Message::ControllerGraphs(message) => self.graphs.update(message),
+        }
+        Command::none()
+    }
+
+    fn view(&self) -> Element<Message> {
+        let col = Column::new()
+            .spacing(20)
+            .push(Text::new("Monitor").size(24))
+            .push(
+                Scrollable::new(
+                    Column::new()
+                        .spacing(20)
+                        .push(self.channels.view(self.refresh))
+                        .push(self.graphs.view().map(Message::ControllerGraphs)),
+                )
+                .height(Length::Fill),
+            )
+            .push(self.log.view());
+        Container::new(col)
+            .width(Length::Fill)
+            .height(Length::Fill)
+            .padding(25)
+            .into()
+    }
+
+    fn theme(&self) -> Theme {
+        theme::iced_theme(self.theme)
+    }
+
+    fn subscription(&self) -> Subscription<Message> {
+        Subscription::batch([
+            stdin_lines(),
+            iced::time::every(self.refresh).map(|_| Message::Tick),
+        ])
+    }
+}
+
+/// Lines the GUI writes to stdin, then `Closed` when it closes the pipe.
+fn stdin_lines() -> Subscription<Message> {
+    iced::subscription::channel(
+        std::any::TypeId::of::<MonitorLine>(),
+        100,
+        |mut output| async move {
+            let (sender, mut lines) = mpsc::unbounded();
+            std::thread::spawn(move || {
+                for line in io::stdin().lines() {
+                    let line = match line {
+                        Ok(line) => line,
+                        Err(_) => break,
+                    };
+                    match serde_json::from_str(&line) {
+                        Ok(line) => {
+                            if sender.unbounded_send(line).is_err() {
+                                break;
+                            }
+                        }
+                        Err(e) => warn!("Ignoring monitor line: {}", e),
+                    }
+                }
+            });
+            while let Some(line) = lines.next().await {
+                let _ = output.send(Message::Line(line)).await;
+            }
+            let _ = output.send(Message::Closed).await;
+            loop {
+                iced::futures::future::pending::<()>().await;
+            }
+        },
+    )
+}
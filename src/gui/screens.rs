@@ -0,0 +1,540 @@
+use iced::widget::{
+    column, radio, Button, Checkbox, Column, Container, PickList, Row, Scrollable, Space, Text,
+    TextInput,
+};
+use iced::{Element, Length, Renderer};
+use iced_aw::NumberInput;
+use std::collections::BTreeMap;
+
+use super::app::{App, Message, MONITOR_REFRESH_INTERVAL};
+use super::components;
+use super::theme;
+use crate::constants;
+use crate::p2p::directory::OpenSession;
+use crate::p2p::playout::{LatencyMode, DEFAULT_DELAY_BARS};
+use crate::p2p::troubleshoot::{CheckResult, Outcome};
+use crate::settings::{self, ThemeType};
+use crate::status::StatusEvent;
+use crate::storage::{format_utc, ConnectionRecord, EventRecord, RecentSession};
+
+/// What the window shows.
+#[derive(Debug)]
+pub enum Screen {
+    Settings,
+    /// Connection history, newest first.
+    History(Vec<ConnectionRecord>),
+    /// Sessions open on the relay.
+    Sessions(Vec<OpenSession>),
+    /// Channel activity of the peers.
+    Monitor,
+    /// The latest session events, newest first.
+    Timeline(Vec<EventRecord>),
+    /// Results of the connection checks.
+    Troubleshoot(Vec<CheckResult>),
+    /// Sessions joined lately, latest first.
+    Recent(Vec<RecentSession>),
+}
+
+pub fn settings(app: &App) -> Element<Message> {
+    let current = &app.app_flags.settings;
+    let choose_theme = Row::new()
+        .push([ThemeType::Light, ThemeType::Dark].iter().fold(
+            column![Text::new("App theme:")].spacing(10),
+            |col: Column<Message>, choice| {
+                col.push(radio(
+                    format!("{choice:?}"),
+                    *choice,
+                    Some(theme::selected(current.theme)),
+                    |choice| {
+                        Message::SettingsChanged(settings::Settings {
+                            theme: Some(choice),
+                            ..current.clone()
+                        })
+                    },
+                ))
+            },
+        ))
+        .push(Space::with_width(Length::Fill));
+
+    let performance_mode = Checkbox::new(
+        "Performance mode, redrawing less to leave the CPU to softsynths",
+        current.performance_mode.unwrap_or(false),
+        |performance_mode| {
+            Message::SettingsChanged(settings::Settings {
+                performance_mode: Some(performance_mode),
+                ..current.clone()
+            })
+        },
+    );
+
+    let name_col = Column::<Message, Renderer>::new()
+        .push(Text::new("Your display name:"))
+        .push(
+            TextInput::new(
+                "Your display name among the nodes",
+                current.name.as_deref().unwrap_or_default(),
+            )
+            .on_input(|s| {
+                Message::SettingsChanged(settings::Settings {
+                    name: Some(s),
+                    ..current.clone()
+                })
+            })
+            .padding(15)
+            .size(20),
+        );
+
+    let port_col = Column::<Message, Renderer>::new()
+        .push(Text::new("Port:"))
+        .push(
+            NumberInput::new(
+                current.port.unwrap_or(0),
+                constants::MAX_PORT_NUMBER,
+                Message::AppPortChanged,
+            )
+            .size(20.0),
+        );
+
+    let selected_midi_device = app.midi_devices.first().cloned();
+    let devices_col = Row::new()
+        .push(
+            Column::new().push(Text::new("Input Midi Device:")).push(
+                Row::new()
+                    .spacing(20)
+                    .push(PickList::<String, Message, Renderer>::new(
+                        app.midi_devices.clone(),
+                        selected_midi_device,
+                        |s| {
+                            Message::SettingsChanged(settings::Settings {
+                                midi_device: Some(s),
+                                ..current.clone()
+                            })
+                        },
+                    ))
+                    .push(
+                        Button::<Message, Renderer>::new("Reload")
+                            .on_press(Message::ReloadMidiDevices),
+                    ),
+            ),
+        )
+        .push(Space::with_width(Length::Fill));
+
+    let learn_button = Button::<Message, Renderer>::new("Learn");
+    let control_col = Column::<Message, Renderer>::new()
+        .spacing(10)
+        .push(Text::new("Control Midi Device:"))
+        .push(PickList::<String, Message, Renderer>::new(
+            app.midi_devices.clone(),
+            current.control_device.clone(),
+            Message::ControlDeviceChanged,
+        ))
+        .push(
+            Row::new()
+                .spacing(20)
+                .push(Text::new("MIDI learn:"))
+                .push(PickList::new(
+                    app.learn_choices(),
+                    app.learn_choice.clone(),
+                    Message::LearnChoice,
+                ))
+                .push(match &app.learn_choice {
+                    Some(target) => learn_button.on_press(Message::Learn(target.clone())),
+                    None => learn_button,
+                })
+                .push(Text::new(match &app.learning {
+                    Some(target) => format!("Move a control to bind it to {}", target),
+                    None => String::new(),
+                })),
+        );
+
+    let latency_choices = [
+        ("Auto", None),
+        ("Live", Some(LatencyMode::Live)),
+        ("Buffered", Some(LatencyMode::Buffered)),
+        ("Bars late", Some(LatencyMode::Bar)),
+    ];
+    let latency_modes = latency_choices.iter().fold(
+        Row::new().spacing(20),
+        |row: Row<Message>, (label, choice)| {
+            row.push(radio(
+                *label,
+                *choice,
+                Some(current.latency_mode),
+                |choice| {
+                    Message::SettingsChanged(settings::Settings {
+                        latency_mode: choice,
+                        ..current.clone()
+                    })
+                },
+            ))
+        },
+    );
+    let delay_bars = current.delay_bars.unwrap_or(DEFAULT_DELAY_BARS);
+    let latency_col = Column::<Message, Renderer>::new()
+        .spacing(10)
+        .push(Text::new("Play MIDI from peers:"))
+        .push(latency_modes)
+        .push(match current.latency_mode {
+            Some(LatencyMode::Bar) => Row::new()
+                .spacing(20)
+                .push(NumberInput::new(delay_bars, 16, Message::DelayBarsChanged).size(20.0))
+                .push(Text::new(match delay_bars {
+                    1 => "Peers are heard a bar late, you play along with their previous bar"
+                        .to_string(),
+                    bars => format!("Peers are heard {} bars late", bars),
+                })),
+            Some(_) => Row::new(),
+            None => Row::new().push(Text::new(
+                "Picked for each peer by round trip time, far away peers are heard a bar late",
+            )),
+        });
+
+    let relay_row = Column::<Message, Renderer>::new()
+        .spacing(5)
+        .push(Text::new("Custom Relay:"))
+        .push(
+            TextInput::new(
+                "Custom Relay address",
+                current.relay_address.as_deref().unwrap_or_default(),
+            )
+            .on_input(|s| {
+                Message::SettingsChanged(settings::Settings {
+                    relay_address: Some(s),
+                    ..current.clone()
+                })
+            })
+            .padding(15)
+            .size(20),
+        )
+        .push(
+            NumberInput::new(
+                current.relay_port.unwrap_or(constants::RELAY_PORT),
+                constants::MAX_PORT_NUMBER,
+                Message::RelayPortChanged,
+            )
+            .size(20.0)
+            .step(1),
+        );
+
+    let bottom_row = Row::new()
+        .spacing(20)
+        .push(Space::with_width(Length::Fill))
+        .push(Button::new("Recent").on_press(Message::ShowRecent))
+        .push(Button::new("History").on_press(Message::ShowHistory))
+        .push(Button::new("Monitor").on_press(Message::ShowMonitor))
+        .push(Button::new("Timeline").on_press(Message::ShowTimeline))
+        .push(Button::new("Troubleshoot").on_press(Message::Troubleshoot))
+        .push(Button::new("Browse Sessions").on_press(Message::BrowseSessions))
+        .push(
+            Button::new(match app.session {
+                Some(_) => "Disconnect",
+                None => "Connect",
+            })
+            .on_press(Message::Connect),
+        )
+        .push(match app.session {
+            Some(_) => Button::new("Tap Tempo").on_press(Message::TapTempo),
+            None => Button::new("Tap Tempo"),
+        })
+        .push(match app.session {
+            Some(_) => Button::new("Start Together").on_press(Message::StartTogether),
+            None => Button::new("Start Together"),
+        })
+        .push(Button::new("Reset Settings").on_press(Message::ResetSettings))
+        .push(Button::new("Save Settings").on_press(Message::SaveSettings));
+
+    // Problems found on start, fixed from here instead of the config file
+    let mut problems = Column::new().spacing(10);
+    if let Some(e) = &app.midi_error {
+        problems = problems.push(components::problem(
+            format!("MIDI is unavailable: {}", e),
+            "Reload MIDI backend",
+            Message::ReloadMidiDevices,
+        ));
+    }
+    if current.relay_address.as_deref().map_or(true, str::is_empty) {
+        problems = problems.push(components::problem(
+            "No relay address set, enter one below or".to_string(),
+            "Use default relay",
+            Message::UseDefaultRelay,
+        ));
+    }
+
+    // New peers wait here until they are let in or turned away
+    let waiting = app
+        .waiting
+        .iter()
+        .fold(Column::new().spacing(10), |col, (peer_id, name)| {
+            col.push(components::join_request(
+                peer_id,
+                name.as_deref(),
+                Message::AcceptPeer(peer_id.clone()),
+                Message::RejectPeer(peer_id.clone()),
+            ))
+        });
+
+    let col = Column::new()
+        .spacing(20)
+        .push(app.notices.view())
+        .push(problems)
+        .push(waiting)
+        .push(Space::with_height(20))
+        .push(choose_theme)
+        .push(performance_mode)
+        .push(name_col)
+        .push(
+            app.addresses
+                .view(&current.ip_addresses)
+                .map(Message::Addresses),
+        )
+        .push(port_col)
+        .push(devices_col)
+        .push(control_col)
+        .push(latency_col)
+        .push(relay_row)
+        .push(bottom_row)
+        .push(
+            app.save_as
+                .view(&app.app_flags.config_path.display().to_string())
+                .map(Message::SaveAs),
+        )
+        .push(app.peers.view().map(Message::PeerPanel))
+        .push(match app.session {
+            Some(_) => app.files.view(Message::CancelPlayback),
+            None => Space::with_height(0).into(),
+        })
+        .push(app.log.view())
+        .align_items(iced::Alignment::Center);
+
+    Container::new(col)
+        .center_x()
+        .center_y()
+        .width(Length::Fill)
+        .height(Length::Fill)
+        .padding(25)
+        .into()
+}
+
+/// Past connection attempts, one per line.
+pub fn history(records: &[ConnectionRecord]) -> Element<Message> {
+    let list = records.iter().fold(
+        Column::new().spacing(5).width(Length::Fill),
+        |col, record| col.push(components::connection_record(record)),
+    );
+    let col = Column::new()
+        .spacing(20)
+        .push(
+            Row::new()
+                .push(Text::new("Connection history").size(24))
+                .push(Space::with_width(Length::Fill))
+                .push(Button::new("Back").on_press(Message::HideHistory)),
+        )
+        .push(match records.is_empty() {
+            true => Text::new("No connections yet"),
+            false => Text::new(format!("{} connection attempts", records.len())),
+        })
+        .push(Scrollable::new(list).height(Length::Fill));
+    Container::new(col)
+        .width(Length::Fill)
+        .height(Length::Fill)
+        .padding(25)
+        .into()
+}
+
+/// Session events from the event log, with a line for each day.
+pub fn timeline(records: &[EventRecord]) -> Element<Message> {
+    // Peers left are named after what they joined as
+    let names: BTreeMap<String, String> = records
+        .iter()
+        .filter_map(|record| match &record.event {
+            StatusEvent::PeerJoined { peer_id, name } => Some((peer_id.clone(), name.clone())),
+            _ => None,
+        })
+        .collect();
+    let mut day = String::new();
+    let mut list = Column::new().spacing(5).width(Length::Fill);
+    for record in records {
+        let record_day = format_utc(record.at)[..10].to_string();
+        if record_day != day {
+            list = list.push(Text::new(record_day.clone()).size(18));
+            day = record_day;
+        }
+        list = list.push(components::event_record(record, &names));
+    }
+    let col = Column::new()
+        .spacing(20)
+        .push(
+            Row::new()
+                .push(Text::new("Timeline").size(24))
+                .push(Space::with_width(Length::Fill))
+                .push(Button::new("Back").on_press(Message::HideTimeline)),
+        )
+        .push(match records.is_empty() {
+            true => Text::new("No session events yet"),
+            false => Text::new(format!("The latest {} session events", records.len())),
+        })
+        .push(Scrollable::new(list).height(Length::Fill));
+    Container::new(col)
+        .width(Length::Fill)
+        .height(Length::Fill)
+        .padding(25)
+        .into()
+}
+
+/// The connection checks in order, with the advice of the one failing.
+pub fn troubleshoot(results: &[CheckResult]) -> Element<Message> {
+    let list = results.iter().fold(
+        Column::new().spacing(10).width(Length::Fill),
+        |col, result| col.push(components::check_result(result)),
+    );
+    let col = Column::new()
+        .spacing(20)
+        .push(
+            Row::new()
+                .push(Text::new("Troubleshoot").size(24))
+                .push(Space::with_width(Length::Fill))
+                .push(Button::new("Again").on_press(Message::Troubleshoot))
+                .push(Button::new("Back").on_press(Message::HideTroubleshoot)),
+        )
+        .push(
+            match results
+                .iter()
+                .any(|result| matches!(result.outcome, Outcome::Failed { .. }))
+            {
+                true => Text::new("A check failed, see what to do about it below"),
+                false => Text::new("The relay can be reached and peers can reach us through it"),
+            },
+        )
+        .push(Scrollable::new(list).height(Length::Fill));
+    Container::new(col)
+        .width(Length::Fill)
+        .height(Length::Fill)
+        .padding(25)
+        .into()
+}
+
+/// Which channels each peer plays notes and controllers on.
+pub fn monitor(app: &App) -> Element<Message> {
+    if app.monitor_window.is_some() {
+        let col = Column::new()
+            .spacing(20)
+            .push(
+                Row::new()
+                    .spacing(20)
+                    .push(Text::new("Monitor").size(24))
+                    .push(Space::with_width(Length::Fill))
+                    .push(Button::new("Bring Back").on_press(Message::AttachMonitor))
+                    .push(Button::new("Back").on_press(Message::HideMonitor)),
+            )
+            .push(Text::new("The monitor is open in a window of its own"));
+        return Container::new(col)
+            .width(Length::Fill)
+            .height(Length::Fill)
+            .padding(25)
+            .into();
+    }
+    let col = Column::new()
+        .spacing(20)
+        .push(
+            Row::new()
+                .spacing(20)
+                .push(Text::new("Monitor").size(24))
+                .push(Space::with_width(Length::Fill))
+                .push(Button::new("Pop Out").on_press(Message::DetachMonitor))
+                .push(Button::new("Back").on_press(Message::HideMonitor)),
+        )
+        .push(match app.session {
+            Some(_) => Text::new("Channels lit by the notes and controllers of each peer"),
+            None => Text::new("Connect to see what the peers play"),
+        })
+        .push(
+            Scrollable::new(
+                Column::new()
+                    .spacing(20)
+                    .push(
+                        app.channels
+                            .view(app.refresh_interval(MONITOR_REFRESH_INTERVAL)),
+                    )
+                    .push(app.graphs.view().map(Message::ControllerGraphs)),
+            )
+            .height(Length::Fill),
+        );
+    Container::new(col)
+        .width(Length::Fill)
+        .height(Length::Fill)
+        .padding(25)
+        .into()
+}
+
+/// A card for each session joined lately, to join it again.
+pub fn recent(sessions: &[RecentSession]) -> Element<Message> {
+    let list = sessions.iter().fold(
+        Column::new().spacing(10).width(Length::Fill),
+        |col, recent| {
+            col.push(
+                Container::new(
+                    Row::new()
+                        .spacing(10)
+                        .align_items(iced::Alignment::Center)
+                        .push(components::recent_session(recent))
+                        .push(Space::with_width(Length::Fill))
+                        .push(Button::new("Rejoin").on_press(Message::Rejoin(recent.clone()))),
+                )
+                .width(Length::Fill)
+                .padding(15)
+                .style(iced::theme::Container::Box),
+            )
+        },
+    );
+    let col = Column::new()
+        .spacing(20)
+        .push(
+            Row::new()
+                .push(Text::new("Recent sessions").size(24))
+                .push(Space::with_width(Length::Fill))
+                .push(Button::new("Settings").on_press(Message::HideRecent)),
+        )
+        .push(match sessions.is_empty() {
+            true => Text::new("No sessions joined yet"),
+            false => Text::new("Join one again with the settings it was joined with"),
+        })
+        .push(Scrollable::new(list).height(Length::Fill));
+    Container::new(col)
+        .width(Length::Fill)
+        .height(Length::Fill)
+        .padding(25)
+        .into()
+}
+
+pub fn sessions(sessions: &[OpenSession]) -> Element<Message> {
+    let list = sessions
+        .iter()
+        .fold(Column::new().spacing(5).width(Length::Fill), |col, open| {
+            col.push(
+                Row::new()
+                    .spacing(10)
+                    .align_items(iced::Alignment::Center)
+                    .push(Text::new(open.to_string()))
+                    .push(Space::with_width(Length::Fill))
+                    .push(Button::new("Join").on_press(Message::JoinSession(open.clone()))),
+            )
+        });
+    let col = Column::new()
+        .spacing(20)
+        .push(
+            Row::new()
+                .push(Text::new("Open sessions").size(24))
+                .push(Space::with_width(Length::Fill))
+                .push(Button::new("Back").on_press(Message::HideSessions)),
+        )
+        .push(match sessions.is_empty() {
+            true => Text::new("No sessions are open on this relay"),
+            false => Text::new(format!("{} sessions open on this relay", sessions.len())),
+        })
+        .push(Scrollable::new(list).height(Length::Fill));
+    Container::new(col)
+        .width(Length::Fill)
+        .height(Length::Fill)
+        .padding(25)
+        .into()
+}
@@ -0,0 +1,166 @@
+use futures::channel::mpsc::UnboundedReceiver;
+use iced::futures::{SinkExt, StreamExt};
+use iced::Subscription;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::warn;
+
+use super::app::Message;
+use crate::config_watcher::{watch_config, ConfigReloader};
+use crate::keybindings::KeyBinding;
+use crate::logging::{self, LogLine};
+use crate::ring::Consumer;
+use crate::session::SessionEvent;
+
+/// Events of a session just started, taken by its subscription.
+pub type SessionEvents = Arc<Mutex<Option<UnboundedReceiver<SessionEvent>>>>;
+
+/// How long the running session's events are gathered before being handed over, read for every
+/// batch so switching performance mode applies right away.
+pub type SessionBatch = Arc<Mutex<Option<Duration>>>;
+
+/// What the control device just opened plays, taken by its subscription.
+pub type ControlEvents = Arc<Mutex<Option<Consumer>>>;
+
+/// Convert an iced key press to the key names used in the `keybindings:` config section.
+fn key_binding(
+    key_code: iced::keyboard::KeyCode,
+    modifiers: iced::keyboard::Modifiers,
+) -> KeyBinding {
+    let mut key = format!("{:?}", key_code).to_lowercase();
+    // Digit keys are named Key0 to Key9
+    if key.len() == 4 && key.starts_with("key") {
+        key = key.split_off(3);
+    }
+    KeyBinding {
+        ctrl: modifiers.control(),
+        alt: modifiers.alt(),
+        shift: modifiers.shift(),
+        logo: modifiers.logo(),
+        key,
+    }
+}
+
+/// Key presses no widget took.
+pub fn keys() -> Subscription<Message> {
+    iced::subscription::events_with(|event, status| match (event, status) {
+        (
+            iced::Event::Keyboard(iced::keyboard::Event::KeyPressed {
+                key_code,
+                modifiers,
+            }),
+            iced::event::Status::Ignored,
+        ) => Some(Message::KeyPressed(key_binding(key_code, modifiers))),
+        _ => None,
+    })
+}
+
+/// Files dropped on the window.
+pub fn dropped_files() -> Subscription<Message> {
+    iced::subscription::events_with(|event, _| match event {
+        iced::Event::Window(iced::window::Event::FileDropped(path)) => {
+            Some(Message::FileDropped(path))
+        }
+        _ => None,
+    })
+}
+
+/// Changes to the config file on disk.
+pub fn config_changes(config_path: PathBuf) -> Subscription<Message> {
+    iced::subscription::channel(
+        std::any::TypeId::of::<ConfigReloader>(),
+        10,
+        move |mut output| async move {
+            let mut watched = watch_config(&config_path).map_err(|e| e.to_string());
+            if let Err(e) = &watched {
+                warn!("Not watching config file for changes: {}", e);
+            }
+            loop {
+                match &mut watched {
+                    Ok((_, changes)) => {
+                        changes.select_next_some().await;
+                        let _ = output.send(Message::ConfigFileChanged).await;
+                    }
+                    Err(_) => iced::futures::future::pending::<()>().await,
+                }
+            }
+        },
+    )
+}
+
+/// Log lines for the log panel.
+pub fn log() -> Subscription<Message> {
+    iced::subscription::channel(
+        std::any::TypeId::of::<LogLine>(),
+        100,
+        |mut output| async move {
+            let mut lines = logging::take_gui_log();
+            loop {
+                match &mut lines {
+                    Some(lines) => match lines.next().await {
+                        Some(line) => {
+                            let _ = output.send(Message::Log(line)).await;
+                        }
+                        None => iced::futures::future::pending::<()>().await,
+                    },
+                    None => iced::futures::future::pending::<()>().await,
+                }
+            }
+        },
+    )
+}
+
+/// Events of the running session, the `session`th one started, then its end. While `batch` holds
+/// an interval the events are handed over together once per interval instead of one by one.
+pub fn session(session: u64, events: SessionEvents, batch: SessionBatch) -> Subscription<Message> {
+    iced::subscription::channel(
+        (std::any::TypeId::of::<SessionEvent>(), session),
+        100,
+        move |mut output| async move {
+            let events = events.lock().ok().and_then(|mut events| events.take());
+            if let Some(mut events) = events {
+                while let Some(event) = events.next().await {
+                    let interval = batch.lock().ok().and_then(|batch| *batch);
+                    let message = match interval {
+                        Some(interval) => {
+                            tokio::time::sleep(interval).await;
+                            let mut batch = vec![event];
+                            while let Ok(Some(event)) = events.try_next() {
+                                batch.push(event);
+                            }
+                            Message::SessionBatch(batch)
+                        }
+                        None => Message::Session(event),
+                    };
+                    let _ = output.send(message).await;
+                }
+                let _ = output.send(Message::SessionEnded).await;
+            }
+            loop {
+                iced::futures::future::pending::<()>().await;
+            }
+        },
+    )
+}
+
+/// MIDI played on the `device`th control device opened.
+pub fn control_midi(device: u64, events: ControlEvents) -> Subscription<Message> {
+    iced::subscription::channel(
+        (std::any::TypeId::of::<Consumer>(), device),
+        100,
+        move |mut output| async move {
+            let events = events.lock().ok().and_then(|mut events| events.take());
+            if let Some(mut events) = events {
+                while let Some(event) = events.next().await {
+                    let _ = output
+                        .send(Message::ControlMidi(event.message().to_vec()))
+                        .await;
+                }
+            }
+            loop {
+                iced::futures::future::pending::<()>().await;
+            }
+        },
+    )
+}
@@ -0,0 +1,25 @@
+use iced::{Color, Theme};
+
+use crate::settings::ThemeType;
+
+/// Errors and failed connections.
+pub const ERROR: Color = Color::from_rgb(1.0, 0.0, 0.0);
+
+pub const WARNING: Color = Color::from_rgb(0.8, 0.5, 0.0);
+
+/// Channel lights of the monitor, for notes, controllers and channels without either lately.
+pub const NOTE_LIGHT: Color = Color::from_rgb(0.1, 0.7, 0.2);
+pub const CONTROL_LIGHT: Color = Color::from_rgb(0.2, 0.4, 0.9);
+pub const LIGHT_OFF: Color = Color::from_rgb(0.6, 0.6, 0.6);
+
+pub fn iced_theme(theme: Option<ThemeType>) -> Theme {
+    match theme {
+        Some(ThemeType::Dark) => Theme::Dark,
+        _ => Theme::Light,
+    }
+}
+
+/// The theme picked in the settings, light unless set.
+pub fn selected(theme: Option<ThemeType>) -> ThemeType {
+    theme.unwrap_or(ThemeType::Light)
+}
@@ -0,0 +1,197 @@
+//! Chords played from single notes, for one-finger pad parts while writing together. Notes played
+//! in a zone are sent to the peers as the chord voiced for the zone, the notes outside of every
+//! zone as they are.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use super::midi::{self, MessageKind};
+
+/// Common chords, by the semitones of their notes above the one played.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Chord {
+    Major,
+    Minor,
+    /// Root and fifth.
+    Power,
+    Octave,
+    Sus2,
+    Sus4,
+    Major7,
+    Minor7,
+    Dominant7,
+}
+
+impl Chord {
+    pub fn intervals(&self) -> &'static [i8] {
+        match self {
+            Chord::Major => &[0, 4, 7],
+            Chord::Minor => &[0, 3, 7],
+            Chord::Power => &[0, 7],
+            Chord::Octave => &[0, 12],
+            Chord::Sus2 => &[0, 2, 7],
+            Chord::Sus4 => &[0, 5, 7],
+            Chord::Major7 => &[0, 4, 7, 11],
+            Chord::Minor7 => &[0, 3, 7, 10],
+            Chord::Dominant7 => &[0, 4, 7, 10],
+        }
+    }
+}
+
+/// Notes played as a chord, from the `harmony:` section of the config file or of a peer.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct HarmonyZone {
+    /// Channel 1 to 16 the zone is on, every channel if unset.
+    pub channel: Option<u8>,
+    /// Lowest note of the zone.
+    pub low: u8,
+    /// Highest note of the zone.
+    pub high: u8,
+    pub chord: Option<Chord>,
+    /// Semitones from the note played of each note of the chord, 0 being the note itself. Used
+    /// instead of `chord` for voicings of your own, like `[-12, 0, 7, 16]`.
+    pub intervals: Vec<i8>,
+}
+
+impl Default for HarmonyZone {
+    fn default() -> Self {
+        HarmonyZone {
+            channel: None,
+            low: 0,
+            high: 127,
+            chord: None,
+            intervals: Vec::new(),
+        }
+    }
+}
+
+impl HarmonyZone {
+    fn contains(&self, channel: u8, note: u8) -> bool {
+        self.channel.map_or(true, |c| c == channel + 1) && (self.low..=self.high).contains(&note)
+    }
+
+    fn voicing(&self) -> &[i8] {
+        match (&self.chord, self.intervals.is_empty()) {
+            (_, false) => &self.intervals,
+            (Some(chord), true) => chord.intervals(),
+            (None, true) => &[0],
+        }
+    }
+}
+
+/// Turns notes played in its zones into chords. Which notes each held key sounds is remembered, so
+/// its note off ends them even if the zones changed meanwhile, and notes shared by the chords of
+/// several held keys keep sounding until the last of them is let go.
+#[derive(Debug, Clone, Default)]
+pub struct Harmonizer {
+    zones: Vec<HarmonyZone>,
+    /// Notes sounded by each held key, by channel and note.
+    held: HashMap<(u8, u8), Vec<u8>>,
+    /// How many held keys sound each note, by channel and note.
+    sounding: HashMap<(u8, u8), usize>,
+}
+
+impl Harmonizer {
+    pub fn new(zones: Vec<HarmonyZone>) -> Self {
+        Harmonizer {
+            zones,
+            ..Default::default()
+        }
+    }
+
+    /// Change the zones, still ending the chords of the keys held with the old ones.
+    pub fn set_zones(&mut self, zones: &[HarmonyZone]) {
+        if self.zones != zones {
+            self.zones = zones.to_vec();
+        }
+    }
+
+    /// Whether keys played as chords are still held.
+    pub fn holds_notes(&self) -> bool {
+        !self.held.is_empty()
+    }
+
+    /// Whether messages can come out any different than they went in.
+    pub fn is_active(&self) -> bool {
+        !self.zones.is_empty() || self.holds_notes()
+    }
+
+    /// The messages to send for `message`: the chord of a note in a zone, the message itself
+    /// otherwise.
+    pub fn apply(&mut self, message: &[u8]) -> Vec<Vec<u8>> {
+        let (kind, channel, note) = match (MessageKind::of(message), midi::channel(message)) {
+            (Some(kind), Some(channel)) => (kind, channel, message.get(1).copied().unwrap_or(0)),
+            _ => return vec![message.to_vec()],
+        };
+        match kind {
+            MessageKind::NoteOn | MessageKind::NoteOff if midi::is_silencing(message) => {
+                match self.held.remove(&(channel, note)) {
+                    Some(notes) => self.release(channel, &notes, message),
+                    None => vec![message.to_vec()],
+                }
+            }
+            MessageKind::NoteOn => {
+                // A key pressed again before its note off ends its previous chord first
+                let mut messages = match self.held.remove(&(channel, note)) {
+                    Some(notes) => self.release(channel, &notes, &[0x80 | channel, note, 0]),
+                    None => Vec::new(),
+                };
+                let zone = match self.zones.iter().find(|zone| zone.contains(channel, note)) {
+                    Some(zone) => zone,
+                    None => {
+                        messages.push(message.to_vec());
+                        return messages;
+                    }
+                };
+                let mut notes: Vec<u8> = zone
+                    .voicing()
+                    .iter()
+                    .map(|interval| note as i16 + *interval as i16)
+                    .filter(|note| (0..=127).contains(note))
+                    .map(|note| note as u8)
+                    .collect();
+                notes.sort_unstable();
+                notes.dedup();
+                for chord_note in &notes {
+                    *self.sounding.entry((channel, *chord_note)).or_default() += 1;
+                    messages.push(with_note(message, *chord_note));
+                }
+                self.held.insert((channel, note), notes);
+                messages
+            }
+            MessageKind::PolyAftertouch => match self.held.get(&(channel, note)) {
+                Some(notes) => notes.iter().map(|n| with_note(message, *n)).collect(),
+                None => vec![message.to_vec()],
+            },
+            // All notes off and all sound off end every chord of the channel
+            MessageKind::ControlChange if matches!(note, 120 | 123) => {
+                self.held.retain(|(c, _), _| *c != channel);
+                self.sounding.retain(|(c, _), _| *c != channel);
+                vec![message.to_vec()]
+            }
+            _ => vec![message.to_vec()],
+        }
+    }
+
+    /// Note offs, like `message`, for the notes no other held key sounds.
+    fn release(&mut self, channel: u8, notes: &[u8], message: &[u8]) -> Vec<Vec<u8>> {
+        let mut messages = Vec::new();
+        for note in notes {
+            let count = self.sounding.entry((channel, *note)).or_default();
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                self.sounding.remove(&(channel, *note));
+                messages.push(with_note(message, *note));
+            }
+        }
+        messages
+    }
+}
+
+fn with_note(message: &[u8], note: u8) -> Vec<u8> {
+    let mut message = message.to_vec();
+    message[1] = note;
+    message
+}
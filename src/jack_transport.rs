@@ -0,0 +1,128 @@
+//! JACK transport, so DAWs running under JACK or PipeWire start and stop with the session.
+
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+
+#[cfg(not(feature = "jack"))]
+use crate::failure::Failure;
+use crate::transport::Transport;
+
+/// Whether JACK transport follows the session or the other way around.
+#[derive(clap::ValueEnum, Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JackTransportMode {
+    /// Start and stop the session when JACK does, taking its tempo.
+    Follow,
+    /// Start, stop and locate JACK when the session does.
+    Drive,
+}
+
+/// Connect to the JACK server and keep its transport in step with the session. Runs on its own
+/// thread until the transport is closed, then leaves the JACK server.
+#[cfg(feature = "jack")]
+pub fn start(transport: Transport, mode: JackTransportMode) -> Result<(), Box<dyn Error>> {
+    use crate::transport::{unix_micros, Source};
+    use std::time::Duration;
+    use tracing::{info, info_span, warn};
+
+    /// How often JACK is checked for start and stop.
+    const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+    let (client, _) = jack::Client::new("p2pmidi", jack::ClientOptions::NO_START_SERVER)
+        .map_err(|e| format!("Could not connect to JACK: {}", e))?;
+    let mut changes = transport.subscribe();
+    std::thread::Builder::new()
+        .name("jack".to_string())
+        .spawn(move || {
+            let _jack = info_span!("jack", ?mode).entered();
+            let jack_transport = client.transport();
+            let sample_rate = client.sample_rate() as f64;
+            let mut rolling = None;
+            'session: loop {
+                match mode {
+                    JackTransportMode::Follow => {
+                        // Nothing to drive, keep the changes from piling up
+                        loop {
+                            match changes.try_next() {
+                                Ok(Some(_)) => {}
+                                Ok(None) => break 'session,
+                                Err(_) => break,
+                            }
+                        }
+                        let position = match jack_transport.query() {
+                            Ok(position) => position,
+                            Err(e) => {
+                                warn!("Error reading JACK transport: {}", e);
+                                std::thread::sleep(POLL_INTERVAL);
+                                continue;
+                            }
+                        };
+                        let now = matches!(
+                            position.state,
+                            jack::TransportState::Rolling | jack::TransportState::Starting
+                        );
+                        if let Some(bbt) = position.pos.bbt() {
+                            if (bbt.bpm - transport.state().tempo).abs() > 0.01 {
+                                transport.set_tempo(bbt.bpm, Source::Jack);
+                            }
+                        }
+                        if rolling != Some(now) {
+                            rolling = Some(now);
+                            info!("JACK transport {}", if now { "rolling" } else { "stopped" });
+                            transport.set_playing(now, Source::Jack);
+                        }
+                    }
+                    JackTransportMode::Drive => {
+                        loop {
+                            let change = match changes.try_next() {
+                                Ok(Some(change)) => change,
+                                Ok(None) => break 'session,
+                                Err(_) => break,
+                            };
+                            if change.source == Source::Jack
+                                || rolling == Some(change.state.playing)
+                            {
+                                continue;
+                            }
+                            rolling = Some(change.state.playing);
+                            let result = match change.state.playing {
+                                true => {
+                                    // Line JACK up with the session beat before rolling
+                                    let beat = change.state.beat_at(unix_micros()).max(0.0);
+                                    let seconds = beat * 60.0 / change.state.tempo;
+                                    jack_transport
+                                        .locate((seconds * sample_rate) as u32)
+                                        .and_then(|_| jack_transport.start())
+                                }
+                                false => jack_transport.stop(),
+                            };
+                            match result {
+                                Ok(_) => info!(
+                                    "JACK transport {}",
+                                    if change.state.playing {
+                                        "started"
+                                    } else {
+                                        "stopped"
+                                    }
+                                ),
+                                Err(e) => warn!("Error driving JACK transport: {}", e),
+                            }
+                        }
+                    }
+                }
+                std::thread::sleep(POLL_INTERVAL);
+            }
+            info!("Leaving JACK");
+            drop(jack_transport);
+            drop(client);
+        })?;
+    Ok(())
+}
+
+#[cfg(not(feature = "jack"))]
+pub fn start(_transport: Transport, _mode: JackTransportMode) -> Result<(), Box<dyn Error>> {
+    Err(
+        Failure::Config("This build has no JACK support, build with --features jack".to_string())
+            .into(),
+    )
+}
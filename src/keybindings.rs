@@ -0,0 +1,339 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::str::FromStr;
+
+/// Things a shortcut or a control of the control device can trigger in the GUI or terminal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Action {
+    Panic,
+    Mute,
+    Connect,
+    SaveSettings,
+    ReloadMidiDevices,
+    TapTempo,
+}
+
+impl Action {
+    pub const ALL: [Action; 6] = [
+        Action::Panic,
+        Action::Mute,
+        Action::Connect,
+        Action::SaveSettings,
+        Action::ReloadMidiDevices,
+        Action::TapTempo,
+    ];
+
+    /// Name used for this action in the `keybindings:` section of the config file.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::Panic => "panic",
+            Action::Mute => "mute",
+            Action::Connect => "connect",
+            Action::SaveSettings => "save_settings",
+            Action::ReloadMidiDevices => "reload_midi_devices",
+            Action::TapTempo => "tap_tempo",
+        }
+    }
+
+    fn default_binding(&self) -> &'static str {
+        match self {
+            Action::Panic => "escape",
+            Action::Mute => "ctrl+m",
+            Action::Connect => "ctrl+enter",
+            Action::SaveSettings => "ctrl+s",
+            Action::ReloadMidiDevices => "ctrl+r",
+            Action::TapTempo => "ctrl+t",
+        }
+    }
+}
+
+impl FromStr for Action {
+    type Err = String;
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Action::ALL
+            .into_iter()
+            .find(|a| a.name() == name)
+            .ok_or_else(|| format!("Unknown keybinding action {:?}", name))
+    }
+}
+
+/// A key with modifiers, written like `ctrl+shift+p`, `escape` or `f1`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct KeyBinding {
+    pub ctrl: bool,
+    pub alt: bool,
+    pub shift: bool,
+    pub logo: bool,
+    /// Lowercase key name.
+    pub key: String,
+}
+
+impl FromStr for KeyBinding {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut binding = KeyBinding {
+            ctrl: false,
+            alt: false,
+            shift: false,
+            logo: false,
+            key: String::new(),
+        };
+        let parts: Vec<String> = s.split('+').map(|p| p.trim().to_lowercase()).collect();
+        let (key, modifiers) = parts.split_last().ok_or("Empty keybinding")?;
+        for modifier in modifiers {
+            match modifier.as_str() {
+                "ctrl" | "control" => binding.ctrl = true,
+                "alt" => binding.alt = true,
+                "shift" => binding.shift = true,
+                "super" | "logo" | "cmd" => binding.logo = true,
+                m => return Err(format!("Unknown modifier {:?} in keybinding {:?}", m, s)),
+            }
+        }
+        if key.is_empty() {
+            return Err(format!("Missing key in keybinding {:?}", s));
+        }
+        binding.key = key.clone();
+        Ok(binding)
+    }
+}
+
+impl KeyBinding {
+    /// The key a line typed on the terminal stands for. The terminal hands over whole lines, so
+    /// a key is pressed and then enter, or written out like `ctrl+m`.
+    pub fn from_terminal(line: &str) -> Option<Self> {
+        let line = line.trim_end_matches(['\r', '\n']);
+        let key = match line.as_bytes() {
+            [0x1B] => "escape".to_string(),
+            [byte @ 0x01..=0x1A] => format!("ctrl+{}", (b'a' + byte - 1) as char),
+            _ => line.trim().to_string(),
+        };
+        KeyBinding::from_str(&key).ok()
+    }
+}
+
+impl fmt::Display for KeyBinding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (enabled, name) in [
+            (self.ctrl, "ctrl"),
+            (self.alt, "alt"),
+            (self.shift, "shift"),
+            (self.logo, "super"),
+        ] {
+            if enabled {
+                write!(f, "{}+", name)?;
+            }
+        }
+        write!(f, "{}", self.key)
+    }
+}
+
+/// Key to action lookup shared by the frontends, built from the defaults and the config file.
+#[derive(Debug, Clone, Default)]
+pub struct ActionTable {
+    actions: HashMap<KeyBinding, Action>,
+}
+
+impl ActionTable {
+    /// Build the table from the `keybindings:` config map of action names to keys. Invalid entries
+    /// are reported and the action keeps its default key.
+    pub fn from_config(config: &BTreeMap<String, String>) -> (Self, Vec<String>) {
+        let mut errors = Vec::new();
+        let mut keys: HashMap<Action, KeyBinding> = Action::ALL
+            .into_iter()
+            .map(|a| (a, KeyBinding::from_str(a.default_binding()).unwrap()))
+            .collect();
+
+        for (name, key) in config {
+            match (Action::from_str(name), KeyBinding::from_str(key)) {
+                (Ok(action), Ok(binding)) => {
+                    keys.insert(action, binding);
+                }
+                (Err(e), _) | (_, Err(e)) => errors.push(e),
+            }
+        }
+
+        let mut actions = HashMap::new();
+        for (action, binding) in keys {
+            if let Some(other) = actions.insert(binding.clone(), action) {
+                errors.push(format!(
+                    "{} is bound to both {} and {}",
+                    binding,
+                    other.name(),
+                    action.name()
+                ));
+            }
+        }
+        (ActionTable { actions }, errors)
+    }
+
+    pub fn action_for(&self, binding: &KeyBinding) -> Option<Action> {
+        self.actions.get(binding).copied()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MidiControlKind {
+    Cc,
+    Note,
+}
+
+/// A control of a MIDI controller, a CC or a note on a channel from 1 to 16, written like
+/// `cc:1:7` or `note:10:36`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MidiControl {
+    pub kind: MidiControlKind,
+    pub channel: u8,
+    pub number: u8,
+}
+
+impl MidiControl {
+    /// The control a message comes from and its value, the velocity for notes and 0 for note
+    /// offs.
+    pub fn of(message: &[u8]) -> Option<(Self, u8)> {
+        let (status, number, value) = match message {
+            [status, number, value] => (*status, *number, *value),
+            _ => return None,
+        };
+        let channel = (status & 0x0F) + 1;
+        let (kind, value) = match status & 0xF0 {
+            0xB0 => (MidiControlKind::Cc, value),
+            0x90 => (MidiControlKind::Note, value),
+            0x80 => (MidiControlKind::Note, 0),
+            _ => return None,
+        };
+        Some((
+            MidiControl {
+                kind,
+                channel,
+                number,
+            },
+            value,
+        ))
+    }
+}
+
+impl FromStr for MidiControl {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "Expected cc:<channel>:<number> or note:<channel>:<number>, got {:?}",
+                s
+            )
+        };
+        let parts: Vec<&str> = s.split(':').map(|p| p.trim()).collect();
+        let (kind, channel, number) = match parts.as_slice() {
+            [kind, channel, number] => (*kind, *channel, *number),
+            _ => return Err(invalid()),
+        };
+        let kind = match kind.to_lowercase().as_str() {
+            "cc" => MidiControlKind::Cc,
+            "note" => MidiControlKind::Note,
+            _ => return Err(invalid()),
+        };
+        let channel: u8 = channel.parse().map_err(|_| invalid())?;
+        let number: u8 = number.parse().map_err(|_| invalid())?;
+        if !(1..=16).contains(&channel) || number > 127 {
+            return Err(invalid());
+        }
+        Ok(MidiControl {
+            kind,
+            channel,
+            number,
+        })
+    }
+}
+
+impl fmt::Display for MidiControl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            MidiControlKind::Cc => "cc",
+            MidiControlKind::Note => "note",
+        };
+        write!(f, "{}:{}:{}", kind, self.channel, self.number)
+    }
+}
+
+/// What a MIDI control operates, written like the action names, `volume:<peer>`, `mute:<peer>`
+/// or `preset:<name>`. Peers are given by PeerId or name.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum MidiTarget {
+    Action(Action),
+    /// Follows a CC or the velocity of a note.
+    PeerVolume(String),
+    PeerMute(String),
+    Preset(String),
+}
+
+impl MidiTarget {
+    /// Whether the target follows the value of its control instead of firing when pressed.
+    pub fn is_continuous(&self) -> bool {
+        matches!(self, MidiTarget::PeerVolume(_))
+    }
+}
+
+impl FromStr for MidiTarget {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("volume", peer)) => Ok(MidiTarget::PeerVolume(peer.to_string())),
+            Some(("mute", peer)) => Ok(MidiTarget::PeerMute(peer.to_string())),
+            Some(("preset", name)) => Ok(MidiTarget::Preset(name.to_string())),
+            Some(_) => Err(format!("Unknown MIDI binding target {:?}", s)),
+            None => Action::from_str(s).map(MidiTarget::Action),
+        }
+    }
+}
+
+impl fmt::Display for MidiTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MidiTarget::Action(action) => write!(f, "{}", action.name()),
+            MidiTarget::PeerVolume(peer) => write!(f, "volume:{}", peer),
+            MidiTarget::PeerMute(peer) => write!(f, "mute:{}", peer),
+            MidiTarget::Preset(name) => write!(f, "preset:{}", name),
+        }
+    }
+}
+
+/// MIDI control to target lookup, built from the `midi_bindings:` config map of controls to
+/// targets. Buttons fire when pressed, so the last value of each control is kept.
+#[derive(Debug, Clone, Default)]
+pub struct MidiTable {
+    targets: HashMap<MidiControl, MidiTarget>,
+    values: HashMap<MidiControl, u8>,
+}
+
+impl MidiTable {
+    /// Build the table from the config, reporting the invalid entries.
+    pub fn from_config(config: &BTreeMap<String, String>) -> (Self, Vec<String>) {
+        let mut errors = Vec::new();
+        let mut targets = HashMap::new();
+        for (control, target) in config {
+            match (MidiControl::from_str(control), MidiTarget::from_str(target)) {
+                (Ok(control), Ok(target)) => {
+                    targets.insert(control, target);
+                }
+                (Err(e), _) | (_, Err(e)) => errors.push(e),
+            }
+        }
+        (
+            MidiTable {
+                targets,
+                values: HashMap::new(),
+            },
+            errors,
+        )
+    }
+
+    /// The target to operate for a control moving to `value`, if it is bound and, for buttons,
+    /// was just pressed: moved off 0.
+    pub fn target_for(&mut self, control: &MidiControl, value: u8) -> Option<&MidiTarget> {
+        let last = self.values.insert(*control, value).unwrap_or(0);
+        let target = self.targets.get(control)?;
+        match target.is_continuous() || (last == 0 && value > 0) {
+            true => Some(target),
+            false => None,
+        }
+    }
+}
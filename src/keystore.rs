@@ -0,0 +1,168 @@
+//! Passphrase protection for identity keys, so a stolen laptop doesn't give away the identity
+//! peers know us by. The passphrase comes from `P2PMIDI_PASSPHRASE`, the OS keyring or the
+//! terminal, in that order.
+
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use std::error::Error;
+use std::path::Path;
+
+#[cfg(not(feature = "keyring"))]
+use crate::failure::Failure;
+use crate::settings::IdentityAction;
+use crate::storage::Storage;
+
+/// Start of a locked key file, plain keys are protobuf and never start like this.
+const MAGIC: &[u8] = b"p2pmidi-locked-key/1\n";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+
+/// Environment variable holding the passphrase, for daemons and relays.
+pub const PASSPHRASE_ENV: &str = "P2PMIDI_PASSPHRASE";
+
+pub fn is_locked(contents: &[u8]) -> bool {
+    contents.starts_with(MAGIC)
+}
+
+fn cipher(passphrase: &str, salt: &[u8]) -> Result<XChaCha20Poly1305, Box<dyn Error>> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| format!("Error deriving the key: {}", e))?;
+    Ok(XChaCha20Poly1305::new(&key.into()))
+}
+
+/// Encrypt `key` with `passphrase`.
+pub fn lock(key: &[u8], passphrase: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let salt: [u8; SALT_LEN] = rand::random();
+    let nonce: [u8; NONCE_LEN] = rand::random();
+    let sealed = cipher(passphrase, &salt)?
+        .encrypt(XNonce::from_slice(&nonce), key)
+        .map_err(|_| "Error encrypting the key")?;
+    Ok([MAGIC, &salt, &nonce, &sealed].concat())
+}
+
+/// Decrypt a key locked with `passphrase`.
+pub fn unlock(contents: &[u8], passphrase: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let rest = contents.strip_prefix(MAGIC).ok_or("Not a locked key")?;
+    if rest.len() < SALT_LEN + NONCE_LEN {
+        return Err("Truncated key file".into());
+    }
+    let (salt, rest) = rest.split_at(SALT_LEN);
+    let (nonce, sealed) = rest.split_at(NONCE_LEN);
+    cipher(passphrase, salt)?
+        .decrypt(XNonce::from_slice(nonce), sealed)
+        .map_err(|_| "Wrong passphrase".into())
+}
+
+/// The passphrase of the locked key at `path`.
+pub fn passphrase(path: &Path) -> Result<String, Box<dyn Error>> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    if let Some(passphrase) = keyring_get(path) {
+        return Ok(passphrase);
+    }
+    if !atty::is(atty::Stream::Stdin) {
+        return Err(format!(
+            "{} is locked, set {} or keep the passphrase in the keyring",
+            path.display(),
+            PASSPHRASE_ENV
+        )
+        .into());
+    }
+    Ok(rpassword::prompt_password(format!(
+        "Passphrase for {}: ",
+        path.display()
+    ))?)
+}
+
+/// Ask for a new passphrase twice.
+fn new_passphrase() -> Result<String, Box<dyn Error>> {
+    let passphrase = rpassword::prompt_password("New passphrase: ")?;
+    if passphrase.is_empty() {
+        return Err("Empty passphrase".into());
+    }
+    if rpassword::prompt_password("Repeat passphrase: ")? != passphrase {
+        return Err("Passphrases don't match".into());
+    }
+    Ok(passphrase)
+}
+
+pub fn run_identity_command(
+    storage: &Storage,
+    action: &IdentityAction,
+) -> Result<(), Box<dyn Error>> {
+    match action {
+        IdentityAction::Lock { relay, keyring } => {
+            let path = identity_path(storage, *relay);
+            // Unlocked with the passphrase it has now, before the keyring holds the new one
+            let identity = storage.load_identity(&path)?;
+            let passphrase = new_passphrase()?;
+            storage.save_identity(&path, &identity, Some(&passphrase))?;
+            if *keyring {
+                keyring_set(&path, &passphrase)?;
+            }
+            println!("Locked {}", path.display());
+        }
+        IdentityAction::Unlock { relay } => {
+            let path = identity_path(storage, *relay);
+            let identity = storage.load_identity(&path)?;
+            storage.save_identity(&path, &identity, None)?;
+            keyring_delete(&path);
+            println!("Unlocked {}", path.display());
+        }
+    }
+    Ok(())
+}
+
+fn identity_path(storage: &Storage, relay: bool) -> std::path::PathBuf {
+    match relay {
+        true => storage.relay_identity_path(),
+        false => storage.identity_path(),
+    }
+}
+
+#[cfg(feature = "keyring")]
+fn keyring_entry(path: &Path) -> keyring::Result<keyring::Entry> {
+    keyring::Entry::new("p2pmidi", &path.display().to_string())
+}
+
+#[cfg(feature = "keyring")]
+fn keyring_get(path: &Path) -> Option<String> {
+    match keyring_entry(path).and_then(|entry| entry.get_password()) {
+        Ok(passphrase) => Some(passphrase),
+        Err(keyring::Error::NoEntry) => None,
+        Err(e) => {
+            tracing::warn!("Error reading the keyring: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(feature = "keyring")]
+fn keyring_set(path: &Path, passphrase: &str) -> Result<(), Box<dyn Error>> {
+    Ok(keyring_entry(path)?.set_password(passphrase)?)
+}
+
+#[cfg(feature = "keyring")]
+fn keyring_delete(path: &Path) {
+    let _ = keyring_entry(path).and_then(|entry| entry.delete_password());
+}
+
+#[cfg(not(feature = "keyring"))]
+fn keyring_get(_path: &Path) -> Option<String> {
+    None
+}
+
+#[cfg(not(feature = "keyring"))]
+fn keyring_set(_path: &Path, _passphrase: &str) -> Result<(), Box<dyn Error>> {
+    Err(Failure::Config(
+        "This build has no keyring support, build with --features keyring".to_string(),
+    )
+    .into())
+}
+
+#[cfg(not(feature = "keyring"))]
+fn keyring_delete(_path: &Path) {}
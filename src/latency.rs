@@ -0,0 +1,159 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+
+use super::clock::{ClockQuality, PeerClock};
+use super::output::Report;
+
+/// Upper bounds of the histogram buckets in microseconds, the last one catching everything else.
+const BUCKETS_US: [u64; 14] = [
+    100,
+    250,
+    500,
+    1_000,
+    2_000,
+    5_000,
+    10_000,
+    20_000,
+    50_000,
+    100_000,
+    200_000,
+    500_000,
+    1_000_000,
+    u64::MAX,
+];
+
+/// Where a MIDI event spends its time between two peers.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    /// From when the event was due on the sender to when it was handed to the network.
+    Send,
+    /// From the sender to the receiver, estimated against the peer clock.
+    Network,
+    /// From receiving the event to writing it out.
+    Output,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stage::Send => write!(f, "send"),
+            Stage::Network => write!(f, "network"),
+            Stage::Output => write!(f, "output"),
+        }
+    }
+}
+
+/// Latencies counted in fixed buckets, cheap enough to record every event.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Histogram {
+    counts: [u64; BUCKETS_US.len()],
+    count: u64,
+    sum_us: u64,
+    max_us: u64,
+}
+
+impl Histogram {
+    pub fn record(&mut self, latency: Duration) {
+        let us = latency.as_micros() as u64;
+        let bucket = BUCKETS_US.iter().position(|&b| us <= b).unwrap_or(0);
+        self.counts[bucket] += 1;
+        self.count += 1;
+        self.sum_us += us;
+        self.max_us = self.max_us.max(us);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Upper bound of the bucket holding the given quantile, between 0 and 1.
+    pub fn quantile(&self, quantile: f64) -> Duration {
+        let rank = (self.count as f64 * quantile).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bucket, count) in BUCKETS_US.iter().zip(self.counts) {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_micros((*bucket).min(self.max_us));
+            }
+        }
+        Duration::from_micros(self.max_us)
+    }
+
+    pub fn mean(&self) -> Duration {
+        match self.count {
+            0 => Duration::ZERO,
+            count => Duration::from_micros(self.sum_us / count),
+        }
+    }
+
+    pub fn max(&self) -> Duration {
+        Duration::from_micros(self.max_us)
+    }
+}
+
+/// A histogram per stage, for `--measure-latency`.
+#[derive(Debug, Clone, Default)]
+pub struct LatencyStats {
+    stages: BTreeMap<Stage, Histogram>,
+}
+
+impl LatencyStats {
+    pub fn record(&mut self, stage: Stage, latency: Duration) {
+        self.stages.entry(stage).or_default().record(latency);
+    }
+
+    pub fn reports(&self) -> Vec<Report> {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        self.stages
+            .iter()
+            .filter(|(_, h)| h.count() > 0)
+            .map(|(stage, h)| Report::LatencyStage {
+                stage: *stage,
+                count: h.count(),
+                p50_ms: ms(h.quantile(0.5)),
+                p99_ms: ms(h.quantile(0.99)),
+                max_ms: ms(h.max()),
+            })
+            .collect()
+    }
+}
+
+/// Estimates one way delay from a peer without synchronized clocks. The fastest transit seen is
+/// taken to be half the round trip time, and anything slower is extra delay on the way. The
+/// fastest transit follows the drift of the peer's clock.
+#[derive(Debug, Clone, Default)]
+pub struct TransitEstimator {
+    clock: PeerClock,
+}
+
+impl TransitEstimator {
+    /// `received_us` is our clock and `sent_us` the timestamp of the peer when sending. `None` for
+    /// a timestamp too far off to be believed, the frame is best dropped.
+    pub fn observe(&mut self, received_us: u64, sent_us: u64, rtt: Duration) -> Option<Duration> {
+        let received_us = i64::try_from(received_us).ok()?;
+        let sent_us = i64::try_from(sent_us).ok()?;
+        if !self.clock.observe(received_us, sent_us) {
+            return None;
+        }
+        let extra = received_us.saturating_sub(self.clock.to_local(sent_us));
+        Some(rtt / 2 + Duration::from_micros(extra.max(0) as u64))
+    }
+
+    /// When a frame sent at `sent_us` would have arrived without any delay on the way.
+    pub fn arrival_us(&self, sent_us: u64) -> u64 {
+        let sent_us = i64::try_from(sent_us).unwrap_or(i64::MAX);
+        self.clock.to_local(sent_us).max(0) as u64
+    }
+
+    pub fn skew_ppm(&self) -> f64 {
+        self.clock.skew_ppm()
+    }
+
+    /// How well the clock of the peer is known at `now_us` on ours.
+    pub fn quality(&self, now_us: u64) -> ClockQuality {
+        self.clock.quality(now_us as i64)
+    }
+}
@@ -0,0 +1,62 @@
+//! Play MIDI with peers over libp2p. Besides the `p2pmidi` binary, the crate can run a session
+//! inside another application:
+//!
+//! ```no_run
+//! use p2pmidi::{ClientOptions, MidiRouter, Mode, Session, SessionEvent};
+//! use futures::StreamExt;
+//!
+//! let key = libp2p::identity::Keypair::generate_ed25519();
+//! let router = MidiRouter::new(Default::default());
+//! let (session, mut events) = Session::start(router, ClientOptions::new(Mode::Listen, key))?;
+//! session.send_midi(&[0x90, 60, 100])?;
+//! while let Some(event) = futures::executor::block_on(events.next()) {
+//!     if let SessionEvent::Midi { peer_id, message, .. } = event {
+//!         println!("{} played {:?}", peer_id, message);
+//!     }
+//! }
+//! session.stop()?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+pub mod arpeggiator;
+pub mod bridge;
+pub mod clock;
+pub mod config_watcher;
+pub mod constants;
+pub mod control;
+pub mod crash;
+pub mod failure;
+#[cfg(feature = "gui")]
+pub mod gui;
+pub mod harmony;
+pub mod jack_transport;
+pub mod keybindings;
+pub mod keystore;
+pub mod latency;
+#[cfg(feature = "link")]
+pub mod link;
+pub mod logging;
+pub mod metrics;
+pub mod midi;
+pub mod midi_clock;
+pub mod migration;
+pub mod output;
+pub mod p2p;
+pub mod profiles;
+pub mod recorder;
+pub mod ring;
+pub mod routing;
+pub mod runtime;
+pub mod session;
+pub mod settings;
+pub mod smf;
+pub mod status;
+pub mod storage;
+pub mod transport;
+pub mod ump;
+pub mod validation;
+pub mod velocity;
+
+pub use p2p::client::{ClientOptions, Mode};
+pub use routing::MidiRouter;
+pub use session::{PeerHandle, Session, SessionEvent};
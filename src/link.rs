@@ -0,0 +1,83 @@
+//! Ableton Link, keeping the session transport in step with Live, Bitwig and iOS apps on the LAN.
+
+use rusty_link::{AblLink, SessionState};
+use std::error::Error;
+use std::time::Duration;
+use tracing::{info, info_span};
+
+use crate::transport::{unix_micros, Source, Transport, TransportState};
+
+/// How often Link is checked for tempo and start or stop changes.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Beats per bar Link aligns the beat grid to.
+const QUANTUM: f64 = 4.0;
+
+/// Join the Link session on the LAN, following its tempo and start and stop, and pass on the
+/// changes coming from the peers. Runs on its own thread until the transport is closed.
+pub fn start(transport: Transport) -> Result<(), Box<dyn Error>> {
+    let mut changes = transport.subscribe();
+    std::thread::Builder::new()
+        .name("link".to_string())
+        .spawn(move || {
+            let _link = info_span!("link").entered();
+            let link = AblLink::new(transport.state().tempo);
+            link.enable_start_stop_sync(true);
+            link.enable(true);
+            let mut session = SessionState::new();
+            let mut last: Option<(f64, bool)> = None;
+            let mut peers = 0;
+            'session: loop {
+                // Changes from elsewhere go to Link
+                loop {
+                    let change = match changes.try_next() {
+                        Ok(Some(change)) => change,
+                        Ok(None) => break 'session,
+                        Err(_) => break,
+                    };
+                    if change.source == Source::Link {
+                        continue;
+                    }
+                    link.capture_app_session_state(&mut session);
+                    let now = link.clock_micros();
+                    session.set_tempo(change.state.tempo, now);
+                    session.set_is_playing(change.state.playing, now as u64);
+                    link.commit_app_session_state(&session);
+                    last = Some((change.state.tempo, change.state.playing));
+                }
+
+                let connected = link.num_peers();
+                if connected != peers {
+                    info!("{} Link peers", connected);
+                    peers = connected;
+                }
+
+                link.capture_app_session_state(&mut session);
+                let current = (session.tempo(), session.is_playing());
+                if last != Some(current) {
+                    last = Some(current);
+                    info!(
+                        "Link at {:.1} bpm, {}",
+                        current.0,
+                        match current.1 {
+                            true => "playing",
+                            false => "stopped",
+                        }
+                    );
+                    transport.set(
+                        TransportState {
+                            tempo: current.0,
+                            playing: current.1,
+                            beat: session.beat_at_time(link.clock_micros(), QUANTUM),
+                            at_us: unix_micros(),
+                        },
+                        Source::Link,
+                    );
+                }
+                std::thread::sleep(POLL_INTERVAL);
+            }
+            info!("Leaving the Link session");
+            link.enable(false);
+        })?;
+    Ok(())
+}
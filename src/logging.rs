@@ -0,0 +1,157 @@
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt::{self, Debug};
+use std::fs::OpenOptions;
+use std::sync::Mutex;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+
+use super::settings::{LogFormat, LogLevel, Settings};
+
+/// Lines waiting for the GUI log panel, set up by [`init`] when running the GUI.
+static GUI_LOG: Mutex<Option<UnboundedReceiver<LogLine>>> = Mutex::new(None);
+
+/// Latest log lines, kept for crash reports.
+static RECENT_LINES: Mutex<VecDeque<LogLine>> = Mutex::new(VecDeque::new());
+const RECENT_LINES_KEPT: usize = 200;
+
+impl From<LogLevel> for Level {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => Level::ERROR,
+            LogLevel::Warn => Level::WARN,
+            LogLevel::Info => Level::INFO,
+            LogLevel::Debug => Level::DEBUG,
+            LogLevel::Trace => Level::TRACE,
+        }
+    }
+}
+
+/// A log event as shown in the GUI log panel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogLine {
+    pub level: Level,
+    pub target: String,
+    pub message: String,
+}
+
+impl fmt::Display for LogLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:>5} {}: {}", self.level, self.target, self.message)
+    }
+}
+
+/// Collects the message and fields of an event into one line.
+#[derive(Default)]
+struct LineVisitor {
+    message: String,
+    fields: Vec<String>,
+}
+
+impl Visit for LineVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        match field.name() {
+            "message" => self.message = format!("{:?}", value),
+            name => self.fields.push(format!("{}={:?}", name, value)),
+        }
+    }
+}
+
+/// Keeps the latest events for crash reports and forwards them to the GUI log panel.
+struct CaptureLayer {
+    gui: Option<UnboundedSender<LogLine>>,
+}
+
+impl<S: Subscriber> Layer<S> for CaptureLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let mut visitor = LineVisitor::default();
+        event.record(&mut visitor);
+        let mut message = visitor.message;
+        for field in visitor.fields {
+            message.push(' ');
+            message.push_str(&field);
+        }
+        let line = LogLine {
+            level: *event.metadata().level(),
+            target: event.metadata().target().to_string(),
+            message,
+        };
+        if let Ok(mut recent) = RECENT_LINES.lock() {
+            if recent.len() == RECENT_LINES_KEPT {
+                recent.pop_front();
+            }
+            recent.push_back(line.clone());
+        }
+        if let Some(gui) = &self.gui {
+            let _ = gui.unbounded_send(line);
+        }
+    }
+}
+
+/// The latest log lines, oldest first. Empty if they are being written right now, so this is safe
+/// to call while panicking.
+pub fn recent_lines() -> Vec<LogLine> {
+    match RECENT_LINES.try_lock() {
+        Ok(recent) => recent.iter().cloned().collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Log lines for the GUI log panel. Only the first call gets them.
+pub fn take_gui_log() -> Option<UnboundedReceiver<LogLine>> {
+    GUI_LOG.lock().ok()?.take()
+}
+
+/// Set up the global tracing subscriber from the logging settings. `RUST_LOG` takes precedence over
+/// the configured log level, and with `gui` events are also kept for the GUI log panel.
+pub fn init(settings: &Settings, gui: bool) -> Result<(), Box<dyn Error>> {
+    let level = Level::from(settings.log_level.unwrap_or(LogLevel::Info));
+    let filter = EnvFilter::builder()
+        .with_default_directive(LevelFilter::from_level(level).into())
+        .from_env_lossy();
+
+    let file = match &settings.log_file {
+        Some(path) => {
+            let path = shellexpand::tilde(&path.display().to_string()).into_owned();
+            Some(OpenOptions::new().create(true).append(true).open(path)?)
+        }
+        None => None,
+    };
+    // Keep stdout clean for reports
+    let ansi = file.is_none();
+    let writer = match file {
+        Some(file) => BoxMakeWriter::new(Mutex::new(file)),
+        None => BoxMakeWriter::new(std::io::stderr),
+    };
+    let output = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi);
+    let output = match settings.log_format.unwrap_or(LogFormat::Text) {
+        LogFormat::Text => output.boxed(),
+        LogFormat::Json => output.json().boxed(),
+    };
+
+    let gui = match gui {
+        true => {
+            let (lines, receiver) = unbounded();
+            if let Ok(mut log) = GUI_LOG.lock() {
+                *log = Some(receiver);
+            }
+            Some(lines)
+        }
+        false => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(output)
+        .with(CaptureLayer { gui })
+        .with(filter)
+        .try_init()
+        .map_err(|e| e.into())
+}
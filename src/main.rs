@@ -1,41 +1,574 @@
-pub mod constants;
-pub mod gui;
-pub mod midi;
-pub mod p2p;
-pub mod settings;
+use libp2p::identity::Keypair;
+use p2pmidi::failure::Failure;
+#[cfg(feature = "gui")]
+use p2pmidi::gui;
+use p2pmidi::p2p::relay_config::{RelayConfig, RelayOptions};
+use p2pmidi::{
+    constants, control, crash, keystore, logging, midi, output, p2p, profiles, routing, settings,
+    storage, validation, velocity,
+};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "relay")]
+fn run_relay(options: RelayOptions, local_key: Keypair) -> Result<(), Failure> {
+    p2p::relay::start_relay_loop(options, local_key).map_err(Failure::from_error)
+}
+
+#[cfg(not(feature = "relay"))]
+fn run_relay(_options: RelayOptions, _local_key: Keypair) -> Result<(), Failure> {
+    Err(Failure::Config(
+        "This build has no relay support, build with --features relay".to_string(),
+    ))
+}
+
+/// Wait for the relay started in this process to accept connections.
+fn wait_for_relay(address: &str, port: u16) -> Result<(), Failure> {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while TcpStream::connect((address, port)).is_err() {
+        if Instant::now() > deadline {
+            return Err(Failure::RelayUnreachable(format!(
+                "the relay did not start listening on port {}",
+                port
+            )));
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    Ok(())
+}
+
+#[cfg(feature = "gui")]
+fn run_gui(
+    settings: settings::Settings,
+    config_path: PathBuf,
+    storage: storage::Storage,
+) -> Result<(), Failure> {
+    let last_crash = crash::take_last_crash(&storage);
+    gui::run_app(settings, config_path, last_crash, storage)
+        .map_err(|e| Failure::Runtime(format!("Error running GUI: {}", e)))
+}
+
+#[cfg(feature = "gui")]
+fn run_monitor_window(settings: &settings::Settings) -> Result<(), Failure> {
+    gui::run_monitor_window(settings.clone())
+        .map_err(|e| Failure::Runtime(format!("Error running monitor window: {}", e)))
+}
+
+#[cfg(not(feature = "gui"))]
+fn run_monitor_window(_settings: &settings::Settings) -> Result<(), Failure> {
+    Err(Failure::Config(
+        "This build has no GUI, build with --features gui".to_string(),
+    ))
+}
+
+#[cfg(not(feature = "gui"))]
+fn run_gui(
+    _settings: settings::Settings,
+    _config_path: PathBuf,
+    _storage: storage::Storage,
+) -> Result<(), Failure> {
+    Err(Failure::Config(
+        "This build has no GUI, build with --features gui or run with --cli".to_string(),
+    ))
+}
 
 fn main() {
-    let (args, mut settings) = settings::get_program_config();
+    let (args, mut settings) = match settings::get_program_config() {
+        Ok(config) => config,
+        Err(failure) => failure.exit(&output::Reporter::default()),
+    };
     settings.apply_default_values();
 
-    if args.as_relay {
-        println!("Running as relay");
-        match p2p::relay::start_relay_loop(settings.relay_port.unwrap(), 42, constants::USE_IPV6) {
-            Ok(_) => (),
-            Err(e) => println!("Error running relay: {}", e),
+    if let Some(settings::Command::Config { action }) = &args.command {
+        if let Err(e) = profiles::run_config_command(action) {
+            Failure::Config(e.to_string()).exit(&output::Reporter::default());
+        }
+        return;
+    }
+
+    if let Some(settings::Command::Ctl { socket, action }) = &args.command {
+        if let Err(e) = control::run_ctl_command(socket.clone(), action) {
+            Failure::from_error(e).exit(&output::Reporter::default());
         }
         return;
     }
 
-    if args.cli && args.gui {
-        panic!("Cannot use both --gui and --cli");
+    if let Some(settings::Command::MonitorWindow) = &args.command {
+        if let Err(failure) = run_monitor_window(&settings) {
+            failure.exit(&output::Reporter::default());
+        }
+        return;
     }
 
-    if args.gui {
-        println!("Running GUI");
-        match gui::run_app(settings) {
-            Ok(_) => (),
-            Err(e) => println!("Error running GUI: {}", e),
+    if let Some(settings::Command::Protocol {
+        action: settings::ProtocolAction::Dump,
+    }) = &args.command
+    {
+        if let Err(e) = p2p::schema::dump() {
+            Failure::from_error(e).exit(&output::Reporter::default());
         }
-    } else {
-        println!("Running CLI");
-        let _ = p2p::client::start_client(
-            p2p::client::Mode::Dial,
-            44,
-            settings.relay_address.unwrap().as_str(),
-            settings.relay_port.unwrap(),
-            42,
-            constants::USE_IPV6,
+        return;
+    }
+
+    if let Some(settings::Command::SwarmKey { out }) = &args.command {
+        if out.exists() {
+            Failure::Config(format!("{} already exists", out.display()))
+                .exit(&output::Reporter::default());
+        }
+        if let Err(e) = std::fs::write(out, p2p::swarm_key::generate()) {
+            Failure::Runtime(format!("Error writing swarm key: {}", e))
+                .exit(&output::Reporter::default());
+        }
+        println!("Swarm key written to {}", out.display());
+        return;
+    }
+
+    if let Err(errors) = args.validate() {
+        Failure::Config(format!(
+            "Invalid arguments:\n{}",
+            validation::describe_errors(&errors)
+        ))
+        .exit(&output::Reporter::default());
+    }
+    // The GUI shows settings errors itself so they can be fixed from there
+    if let Err(errors) = settings.validate() {
+        if !args.gui {
+            Failure::Config(format!(
+                "Invalid settings in {}:\n{}",
+                args.config_path.display(),
+                validation::describe_errors(&errors)
+            ))
+            .exit(&output::Reporter::default());
+        }
+    }
+
+    // The relay config wins over the main one, for the settings they share
+    let relay_config = match args.as_relay {
+        true => match RelayConfig::load(args.relay_config.as_deref()) {
+            Ok(config) => {
+                config.apply_to(&mut settings);
+                config
+            }
+            Err(e) => Failure::Config(e.to_string()).exit(&output::Reporter::default()),
+        },
+        false => RelayConfig::default(),
+    };
+
+    // Only errors are logged when quiet unless a log level was asked for
+    if args.quiet && settings.log_level.is_none() {
+        settings.log_level = Some(settings::LogLevel::Error);
+    }
+    // Sessions without a window run in the terminal even when the GUI was asked for
+    let run_gui = args.gui
+        && !matches!(
+            &args.command,
+            Some(
+                settings::Command::Daemon { .. }
+                    | settings::Command::Record { .. }
+                    | settings::Command::Ping { .. }
+                    | settings::Command::Troubleshoot { .. }
+                    | settings::Command::Play { .. }
+                    | settings::Command::Archive { .. }
+                    | settings::Command::Sessions
+                    | settings::Command::Selftest { .. }
+                    | settings::Command::Calibrate { .. }
+                    | settings::Command::Peers { .. }
+            )
         );
+    // Sessions in a terminal keep a status line, with only warnings logged around it
+    let runs_session = matches!(
+        &args.command,
+        None | Some(
+            settings::Command::Connect { .. }
+                | settings::Command::Record { .. }
+                | settings::Command::Daemon { .. }
+        )
+    );
+    let status_line = runs_session
+        && !run_gui
+        && !args.verbose
+        && !args.json
+        && !args.quiet
+        && atty::is(atty::Stream::Stdout);
+    if status_line && settings.log_level.is_none() {
+        settings.log_level = Some(settings::LogLevel::Warn);
+    }
+    if let Err(e) = logging::init(&settings, run_gui) {
+        eprintln!("Error setting up logging: {}", e);
+    }
+
+    let reporter = output::Reporter {
+        json: args.json,
+        quiet: args.quiet,
+        status_line: false,
+    };
+    let storage = storage::Storage::new(args.data_dir.as_deref());
+    crash::install_panic_hook(&storage, &settings);
+
+    if let Some(settings::Command::Connect { last: true, .. }) = &args.command {
+        match storage.recent_sessions() {
+            Ok(sessions) => match sessions.first() {
+                Some(session) => session.apply_to(&mut settings),
+                None => Failure::Config("No session joined yet".to_string()).exit(&reporter),
+            },
+            Err(e) => Failure::Runtime(format!("Error reading the recent sessions: {}", e))
+                .exit(&reporter),
+        }
+    }
+
+    if let Some(settings::Command::Identity { action }) = &args.command {
+        if let Err(e) = keystore::run_identity_command(&storage, action) {
+            Failure::from_error(e).exit(&reporter);
+        }
+        return;
+    }
+    if let Some(settings::Command::Peers { name }) = &args.command {
+        if let Err(e) = p2p::history::run_peers(&storage, name.as_deref()) {
+            Failure::from_error(e).exit(&reporter);
+        }
+        return;
+    }
+    if let Some(settings::Command::Calibrate { notes, save }) = &args.command {
+        let interactive = !args.no_prompt && atty::is(atty::Stream::Stdin);
+        if let Err(e) =
+            velocity::run_calibration(&settings, &args.config_path, *notes, *save, interactive)
+        {
+            Failure::from_error(e).exit(&reporter);
+        }
+        return;
+    }
+    let swarm_key = match settings.swarm_key.as_deref().map(p2p::swarm_key::load) {
+        Some(Ok(key)) => Some(key),
+        Some(Err(e)) => Failure::Config(e.to_string()).exit(&reporter),
+        None => None,
+    };
+    let mut relay_peer_id = match settings
+        .relay_peer_id
+        .as_deref()
+        .map(str::parse::<libp2p::PeerId>)
+    {
+        Some(Ok(peer_id)) => Some(peer_id),
+        Some(Err(e)) => Failure::Config(format!("Invalid relay_peer_id: {}", e)).exit(&reporter),
+        None => None,
+    };
+
+    if let Some(settings::Command::Ping {
+        target,
+        count,
+        hole_punch,
+        timeout,
+    }) = &args.command
+    {
+        let options = p2p::probe::ProbeOptions {
+            target: target.clone(),
+            count: *count,
+            hole_punch: *hole_punch,
+            timeout: std::time::Duration::from_secs(*timeout),
+            relay_address: settings.relay_address.unwrap(),
+            relay_port: settings.relay_port.unwrap(),
+            relay_peer_id,
+            use_ipv6: constants::USE_IPV6,
+            bind_address: settings.bind_address,
+            swarm_key,
+        };
+        if let Err(e) = p2p::probe::run_probe(options, reporter) {
+            Failure::from_error(e).exit(&reporter);
+        }
+        return;
+    }
+
+    if let Some(settings::Command::Troubleshoot { peer, timeout }) = &args.command {
+        let peer = match peer {
+            Some(peer) => {
+                let book = storage.address_book().unwrap_or_default();
+                match book.get(peer).unwrap_or(peer).parse::<libp2p::PeerId>() {
+                    Ok(peer_id) => Some(peer_id),
+                    Err(e) => {
+                        Failure::Config(format!("Invalid peer {}: {}", peer, e)).exit(&reporter)
+                    }
+                }
+            }
+            None => None,
+        };
+        let options = p2p::troubleshoot::TroubleshootOptions {
+            relay_address: settings.relay_address.unwrap(),
+            relay_port: settings.relay_port.unwrap(),
+            relay_peer_id,
+            use_ipv6: constants::USE_IPV6,
+            bind_address: settings.bind_address,
+            swarm_key,
+            peer,
+            timeout: std::time::Duration::from_secs(*timeout),
+        };
+        if let Err(e) = p2p::troubleshoot::run_troubleshoot(options, reporter) {
+            Failure::from_error(e).exit(&reporter);
+        }
+        return;
+    }
+
+    if let Some(settings::Command::Archive { session, out }) = &args.command {
+        let local_key = match storage.load_identity(&storage.identity_path()) {
+            Ok(key) => key,
+            Err(e) => Failure::Runtime(format!("Error loading identity: {}", e)).exit(&reporter),
+        };
+        let options = p2p::archive::FetchOptions {
+            session: session.clone(),
+            out: out.clone(),
+            relay_address: settings.relay_address.unwrap(),
+            relay_port: settings.relay_port.unwrap(),
+            relay_peer_id,
+            use_ipv6: constants::USE_IPV6,
+            bind_address: settings.bind_address,
+            swarm_key,
+        };
+        if let Err(e) = p2p::archive::run_fetch(options, local_key) {
+            Failure::from_error(e).exit(&reporter);
+        }
+        return;
+    }
+
+    if let Some(settings::Command::Sessions) = &args.command {
+        let local_key = match storage.load_identity(&storage.identity_path()) {
+            Ok(key) => key,
+            Err(e) => Failure::Runtime(format!("Error loading identity: {}", e)).exit(&reporter),
+        };
+        let options = p2p::directory::ListOptions {
+            relay_address: settings.relay_address.unwrap(),
+            relay_port: settings.relay_port.unwrap(),
+            relay_peer_id,
+            use_ipv6: constants::USE_IPV6,
+            bind_address: settings.bind_address,
+            swarm_key,
+            token: settings.relay_token,
+        };
+        if let Err(e) = p2p::directory::run_list(options, local_key, reporter) {
+            Failure::from_error(e).exit(&reporter);
+        }
+        return;
+    }
+
+    if let Some(settings::Command::Play { file, to }) = &args.command {
+        let options = p2p::play::PlayOptions {
+            file: file.clone(),
+            target: to.clone(),
+            measure_latency: args.measure_latency,
+            relay_address: settings.relay_address.unwrap(),
+            relay_port: settings.relay_port.unwrap(),
+            relay_peer_id,
+            use_ipv6: constants::USE_IPV6,
+            bind_address: settings.bind_address,
+            swarm_key,
+        };
+        if let Err(e) = p2p::play::run_play(options, reporter) {
+            Failure::from_error(e).exit(&reporter);
+        }
+        return;
+    }
+
+    if let Some(settings::Command::Selftest { soak, rate, memory }) = &args.command {
+        let options = p2p::selftest::SelftestOptions {
+            duration: *soak,
+            rate: *rate,
+            memory: *memory,
+        };
+        if let Err(e) = p2p::selftest::run_selftest(options, reporter) {
+            Failure::from_error(e).exit(&reporter);
+        }
+        return;
+    }
+
+    // With --cli or a session command the relay runs alongside a client joining through it
+    let hosting = args.as_relay
+        && (args.cli
+            || matches!(
+                &args.command,
+                Some(
+                    settings::Command::Connect { .. }
+                        | settings::Command::Record { .. }
+                        | settings::Command::Daemon { .. }
+                )
+            ));
+    let mut hosted_relay = None;
+    if args.as_relay {
+        tracing::info!("Running as relay");
+        let identity_path = relay_config
+            .identity_path
+            .clone()
+            .unwrap_or_else(|| storage.relay_identity_path());
+        let local_key = match storage.load_identity(&identity_path) {
+            Ok(k) => k,
+            Err(e) => {
+                Failure::Runtime(format!("Error loading relay identity: {}", e)).exit(&reporter)
+            }
+        };
+        let port = settings.relay_port.unwrap();
+        // Checked when loaded
+        let options = RelayOptions {
+            port,
+            listen_addresses: relay_config.listen_multiaddrs().unwrap_or_default(),
+            use_ipv6: constants::USE_IPV6,
+            swarm_key,
+            archive_dir: settings.archive_dir.clone(),
+            directory: settings.directory.unwrap_or(false),
+            token: settings.relay_token.clone(),
+            limits: relay_config.limits.clone(),
+            allow_peers: relay_config.allowed_peers().unwrap_or_default(),
+            metrics_address: relay_config.metrics_address,
+            webhook: relay_config.webhook.clone(),
+        };
+        if !hosting {
+            if let Err(failure) = run_relay(options, local_key) {
+                failure.exit(&reporter);
+            }
+            return;
+        }
+        // The client keeps its own identity, a swarm can't dial its own PeerId
+        let relay_peer = local_key.public().to_peer_id();
+        std::thread::spawn(move || {
+            if let Err(failure) = run_relay(options, local_key) {
+                failure.exit(&reporter);
+            }
+        });
+        let loopback = match constants::USE_IPV6 {
+            true => "::1",
+            false => "127.0.0.1",
+        };
+        if let Err(failure) = wait_for_relay(loopback, port) {
+            failure.exit(&reporter);
+        }
+        hosted_relay = Some((relay_peer, loopback));
+        if settings.relay_address.as_deref() == Some(constants::RELAY_ADDRESS) {
+            tracing::warn!(
+                "Set relay_address to the public address of this machine so invites point to it"
+            );
+        }
+    }
+
+    let control_socket = match &args.command {
+        Some(settings::Command::Daemon { socket }) => {
+            Some(socket.clone().unwrap_or_else(control::default_socket_path))
+        }
+        _ => None,
+    };
+
+    let (target, record_path) = match &args.command {
+        Some(settings::Command::Connect { target, .. }) => (target.clone(), None),
+        Some(settings::Command::Record { target, out, .. }) => (target.clone(), Some(out.clone())),
+        _ => (args.target.clone(), None),
+    };
+    let listen = matches!(
+        &args.command,
+        Some(settings::Command::Record { listen: true, .. })
+    );
+
+    // Names come from the address book, invites also bring their relay
+    let mut relay_address = settings.relay_address.clone().unwrap();
+    let mut relay_port = settings.relay_port.unwrap();
+    let book = match storage.address_book() {
+        Ok(b) => b,
+        Err(e) => {
+            Failure::Runtime(format!("Error reading the address book: {}", e)).exit(&reporter)
+        }
+    };
+    let mut invite_token = None;
+    let target = match target.map(|t| book.get(&t).cloned().unwrap_or(t)) {
+        Some(t) if p2p::invite::Invite::is_invite(&t) => match t.parse::<p2p::invite::Invite>() {
+            Ok(invite) => {
+                // The pinned PeerId is of our own relay
+                if (&invite.relay_address, invite.relay_port) != (&relay_address, relay_port) {
+                    relay_peer_id = None;
+                }
+                relay_address = invite.relay_address;
+                relay_port = invite.relay_port;
+                invite_token = invite.token;
+                Some(invite.peer_id.to_string())
+            }
+            Err(e) => Failure::Config(e).exit(&reporter),
+        },
+        t => t,
+    };
+    // Join our own relay over loopback, invites still carry its public address
+    let mut invite_relay_address = None;
+    if let Some((peer_id, loopback)) = hosted_relay {
+        invite_relay_address = Some(std::mem::replace(&mut relay_address, loopback.to_string()));
+        relay_peer_id = Some(peer_id);
+    }
+    // Without a peer given, dial the configured ones, or wait for peers to join if there are none
+    // or `record --listen` asks to
+    let addresses: Vec<String> = match (&target, listen) {
+        (Some(_), _) | (None, true) => Vec::new(),
+        (None, false) => settings
+            .ip_addresses
+            .iter()
+            .map(|a| book.get(a).cloned().unwrap_or_else(|| a.clone()))
+            .collect(),
+    };
+    let mode = match (&target, addresses.is_empty()) {
+        (None, true) => p2p::client::Mode::Listen,
+        _ => p2p::client::Mode::Dial,
+    };
+
+    if run_gui {
+        tracing::info!("Running GUI");
+        if let Err(failure) = run_gui(settings, args.config_path, storage) {
+            failure.exit(&reporter);
+        }
+    } else {
+        tracing::info!("Running CLI");
+        if matches!(mode, p2p::client::Mode::Listen) {
+            tracing::info!("No peer to dial, waiting for peers to join with the invite");
+        }
+        let local_key = match storage.load_identity(&storage.identity_path()) {
+            Ok(k) => k,
+            Err(e) => Failure::Runtime(format!("Error loading identity: {}", e)).exit(&reporter),
+        };
+        if let Some(device) = &settings.midi_device {
+            match midi::get_midi_input() {
+                Ok(inputs) if inputs.contains(device) => (),
+                Ok(_) => Failure::MidiDeviceMissing(device.clone()).exit(&reporter),
+                Err(e) => {
+                    Failure::Runtime(format!("Error creating midi input: {}", e)).exit(&reporter)
+                }
+            }
+        }
+        let router = routing::MidiRouter::new(settings.peers.clone());
+        let mut options = match p2p::client::ClientOptions::from_settings(
+            &settings,
+            args.config_path,
+            local_key,
+            storage,
+        ) {
+            Ok(options) => options,
+            Err(e) => Failure::Config(e.to_string()).exit(&reporter),
+        };
+        options.mode = mode;
+        options.target = target;
+        options.addresses = addresses;
+        options.relay_address = relay_address;
+        options.relay_port = relay_port;
+        options.relay_peer_id = relay_peer_id;
+        options.invite_relay_address = invite_relay_address;
+        options.control_socket = control_socket;
+        options.record_path = record_path;
+        options.measure_latency = args.measure_latency;
+        options.session_report = args.session_report.clone();
+        options.simulate_network = args.simulate_network.clone();
+        options.link = args.link;
+        if args.trust_new_peers {
+            options.auto_accept = p2p::trust::AutoAccept::Everyone;
+        }
+        options.invite_token = invite_token;
+        options.invite_expires = args.invite_expires;
+        options.invite_once = args.invite_once;
+        options.interactive =
+            !args.no_prompt && !args.json && !args.quiet && atty::is(atty::Stream::Stdin);
+        options.reporter = output::Reporter {
+            status_line,
+            ..reporter
+        };
+        if let Err(e) = p2p::client::start_client(router, options) {
+            Failure::from_error(e).exit(&reporter);
+        }
     }
 }
@@ -0,0 +1,164 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn};
+
+use super::runtime;
+
+/// Counters and gauges of a running session, exported in the Prometheus text format.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    connected_peers: AtomicU64,
+    connections_total: AtomicU64,
+    midi_events_received: AtomicU64,
+    midi_events_sent: AtomicU64,
+    midi_events_dropped: AtomicU64,
+    /// Last round trip time to each connected peer, in seconds.
+    rtt: Mutex<BTreeMap<String, f64>>,
+}
+
+impl Metrics {
+    pub fn peer_connected(&self) {
+        self.connected_peers.fetch_add(1, Ordering::Relaxed);
+        self.connections_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn peer_disconnected(&self, peer_id: &str) {
+        let _ = self
+            .connected_peers
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1));
+        if let Ok(mut rtt) = self.rtt.lock() {
+            rtt.remove(peer_id);
+        }
+    }
+
+    pub fn set_rtt(&self, peer_id: &str, rtt: Duration) {
+        if let Ok(mut rtts) = self.rtt.lock() {
+            rtts.insert(peer_id.to_string(), rtt.as_secs_f64());
+        }
+    }
+
+    pub fn midi_received(&self, events: usize) {
+        self.midi_events_received
+            .fetch_add(events as u64, Ordering::Relaxed);
+    }
+
+    pub fn midi_sent(&self, events: usize) {
+        self.midi_events_sent
+            .fetch_add(events as u64, Ordering::Relaxed);
+    }
+
+    /// MIDI events sent and received so far.
+    pub fn midi_events(&self) -> u64 {
+        self.midi_events_received.load(Ordering::Relaxed)
+            + self.midi_events_sent.load(Ordering::Relaxed)
+    }
+
+    /// MIDI events dropped by filters, transposition or invalid data.
+    pub fn midi_dropped(&self, events: usize) {
+        self.midi_events_dropped
+            .fetch_add(events as u64, Ordering::Relaxed);
+    }
+
+    /// All metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value);
+        };
+        metric(
+            "p2pmidi_connected_peers",
+            "gauge",
+            "Peers currently connected.",
+            self.connected_peers.load(Ordering::Relaxed),
+        );
+        metric(
+            "p2pmidi_connections_total",
+            "counter",
+            "Peer connections established.",
+            self.connections_total.load(Ordering::Relaxed),
+        );
+        metric(
+            "p2pmidi_midi_events_received_total",
+            "counter",
+            "MIDI events received from peers.",
+            self.midi_events_received.load(Ordering::Relaxed),
+        );
+        metric(
+            "p2pmidi_midi_events_sent_total",
+            "counter",
+            "MIDI events sent to peers.",
+            self.midi_events_sent.load(Ordering::Relaxed),
+        );
+        metric(
+            "p2pmidi_midi_events_dropped_total",
+            "counter",
+            "MIDI events received and dropped.",
+            self.midi_events_dropped.load(Ordering::Relaxed),
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP p2pmidi_peer_rtt_seconds Last round trip time to a peer."
+        );
+        let _ = writeln!(out, "# TYPE p2pmidi_peer_rtt_seconds gauge");
+        if let Ok(rtts) = self.rtt.lock() {
+            for (peer, rtt) in rtts.iter() {
+                let _ = writeln!(out, "p2pmidi_peer_rtt_seconds{{peer=\"{}\"}} {}", peer, rtt);
+            }
+        }
+        out
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream, metrics: &Metrics) -> std::io::Result<()> {
+    let mut request_line = String::new();
+    BufReader::new(&mut stream)
+        .read_line(&mut request_line)
+        .await?;
+    debug!("Metrics request: {}", request_line.trim());
+
+    let response = match request_line.split_whitespace().nth(1) {
+        Some("/metrics") => {
+            let body = metrics.render();
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+    stream.write_all(response.as_bytes()).await
+}
+
+/// Serve `GET /metrics` over HTTP on `address` in a background task.
+pub fn serve(address: SocketAddr, metrics: Arc<Metrics>) -> Result<(), Box<dyn Error>> {
+    let listener = std::net::TcpListener::bind(address)?;
+    listener.set_nonblocking(true)?;
+    let _runtime = runtime::enter();
+    let listener = TcpListener::from_std(listener)?;
+    info!("Metrics available at http://{}/metrics", address);
+
+    runtime::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    if let Err(e) = handle_connection(stream, &metrics).await {
+                        debug!("Error answering metrics request: {}", e);
+                    }
+                }
+                Err(e) => warn!("Error accepting metrics connection: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
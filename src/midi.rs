@@ -1,7 +1,98 @@
 use std::error::Error;
 
-use midir::{Ignore, MidiInput, MidiOutput};
+#[cfg(feature = "midi")]
+use midir::{Ignore, MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+use serde::{Deserialize, Serialize};
 
+use crate::ring::Producer;
+
+/// Kinds of MIDI messages, as told by their status byte.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    NoteOff,
+    NoteOn,
+    PolyAftertouch,
+    ControlChange,
+    ProgramChange,
+    ChannelAftertouch,
+    PitchBend,
+    SysEx,
+    Clock,
+    System,
+}
+
+impl MessageKind {
+    pub fn from_status(status: u8) -> Option<Self> {
+        match status {
+            0x80..=0x8F => Some(MessageKind::NoteOff),
+            0x90..=0x9F => Some(MessageKind::NoteOn),
+            0xA0..=0xAF => Some(MessageKind::PolyAftertouch),
+            0xB0..=0xBF => Some(MessageKind::ControlChange),
+            0xC0..=0xCF => Some(MessageKind::ProgramChange),
+            0xD0..=0xDF => Some(MessageKind::ChannelAftertouch),
+            0xE0..=0xEF => Some(MessageKind::PitchBend),
+            0xF0 | 0xF7 => Some(MessageKind::SysEx),
+            0xF8 | 0xFA | 0xFB | 0xFC => Some(MessageKind::Clock),
+            0xF1..=0xFF => Some(MessageKind::System),
+            _ => None,
+        }
+    }
+
+    pub fn of(message: &[u8]) -> Option<Self> {
+        message
+            .first()
+            .and_then(|status| Self::from_status(*status))
+    }
+
+    /// Whether messages of this kind carry a channel in the low nibble of the status byte.
+    pub fn has_channel(&self) -> bool {
+        !matches!(
+            self,
+            MessageKind::SysEx | MessageKind::Clock | MessageKind::System
+        )
+    }
+}
+
+/// Data bytes following a status byte, not counting SysEx which runs until its end byte.
+pub fn data_len(status: u8) -> usize {
+    match status {
+        0xC0..=0xDF | 0xF1 | 0xF3 => 1,
+        0x80..=0xEF | 0xF2 => 2,
+        _ => 0,
+    }
+}
+
+/// Sustain off and all notes off on every channel, to silence stuck notes.
+pub fn all_notes_off() -> Vec<Vec<u8>> {
+    (0..16u8)
+        .flat_map(|channel| [vec![0xB0 | channel, 64, 0], vec![0xB0 | channel, 123, 0]])
+        .collect()
+}
+
+/// Whether the message ends notes: note offs, sustain releases and all sound or notes off.
+pub fn is_silencing(message: &[u8]) -> bool {
+    match MessageKind::of(message) {
+        Some(MessageKind::NoteOff) => true,
+        Some(MessageKind::NoteOn) => message.get(2) == Some(&0),
+        Some(MessageKind::ControlChange) => match (message.get(1), message.get(2)) {
+            (Some(64), Some(value)) => *value < 64,
+            (Some(120 | 123), _) => true,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Zero based channel of a channel voice message.
+pub fn channel(message: &[u8]) -> Option<u8> {
+    match MessageKind::of(message) {
+        Some(kind) if kind.has_channel() => Some(message[0] & 0x0F),
+        _ => None,
+    }
+}
+
+#[cfg(feature = "midi")]
 pub fn display_devices() -> Result<(), Box<dyn Error>> {
     let mut midi_in = MidiInput::new("midir test input")?;
     midi_in.ignore(Ignore::None);
@@ -20,6 +111,7 @@ pub fn display_devices() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+#[cfg(feature = "midi")]
 pub fn get_midi_list<T: midir::MidiIO>(midi: &T) -> Vec<String> {
     midi.ports()
         .iter()
@@ -27,6 +119,7 @@ pub fn get_midi_list<T: midir::MidiIO>(midi: &T) -> Vec<String> {
         .collect::<Vec<String>>()
 }
 
+#[cfg(feature = "midi")]
 pub fn get_midi_list_from_result<T: midir::MidiIO>(
     midi: Result<T, midir::InitError>,
 ) -> Result<Vec<String>, String> {
@@ -36,10 +129,104 @@ pub fn get_midi_list_from_result<T: midir::MidiIO>(
     }
 }
 
+/// An open input device, closed when dropped.
+#[cfg(feature = "midi")]
+pub type InputConnection = MidiInputConnection<Producer>;
+
+/// Open the input device named `device` and queue everything it plays. The device stays open
+/// until the returned connection is dropped.
+#[cfg(feature = "midi")]
+pub fn connect_input(device: &str, producer: Producer) -> Result<InputConnection, Box<dyn Error>> {
+    let mut midi_in = MidiInput::new("p2pmidi input")?;
+    midi_in.ignore(Ignore::None);
+    let port = midi_in
+        .ports()
+        .into_iter()
+        .find(|p| midi_in.port_name(p).map_or(false, |name| name == device))
+        .ok_or_else(|| format!("MIDI input {} not found", device))?;
+    let connection = midi_in
+        .connect(
+            &port,
+            "p2pmidi",
+            // Runs on the MIDI thread, pushing must stay free of locks and allocations
+            |_, message, producer| {
+                producer.push(message);
+            },
+            producer,
+        )
+        .map_err(|e| e.to_string())?;
+    Ok(connection)
+}
+
+/// An open output device, closed when dropped.
+#[cfg(feature = "midi")]
+pub type OutputConnection = MidiOutputConnection;
+
+/// Open the output device named `device`.
+#[cfg(feature = "midi")]
+pub fn connect_output(device: &str) -> Result<OutputConnection, Box<dyn Error>> {
+    let midi_out = MidiOutput::new("p2pmidi output")?;
+    let port = midi_out
+        .ports()
+        .into_iter()
+        .find(|p| midi_out.port_name(p).map_or(false, |name| name == device))
+        .ok_or_else(|| format!("MIDI output {} not found", device))?;
+    let connection = midi_out
+        .connect(&port, "p2pmidi")
+        .map_err(|e| e.to_string())?;
+    Ok(connection)
+}
+
+#[cfg(feature = "midi")]
 pub fn get_midi_input() -> Result<Vec<String>, String> {
     get_midi_list_from_result(MidiInput::new("midir test input"))
 }
 
+#[cfg(feature = "midi")]
 pub fn get_midi_output() -> Result<Vec<String>, String> {
     get_midi_list_from_result(MidiOutput::new("midir test output"))
 }
+
+#[cfg(not(feature = "midi"))]
+const NO_MIDI: &str = "This build has no MIDI device support, build with --features midi";
+
+#[cfg(not(feature = "midi"))]
+pub struct InputConnection;
+
+#[cfg(not(feature = "midi"))]
+pub fn display_devices() -> Result<(), Box<dyn Error>> {
+    Err(crate::failure::Failure::Config(NO_MIDI.to_string()).into())
+}
+
+#[cfg(not(feature = "midi"))]
+pub fn connect_input(
+    _device: &str,
+    _producer: Producer,
+) -> Result<InputConnection, Box<dyn Error>> {
+    Err(crate::failure::Failure::Config(NO_MIDI.to_string()).into())
+}
+
+#[cfg(not(feature = "midi"))]
+pub struct OutputConnection;
+
+#[cfg(not(feature = "midi"))]
+impl OutputConnection {
+    pub fn send(&mut self, _message: &[u8]) -> Result<(), String> {
+        Err(NO_MIDI.to_string())
+    }
+}
+
+#[cfg(not(feature = "midi"))]
+pub fn connect_output(_device: &str) -> Result<OutputConnection, Box<dyn Error>> {
+    Err(crate::failure::Failure::Config(NO_MIDI.to_string()).into())
+}
+
+#[cfg(not(feature = "midi"))]
+pub fn get_midi_input() -> Result<Vec<String>, String> {
+    Err(NO_MIDI.to_string())
+}
+
+#[cfg(not(feature = "midi"))]
+pub fn get_midi_output() -> Result<Vec<String>, String> {
+    Err(NO_MIDI.to_string())
+}
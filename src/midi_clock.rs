@@ -0,0 +1,101 @@
+//! MIDI clock played on a local output from the session transport, so hardware sequencers and drum
+//! machines follow the session tempo and start and stop with it even when no peer sends clock.
+
+use std::error::Error;
+use std::time::Duration;
+use tracing::{debug, info, info_span, warn};
+
+use crate::midi::{connect_output, OutputConnection};
+use crate::transport::{unix_micros, Transport, TransportState, CLOCK_PPQN};
+
+/// Longest wait between looking at the transport.
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+const CLOCK: u8 = 0xF8;
+const START: u8 = 0xFA;
+const CONTINUE: u8 = 0xFB;
+const STOP: u8 = 0xFC;
+const SONG_POSITION: u8 = 0xF2;
+
+fn send(output: &mut OutputConnection, message: &[u8]) {
+    if let Err(e) = output.send(message) {
+        warn!("Error sending MIDI clock: {}", e);
+    }
+}
+
+/// Start from the top, or from where the session is.
+fn send_start(output: &mut OutputConnection, state: &TransportState) {
+    // Song position counts sixteenths
+    let position = (state.beat_at(unix_micros()).max(0.0) * 4.0) as u16 & 0x3FFF;
+    if position == 0 {
+        send(output, &[START]);
+        return;
+    }
+    send(
+        output,
+        &[
+            SONG_POSITION,
+            (position & 0x7F) as u8,
+            (position >> 7) as u8,
+        ],
+    );
+    send(output, &[CONTINUE]);
+}
+
+/// Open `device` and play clock on it, and start and stop when the session does. The clock keeps
+/// running while stopped so the gear already has the tempo when it starts. Runs on its own thread
+/// until the transport is closed, then stops the gear and closes `device`.
+pub fn start(transport: Transport, device: &str) -> Result<(), Box<dyn Error>> {
+    let mut output = connect_output(device)
+        .map_err(|e| format!("Error opening MIDI clock output {}: {}", device, e))?;
+    let mut changes = transport.subscribe();
+    let device = device.to_string();
+    std::thread::Builder::new()
+        .name("midi clock".to_string())
+        .spawn(move || {
+            let _clock = info_span!("midi_clock", %device).entered();
+            info!("Sending MIDI clock");
+            let mut playing = transport.state().playing;
+            if playing {
+                send_start(&mut output, &transport.state());
+            }
+            let mut sent_until = unix_micros();
+            'session: loop {
+                loop {
+                    let change = match changes.try_next() {
+                        Ok(Some(change)) => change,
+                        Ok(None) => break 'session,
+                        Err(_) => break,
+                    };
+                    if change.state.playing != playing {
+                        playing = change.state.playing;
+                        match playing {
+                            true => send_start(&mut output, &change.state),
+                            false => send(&mut output, &[STOP]),
+                        }
+                    }
+                }
+                let grid = TransportState {
+                    playing: true,
+                    ..transport.state()
+                };
+                let now = unix_micros();
+                let ticks = grid.clock_ticks(sent_until, now + 1);
+                // A beat jumping ahead would send a burst of ticks, the gear picks up from here
+                match ticks.len() > CLOCK_PPQN as usize {
+                    true => debug!("Skipped {} clock ticks", ticks.len()),
+                    false => ticks.iter().for_each(|_| send(&mut output, &[CLOCK])),
+                }
+                sent_until = now + 1;
+                let next_tick = (grid.beat_at(now) * CLOCK_PPQN as f64).floor() + 1.0;
+                let wait = grid
+                    .time_of_beat(next_tick / CLOCK_PPQN as f64)
+                    .saturating_sub(now);
+                std::thread::sleep(Duration::from_micros(wait).min(POLL_INTERVAL));
+            }
+            info!("Stopping MIDI clock");
+            send(&mut output, &[STOP]);
+            drop(output);
+        })?;
+    Ok(())
+}
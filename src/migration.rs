@@ -0,0 +1,52 @@
+use serde_json::Value;
+
+/// Version of the config file schema written by this build.
+pub const CONFIG_VERSION: u32 = 1;
+
+/// Upgrades a config from the version matching its index in `MIGRATIONS` to the next one.
+type Migration = fn(&mut serde_json::Map<String, Value>);
+
+const MIGRATIONS: [Migration; CONFIG_VERSION as usize] = [v0_to_v1];
+
+/// Files written before versioning. Nothing was renamed, they only get the version field.
+fn v0_to_v1(_config: &mut serde_json::Map<String, Value>) {}
+
+/// Version of a parsed config file. Files without `config_version` predate versioning.
+pub fn config_version(config: &Value) -> Result<u32, String> {
+    match config.get("config_version") {
+        None | Some(Value::Null) => Ok(0),
+        Some(v) => v
+            .as_u64()
+            .map(|v| v as u32)
+            .ok_or_else(|| format!("config_version must be a number, found {}", v)),
+    }
+}
+
+/// Upgrade a parsed config file to `CONFIG_VERSION`.
+///
+/// Returns the version the config had if it was migrated, or `None` if it was already current.
+pub fn migrate(config: &mut Value) -> Result<Option<u32>, String> {
+    if config.is_null() {
+        return Ok(None);
+    }
+    let version = config_version(config)?;
+    let map = match config {
+        Value::Object(map) => map,
+        _ => return Err("Expected the config file to contain a map of settings".to_string()),
+    };
+    if version > CONFIG_VERSION {
+        return Err(format!(
+            "Config file version {} is newer than the supported version {}, please update p2pmidi",
+            version, CONFIG_VERSION
+        ));
+    }
+    if version == CONFIG_VERSION {
+        return Ok(None);
+    }
+
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(map);
+    }
+    map.insert("config_version".to_string(), Value::from(CONFIG_VERSION));
+    Ok(Some(version))
+}
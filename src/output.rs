@@ -0,0 +1,407 @@
+use serde::Serialize;
+use std::fmt;
+use std::io::Write;
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use super::latency::Stage;
+use super::p2p::directory::OpenSession;
+use super::p2p::loss::LossStats;
+use super::p2p::playout::{LatencyMode, DEFAULT_DELAY_BARS, JITTER_BUFFER};
+use super::p2p::selftest::SoakReport;
+use super::p2p::summary::SessionReport;
+use super::p2p::troubleshoot::CheckResult;
+use super::transport::Source;
+
+/// Something worth telling the user about while the client runs.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Report {
+    Status {
+        state: String,
+    },
+    Listening {
+        address: String,
+    },
+    Invite {
+        invite: String,
+    },
+    PeerConnected {
+        peer_id: String,
+        name: String,
+        /// Its short authentication string was compared before.
+        verified: bool,
+    },
+    PeerDisconnected {
+        peer_id: String,
+    },
+    /// A peer not let in automatically, waiting to be accepted.
+    UntrustedPeer {
+        peer_id: String,
+        name: Option<String>,
+    },
+    /// Emoji to compare with a peer not verified yet.
+    Verify {
+        peer_id: String,
+        name: String,
+        emoji: String,
+        words: String,
+    },
+    /// A peer goes by the name of another one accepted before.
+    NameTaken {
+        peer_id: String,
+        name: String,
+        known_peer_id: String,
+    },
+    Peers {
+        peers: Vec<String>,
+    },
+    Latency {
+        peer_id: String,
+        rtt_ms: f64,
+    },
+    LatencyStage {
+        stage: Stage,
+        count: u64,
+        p50_ms: f64,
+        p99_ms: f64,
+        max_ms: f64,
+    },
+    /// Per peer loss counters, keyed by display name.
+    Loss {
+        peers: BTreeMap<String, LossStats>,
+    },
+    /// A peer can't keep up with the MIDI sent to it and some is being dropped.
+    Overload {
+        peer_id: String,
+    },
+    /// A peer sends more MIDI than its rate limit and some is being dropped.
+    RateLimited {
+        peer_id: String,
+        rate: u32,
+    },
+    /// MIDI from a peer is now played live, buffered or bars late.
+    LatencyMode {
+        peer_id: String,
+        mode: LatencyMode,
+        /// How many bars late, in bar mode.
+        delay_bars: Option<u32>,
+    },
+    Ping {
+        target: String,
+        transport: String,
+        received: usize,
+        min_ms: f64,
+        avg_ms: f64,
+        max_ms: f64,
+    },
+    /// A peer reached through the relay until now is connected to directly.
+    UpgradedToDirect {
+        peer_id: String,
+        transport: String,
+    },
+    /// A peer offers a file, to accept or decline by its id.
+    FileOffered {
+        peer_id: String,
+        id: u64,
+        name: String,
+        size: u64,
+    },
+    FileReceived {
+        peer_id: String,
+        name: String,
+        path: PathBuf,
+    },
+    /// A peer declined a file we offered.
+    FileDeclined {
+        peer_id: String,
+        name: String,
+    },
+    /// Sessions published on the relay.
+    Sessions {
+        sessions: Vec<OpenSession>,
+    },
+    Progress {
+        position_s: f64,
+        duration_s: f64,
+    },
+    Session(SessionReport),
+    /// The session tempo changed or it started or stopped.
+    Transport {
+        tempo: f64,
+        playing: bool,
+        source: Source,
+    },
+    /// Beats left before a start scheduled with the peers, 0 when it starts.
+    CountIn {
+        beats_left: u32,
+    },
+    /// Progress of `p2pmidi selftest`.
+    Soak(SoakReport),
+    /// A check of `p2pmidi troubleshoot`.
+    Check(CheckResult),
+    Error {
+        message: String,
+    },
+}
+
+impl Report {
+    /// Reports sent over and over that the status line sums up instead.
+    fn is_chatter(&self) -> bool {
+        matches!(
+            self,
+            Report::Latency { .. } | Report::Peers { .. } | Report::Loss { .. }
+        )
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Report::Status { state } => write!(f, "Status: {}", state),
+            Report::Listening { address } => write!(f, "Listening on {}", address),
+            Report::Invite { invite } => write!(f, "Invite others with: {}", invite),
+            Report::PeerConnected {
+                peer_id,
+                name,
+                verified,
+            } => {
+                write!(f, "Peer connected: {} ({})", name, peer_id)?;
+                match verified {
+                    true => write!(f, " ✔ verified"),
+                    false => Ok(()),
+                }
+            }
+            Report::PeerDisconnected { peer_id } => write!(f, "Peer disconnected: {}", peer_id),
+            Report::UntrustedPeer { peer_id, name } => write!(
+                f,
+                "{} ({}) wants to join. Type y to accept, or run `p2pmidi ctl accept {}`",
+                name.as_deref().unwrap_or("Unknown peer"),
+                peer_id,
+                peer_id
+            ),
+            Report::Verify {
+                peer_id,
+                name,
+                emoji,
+                words,
+            } => write!(
+                f,
+                "Check with {} that they see {} ({}), then run `p2pmidi ctl verify {}`",
+                name, emoji, words, peer_id
+            ),
+            Report::NameTaken {
+                peer_id,
+                name,
+                known_peer_id,
+            } => write!(
+                f,
+                "WARNING: {} calls itself {}, but {} was {} until now. Someone may be impersonating them!",
+                peer_id, name, name, known_peer_id
+            ),
+            Report::Peers { peers } => write!(f, "Peers: {}", peers.join(", ")),
+            Report::Latency { peer_id, rtt_ms } => {
+                write!(f, "Latency to {}: {:.1} ms", peer_id, rtt_ms)
+            }
+            Report::LatencyStage {
+                stage,
+                count,
+                p50_ms,
+                p99_ms,
+                max_ms,
+            } => write!(
+                f,
+                "Latency {}: {} events, p50/p99/max = {:.1}/{:.1}/{:.1} ms",
+                stage, count, p50_ms, p99_ms, max_ms
+            ),
+            Report::Loss { peers } => {
+                write!(f, "Loss:")?;
+                for (i, (name, stats)) in peers.iter().enumerate() {
+                    write!(
+                        f,
+                        "{} {} {} received, {} lost, {} late, {} out of window",
+                        if i == 0 { "" } else { ";" },
+                        name,
+                        stats.received,
+                        stats.lost,
+                        stats.late,
+                        stats.out_of_window
+                    )?;
+                }
+                Ok(())
+            }
+            Report::Overload { peer_id } => {
+                write!(f, "{} can't keep up, dropping MIDI", peer_id)
+            }
+            Report::RateLimited { peer_id, rate } => write!(
+                f,
+                "{} sends more than {} MIDI events a second, dropping the excess",
+                peer_id, rate
+            ),
+            Report::LatencyMode {
+                peer_id,
+                mode,
+                delay_bars,
+            } => match mode {
+                LatencyMode::Live => write!(f, "Playing {} live", peer_id),
+                LatencyMode::Buffered => write!(
+                    f,
+                    "Playing {} through a {}ms jitter buffer",
+                    peer_id,
+                    JITTER_BUFFER.as_millis()
+                ),
+                LatencyMode::Bar => match delay_bars.unwrap_or(DEFAULT_DELAY_BARS) {
+                    1 => write!(f, "Playing {} a bar late", peer_id),
+                    bars => write!(f, "Playing {} {} bars late", peer_id, bars),
+                },
+            },
+            Report::Ping {
+                target,
+                transport,
+                received,
+                min_ms,
+                avg_ms,
+                max_ms,
+            } => write!(
+                f,
+                "{} via {}: {} replies, min/avg/max = {:.1}/{:.1}/{:.1} ms",
+                target, transport, received, min_ms, avg_ms, max_ms
+            ),
+            Report::UpgradedToDirect { peer_id, transport } => write!(
+                f,
+                "Upgraded to direct: {} is now reached over {} instead of the relay",
+                peer_id, transport
+            ),
+            Report::FileOffered {
+                peer_id,
+                id,
+                name,
+                size,
+            } => write!(
+                f,
+                "{} offers {} ({} bytes), take it with `p2pmidi ctl file accept {} {}`",
+                peer_id, name, size, peer_id, id
+            ),
+            Report::FileReceived {
+                peer_id,
+                name,
+                path,
+            } => write!(
+                f,
+                "Received {} from {}, saved to {}",
+                name,
+                peer_id,
+                path.display()
+            ),
+            Report::FileDeclined { peer_id, name } => {
+                write!(f, "{} declined {}", peer_id, name)
+            }
+            Report::Sessions { sessions } => {
+                write!(f, "Open sessions on the relay:")?;
+                if sessions.is_empty() {
+                    write!(f, " none")?;
+                }
+                for session in sessions {
+                    write!(f, "\n  {}: {}", session, session.peers.join(", "))?;
+                }
+                Ok(())
+            }
+            Report::Progress {
+                position_s,
+                duration_s,
+            } => write!(f, "Playing {:.0}/{:.0}s", position_s, duration_s),
+            Report::Session(report) => write!(f, "{}", report),
+            Report::Transport {
+                tempo,
+                playing,
+                source,
+            } => write!(
+                f,
+                "{} at {:.1} bpm (from {:?})",
+                if *playing { "Playing" } else { "Stopped" },
+                tempo,
+                source
+            ),
+            Report::CountIn { beats_left: 0 } => write!(f, "Go!"),
+            Report::CountIn { beats_left } => write!(f, "Starting in {}", beats_left),
+            Report::Soak(report) => write!(f, "{}", report),
+            Report::Check(result) => write!(f, "{}", result),
+            Report::Error { message } => write!(f, "Error: {}", message),
+        }
+    }
+}
+
+/// The state of a session at a glance, kept on one line at the bottom of the terminal.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StatusLine {
+    pub peers: usize,
+    pub tempo: f64,
+    pub playing: bool,
+    /// Slowest round trip time to a connected peer.
+    pub rtt_ms: Option<f64>,
+    /// MIDI events sent and received a second.
+    pub events_per_s: f64,
+}
+
+impl fmt::Display for StatusLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.peers {
+            1 => write!(f, "1 peer")?,
+            n => write!(f, "{} peers", n)?,
+        }
+        write!(
+            f,
+            " | {} {:.1} bpm",
+            if self.playing { "▶" } else { "■" },
+            self.tempo
+        )?;
+        if let Some(rtt_ms) = self.rtt_ms {
+            write!(f, " | RTT {:.1} ms", rtt_ms)?;
+        }
+        write!(f, " | {:.0} events/s", self.events_per_s)
+    }
+}
+
+/// Prints reports for humans, as JSON lines for scripts, or not at all.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Reporter {
+    pub json: bool,
+    pub quiet: bool,
+    /// Keep a status line at the bottom instead of printing latency, peers and loss as they come.
+    pub status_line: bool,
+}
+
+impl Reporter {
+    pub fn report(&self, report: Report) {
+        if self.json {
+            match serde_json::to_string(&report) {
+                Ok(line) => println!("{}", line),
+                Err(e) => tracing::error!("Error serializing report: {}", e),
+            }
+        } else if self.status_line {
+            if !report.is_chatter() {
+                // Printed over the status line, which is drawn again below it on the next update
+                println!("\r\x1b[2K{}", report);
+            }
+        } else if !self.quiet || matches!(report, Report::Error { .. }) {
+            println!("{}", report);
+        }
+    }
+
+    /// Draw the status line again, if there is one.
+    pub fn status(&self, line: &StatusLine) {
+        if self.status_line {
+            let mut stdout = std::io::stdout().lock();
+            let _ = write!(stdout, "\r\x1b[2K{}", line);
+            let _ = stdout.flush();
+        }
+    }
+
+    /// Move past the status line, so what is printed next starts on a line of its own.
+    pub fn end_status(&self) {
+        if self.status_line {
+            println!("\r\x1b[2K");
+        }
+    }
+}
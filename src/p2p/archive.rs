@@ -0,0 +1,578 @@
+//! Sessions recorded on the relay, for bands without a machine they trust to record. Peers that
+//! agree to it send a copy of what they play to the relay, flagged with what they consent to: being
+//! recorded at all, and being downloaded by the others in the session. The relay only records when
+//! started with an archive directory, and hands a session out only to those who played in it. The
+//! first peer to record in a session starts it, others are let in once the relay has carried a
+//! circuit between them and someone already in it, so a guessed session name gets nobody in.
+//! Sessions idle for a while are only kept on disk, where they can't be recorded into again.
+
+use async_trait::async_trait;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::{future::FutureExt, stream::StreamExt};
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use libp2p::{
+    identity, ping, pnet::PreSharedKey, request_response, swarm::SwarmEvent, PeerId, StreamProtocol,
+};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::{info, info_span, warn};
+
+use super::client::{bootstrap, build_swarm, relay_multiaddr, Event};
+use super::protocol::{decode_frames, encode_frames, MidiFrame, TRACKS_WIRE_VERSION};
+use super::trust::agent_version;
+use crate::recorder::SessionRecorder;
+use crate::runtime;
+
+/// Protocol peers send MIDI to the relay with, and fetch recordings over.
+pub const ARCHIVE_PROTOCOL: StreamProtocol = StreamProtocol::new("/p2pmidi/archive/1.0.0");
+
+/// Largest request or recording accepted.
+const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Bytes of MIDI kept in memory for one session, after which it takes no more.
+const MAX_SESSION_SIZE: usize = 64 * 1024 * 1024;
+
+/// Bytes of MIDI kept in memory for all sessions together.
+const MAX_ARCHIVE_SIZE: usize = 512 * 1024 * 1024;
+
+/// Sessions in memory one peer may be playing in.
+const MAX_SESSIONS_PER_PEER: usize = 4;
+
+/// How long a session is kept in memory, to be fetched, after the last MIDI recorded into it.
+const IDLE_SESSION: Duration = Duration::from_secs(60 * 60);
+
+/// What keeping a message costs besides its bytes: its time, peer and track.
+const RECORDED_MESSAGE_OVERHEAD: usize = 64;
+
+/// Circuits between peers remembered to let them into each other's sessions.
+const MAX_CIRCUITS: usize = 4096;
+
+/// Consent flag: what the sender plays may be recorded.
+const CONSENT_RECORD: u8 = 0x01;
+/// Consent flag: others who played in the session may download it.
+const CONSENT_SHARE: u8 = 0x02;
+
+const STORE: u8 = 1;
+const FETCH: u8 = 2;
+
+const STORED: u8 = 1;
+const RECORDING: u8 = 2;
+const REFUSED: u8 = 3;
+
+/// What a sender agrees to, sent with every batch so it can be taken back at any time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Consent {
+    pub record: bool,
+    pub share: bool,
+}
+
+impl Consent {
+    fn to_flags(self) -> u8 {
+        (self.record as u8 * CONSENT_RECORD) | (self.share as u8 * CONSENT_SHARE)
+    }
+
+    fn from_flags(flags: u8) -> Self {
+        Consent {
+            record: flags & CONSENT_RECORD != 0,
+            share: flags & CONSENT_SHARE != 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ArchiveRequest {
+    /// Record these frames in `session`, under the sender's `name`.
+    Store {
+        session: String,
+        name: String,
+        consent: Consent,
+        frames: Vec<MidiFrame>,
+    },
+    /// The recording of `session`, as far as the asking peer may have it.
+    Fetch { session: String },
+}
+
+#[derive(Debug, Clone)]
+pub enum ArchiveResponse {
+    Stored,
+    /// A standard MIDI file.
+    Recording(Bytes),
+    Refused(String),
+}
+
+/// Session names are file names on the relay.
+pub fn valid_session(session: &str) -> bool {
+    !session.is_empty()
+        && session.len() <= u8::MAX as usize
+        && session
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn get_str(bytes: &mut Bytes) -> io::Result<String> {
+    if bytes.is_empty() {
+        return Err(invalid("Truncated archive message"));
+    }
+    let len = bytes.get_u8() as usize;
+    if bytes.len() < len {
+        return Err(invalid("Truncated archive message"));
+    }
+    String::from_utf8(bytes.split_to(len).to_vec()).map_err(|_| invalid("Invalid UTF-8"))
+}
+
+fn put_str(buffer: &mut BytesMut, s: &str) {
+    let s = &s.as_bytes()[..s.len().min(u8::MAX as usize)];
+    buffer.put_u8(s.len() as u8);
+    buffer.put_slice(s);
+}
+
+async fn read_message<T: AsyncRead + Unpin + Send>(io: &mut T) -> io::Result<Bytes> {
+    let mut len = [0u8; 4];
+    io.read_exact(&mut len).await?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_MESSAGE_SIZE {
+        return Err(invalid(&format!(
+            "Archive message of {} bytes is too large",
+            len
+        )));
+    }
+    let mut buffer = vec![0; len];
+    io.read_exact(&mut buffer).await?;
+    Ok(buffer.into())
+}
+
+async fn write_message<T: AsyncWrite + Unpin + Send>(io: &mut T, body: BytesMut) -> io::Result<()> {
+    io.write_all(&(body.len() as u32).to_be_bytes()).await?;
+    io.write_all(&body).await
+}
+
+/// Length prefixed requests of `kind: u8, session_len: u8, session`, stores followed by
+/// `consent: u8, name_len: u8, name` and a batch of frames with tracks. Responses are
+/// `kind: u8` and the recording or the reason for refusing.
+#[derive(Debug, Clone, Default)]
+pub struct ArchiveCodec;
+
+#[async_trait]
+impl request_response::Codec for ArchiveCodec {
+    type Protocol = StreamProtocol;
+    type Request = ArchiveRequest;
+    type Response = ArchiveResponse;
+
+    async fn read_request<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+    ) -> io::Result<ArchiveRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut bytes = read_message(io).await?;
+        if bytes.is_empty() {
+            return Err(invalid("Empty archive request"));
+        }
+        let kind = bytes.get_u8();
+        let session = get_str(&mut bytes)?;
+        match kind {
+            STORE => {
+                if bytes.is_empty() {
+                    return Err(invalid("Truncated archive request"));
+                }
+                let consent = Consent::from_flags(bytes.get_u8());
+                let name = get_str(&mut bytes)?;
+                let frames = decode_frames(bytes).map_err(|e| invalid(&e.to_string()))?;
+                Ok(ArchiveRequest::Store {
+                    session,
+                    name,
+                    consent,
+                    frames,
+                })
+            }
+            FETCH => Ok(ArchiveRequest::Fetch { session }),
+            kind => Err(invalid(&format!("Unknown archive request {}", kind))),
+        }
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+    ) -> io::Result<ArchiveResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut bytes = read_message(io).await?;
+        if bytes.is_empty() {
+            return Err(invalid("Empty archive response"));
+        }
+        match bytes.get_u8() {
+            STORED => Ok(ArchiveResponse::Stored),
+            RECORDING => Ok(ArchiveResponse::Recording(bytes)),
+            REFUSED => Ok(ArchiveResponse::Refused(
+                String::from_utf8_lossy(&bytes).into_owned(),
+            )),
+            kind => Err(invalid(&format!("Unknown archive response {}", kind))),
+        }
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        request: ArchiveRequest,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let mut body = BytesMut::new();
+        match request {
+            ArchiveRequest::Store {
+                session,
+                name,
+                consent,
+                frames,
+            } => {
+                body.put_u8(STORE);
+                put_str(&mut body, &session);
+                body.put_u8(consent.to_flags());
+                put_str(&mut body, &name);
+                let frames = encode_frames(&frames, TRACKS_WIRE_VERSION)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                body.put_slice(&frames);
+            }
+            ArchiveRequest::Fetch { session } => {
+                body.put_u8(FETCH);
+                put_str(&mut body, &session);
+            }
+        }
+        write_message(io, body).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        response: ArchiveResponse,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let mut body = BytesMut::new();
+        match response {
+            ArchiveResponse::Stored => body.put_u8(STORED),
+            ArchiveResponse::Recording(smf) => {
+                body.put_u8(RECORDING);
+                body.put_slice(&smf);
+            }
+            ArchiveResponse::Refused(reason) => {
+                body.put_u8(REFUSED);
+                body.put_slice(reason.as_bytes());
+            }
+        }
+        write_message(io, body).await
+    }
+}
+
+/// One session recorded on the relay.
+struct ArchivedSession {
+    recorder: SessionRecorder,
+    /// Whether each peer that played in it lets the others download what it played.
+    shared: HashMap<String, bool>,
+    /// Bytes kept in memory, counted towards `MAX_SESSION_SIZE`.
+    size: usize,
+    /// Recorded since it was last written to disk.
+    changed: bool,
+    /// When MIDI was last recorded into it.
+    stored_at: Instant,
+}
+
+impl ArchivedSession {
+    fn new() -> Self {
+        ArchivedSession {
+            recorder: SessionRecorder::default(),
+            shared: HashMap::new(),
+            size: 0,
+            changed: false,
+            stored_at: Instant::now(),
+        }
+    }
+}
+
+/// The sessions a relay records, written to a directory of standard MIDI files.
+pub struct RelayArchive {
+    dir: PathBuf,
+    sessions: HashMap<String, ArchivedSession>,
+    /// Bytes kept in memory for all sessions, counted towards `MAX_ARCHIVE_SIZE`.
+    size: usize,
+    /// Peers the relay carried a circuit between lately, the oldest first.
+    circuits: VecDeque<(String, String)>,
+    circuit_set: HashSet<(String, String)>,
+}
+
+impl RelayArchive {
+    pub fn new(dir: PathBuf) -> Self {
+        RelayArchive {
+            dir,
+            sessions: HashMap::new(),
+            size: 0,
+            circuits: VecDeque::new(),
+            circuit_set: HashSet::new(),
+        }
+    }
+
+    /// Remember that two peers reached each other through the relay.
+    pub fn circuit_opened(&mut self, src: &PeerId, dst: &PeerId) {
+        let pair = circuit_pair(&src.to_string(), &dst.to_string());
+        if !self.circuit_set.insert(pair.clone()) {
+            return;
+        }
+        self.circuits.push_back(pair);
+        if self.circuits.len() > MAX_CIRCUITS {
+            if let Some(oldest) = self.circuits.pop_front() {
+                self.circuit_set.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn handle(&mut self, peer: &PeerId, request: ArchiveRequest) -> ArchiveResponse {
+        let result = match request {
+            ArchiveRequest::Store {
+                session,
+                name,
+                consent,
+                frames,
+            } => self
+                .store(peer, &session, &name, consent, &frames)
+                .map(|_| ArchiveResponse::Stored),
+            ArchiveRequest::Fetch { session } => self
+                .fetch(peer, &session)
+                .map(|smf| ArchiveResponse::Recording(smf.into())),
+        };
+        result.unwrap_or_else(ArchiveResponse::Refused)
+    }
+
+    fn store(
+        &mut self,
+        peer: &PeerId,
+        session: &str,
+        name: &str,
+        consent: Consent,
+        frames: &[MidiFrame],
+    ) -> Result<(), String> {
+        if !consent.record {
+            return Err("Not recorded without consent".to_string());
+        }
+        if !valid_session(session) {
+            return Err(format!("Invalid session name {:?}", session));
+        }
+        if frames.is_empty() {
+            return Err("Nothing to record".to_string());
+        }
+        let peer_id = peer.to_string();
+        // Only someone who played with a peer already in the session may join it
+        match self.sessions.get(session) {
+            Some(archived) if !archived.shared.contains_key(&peer_id) => {
+                let joined = archived
+                    .shared
+                    .keys()
+                    .any(|member| self.circuit_set.contains(&circuit_pair(&peer_id, member)));
+                if !joined {
+                    return Err(format!(
+                        "Session {:?} is taken, play with its peers through this relay to join it",
+                        session
+                    ));
+                }
+                self.check_sessions_of(&peer_id)?;
+            }
+            Some(_) => {}
+            // Sessions only on disk are over, recording again would overwrite them
+            None if self.session_path(session).exists() => {
+                return Err(format!("Session {:?} is over", session));
+            }
+            None => self.check_sessions_of(&peer_id)?,
+        }
+        let size: usize = frames
+            .iter()
+            .map(|frame| frame.message.len() + RECORDED_MESSAGE_OVERHEAD)
+            .sum();
+        if self.size + size > MAX_ARCHIVE_SIZE {
+            return Err("The archive of this relay is full".to_string());
+        }
+        let archived = self
+            .sessions
+            .entry(session.to_string())
+            .or_insert_with(ArchivedSession::new);
+        if archived.size + size > MAX_SESSION_SIZE {
+            return Err(format!("Session {:?} is full", session));
+        }
+        let name = match name.is_empty() {
+            true => &peer_id,
+            false => name,
+        };
+        for frame in frames {
+            archived.recorder.record(&peer_id, name, frame);
+        }
+        archived.shared.insert(peer_id, consent.share);
+        archived.size += size;
+        archived.changed = true;
+        archived.stored_at = Instant::now();
+        self.size += size;
+        Ok(())
+    }
+
+    /// Refuse a peer one more session in memory once it plays in `MAX_SESSIONS_PER_PEER`.
+    fn check_sessions_of(&self, peer_id: &str) -> Result<(), String> {
+        let sessions = self
+            .sessions
+            .values()
+            .filter(|archived| archived.shared.contains_key(peer_id))
+            .count();
+        match sessions < MAX_SESSIONS_PER_PEER {
+            true => Ok(()),
+            false => Err(format!(
+                "Already recording {} sessions on this relay",
+                sessions
+            )),
+        }
+    }
+
+    fn session_path(&self, session: &str) -> PathBuf {
+        self.dir.join(format!("{}.mid", session))
+    }
+
+    /// Everything `peer` played in `session`, and what the others let it have.
+    fn fetch(&self, peer: &PeerId, session: &str) -> Result<Vec<u8>, String> {
+        let peer_id = peer.to_string();
+        let archived = self
+            .sessions
+            .get(session)
+            .filter(|s| s.shared.contains_key(&peer_id))
+            .ok_or_else(|| format!("No session {:?} you played in", session))?;
+        archived
+            .recorder
+            .to_smf(|p| p == peer_id || archived.shared.get(p) == Some(&true))
+            .map_err(|e| e.to_string())
+    }
+
+    /// Write the sessions recorded into since the last time, everyone who consented in them, and
+    /// drop the ones saved and idle for `IDLE_SESSION` from memory.
+    pub fn save(&mut self) {
+        for (session, archived) in self.sessions.iter_mut().filter(|(_, s)| s.changed) {
+            let path = self.dir.join(format!("{}.mid", session));
+            match archived.recorder.save(&path) {
+                Ok(()) => archived.changed = false,
+                Err(e) => warn!("Error saving archived session to {:?}: {}", path, e),
+            }
+        }
+        let size = &mut self.size;
+        self.sessions.retain(|session, archived| {
+            let keep = archived.changed || archived.stored_at.elapsed() < IDLE_SESSION;
+            if !keep {
+                info!("Archived session {} is over", session);
+                *size -= archived.size;
+            }
+            keep
+        });
+    }
+}
+
+/// Two peers in the same order whichever opened the circuit.
+fn circuit_pair(a: &str, b: &str) -> (String, String) {
+    match a < b {
+        true => (a.to_string(), b.to_string()),
+        false => (b.to_string(), a.to_string()),
+    }
+}
+
+/// Settings of a `p2pmidi archive` run.
+#[derive(Clone, Debug)]
+pub struct FetchOptions {
+    pub session: String,
+    pub out: PathBuf,
+    pub relay_address: String,
+    pub relay_port: u16,
+    pub relay_peer_id: Option<PeerId>,
+    pub use_ipv6: bool,
+    pub bind_address: Option<IpAddr>,
+    pub swarm_key: Option<PreSharedKey>,
+}
+
+/// Download the recording of a session from the relay. It has to be fetched with the identity
+/// that played in it.
+pub fn run_fetch(
+    options: FetchOptions,
+    local_key: identity::Keypair,
+) -> Result<(), Box<dyn Error>> {
+    let _fetch = info_span!("archive", session = %options.session).entered();
+    let _runtime = runtime::enter();
+    let relay_address = relay_multiaddr(
+        &options.relay_address,
+        options.relay_port,
+        options.use_ipv6,
+        options.relay_peer_id,
+    )?;
+    let mut swarm = build_swarm(
+        &local_key,
+        ping::Config::new(),
+        agent_version(None),
+        options.swarm_key,
+    )?;
+    let relay_peer_id = bootstrap(&mut swarm, &relay_address, options.bind_address)?;
+    swarm.behaviour_mut().archive.send_request(
+        &relay_peer_id,
+        ArchiveRequest::Fetch {
+            session: options.session.clone(),
+        },
+    );
+
+    let recording: Result<Bytes, String> = runtime::block_on(async {
+        let mut deadline = futures_timer::Delay::new(Duration::from_secs(60)).fuse();
+        loop {
+            futures::select! {
+                event = swarm.select_next_some() => match event {
+                    SwarmEvent::Behaviour(Event::Archive(request_response::Event::Message {
+                        message: request_response::Message::Response { response, .. },
+                        ..
+                    })) => {
+                        return match response {
+                            ArchiveResponse::Recording(smf) => Ok(smf),
+                            ArchiveResponse::Refused(reason) => Err(reason),
+                            ArchiveResponse::Stored => Err("Unexpected answer from the relay".to_string()),
+                        };
+                    }
+                    SwarmEvent::Behaviour(Event::Archive(request_response::Event::OutboundFailure {
+                        error,
+                        ..
+                    })) => return Err(format!("The relay does not archive sessions: {}", error)),
+                    _ => {}
+                },
+                _ = deadline => return Err("The relay did not answer".to_string()),
+            }
+        }
+    });
+    let recording = recording?;
+    write_recording(&options.out, &recording)?;
+    info!(
+        "Saved session {} to {}",
+        options.session,
+        options.out.display()
+    );
+    Ok(())
+}
+
+fn write_recording(path: &Path, smf: &[u8]) -> Result<(), Box<dyn Error>> {
+    if let Some(parent) = path.parent() {
+        if !parent.as_os_str().is_empty() {
+            std::fs::create_dir_all(parent)?;
+        }
+    }
+    std::fs::write(path, smf)?;
+    Ok(())
+}
@@ -0,0 +1,238 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+
+use super::protocol::{
+    encoded_len, frame_len, EventClass, MidiFrame, MAX_BATCH_FRAMES, MAX_BATCH_SIZE,
+    TRACKS_WIRE_VERSION,
+};
+use crate::midi::{self, MessageKind};
+
+/// Batches sent to a peer and not acknowledged yet before more MIDI is held back.
+const MAX_IN_FLIGHT: usize = 8;
+
+/// Frames held back for a peer before some are dropped.
+const MAX_QUEUED: usize = 512;
+
+/// What to drop when a peer can't keep up with the MIDI sent to it. Note offs and other messages
+/// ending notes are never dropped.
+#[derive(clap::ValueEnum, Debug, Default, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BackpressurePolicy {
+    /// Drop the oldest messages of the lowest priority classes first: controllers and pitch
+    /// bend, then clock, then SysEx, then notes.
+    #[default]
+    DropOldest,
+    /// Drop new messages until the peer catches up.
+    DropNewest,
+}
+
+/// What goes when more MIDI of a class waits for a peer than there is room for.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DropPolicy {
+    /// The oldest message of the class waiting makes room for the new one.
+    Oldest,
+    /// New messages of the class are dropped until the peer catches up.
+    Newest,
+    /// Nothing of the class is dropped, however long it waits.
+    Never,
+}
+
+/// Changes to how one class of MIDI waits for a peer, from the `priority_classes:` section of the
+/// config file, like `clock: { priority: 0, drop: newest }`.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClassPolicy {
+    /// Classes with a lower priority are dropped first when the link is full.
+    pub priority: Option<u8>,
+    /// Messages of the class waiting for a peer before some of them are dropped.
+    pub max_queued: Option<usize>,
+    pub drop: Option<DropPolicy>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+struct ClassLimits {
+    priority: u8,
+    max_queued: usize,
+    drop: DropPolicy,
+}
+
+/// How each class of MIDI waits for a peer that can't keep up: the defaults of the backpressure
+/// policy, changed by the classes of the config file.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct PriorityModel {
+    classes: [ClassLimits; EventClass::ALL.len()],
+}
+
+impl PriorityModel {
+    pub fn new(policy: BackpressurePolicy, overrides: &BTreeMap<EventClass, ClassPolicy>) -> Self {
+        let classes = EventClass::ALL.map(|class| {
+            // Notes and the pedal are kept longest, controllers and clock matter only until the
+            // next one
+            let priority = match class {
+                EventClass::Note | EventClass::Sustain => 3,
+                EventClass::SysEx => 2,
+                EventClass::Clock => 1,
+                EventClass::PitchBend | EventClass::Controller => 0,
+            };
+            let drop = match policy {
+                BackpressurePolicy::DropOldest => DropPolicy::Oldest,
+                BackpressurePolicy::DropNewest => DropPolicy::Newest,
+            };
+            let changed = overrides.get(&class).copied().unwrap_or_default();
+            ClassLimits {
+                priority: changed.priority.unwrap_or(priority),
+                max_queued: changed.max_queued.unwrap_or(MAX_QUEUED),
+                drop: changed.drop.unwrap_or(drop),
+            }
+        });
+        PriorityModel { classes }
+    }
+}
+
+impl Default for PriorityModel {
+    fn default() -> Self {
+        PriorityModel::new(BackpressurePolicy::default(), &BTreeMap::new())
+    }
+}
+
+/// Messages that only matter until the next one of their kind, so losing old ones hurts least.
+pub(crate) fn is_continuous(message: &[u8]) -> bool {
+    matches!(
+        MessageKind::of(message),
+        Some(
+            MessageKind::ControlChange
+                | MessageKind::PolyAftertouch
+                | MessageKind::ChannelAftertouch
+                | MessageKind::PitchBend
+                | MessageKind::Clock
+        )
+    ) && !midi::is_silencing(message)
+}
+
+/// MIDI waiting to be sent to one peer, queued by class and sent as a batch whenever the peer
+/// acknowledged enough of the previous ones.
+#[derive(Debug)]
+pub struct OutboundQueue {
+    model: PriorityModel,
+    in_flight: usize,
+    /// By class, in the order of `EventClass::ALL`.
+    queued: [VecDeque<MidiFrame>; EventClass::ALL.len()],
+    /// Frames dropped since the queue last drained.
+    dropped: u64,
+}
+
+impl OutboundQueue {
+    pub fn new(model: PriorityModel) -> Self {
+        OutboundQueue {
+            model,
+            in_flight: 0,
+            queued: Default::default(),
+            dropped: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.queued.iter().map(VecDeque::len).sum()
+    }
+
+    /// Queue frames for the peer, sharing their messages with the other peers they go to. Returns
+    /// how many frames were dropped to make room.
+    pub fn push(&mut self, frames: &[MidiFrame]) -> usize {
+        let mut dropped = 0;
+        for frame in frames {
+            let class = frame.class() as usize;
+            let limits = self.model.classes[class];
+            let class_full = self.queued[class].len() >= limits.max_queued;
+            let full = self.len() >= MAX_QUEUED;
+            // Note offs are never dropped, they would leave notes hanging
+            if midi::is_silencing(&frame.message)
+                || limits.drop == DropPolicy::Never
+                || !(class_full || full)
+            {
+                self.queued[class].push_back(frame.clone());
+                continue;
+            }
+            let room = match class_full {
+                true => limits.drop == DropPolicy::Oldest && self.evict(class),
+                false => self.evict_below(limits.priority),
+            };
+            // With nothing droppable queued the new frame is the one to go
+            if room {
+                self.queued[class].push_back(frame.clone());
+            }
+            dropped += 1;
+        }
+        self.dropped += dropped as u64;
+        dropped
+    }
+
+    /// Drop a frame of `class` the way the class drops them. Returns whether there was one.
+    fn evict(&mut self, class: usize) -> bool {
+        let queued = &mut self.queued[class];
+        let droppable = |f: &MidiFrame| !midi::is_silencing(&f.message);
+        let i = match self.model.classes[class].drop {
+            DropPolicy::Oldest => queued.iter().position(droppable),
+            DropPolicy::Newest => queued.iter().rposition(droppable),
+            DropPolicy::Never => None,
+        };
+        i.and_then(|i| queued.remove(i)).is_some()
+    }
+
+    /// Drop a frame of the lowest priority class at or under `priority` that has one.
+    fn evict_below(&mut self, priority: u8) -> bool {
+        let mut classes: Vec<usize> = (0..self.queued.len())
+            .filter(|class| self.model.classes[*class].priority <= priority)
+            .collect();
+        classes.sort_by_key(|class| self.model.classes[*class].priority);
+        classes.into_iter().any(|class| self.evict(class))
+    }
+
+    /// As much as fits in a batch, in the order it was sent, if the peer is ready for another
+    /// batch. The rest waits for the next one.
+    pub fn take_batch(&mut self) -> Option<Vec<MidiFrame>> {
+        if self.in_flight >= MAX_IN_FLIGHT || self.len() == 0 {
+            return None;
+        }
+        self.in_flight += 1;
+        let mut batch: Vec<MidiFrame> = Vec::new();
+        let mut size = encoded_len(&[], TRACKS_WIRE_VERSION);
+        while batch.len() < MAX_BATCH_FRAMES {
+            // Every class is queued in the order it was sent, the oldest front goes first
+            let queued = match self
+                .queued
+                .iter_mut()
+                .filter(|queued| !queued.is_empty())
+                .min_by_key(|queued| queued[0].seq)
+            {
+                Some(queued) => queued,
+                None => break,
+            };
+            let len = frame_len(&queued[0], TRACKS_WIRE_VERSION);
+            if !batch.is_empty() && size + len > MAX_BATCH_SIZE {
+                break;
+            }
+            size += len;
+            batch.extend(queued.pop_front());
+        }
+        Some(batch)
+    }
+
+    /// A batch was acknowledged or failed.
+    pub fn completed(&mut self) {
+        self.in_flight = self.in_flight.saturating_sub(1);
+    }
+
+    /// Whether frames have been dropped since the queue last drained.
+    pub fn is_overloaded(&self) -> bool {
+        self.dropped > 0
+    }
+
+    /// Once the queue drained after an overload, returns how many frames were dropped in total.
+    pub fn take_recovery(&mut self) -> Option<u64> {
+        if self.dropped == 0 || self.len() != 0 {
+            return None;
+        }
+        Some(std::mem::take(&mut self.dropped))
+    }
+}
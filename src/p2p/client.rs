@@ -1,6 +1,7 @@
+use bytes::Bytes;
 use futures::{
-    executor::{block_on, ThreadPool},
-    future::{Either, FutureExt},
+    channel::mpsc::{UnboundedReceiver, UnboundedSender},
+    future::{Either, Fuse, FutureExt},
     stream::StreamExt,
 };
 use futures_timer;
@@ -8,18 +9,78 @@ use libp2p::{
     core::{
         multiaddr::{Multiaddr, Protocol},
         muxing::StreamMuxerBox,
-        transport::Transport,
+        transport::{OptionalTransport, Transport, TransportError},
         upgrade,
     },
     dcutr,
-    dns::DnsConfig,
-    identify, identity, noise, ping, relay,
-    swarm::{NetworkBehaviour, SwarmBuilder, SwarmEvent},
+    dns::TokioDnsConfig,
+    identify, identity, noise, ping,
+    pnet::PreSharedKey,
+    relay, request_response,
+    swarm::{
+        dial_opts::DialOpts, ConnectionId, DialError, NetworkBehaviour, Swarm, SwarmBuilder,
+        SwarmEvent,
+    },
     tcp, yamux, PeerId,
 };
 use libp2p_quic as quic;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::error::Error;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, info_span, trace, warn, Span};
+
+use crate::arpeggiator::Arpeggiator;
+use crate::bridge::gateway::BrowserLimits;
+use crate::bridge::{self, BridgeEvent, BridgeMidi, BridgeOptions, Bridges};
+use crate::clock::ClockQuality;
+use crate::config_watcher::{watch_config, ConfigReloader};
+use crate::constants;
+use crate::control::{self, ControlRequest, ControlResponse, PendingRequest};
+use crate::failure::Failure;
+use crate::harmony::{Harmonizer, HarmonyZone};
+use crate::jack_transport::{self, JackTransportMode};
+use crate::keybindings::{Action, ActionTable, KeyBinding};
+use crate::latency::{LatencyStats, Stage, TransitEstimator};
+use crate::metrics::{self, Metrics};
+use crate::midi;
+use crate::midi_clock;
+use crate::output::{Report, Reporter, StatusLine};
+use crate::recorder::SessionRecorder;
+use crate::ring;
+use crate::routing::{port_names, MidiRouter, TransportPreference};
+use crate::runtime;
+use crate::settings::Settings;
+use crate::status::{StatusEvent, StatusOptions, StatusPublisher};
+use crate::storage::{Direction, RecentSession, Retention, SessionSettings, Storage};
+use crate::transport::{
+    unix_micros, CountIn, ScheduledStart, Source, TapTempo, Transport, TransportState,
+    MAX_COUNT_IN, START_LEAD, TEMPO_RANGE,
+};
+use crate::velocity::VelocityCurve;
+
+use super::archive::{self, ArchiveCodec, ArchiveRequest, ArchiveResponse, Consent};
+use super::backpressure::{BackpressurePolicy, ClassPolicy, OutboundQueue, PriorityModel};
+use super::directory::{self, DirectoryCodec, DirectoryRequest, DirectoryResponse};
+use super::history::ConnectionHistory;
+use super::invite::{Invite, InviteToken, TokenChecker, ONCE_VALIDITY};
+use super::loss::{LossStats, SequenceTracker};
+use super::paths::ConnectionPath;
+use super::playout::{
+    session_latency, LatencyMode, Playout, Route, Thru, Timing, TimingSmoother, DEFAULT_DELAY_BARS,
+};
+use super::protocol::{self, EventClass, FrameSequencer, MessageArena, MidiCodec, MidiFrame};
+use super::ratelimit::{RateLimitPolicy, RateLimiter, Verdict};
+use super::relay_hint::{self, RelayHint, RelayHintCodec};
+use super::sas::ShortAuthString;
+use super::simulate::{NetworkConditions, NetworkSimulator};
+use super::summary::SessionSummary;
+use super::swarm_key;
+use super::transfer::{self, FileOffer, TransferCodec, TransferRequest, TransferResponse};
+use super::trust::{self, AutoAccept, Trust, TrustStore};
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Mode {
@@ -38,43 +99,545 @@ impl FromStr for Mode {
     }
 }
 
-pub fn start_client(
-    mode: Mode,
-    secret_key_seed: u8,
-    relay_address_str: &str,
-    relay_port: u16,
-    remote_peer_id_u8: u8,
+/// Everything needed to start a client session.
+#[derive(Clone, Debug)]
+pub struct ClientOptions {
+    pub mode: Mode,
+    pub local_key: identity::Keypair,
+    /// Name announced to peers.
+    pub name: Option<String>,
+    pub relay_address: String,
+    pub relay_port: u16,
+    /// Refuse a relay presenting another PeerId.
+    pub relay_peer_id: Option<PeerId>,
+    /// Relay address given out in invites instead of `relay_address`, when the relay runs in this
+    /// process and is reached over loopback.
+    pub invite_relay_address: Option<String>,
+    /// More relays, as host[:port], to hold reservations on. Peers agree on the one closest to
+    /// both of them to go through.
+    pub relays: Vec<String>,
+    /// PeerId or multiaddr to dial, this or `addresses` is required in dial mode.
+    pub target: Option<String>,
+    /// More peers to dial, from the `ip_addresses` setting. Those dialed by hostname are dialed
+    /// again when they leave.
+    pub addresses: Vec<String>,
+    /// Port dialed on peers given as an IP address or hostname without one.
+    pub port: Option<u16>,
+    pub use_ipv6: bool,
+    /// Local address to listen and dial from instead of all interfaces.
+    pub bind_address: Option<IpAddr>,
+    /// Config file watched for live changes.
+    pub config_path: PathBuf,
+    /// MIDI input device streamed to every connected peer.
+    pub midi_device: Option<String>,
+    /// Track label sent with MIDI from the input device.
+    pub track: Option<String>,
+    /// Zones of notes from the input device sent as chords, unless a peer has its own.
+    pub harmony: Vec<HarmonyZone>,
+    /// Velocities played on the input device mapped to those sent.
+    pub velocity_curve: Option<VelocityCurve>,
+    /// MIDI output device local thru plays on.
+    pub midi_output: Option<String>,
+    /// Play the input device on the output device too, live or matched to the session latency.
+    pub thru: Option<Thru>,
+    /// Session the relay records what the input device plays in, with consent to it.
+    pub archive: Option<String>,
+    /// Let the others in the archived session download what we played.
+    pub archive_share: bool,
+    /// Name the session is listed under in the directory of the relay.
+    pub publish: Option<String>,
+    /// Token the directory of the relay asks for.
+    pub relay_token: Option<String>,
+    /// What to drop when a peer can't keep up.
+    pub backpressure: BackpressurePolicy,
+    /// How each class of MIDI waits for a peer, changed from the defaults of `backpressure`.
+    pub priority_classes: BTreeMap<EventClass, ClassPolicy>,
+    /// Most MIDI events a second taken from each peer.
+    pub max_inbound_rate: Option<u32>,
+    /// What happens to MIDI over the inbound rate limit.
+    pub rate_limit_policy: RateLimitPolicy,
+    /// Play every peer this way instead of picking by round trip time.
+    pub latency_mode: Option<LatencyMode>,
+    /// Bars MIDI from peers is late by in bar mode.
+    pub delay_bars: u32,
+    /// Unix socket to accept `p2pmidi ctl` commands on.
+    pub control_socket: Option<PathBuf>,
+    /// Standard MIDI file to record everything received to.
+    pub record_path: Option<PathBuf>,
+    /// Also record every session to the automatic recordings directory, deleting the ones past
+    /// this retention.
+    pub auto_record: Option<Retention>,
+    /// Where recordings are indexed.
+    pub storage: Storage,
+    /// Serve Prometheus metrics over HTTP on this address.
+    pub metrics_address: Option<SocketAddr>,
+    /// Keep latency histograms of received MIDI and report them periodically.
+    pub measure_latency: bool,
+    /// Also write the session report as JSON to this file when the session ends.
+    pub session_report: Option<PathBuf>,
+    /// Latency, jitter and loss added to received MIDI, for development.
+    pub simulate_network: Option<NetworkConditions>,
+    /// RTP-MIDI and other protocols to bridge into the session.
+    pub bridges: BridgeOptions,
+    /// Sync the session transport with Ableton Link.
+    pub link: bool,
+    /// Where to publish session events for show control.
+    pub status: StatusOptions,
+    /// Follow or drive JACK transport.
+    pub jack_transport: Option<JackTransportMode>,
+    /// MIDI output device clock is sent on from the session transport.
+    pub clock_output: Option<String>,
+    /// Only talk to nodes holding this key.
+    pub swarm_key: Option<PreSharedKey>,
+    /// Peers let in without asking.
+    pub auto_accept: AutoAccept,
+    /// Token from the invite of the peer dialed, presented once connected.
+    pub invite_token: Option<InviteToken>,
+    /// Give out invites that expire after this long.
+    pub invite_expires: Option<Duration>,
+    /// Give out invites letting only one peer in.
+    pub invite_once: bool,
+    /// Ask on the terminal whether to accept new peers and take shortcuts typed on it.
+    pub interactive: bool,
+    /// Shortcut overrides of the `keybindings:` config section, for the terminal.
+    pub keybindings: BTreeMap<String, String>,
+    pub reporter: Reporter,
+}
+
+impl ClientOptions {
+    /// Options for a session through the default relay with no MIDI device, config file watching
+    /// or reports, for applications embedding p2pmidi.
+    pub fn new(mode: Mode, local_key: identity::Keypair) -> Self {
+        ClientOptions {
+            mode,
+            local_key,
+            name: None,
+            relay_address: constants::RELAY_ADDRESS.to_string(),
+            relay_port: constants::RELAY_PORT,
+            relay_peer_id: None,
+            invite_relay_address: None,
+            relays: Vec::new(),
+            target: None,
+            addresses: Vec::new(),
+            port: None,
+            use_ipv6: constants::USE_IPV6,
+            bind_address: None,
+            config_path: PathBuf::from(
+                shellexpand::tilde(constants::DEFAULT_CONFIG_PATH).into_owned(),
+            ),
+            midi_device: None,
+            track: None,
+            harmony: Vec::new(),
+            velocity_curve: None,
+            midi_output: None,
+            thru: None,
+            archive: None,
+            archive_share: true,
+            publish: None,
+            relay_token: None,
+            backpressure: BackpressurePolicy::default(),
+            priority_classes: BTreeMap::new(),
+            max_inbound_rate: None,
+            rate_limit_policy: RateLimitPolicy::default(),
+            latency_mode: None,
+            delay_bars: DEFAULT_DELAY_BARS,
+            control_socket: None,
+            record_path: None,
+            auto_record: None,
+            storage: Storage::new(None),
+            metrics_address: None,
+            measure_latency: false,
+            session_report: None,
+            simulate_network: None,
+            bridges: BridgeOptions::default(),
+            status: StatusOptions::default(),
+            link: false,
+            jack_transport: None,
+            clock_output: None,
+            swarm_key: None,
+            auto_accept: AutoAccept::default(),
+            invite_token: None,
+            invite_expires: None,
+            invite_once: false,
+            interactive: false,
+            keybindings: BTreeMap::new(),
+            reporter: Reporter {
+                json: false,
+                quiet: true,
+                status_line: false,
+            },
+        }
+    }
+
+    /// Options for a session as `settings` describe it, dialing the peers in `ip_addresses` or
+    /// waiting for peers to join. What only the command line gives, like the peer to dial or the
+    /// control socket, is left to the caller.
+    pub fn from_settings(
+        settings: &Settings,
+        config_path: PathBuf,
+        local_key: identity::Keypair,
+        storage: Storage,
+    ) -> Result<Self, Box<dyn Error>> {
+        let book = storage.address_book()?;
+        let addresses: Vec<String> = settings
+            .ip_addresses
+            .iter()
+            .map(|a| book.get(a).cloned().unwrap_or_else(|| a.clone()))
+            .collect();
+        let mode = match addresses.is_empty() {
+            true => Mode::Listen,
+            false => Mode::Dial,
+        };
+        let defaults = ClientOptions::new(mode, local_key);
+        Ok(ClientOptions {
+            name: settings.name.clone(),
+            relay_address: settings
+                .relay_address
+                .clone()
+                .unwrap_or(defaults.relay_address.clone()),
+            relay_port: settings.relay_port.unwrap_or(defaults.relay_port),
+            relay_peer_id: match &settings.relay_peer_id {
+                Some(peer_id) => Some(
+                    peer_id
+                        .parse()
+                        .map_err(|e| format!("Invalid relay_peer_id: {}", e))?,
+                ),
+                None => None,
+            },
+            relays: settings.relays.clone(),
+            addresses,
+            port: settings.port,
+            bind_address: settings.bind_address,
+            config_path,
+            midi_device: settings.midi_device.clone(),
+            track: settings.track.clone(),
+            harmony: settings.harmony.clone(),
+            velocity_curve: settings.velocity_curve.clone(),
+            midi_output: settings.midi_output.clone(),
+            thru: settings.thru,
+            archive: settings.archive.clone(),
+            archive_share: settings.archive_share.unwrap_or(true),
+            publish: settings.publish.clone(),
+            relay_token: settings.relay_token.clone(),
+            backpressure: settings.backpressure.unwrap_or_default(),
+            priority_classes: settings.priority_classes.clone(),
+            max_inbound_rate: settings.max_inbound_rate,
+            rate_limit_policy: settings.rate_limit_policy.unwrap_or_default(),
+            latency_mode: settings.latency_mode,
+            delay_bars: settings.delay_bars.unwrap_or(DEFAULT_DELAY_BARS),
+            auto_record: settings.auto_record.unwrap_or(false).then_some(Retention {
+                keep_last: settings.auto_record_keep,
+                keep_days: settings.auto_record_days,
+            }),
+            storage,
+            metrics_address: settings.metrics_address,
+            bridges: BridgeOptions {
+                rtp_midi_port: settings.rtp_midi_port,
+                rtp_midi_invite: settings.rtp_midi_invite.clone(),
+                osc_port: settings.osc_port,
+                osc_send: settings.osc_send.clone(),
+                osc_map: settings.osc_map.clone(),
+                gateway_address: settings.gateway_address,
+                ipmidi: settings.ipmidi.clone(),
+                network_midi2_port: settings.network_midi2_port,
+                virtual_ports: settings
+                    .virtual_ports
+                    .unwrap_or(cfg!(all(feature = "midi", unix))),
+            },
+            status: StatusOptions {
+                mqtt: settings.status_mqtt.clone(),
+                websocket: settings.status_websocket,
+                latency_alarm_ms: settings.latency_alarm_ms,
+            },
+            jack_transport: settings.jack_transport,
+            clock_output: settings.clock_output.clone(),
+            swarm_key: match &settings.swarm_key {
+                Some(path) => Some(swarm_key::load(path)?),
+                None => None,
+            },
+            auto_accept: settings.auto_accept.unwrap_or_default(),
+            keybindings: settings.keybindings.clone(),
+            ..defaults
+        })
+    }
+}
+
+/// How long the relay has to answer before giving up on it.
+const RELAY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often latency histograms are reported with `--measure-latency`.
+const LATENCY_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often the loss summary is reported.
+const LOSS_REPORT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// MIDI messages the input device can get ahead of the network by.
+const MIDI_QUEUE_CAPACITY: usize = 1024;
+
+/// How often a recording in progress is written to disk.
+const RECORD_SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Play MIDI from the input device on the local thru output, if there is one.
+fn play_thru(output: &mut Option<midi::OutputConnection>, message: &[u8]) {
+    if let Some(output) = output {
+        if let Err(e) = output.send(message) {
+            warn!("Error playing local thru: {}", e);
+        }
+    }
+}
+
+/// How long the routing and latency mode of a peer that left are kept, to resume with if it
+/// reconnects.
+const RECONNECT_GRACE: Duration = Duration::from_secs(120);
+
+/// What is kept of a peer that left, besides its route.
+struct DepartedPeer {
+    left: Instant,
+    name: Option<String>,
+    auto_mode: Option<LatencyMode>,
+    latency_mode: Option<(LatencyMode, Option<u32>)>,
+}
+
+/// How long a peer is only reached through the relay before hole punching to it is tried again.
+const HOLE_PUNCH_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often arpeggios are scheduled, each time up to `ARP_LOOKAHEAD` ahead.
+const ARP_INTERVAL: Duration = Duration::from_millis(10);
+const ARP_LOOKAHEAD: Duration = Duration::from_millis(30);
+
+/// How long a file has to arrive once fetched.
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often the status line is drawn again.
+const STATUS_LINE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often peers dialed by hostname that left or could not be reached are dialed again,
+/// resolving their hostname anew.
+const REDIAL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A PeerId is reached through the relay circuit, a multiaddr is dialed as is and an IP address or
+/// hostname over TCP, on its `:port` or on `port`. Hostnames, including `/dns` and `/dnsaddr`
+/// multiaddrs, are resolved on every dial.
+pub(crate) fn dial_address(
+    relay_address: &Multiaddr,
+    target: &str,
+    port: Option<u16>,
+) -> Result<Multiaddr, String> {
+    if let Ok(peer_id) = PeerId::from_str(target) {
+        return Ok(relay_address
+            .clone()
+            .with(Protocol::P2pCircuit)
+            .with(Protocol::P2p(peer_id)));
+    }
+    if target.starts_with('/') {
+        return Multiaddr::from_str(target)
+            .map_err(|e| format!("Invalid address {:?}: {}", target, e));
+    }
+    let (host, port) = match SocketAddr::from_str(target) {
+        Ok(address) => (address.ip().to_string(), Some(address.port())),
+        Err(_) => match target.rsplit_once(':') {
+            // Bare IPv6 addresses are full of colons
+            Some((host, port)) if !host.contains(':') => match port.parse() {
+                Ok(port) => (host.to_string(), Some(port)),
+                Err(_) => return Err(format!("Invalid port in {:?}", target)),
+            },
+            _ => (target.to_string(), port),
+        },
+    };
+    let port = port.ok_or_else(|| {
+        format!(
+            "No port to dial {} on, give it as {}:<port> or set port",
+            target, target
+        )
+    })?;
+    let host = match IpAddr::from_str(&host) {
+        Ok(IpAddr::V4(ip)) => Protocol::Ip4(ip),
+        Ok(IpAddr::V6(ip)) => Protocol::Ip6(ip),
+        Err(_) => Protocol::Dns(host.into()),
+    };
+    Ok(Multiaddr::empty().with(host).with(Protocol::Tcp(port)))
+}
+
+/// `address` of a relay, ending in its PeerId to reach peers through it.
+fn relay_with_peer_id(address: &Multiaddr, relay: PeerId) -> Multiaddr {
+    match address.iter().last() {
+        Some(Protocol::P2p(_)) => address.clone(),
+        _ => address.clone().with(Protocol::P2p(relay)),
+    }
+}
+
+/// The peer an address leads to, past the relay of a circuit address.
+fn dialed_peer(address: &Multiaddr) -> Option<PeerId> {
+    address
+        .iter()
+        .filter_map(|p| match p {
+            Protocol::P2p(peer_id) => Some(peer_id),
+            _ => None,
+        })
+        .last()
+}
+
+/// How a connection reaches the peer, from its remote address.
+pub(crate) fn describe_transport(address: &Multiaddr) -> &'static str {
+    let protocols: Vec<Protocol> = address.iter().collect();
+    if protocols.iter().any(|p| matches!(p, Protocol::P2pCircuit)) {
+        "relayed"
+    } else if protocols
+        .iter()
+        .any(|p| matches!(p, Protocol::QuicV1 | Protocol::Quic))
+    {
+        "QUIC"
+    } else if protocols.iter().any(|p| matches!(p, Protocol::Tcp(_))) {
+        "TCP"
+    } else {
+        "unknown"
+    }
+}
+
+/// Relay address in multiaddr form, ending in the PeerId the relay must have when pinned.
+pub(crate) fn relay_multiaddr(
+    host: &str,
+    port: u16,
     use_ipv6: bool,
-) -> Result<(), Box<dyn Error>> {
+    peer_id: Option<PeerId>,
+) -> Result<Multiaddr, String> {
     let protocol = match use_ipv6 {
         true => "ip6",
         false => "ip4",
     };
-    let address = format!("/{}/{}/tcp/{}", protocol, relay_address_str, relay_port);
-    println!("Connecting to relay at {}", address);
-    let relay_address = Multiaddr::from_str(address.as_str()).unwrap();
-    let remote_peer_id = PeerId::from(generate_ed25519(remote_peer_id_u8).public());
+    let address = format!("/{}/{}/tcp/{}", protocol, host, port);
+    let address = Multiaddr::from_str(address.as_str())
+        .map_err(|e| format!("Invalid relay address {}: {}", address, e))?;
+    Ok(match peer_id {
+        Some(peer_id) => address.with(Protocol::P2p(peer_id)),
+        None => address,
+    })
+}
 
-    let local_key = generate_ed25519(secret_key_seed);
-    let local_peer_id = PeerId::from(local_key.public());
-    println!("Local peer id: {:?}", local_peer_id);
+#[derive(NetworkBehaviour)]
+#[behaviour(to_swarm = "Event")]
+pub(crate) struct Behaviour {
+    relay_client: relay::client::Behaviour,
+    ping: ping::Behaviour,
+    identify: identify::Behaviour,
+    dcutr: dcutr::Behaviour,
+    pub(crate) midi: request_response::Behaviour<MidiCodec>,
+    pub(crate) archive: request_response::Behaviour<ArchiveCodec>,
+    pub(crate) directory: request_response::Behaviour<DirectoryCodec>,
+    relay_hint: request_response::Behaviour<RelayHintCodec>,
+    transfer: request_response::Behaviour<TransferCodec>,
+}
 
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
+pub(crate) enum Event {
+    Ping(ping::Event),
+    Identify(identify::Event),
+    Relay(relay::client::Event),
+    Dcutr(dcutr::Event),
+    Midi(request_response::Event<Vec<MidiFrame>, ()>),
+    Archive(request_response::Event<ArchiveRequest, ArchiveResponse>),
+    Directory(request_response::Event<DirectoryRequest, DirectoryResponse>),
+    RelayHint(request_response::Event<RelayHint, RelayHint>),
+    Transfer(request_response::Event<TransferRequest, TransferResponse>),
+}
+
+impl From<ping::Event> for Event {
+    fn from(e: ping::Event) -> Self {
+        Event::Ping(e)
+    }
+}
+
+impl From<identify::Event> for Event {
+    fn from(e: identify::Event) -> Self {
+        Event::Identify(e)
+    }
+}
+
+impl From<relay::client::Event> for Event {
+    fn from(e: relay::client::Event) -> Self {
+        Event::Relay(e)
+    }
+}
+
+impl From<dcutr::Event> for Event {
+    fn from(e: dcutr::Event) -> Self {
+        Event::Dcutr(e)
+    }
+}
+
+impl From<request_response::Event<Vec<MidiFrame>, ()>> for Event {
+    fn from(e: request_response::Event<Vec<MidiFrame>, ()>) -> Self {
+        Event::Midi(e)
+    }
+}
+
+impl From<request_response::Event<ArchiveRequest, ArchiveResponse>> for Event {
+    fn from(e: request_response::Event<ArchiveRequest, ArchiveResponse>) -> Self {
+        Event::Archive(e)
+    }
+}
+
+impl From<request_response::Event<DirectoryRequest, DirectoryResponse>> for Event {
+    fn from(e: request_response::Event<DirectoryRequest, DirectoryResponse>) -> Self {
+        Event::Directory(e)
+    }
+}
+
+impl From<request_response::Event<RelayHint, RelayHint>> for Event {
+    fn from(e: request_response::Event<RelayHint, RelayHint>) -> Self {
+        Event::RelayHint(e)
+    }
+}
+
+impl From<request_response::Event<TransferRequest, TransferResponse>> for Event {
+    fn from(e: request_response::Event<TransferRequest, TransferResponse>) -> Self {
+        Event::Transfer(e)
+    }
+}
+
+/// Take the file `peer_id` offered with `id` off the offers, returning who to answer.
+fn take_offer(file_offers: &mut Vec<FileOffer>, peer_id: &str, id: u64) -> Option<PeerId> {
+    let position = file_offers
+        .iter()
+        .position(|offer| offer.peer_id == peer_id && offer.id == id)?;
+    file_offers.remove(position);
+    PeerId::from_str(peer_id).ok()
+}
+
+/// Build the client swarm: relay client, TCP and QUIC transports with DNS resolution.
+pub(crate) fn build_swarm(
+    local_key: &identity::Keypair,
+    ping_config: ping::Config,
+    agent_version: String,
+    swarm_key: Option<PreSharedKey>,
+) -> Result<Swarm<Behaviour>, Failure> {
+    let local_peer_id = PeerId::from(local_key.public());
     let (relay_transport, client) = relay::client::new(local_peer_id);
 
     let transport = {
+        // QUIC can't carry the swarm key of a private swarm
+        let quic_transport = match swarm_key {
+            Some(_) => OptionalTransport::none(),
+            None => {
+                OptionalTransport::some(quic::tokio::Transport::new(quic::Config::new(local_key)))
+            }
+        };
         let relay_tcp_quic_transport = relay_transport
-            .or_transport(tcp::async_io::Transport::new(
+            .or_transport(swarm_key::tcp_transport(
                 tcp::Config::default().port_reuse(true),
+                swarm_key,
             ))
             .upgrade(upgrade::Version::V1)
-            .authenticate(noise::Config::new(&local_key).unwrap())
+            .authenticate(
+                noise::Config::new(local_key)
+                    .map_err(|e| Failure::Runtime(format!("Error setting up noise: {}", e)))?,
+            )
             .multiplex(yamux::Config::default())
-            .or_transport(quic::async_std::Transport::new(quic::Config::new(
-                &local_key,
-            )));
+            .or_transport(quic_transport);
 
-        block_on(DnsConfig::system(relay_tcp_quic_transport))
-            .unwrap()
+        TokioDnsConfig::system(relay_tcp_quic_transport)
+            .map_err(|e| Failure::Runtime(format!("Error reading the DNS configuration: {}", e)))?
             .map(|either_output, _| match either_output {
                 Either::Left((peer_id, muxer)) => (peer_id, StreamMuxerBox::new(muxer)),
                 Either::Right((peer_id, muxer)) => (peer_id, StreamMuxerBox::new(muxer)),
@@ -82,84 +645,109 @@ pub fn start_client(
             .boxed()
     };
 
-    #[derive(NetworkBehaviour)]
-    #[behaviour(to_swarm = "Event")]
-    struct Behaviour {
-        relay_client: relay::client::Behaviour,
-        ping: ping::Behaviour,
-        identify: identify::Behaviour,
-        dcutr: dcutr::Behaviour,
-    }
-
-    #[derive(Debug)]
-    #[allow(clippy::large_enum_variant)]
-    enum Event {
-        Ping(ping::Event),
-        Identify(identify::Event),
-        Relay(relay::client::Event),
-        Dcutr(dcutr::Event),
-    }
-
-    impl From<ping::Event> for Event {
-        fn from(e: ping::Event) -> Self {
-            Event::Ping(e)
-        }
-    }
-
-    impl From<identify::Event> for Event {
-        fn from(e: identify::Event) -> Self {
-            Event::Identify(e)
-        }
-    }
-
-    impl From<relay::client::Event> for Event {
-        fn from(e: relay::client::Event) -> Self {
-            Event::Relay(e)
-        }
-    }
-
-    impl From<dcutr::Event> for Event {
-        fn from(e: dcutr::Event) -> Self {
-            Event::Dcutr(e)
-        }
-    }
-
     let behaviour = Behaviour {
         relay_client: client,
-        ping: ping::Behaviour::new(ping::Config::new()),
-        identify: identify::Behaviour::new(identify::Config::new(
-            "/TODO/0.0.1".to_string(),
-            local_key.public(),
-        )),
+        ping: ping::Behaviour::new(ping_config),
+        identify: identify::Behaviour::new(
+            identify::Config::new("/TODO/0.0.1".to_string(), local_key.public())
+                .with_agent_version(agent_version),
+        ),
         dcutr: dcutr::Behaviour::new(local_peer_id),
+        midi: request_response::Behaviour::new(
+            [
+                (
+                    protocol::TRACKS_PROTOCOL,
+                    request_response::ProtocolSupport::Full,
+                ),
+                (protocol::PROTOCOL, request_response::ProtocolSupport::Full),
+            ],
+            request_response::Config::default(),
+        ),
+        // Only relays archive sessions
+        archive: request_response::Behaviour::new(
+            [(
+                archive::ARCHIVE_PROTOCOL,
+                request_response::ProtocolSupport::Outbound,
+            )],
+            request_response::Config::default(),
+        ),
+        directory: request_response::Behaviour::new(
+            [(
+                directory::DIRECTORY_PROTOCOL,
+                request_response::ProtocolSupport::Outbound,
+            )],
+            request_response::Config::default(),
+        ),
+        relay_hint: request_response::Behaviour::new(
+            [(
+                relay_hint::RELAY_HINT_PROTOCOL,
+                request_response::ProtocolSupport::Full,
+            )],
+            request_response::Config::default(),
+        ),
+        // Files take longer than the default timeout over slow links
+        transfer: request_response::Behaviour::new(
+            [(
+                transfer::TRANSFER_PROTOCOL,
+                request_response::ProtocolSupport::Full,
+            )],
+            request_response::Config::default().with_request_timeout(TRANSFER_TIMEOUT),
+        ),
     };
 
-    let mut swarm = match ThreadPool::new() {
-        Ok(tp) => SwarmBuilder::with_executor(transport, behaviour, local_peer_id, tp),
-        Err(_) => SwarmBuilder::without_executor(transport, behaviour, local_peer_id),
-    }
-    .build();
+    Ok(SwarmBuilder::with_tokio_executor(transport, behaviour, local_peer_id).build())
+}
 
-    swarm
-        .listen_on("/ip4/0.0.0.0/udp/0/quic-v1".parse().unwrap())
-        .unwrap();
-    swarm
-        .listen_on("/ip4/0.0.0.0/tcp/0".parse().unwrap())
-        .unwrap();
+/// Addresses to listen on, on every interface or only on `bind_address`. Dialing goes out of the
+/// interface listened on.
+fn listen_addresses(bind_address: Option<IpAddr>) -> [Multiaddr; 2] {
+    let ip = match bind_address {
+        Some(IpAddr::V4(ip)) => Protocol::Ip4(ip),
+        Some(IpAddr::V6(ip)) => Protocol::Ip6(ip),
+        None => Protocol::Ip4(Ipv4Addr::UNSPECIFIED),
+    };
+    let base = Multiaddr::empty().with(ip);
+    [
+        base.clone().with(Protocol::Udp(0)).with(Protocol::QuicV1),
+        base.with(Protocol::Tcp(0)),
+    ]
+}
+
+/// Listen on all interfaces or the one bound to, then connect to the relay to learn our public
+/// address and let it learn its own. Returns the PeerId of the relay.
+pub(crate) fn bootstrap(
+    swarm: &mut Swarm<Behaviour>,
+    relay_address: &Multiaddr,
+    bind_address: Option<IpAddr>,
+) -> Result<PeerId, Failure> {
+    for address in listen_addresses(bind_address) {
+        match swarm.listen_on(address.clone()) {
+            Ok(_) => {}
+            // QUIC is off in private swarms
+            Err(TransportError::MultiaddrNotSupported(_)) => {
+                debug!("Not listening on {}", address)
+            }
+            Err(e) => {
+                return Err(Failure::Runtime(format!(
+                    "Could not listen on {}: {}",
+                    address, e
+                )))
+            }
+        }
+    }
 
     // Wait to listen on all interfaces.
-    block_on(async {
+    runtime::block_on(async {
         let mut delay = futures_timer::Delay::new(std::time::Duration::from_secs(1)).fuse();
         loop {
             futures::select! {
-                event = swarm.next() => {
-                    match event.unwrap() {
-                        SwarmEvent::NewListenAddr { address, .. } => {
-                            println!("Listening on {:?}", address);
-                        }
-                        event => panic!("{event:?}"),
+                event = swarm.select_next_some() => match event {
+                    SwarmEvent::NewListenAddr { address, .. } => {
+                        info!("Listening on {:?}", address);
                     }
-                }
+                    // Someone may already be connecting to us, that is fine
+                    event => debug!("{:?}", event),
+                },
                 _ = delay => {
                     // Likely listening on all interfaces now, thus continuing by breaking the loop.
                     break;
@@ -170,94 +758,2464 @@ pub fn start_client(
 
     // Connect to the relay server. Not for the reservation or relayed connection, but to (a) learn
     // our local public address and (b) enable a freshly started relay to learn its public address.
-    swarm.dial(relay_address.clone()).unwrap();
-    block_on(async {
-        let mut learned_observed_addr = false;
+    swarm
+        .dial(relay_address.clone())
+        .map_err(|e| Failure::RelayUnreachable(e.to_string()))?;
+    runtime::block_on(async {
+        let mut learned_observed_addr = None;
         let mut told_relay_observed_addr = false;
+        let mut deadline = futures_timer::Delay::new(RELAY_TIMEOUT).fuse();
 
         loop {
-            match swarm.next().await.unwrap() {
-                SwarmEvent::NewListenAddr { .. } => {}
-                SwarmEvent::Dialing { .. } => {}
-                SwarmEvent::ConnectionEstablished { .. } => {}
-                SwarmEvent::Behaviour(Event::Ping(_)) => {}
+            let event = futures::select! {
+                event = swarm.select_next_some() => event,
+                _ = deadline => {
+                    return Err(Failure::RelayUnreachable(format!(
+                        "{}: no answer after {}s",
+                        relay_address,
+                        RELAY_TIMEOUT.as_secs()
+                    )));
+                }
+            };
+            match event {
                 SwarmEvent::Behaviour(Event::Identify(identify::Event::Sent { .. })) => {
-                    println!("Told relay its public address.");
+                    info!("Told relay its public address.");
                     told_relay_observed_addr = true;
                 }
                 SwarmEvent::Behaviour(Event::Identify(identify::Event::Received {
+                    peer_id,
                     info: identify::Info { observed_addr, .. },
-                    ..
                 })) => {
-                    println!("Relay told us our public address: {:?}", observed_addr);
+                    info!("Relay told us our public address: {:?}", observed_addr);
                     swarm.add_external_address(observed_addr);
-                    learned_observed_addr = true;
+                    learned_observed_addr = Some(peer_id);
+                }
+                SwarmEvent::OutgoingConnectionError {
+                    error: DialError::WrongPeerId { obtained, .. },
+                    ..
+                } => {
+                    return Err(Failure::RelayUnreachable(format!(
+                        "{} presented PeerId {} instead of the pinned relay_peer_id, its address \
+                         may have been hijacked",
+                        relay_address, obtained
+                    )));
+                }
+                SwarmEvent::OutgoingConnectionError { error, .. } => {
+                    return Err(Failure::RelayUnreachable(format!(
+                        "{}: {}",
+                        relay_address, error
+                    )));
                 }
-                event => panic!("Unknown event {event:?}"),
+                event => debug!("{:?}", event),
             }
 
-            if learned_observed_addr && told_relay_observed_addr {
-                break;
+            if let (Some(relay_peer_id), true) = (learned_observed_addr, told_relay_observed_addr) {
+                break Ok(relay_peer_id);
+            }
+        }
+    })
+}
+
+fn loss_by_peer_id(sequences: &HashMap<PeerId, SequenceTracker>) -> BTreeMap<String, LossStats> {
+    sequences
+        .iter()
+        .map(|(peer, sequence)| (peer.to_string(), sequence.stats().clone()))
+        .collect()
+}
+
+fn loss_by_name(
+    sequences: &HashMap<PeerId, SequenceTracker>,
+    router: &MidiRouter,
+) -> BTreeMap<String, LossStats> {
+    sequences
+        .iter()
+        .map(|(peer, sequence)| {
+            let name = router
+                .route(&peer.to_string())
+                .map(|r| r.display_name.clone())
+                .unwrap_or_else(|| peer.to_string());
+            (name, sequence.stats().clone())
+        })
+        .collect()
+}
+
+/// List the session in the directory of the relay, for as long as we stay connected to it.
+fn publish_session(
+    swarm: &mut Swarm<Behaviour>,
+    relay_peer_id: &PeerId,
+    session: &str,
+    token: &Option<String>,
+) {
+    swarm.behaviour_mut().directory.send_request(
+        relay_peer_id,
+        DirectoryRequest::Register {
+            session: session.to_string(),
+            token: token.clone(),
+        },
+    );
+}
+
+/// Send a peer whatever MIDI it is ready for.
+fn flush_midi(swarm: &mut Swarm<Behaviour>, queue: &mut OutboundQueue, peer: &PeerId) {
+    if let Some(frames) = queue.take_batch() {
+        swarm.behaviour_mut().midi.send_request(peer, frames);
+    }
+    if let Some(dropped) = queue.take_recovery() {
+        info!(
+            "{} caught up after {} MIDI messages were dropped",
+            peer, dropped
+        );
+    }
+}
+
+/// Queue MIDI for a peer and send what it is ready for. Returns how many frames were dropped
+/// because the peer can't keep up.
+fn send_midi(
+    swarm: &mut Swarm<Behaviour>,
+    queue: &mut OutboundQueue,
+    peer: &PeerId,
+    frames: &[MidiFrame],
+    reporter: &Reporter,
+) -> usize {
+    let was_overloaded = queue.is_overloaded();
+    let dropped = queue.push(frames);
+    if dropped > 0 && !was_overloaded {
+        warn!("{} can't keep up, dropping MIDI", peer);
+        reporter.report(Report::Overload {
+            peer_id: peer.to_string(),
+        });
+    }
+    flush_midi(swarm, queue, peer);
+    dropped
+}
+
+/// Send `frames` to each of `peers` with a queue, counting what was sent and dropped.
+fn broadcast<'a>(
+    swarm: &mut Swarm<Behaviour>,
+    outbound: &mut HashMap<PeerId, OutboundQueue>,
+    peers: impl IntoIterator<Item = &'a PeerId>,
+    frames: &[MidiFrame],
+    metrics: &Metrics,
+    summary: &mut SessionSummary,
+    reporter: &Reporter,
+) {
+    for peer in peers {
+        if let Some(queue) = outbound.get_mut(peer) {
+            let dropped = send_midi(swarm, queue, peer, frames, reporter);
+            metrics.midi_dropped(dropped);
+            metrics.midi_sent(frames.len() - dropped);
+            summary.sent(frames.len() - dropped);
+        }
+    }
+}
+
+/// Frame what was played on the input device, as chords where `harmonizer` makes them.
+fn frame_played(
+    played: &[ring::RawEvent],
+    harmonizer: &mut Harmonizer,
+    sequencer: &mut FrameSequencer,
+    arena: &mut MessageArena,
+    track: &Bytes,
+) -> Vec<MidiFrame> {
+    let mut frames = Vec::with_capacity(played.len());
+    for event in played {
+        if !harmonizer.is_active() {
+            frames.push(
+                sequencer
+                    .frame_at(arena.copy(event.message()), event.at)
+                    .on_track(track),
+            );
+            continue;
+        }
+        for message in harmonizer.apply(event.message()) {
+            frames.push(
+                sequencer
+                    .frame_at(arena.copy(&message), event.at)
+                    .on_track(track),
+            );
+        }
+    }
+    frames
+}
+
+/// The harmonizer of a peer with chords of its own. One whose chords were taken away is kept
+/// until the keys it holds are let go, so their chords end.
+fn peer_harmonizer<'a>(
+    harmonizers: &'a mut HashMap<PeerId, Harmonizer>,
+    peer: &PeerId,
+    zones: Option<&Vec<HarmonyZone>>,
+) -> Option<&'a mut Harmonizer> {
+    if zones.is_none() && !harmonizers.get(peer).map_or(false, |h| h.holds_notes()) {
+        harmonizers.remove(peer);
+        return None;
+    }
+    let harmonizer = harmonizers.entry(*peer).or_default();
+    harmonizer.set_zones(zones.map_or(&[], |zones| zones.as_slice()));
+    Some(harmonizer)
+}
+
+/// Arpeggiators of the peers whose routes have one, for each track they play.
+type Arpeggiators = HashMap<(PeerId, Option<String>), Arpeggiator>;
+
+/// Play MIDI from a peer, handing its notes to its arpeggiator if its route has one.
+fn deliver(
+    bridges: &mut Bridges,
+    arpeggiators: &mut Arpeggiators,
+    router: &MidiRouter,
+    peer: PeerId,
+    event: BridgeEvent,
+) {
+    let config = router
+        .route(&peer.to_string())
+        .and_then(|route| route.arpeggiator.as_ref());
+    let taken = match (config, &event) {
+        (Some(config), BridgeEvent::Midi { message, track, .. }) => {
+            let arpeggiator = arpeggiators
+                .entry((peer, track.clone()))
+                .or_insert_with(|| Arpeggiator::new(config.clone()));
+            arpeggiator.set_config(config);
+            arpeggiator.take(message)
+        }
+        (None, _) => {
+            arpeggiators.retain(|(p, _), _| *p != peer);
+            false
+        }
+        _ => false,
+    };
+    if !taken {
+        bridges.send(event);
+    }
+}
+
+/// A timer going off on the next beat of a count-in.
+fn next_count_in(count_in: &CountIn) -> Fuse<futures_timer::Delay> {
+    let now = Instant::now();
+    futures_timer::Delay::new(count_in.next_beat(now).saturating_duration_since(now)).fuse()
+}
+
+/// Change the tempo right away while the transport is stopped, or else from the next bar line on,
+/// so it lands musically for everyone. Returns the change waiting for its bar line and the timer
+/// going off on it.
+fn change_tempo(
+    transport: &Transport,
+    tempo: f64,
+) -> (
+    Option<(TransportState, TransportState)>,
+    Fuse<futures_timer::Delay>,
+) {
+    let state = transport.state();
+    if !state.playing {
+        transport.set_tempo(tempo, Source::Local);
+        return (None, Fuse::terminated());
+    }
+    let now = unix_micros();
+    let change = state.with_tempo_from_next_bar(tempo, now);
+    debug!("Tempo {:.1} BPM from beat {}", tempo, change.beat);
+    let wait = Duration::from_micros(change.at_us.saturating_sub(now));
+    (
+        Some((state, change)),
+        futures_timer::Delay::new(wait).fuse(),
+    )
+}
+
+/// Schedule the arpeggio steps due before the next time this is called.
+fn schedule_arpeggios(
+    arpeggiators: &mut Arpeggiators,
+    transport: &TransportState,
+    playout: &Playout,
+) {
+    let now = Instant::now();
+    let now_us = unix_micros();
+    let until_us = now_us + ARP_LOOKAHEAD.as_micros() as u64;
+    for ((peer, track), arpeggiator) in arpeggiators.iter_mut() {
+        for note in arpeggiator.steps(transport, now_us, until_us) {
+            for (at_us, message) in [(note.on_us, note.note_on()), (note.off_us, note.note_off())] {
+                let event = BridgeEvent::Midi {
+                    peer_id: peer.to_string(),
+                    message: Bytes::copy_from_slice(&message),
+                    track: track.clone(),
+                };
+                let at = now + Duration::from_micros(at_us.saturating_sub(now_us));
+                playout.play_at(at, Route::Arpeggio(*peer, event));
             }
         }
+    }
+}
+
+/// Write a recording if anything was recorded since it was last saved.
+fn save_recording(
+    recorder: &SessionRecorder,
+    path: &Path,
+    saved_events: &mut usize,
+    reporter: &Reporter,
+) {
+    if recorder.len() == *saved_events {
+        return;
+    }
+    match recorder.save(path) {
+        Ok(_) => {
+            *saved_events = recorder.len();
+            debug!("Saved {} recorded events to {:?}", saved_events, path);
+        }
+        Err(e) => reporter.report(Report::Error {
+            message: format!("Could not save recording to {:?}: {}", path, e),
+        }),
+    }
+}
+
+/// Start a new recording to `path` and list it in the recordings index.
+fn start_recording(storage: &Storage, path: PathBuf) -> (SessionRecorder, PathBuf) {
+    info!("Recording received MIDI to {:?}", path);
+    if let Err(e) = storage.add_recording(&path) {
+        warn!("Could not add {:?} to the recordings index: {}", path, e);
+    }
+    (SessionRecorder::default(), path)
+}
+
+/// Write the automatic recording, listing it in the recordings index once anything was recorded.
+fn save_auto_recording(
+    storage: &Storage,
+    recorder: &SessionRecorder,
+    path: &Path,
+    saved_events: &mut usize,
+    reporter: &Reporter,
+) {
+    let first = *saved_events == 0;
+    save_recording(recorder, path, saved_events, reporter);
+    if first && *saved_events > 0 {
+        if let Err(e) = storage.add_recording(path) {
+            warn!("Could not add {:?} to the recordings index: {}", path, e);
+        }
+    }
+}
+
+/// Delete the automatic recordings past `retention`.
+fn prune_auto_recordings(storage: &Storage, retention: &Retention) {
+    if let Err(e) = storage.prune_auto_recordings(retention) {
+        warn!("Could not delete old automatic recordings: {}", e);
+    }
+}
+
+/// Put the session first in the recent sessions list.
+fn remember_session(storage: &Storage, session: &RecentSession) {
+    if let Err(e) = storage.remember_session(session) {
+        warn!("Could not save the recent sessions: {}", e);
+    }
+}
+
+/// Lines typed on the terminal, read on their own thread.
+fn read_terminal() -> UnboundedReceiver<String> {
+    let (sender, receiver) = futures::channel::mpsc::unbounded();
+    let spawned = std::thread::Builder::new()
+        .name("stdin".to_string())
+        .spawn(move || {
+            for line in std::io::stdin().lines() {
+                match line {
+                    Ok(line) if sender.unbounded_send(line).is_ok() => {}
+                    _ => break,
+                }
+            }
+        });
+    if let Err(e) = spawned {
+        warn!("Not reading from the terminal: {}", e);
+    }
+    receiver
+}
+
+/// Stop waiting on `peer` to be accepted. True if it was waiting.
+fn take_pending(
+    peer: &PeerId,
+    pending: &mut VecDeque<PeerId>,
+    unnamed: &mut HashSet<PeerId>,
+) -> bool {
+    let position = pending.iter().position(|p| p == peer);
+    if let Some(position) = position {
+        pending.remove(position);
+    }
+    unnamed.remove(peer) || position.is_some()
+}
+
+/// Our invite, with a token when it should expire or only let one peer in.
+fn make_invite(
+    local_key: &identity::Keypair,
+    relay_address: &str,
+    relay_port: u16,
+    expires: Option<Duration>,
+    once: bool,
+) -> Result<Invite, Box<dyn Error>> {
+    let token = match (expires, once) {
+        (None, false) => None,
+        (expires, once) => Some(InviteToken::issue(
+            local_key,
+            expires.unwrap_or(ONCE_VALIDITY),
+            once,
+        )?),
+    };
+    Ok(Invite {
+        relay_address: relay_address.to_string(),
+        relay_port,
+        peer_id: local_key.public().to_peer_id(),
+        token,
+    })
+}
+
+#[cfg(feature = "link")]
+fn start_link(transport: &Transport) -> Result<(), Box<dyn Error>> {
+    crate::link::start(transport.clone())
+}
+
+#[cfg(not(feature = "link"))]
+fn start_link(_transport: &Transport) -> Result<(), Box<dyn Error>> {
+    Err(Failure::Config(
+        "This build has no Ableton Link support, build with --features link".to_string(),
+    )
+    .into())
+}
+
+/// Channels an application embedding the session drives it through, see
+/// [`Session`](crate::session::Session).
+#[derive(Default)]
+pub(crate) struct Embedding {
+    /// Hears what bridges hear.
+    pub events: Option<UnboundedSender<BridgeEvent>>,
+    /// MIDI played into the session.
+    pub input: Option<UnboundedReceiver<BridgeMidi>>,
+    pub control: Option<UnboundedReceiver<PendingRequest>>,
+    /// Ends the session, instead of Ctrl-C.
+    pub shutdown: Option<UnboundedReceiver<()>>,
+}
+
+pub fn start_client(router: MidiRouter, options: ClientOptions) -> Result<(), Box<dyn Error>> {
+    run_client(router, options, Embedding::default())
+}
+
+pub(crate) fn run_client(
+    mut router: MidiRouter,
+    options: ClientOptions,
+    embedding: Embedding,
+) -> Result<(), Box<dyn Error>> {
+    let ClientOptions {
+        mode,
+        local_key,
+        relay_address: relay_host,
+        relay_port,
+        relay_peer_id,
+        invite_relay_address,
+        relays: extra_relays,
+        target,
+        addresses,
+        port,
+        use_ipv6,
+        bind_address,
+        config_path,
+        midi_device,
+        midi_output,
+        thru,
+        archive,
+        archive_share,
+        publish,
+        relay_token,
+        track,
+        harmony,
+        mut velocity_curve,
+        backpressure,
+        priority_classes,
+        max_inbound_rate,
+        rate_limit_policy,
+        latency_mode,
+        delay_bars,
+        control_socket,
+        record_path,
+        auto_record,
+        storage,
+        metrics_address,
+        measure_latency,
+        session_report,
+        simulate_network,
+        bridges: bridge_options,
+        status: status_options,
+        link,
+        jack_transport,
+        clock_output,
+        swarm_key,
+        name,
+        auto_accept,
+        invite_token,
+        invite_expires,
+        invite_once,
+        interactive,
+        keybindings,
+        reporter,
+    } = options;
+    let _runtime = runtime::enter();
+    let mut recent = RecentSession {
+        at: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+        targets: target.iter().chain(addresses.iter()).cloned().collect(),
+        peers: Vec::new(),
+        settings: SessionSettings {
+            relay_address: relay_host.clone(),
+            relay_port,
+            relay_peer_id: relay_peer_id.map(|peer_id| peer_id.to_string()),
+            midi_device: midi_device.clone(),
+            midi_output: midi_output.clone(),
+            track: track.clone(),
+            latency_mode,
+            delay_bars,
+        },
+    };
+    let relay_address = relay_multiaddr(&relay_host, relay_port, use_ipv6, relay_peer_id)
+        .map_err(Failure::Config)?;
+    let invite_relay_address = invite_relay_address.unwrap_or_else(|| relay_host.clone());
+    info!("Connecting to relay at {}", relay_address);
+    reporter.report(Report::Status {
+        state: "connecting to relay".to_string(),
     });
+    let mut connected_peers = HashSet::new();
+    // Events about a connected peer are logged within its span
+    let mut peer_spans: HashMap<PeerId, Span> = HashMap::new();
+
+    let local_peer_id = PeerId::from(local_key.public());
+    info!("Local peer id: {:?}", local_peer_id);
+
+    let private_swarm = swarm_key.is_some();
+    let mut swarm = build_swarm(
+        &local_key,
+        ping::Config::new(),
+        trust::agent_version(name.as_deref()),
+        swarm_key,
+    )?;
+    let relay_peer_id = bootstrap(&mut swarm, &relay_address, bind_address)?;
+    let mut publish = publish;
+    if let Some(session) = &publish {
+        publish_session(&mut swarm, &relay_peer_id, session, &relay_token);
+    }
+    let mut sequencer = FrameSequencer::default();
+    let mut dial_target = None;
+    // Peers we dialed are let in without asking
+    let mut dialed = HashSet::new();
+    // Addresses from `addresses` being dialed, the peers they led to and those to dial again
+    let mut dialing: HashMap<ConnectionId, Multiaddr> = HashMap::new();
+    let mut redials: HashMap<PeerId, Multiaddr> = HashMap::new();
+    let mut redial_queue: Vec<Multiaddr> = Vec::new();
+    // Peers only reached through the relay since when, and the relayed connections dialed to hole
+    // punch to them again
+    let mut relayed_since: HashMap<PeerId, Instant> = HashMap::new();
+    let mut hole_punch_dials: HashSet<ConnectionId> = HashSet::new();
+    // Every relay connected to and where, those holding a reservation for us, and the relay agreed
+    // on with each peer
+    let mut relays: HashMap<PeerId, Multiaddr> = HashMap::new();
+    relays.insert(
+        relay_peer_id,
+        relay_with_peer_id(&relay_address, relay_peer_id),
+    );
+    let mut reserved: HashSet<PeerId> = HashSet::new();
+    let mut agreed_relays: HashMap<PeerId, PeerId> = HashMap::new();
+    let mut dialing_relays: HashMap<ConnectionId, Multiaddr> = HashMap::new();
+    for host in &extra_relays {
+        let address = match dial_address(&relay_address, host, Some(relay_port)) {
+            Ok(address) => address,
+            Err(e) => {
+                warn!("Not connecting to relay {}: {}", host, e);
+                continue;
+            }
+        };
+        info!("Connecting to relay at {}", address);
+        let opts = DialOpts::from(address.clone());
+        let connection_id = opts.connection_id();
+        dialing_relays.insert(connection_id, address);
+        if let Err(e) = swarm.dial(opts) {
+            warn!("Could not connect to relay {}: {}", host, e);
+            dialing_relays.remove(&connection_id);
+        }
+    }
 
     match mode {
         Mode::Dial => {
-            swarm
-                .dial(
-                    relay_address
-                        .with(Protocol::P2pCircuit)
-                        .with(Protocol::P2p(remote_peer_id)),
-                )
-                .unwrap();
+            if target.is_none() && addresses.is_empty() {
+                return Err(Failure::Config("No peer to dial".to_string()).into());
+            }
+            if let Some(target) = &target {
+                let address =
+                    dial_address(&relay_address, target, port).map_err(Failure::Config)?;
+                dial_target = dialed_peer(&address);
+                swarm
+                    .dial(address)
+                    .map_err(|e| Failure::PeerUnreachable(e.to_string()))?;
+            }
+            for target in &addresses {
+                let address = match dial_address(&relay_address, target, port) {
+                    Ok(address) => address,
+                    Err(e) => {
+                        warn!("Not dialing {}: {}", target, e);
+                        continue;
+                    }
+                };
+                dialed.extend(dialed_peer(&address));
+                let opts = DialOpts::from(address.clone());
+                let connection_id = opts.connection_id();
+                dialing.insert(connection_id, address);
+                if let Err(e) = swarm.dial(opts) {
+                    warn!("Could not dial {}: {}", target, e);
+                    dialing.remove(&connection_id);
+                }
+            }
         }
         Mode::Listen => {
             swarm
-                .listen_on(relay_address.with(Protocol::P2pCircuit))
-                .unwrap();
+                .listen_on(relay_address.clone().with(Protocol::P2pCircuit))
+                .map_err(|e| Failure::RelayUnreachable(e.to_string()))?;
+        }
+    }
+
+    reporter.report(Report::Status {
+        state: match mode {
+            Mode::Dial => "dialing".to_string(),
+            Mode::Listen => "waiting for peers".to_string(),
+        },
+    });
+
+    // Apply config file edits to the running session
+    let mut reloader = ConfigReloader::new(&config_path);
+    let (_watcher, mut config_changes) = match watch_config(&config_path) {
+        Ok((w, c)) => (Some(w), c),
+        Err(e) => {
+            warn!("Not watching config file for changes: {}", e);
+            (None, futures::channel::mpsc::unbounded().1)
+        }
+    };
+
+    let (control_sender, control_requests) = futures::channel::mpsc::unbounded();
+    if let Some(socket) = &control_socket {
+        control::serve(socket, control_sender)?;
+    }
+    let mut control_requests = futures::stream::select(
+        control_requests,
+        embedding
+            .control
+            .unwrap_or_else(|| futures::channel::mpsc::unbounded().1),
+    );
+
+    let metrics = Arc::new(Metrics::default());
+    if let Some(address) = metrics_address {
+        metrics::serve(address, metrics.clone())?;
+    }
+
+    // MIDI for each peer waits here while the peer is busy with earlier batches
+    let mut outbound: HashMap<PeerId, OutboundQueue> = HashMap::new();
+    let priority_model = PriorityModel::new(backpressure, &priority_classes);
+
+    // The input callback hands MIDI over through a queue it can push to without blocking
+    let (producer, mut midi_input) = ring::ring_buffer(MIDI_QUEUE_CAPACITY);
+    let mut arena = MessageArena::default();
+    // What was taken off the queue in one go, reused so events aren't allocated for one by one
+    let mut input_batch: Vec<ring::RawEvent> = Vec::new();
+    let input_track = Bytes::from(track.unwrap_or_default());
+    // Chords played for every peer, and for those with chords of their own
+    let mut harmonizer = Harmonizer::new(harmony);
+    let mut arpeggiators = Arpeggiators::new();
+    let mut peer_harmonizers: HashMap<PeerId, Harmonizer> = HashMap::new();
+    let _input = match &midi_device {
+        Some(device) => Some(midi::connect_input(device, producer)?),
+        None => None,
+    };
+    // Copies of what the input device plays go to the relay archive with this consent
+    let mut archive = archive;
+    let archive_name = name.clone().unwrap_or_default();
+    let consent = Consent {
+        record: true,
+        share: archive_share,
+    };
+    let mut thru_output = match (thru, &midi_output) {
+        (Some(_), Some(device)) => Some(midi::connect_output(device)?),
+        (Some(_), None) => {
+            return Err(Failure::Config("Local thru needs an output device".to_string()).into())
         }
+        (None, _) => None,
+    };
+
+    // Bridges hear what peers play and play into the session themselves
+    let mut bridges = Bridges::default();
+    let (bridge_input, bridged) = futures::channel::mpsc::unbounded();
+    let browsers = BrowserLimits {
+        config: router.configs().get("browser").cloned().unwrap_or_default(),
+        max_inbound_rate,
+        rate_limit_policy,
+    };
+    bridge::start(&bridge_options, browsers, &mut bridges, bridge_input)?;
+    if let Some(events) = embedding.events {
+        bridges.add(events);
+    }
+    let mut bridged = futures::stream::select(
+        bridged,
+        embedding
+            .input
+            .unwrap_or_else(|| futures::channel::mpsc::unbounded().1),
+    );
+
+    let mut status = StatusPublisher::start(&status_options, &storage)?;
+
+    // Peers are let in once accepted, by hand unless auto accepted
+    let mut trust = TrustStore::load(&storage)?;
+    let mut tokens = TokenChecker::load(local_key.public(), &storage)?;
+    let mut history = ConnectionHistory::new(&storage);
+    let (admit, mut admissions) = futures::channel::mpsc::unbounded();
+    // Waiting for their name before asking, then waiting for an answer
+    let mut unnamed: HashSet<PeerId> = HashSet::new();
+    let mut pending: VecDeque<PeerId> = VecDeque::new();
+    let mut peer_names: HashMap<PeerId, String> = HashMap::new();
+    let mut transports: HashMap<PeerId, &'static str> = HashMap::new();
+    // Every connection to each peer and its transport, to keep peers on the one they prefer
+    let mut connections: HashMap<PeerId, Vec<(ConnectionId, &'static str)>> = HashMap::new();
+    // How each connection gets to its peer, for the status
+    let mut paths: HashMap<ConnectionId, (PeerId, ConnectionPath)> = HashMap::new();
+    // Files we offered by id, and those peers offered waiting for an answer
+    let mut offered_files: HashMap<u64, (PeerId, String, Vec<u8>)> = HashMap::new();
+    let mut file_offers: Vec<FileOffer> = Vec::new();
+    let mut next_file_id: u64 = 0;
+    let mut typed = match interactive {
+        true => read_terminal(),
+        false => futures::channel::mpsc::unbounded().1,
+    };
+    let (actions, keybinding_errors) = ActionTable::from_config(&keybindings);
+    for e in keybinding_errors {
+        warn!("{}", e);
+    }
+    // Input device MIDI is kept from the peers while muted
+    let mut muted = false;
+
+    // Tempo and start and stop, shared with the peers and Link
+    let transport = Transport::default();
+    // Link, JACK and the clock output stop with the session
+    let _close_transport = transport.close_on_drop();
+    let mut transport_changes = transport.subscribe();
+    if link {
+        start_link(&transport)?;
+    }
+    if let Some(mode) = jack_transport {
+        jack_transport::start(transport.clone(), mode)?;
+    }
+    if let Some(device) = &clock_output {
+        midi_clock::start(transport.clone(), device)?;
+    }
+
+    // Ctrl-C ends the session cleanly, unless the application embedding it does
+    let mut shutdown = match embedding.shutdown {
+        Some(shutdown) => shutdown,
+        None => {
+            let (shutdown_sender, shutdown) = futures::channel::mpsc::unbounded();
+            if let Err(e) = ctrlc::set_handler(move || {
+                let _ = shutdown_sender.unbounded_send(());
+            }) {
+                warn!("Could not handle Ctrl-C: {}", e);
+            }
+            shutdown
+        }
+    };
+    let mut summary = SessionSummary::default();
+
+    let session_start = Instant::now();
+    let mut latency = LatencyStats::default();
+    let mut transits: HashMap<PeerId, TransitEstimator> = HashMap::new();
+    let mut rtts: HashMap<PeerId, Duration> = HashMap::new();
+    let (playout, mut played) = Playout::new();
+    let mut auto_modes: HashMap<PeerId, LatencyMode> = HashMap::new();
+    // Peers that left lately, to resume with if they are back
+    let mut departed: HashMap<PeerId, DepartedPeer> = HashMap::new();
+    // With the bars late for bar mode
+    let mut latency_modes: HashMap<PeerId, (LatencyMode, Option<u32>)> = HashMap::new();
+    let mut latency_timer = futures_timer::Delay::new(LATENCY_REPORT_INTERVAL).fuse();
+    let mut sequences: HashMap<PeerId, SequenceTracker> = HashMap::new();
+    let mut limiters: HashMap<PeerId, RateLimiter> = HashMap::new();
+    let mut smoothers: HashMap<PeerId, TimingSmoother> = HashMap::new();
+    let mut loss_timer = futures_timer::Delay::new(LOSS_REPORT_INTERVAL).fuse();
+
+    let mut recording = record_path.map(|path| start_recording(&storage, path));
+    if let Some((_, path)) = &recording {
+        status.publish(StatusEvent::Recording {
+            recording: true,
+            path: Some(path.clone()),
+        });
+    }
+    let mut saved_events = 0;
+    // Sessions that were never recorded leave no file, so it is only indexed once saved
+    let mut auto_recording = auto_record.map(|retention| {
+        prune_auto_recordings(&storage, &retention);
+        let path = storage.auto_recording_path();
+        debug!("Recording the session to {:?}", path);
+        (SessionRecorder::default(), path)
+    });
+    let mut auto_saved_events = 0;
+    remember_session(&storage, &recent);
+    let mut save_timer = futures_timer::Delay::new(RECORD_SAVE_INTERVAL).fuse();
+    let mut status_line_timer = futures_timer::Delay::new(STATUS_LINE_INTERVAL).fuse();
+    let mut redial_timer = futures_timer::Delay::new(REDIAL_INTERVAL).fuse();
+    let mut arp_timer = futures_timer::Delay::new(ARP_INTERVAL).fuse();
+    // A tempo change waiting for its bar line, with the state it changes
+    let mut tempo_change: Option<(TransportState, TransportState)> = None;
+    let mut tempo_timer: Fuse<futures_timer::Delay> = Fuse::terminated();
+    let mut tap_tempo = TapTempo::default();
+    let mut count_in: Option<CountIn> = None;
+    let mut count_in_timer: Fuse<futures_timer::Delay> = Fuse::terminated();
+    let mut hole_punch_timer = futures_timer::Delay::new(HOLE_PUNCH_RETRY_INTERVAL).fuse();
+    // MIDI events counted at the last status line, for the rate
+    let mut status_line_events = 0;
+
+    // Received MIDI goes through the simulated network conditions, if any, before it is played
+    let (deliver, mut delivered) = futures::channel::mpsc::unbounded();
+    if let Some(conditions) = &simulate_network {
+        warn!("Simulating network conditions: {}", conditions);
     }
+    let simulator = NetworkSimulator::new(simulate_network, deliver);
 
-    block_on(async {
+    // The swarm runs as its own task, the MIDI input, control socket and timers reach it through
+    // channels
+    let session = runtime::spawn(async move {
         loop {
-            match swarm.next().await.unwrap() {
-                SwarmEvent::NewListenAddr { address, .. } => {
-                    println!("Listening on {:?}", address);
-                }
-                SwarmEvent::Behaviour(Event::Relay(
-                    relay::client::Event::ReservationReqAccepted { .. },
-                )) => {
-                    assert!(mode == Mode::Listen);
-                    println!("Relay accepted our reservation request.");
-                }
-                SwarmEvent::Behaviour(Event::Relay(event)) => {
-                    println!("{:?}", event)
+            futures::select! {
+                event = swarm.select_next_some() => match event {
+                    SwarmEvent::NewListenAddr { address, .. } => {
+                        info!("Listening on {:?}", address);
+                        reporter.report(Report::Listening {
+                            address: address.to_string(),
+                        });
+                    }
+                    SwarmEvent::Behaviour(Event::Relay(
+                        relay::client::Event::ReservationReqAccepted { relay_peer_id: relay, .. },
+                    )) => {
+                        reserved.insert(relay);
+                        if relay != relay_peer_id {
+                            info!("Relay {} accepted our reservation request.", relay);
+                            continue;
+                        }
+                        info!("Relay accepted our reservation request.");
+                        match make_invite(&local_key, &invite_relay_address, relay_port, invite_expires, invite_once) {
+                            Ok(invite) => reporter.report(Report::Invite {
+                                invite: invite.to_string(),
+                            }),
+                            Err(e) => warn!("Could not make an invite: {}", e),
+                        }
+                    }
+                    SwarmEvent::Behaviour(Event::Relay(event)) => {
+                        debug!("{:?}", event)
+                    }
+                    SwarmEvent::Behaviour(Event::Dcutr(dcutr::Event::DirectConnectionUpgradeFailed {
+                        remote_peer_id,
+                        error,
+                    })) => {
+                        // The relayed connection keeps carrying MIDI meanwhile
+                        info!(
+                            "Hole punching to {} failed, staying relayed and trying again within {:?}: {}",
+                            remote_peer_id, HOLE_PUNCH_RETRY_INTERVAL, error
+                        );
+                        status.publish(StatusEvent::DirectConnection {
+                            peer_id: remote_peer_id.to_string(),
+                            error: Some(error.to_string()),
+                        });
+                    }
+                    SwarmEvent::Behaviour(Event::Dcutr(dcutr::Event::DirectConnectionUpgradeSucceeded {
+                        remote_peer_id,
+                    })) => {
+                        info!("Connected directly to {}", remote_peer_id);
+                        status.publish(StatusEvent::DirectConnection {
+                            peer_id: remote_peer_id.to_string(),
+                            error: None,
+                        });
+                    }
+                    SwarmEvent::Behaviour(Event::Dcutr(event)) => {
+                        debug!("{:?}", event)
+                    }
+                    SwarmEvent::Behaviour(Event::Identify(identify::Event::Received {
+                        peer_id,
+                        info,
+                    })) => {
+                        let name = trust::peer_name(&info.agent_version);
+                        if let Some(name) = &name {
+                            peer_names.insert(peer_id, name.clone());
+                        }
+                        if let (Trust::NameTaken { known_peer_id }, Some(name)) =
+                            (trust.check(&peer_id.to_string(), name.as_deref()), &name)
+                        {
+                            warn!(
+                                "{} calls itself {}, which is the name of {}",
+                                peer_id, name, known_peer_id
+                            );
+                            reporter.report(Report::NameTaken {
+                                peer_id: peer_id.to_string(),
+                                name: name.clone(),
+                                known_peer_id,
+                            });
+                        }
+                        if unnamed.remove(&peer_id) {
+                            pending.push_back(peer_id);
+                            bridges.send(BridgeEvent::PeerWaiting {
+                                peer_id: peer_id.to_string(),
+                                name: name.clone(),
+                            });
+                            // One question at a time, the answer goes to the oldest
+                            if pending.len() == 1 {
+                                reporter.report(Report::UntrustedPeer {
+                                    peer_id: peer_id.to_string(),
+                                    name,
+                                });
+                            }
+                        }
+                    }
+                    SwarmEvent::Behaviour(Event::Identify(event)) => {
+                        debug!("{:?}", event)
+                    }
+                    SwarmEvent::Behaviour(Event::Ping(ping::Event {
+                        peer,
+                        result: Ok(rtt),
+                        ..
+                    })) => {
+                        if let Some(span) = peer_spans.get(&peer) {
+                            span.in_scope(|| trace!("RTT {:?}", rtt));
+                        }
+                        rtts.insert(peer, rtt);
+                        history.rtt(&peer, rtt);
+                        if connected_peers.contains(&peer) {
+                            metrics.set_rtt(&peer.to_string(), rtt);
+                            summary.rtt(rtt);
+                            reporter.report(Report::Latency {
+                                peer_id: peer.to_string(),
+                                rtt_ms: rtt.as_secs_f64() * 1000.0,
+                            });
+                            status.latency(&peer.to_string(), rtt.as_secs_f64() * 1000.0);
+                        }
+                        let auto = LatencyMode::for_rtt(rtt, auto_modes.get(&peer).copied());
+                        auto_modes.insert(peer, auto);
+                        let route = router.route(&peer);
+                        let mode = route.and_then(|r| r.latency_mode).or(latency_mode).unwrap_or(auto);
+                        let bars = match mode {
+                            LatencyMode::Bar => {
+                                Some(route.and_then(|r| r.delay_bars).unwrap_or(delay_bars))
+                            }
+                            _ => None,
+                        };
+                        if connected_peers.contains(&peer)
+                            && latency_modes.insert(peer, (mode, bars)) != Some((mode, bars))
+                        {
+                            let report = Report::LatencyMode {
+                                peer_id: peer.to_string(),
+                                mode,
+                                delay_bars: bars,
+                            };
+                            info!("{}", report);
+                            reporter.report(report);
+                            status.publish(StatusEvent::LatencyMode {
+                                peer_id: peer.to_string(),
+                                mode,
+                                delay_bars: bars,
+                            });
+                        }
+                    }
+                    SwarmEvent::Behaviour(Event::Ping(_)) => {}
+                    SwarmEvent::Behaviour(Event::Midi(request_response::Event::Message {
+                        peer,
+                        message: request_response::Message::Request { request_id, request, channel },
+                    })) => {
+                        let _ = swarm.behaviour_mut().midi.send_response(channel, ());
+                        simulator.receive((peer, request_id), request);
+                    }
+                    SwarmEvent::Behaviour(Event::Midi(request_response::Event::Message {
+                        peer,
+                        message: request_response::Message::Response { .. },
+                    })) => {
+                        if let Some(queue) = outbound.get_mut(&peer) {
+                            queue.completed();
+                            flush_midi(&mut swarm, queue, &peer);
+                        }
+                    }
+                    SwarmEvent::Behaviour(Event::Midi(request_response::Event::OutboundFailure {
+                        peer,
+                        error,
+                        ..
+                    })) => {
+                        warn!("Error sending MIDI to {}: {}", peer, error);
+                        if let Some(queue) = outbound.get_mut(&peer) {
+                            queue.completed();
+                            flush_midi(&mut swarm, queue, &peer);
+                        }
+                    }
+                    SwarmEvent::Behaviour(Event::Midi(event)) => {
+                        debug!("{:?}", event)
+                    }
+                    SwarmEvent::Behaviour(Event::Archive(request_response::Event::Message {
+                        message: request_response::Message::Response {
+                            response: ArchiveResponse::Refused(reason),
+                            ..
+                        },
+                        ..
+                    })) => {
+                        warn!("The relay did not archive MIDI: {}", reason);
+                    }
+                    SwarmEvent::Behaviour(Event::Archive(request_response::Event::OutboundFailure {
+                        error: request_response::OutboundFailure::UnsupportedProtocols,
+                        ..
+                    })) => {
+                        warn!("The relay does not archive sessions, not sending it MIDI");
+                        archive = None;
+                    }
+                    SwarmEvent::Behaviour(Event::Archive(request_response::Event::OutboundFailure {
+                        error,
+                        ..
+                    })) => {
+                        warn!("Error sending MIDI to the relay archive: {}", error);
+                    }
+                    SwarmEvent::Behaviour(Event::Archive(event)) => {
+                        debug!("{:?}", event)
+                    }
+                    SwarmEvent::Behaviour(Event::Directory(request_response::Event::Message {
+                        message: request_response::Message::Response { response, .. },
+                        ..
+                    })) => match response {
+                        DirectoryResponse::Registered => {
+                            info!("Listed as {} in the directory of the relay", publish.as_deref().unwrap_or_default());
+                        }
+                        DirectoryResponse::Refused { reason } => {
+                            warn!("The relay did not list the session: {}", reason);
+                        }
+                        response => debug!("{:?}", response),
+                    },
+                    SwarmEvent::Behaviour(Event::Directory(request_response::Event::OutboundFailure {
+                        error: request_response::OutboundFailure::UnsupportedProtocols,
+                        ..
+                    })) => {
+                        warn!("The relay keeps no session directory, the session is not listed");
+                        publish = None;
+                    }
+                    SwarmEvent::Behaviour(Event::Directory(event)) => {
+                        debug!("{:?}", event)
+                    }
+                    SwarmEvent::Behaviour(Event::Transfer(request_response::Event::Message {
+                        peer,
+                        message: request_response::Message::Request { request, channel, .. },
+                    })) => {
+                        let response = match request {
+                            // Only peers let in may send files
+                            _ if !connected_peers.contains(&peer) => TransferResponse::Refused {
+                                reason: "Not accepted in the session".to_string(),
+                            },
+                            TransferRequest::Offer { size, .. } if size > transfer::MAX_FILE_SIZE => {
+                                TransferResponse::Refused {
+                                    reason: format!(
+                                        "Files up to {} bytes are taken",
+                                        transfer::MAX_FILE_SIZE
+                                    ),
+                                }
+                            }
+                            TransferRequest::Offer { .. }
+                                if file_offers.len() >= transfer::MAX_PENDING_OFFERS =>
+                            {
+                                TransferResponse::Refused {
+                                    reason: "Too many files waiting to be accepted".to_string(),
+                                }
+                            }
+                            TransferRequest::Offer { id, name, size } => {
+                                info!("{} offers {} ({} bytes)", peer, name, size);
+                                reporter.report(Report::FileOffered {
+                                    peer_id: peer.to_string(),
+                                    id,
+                                    name: name.clone(),
+                                    size,
+                                });
+                                file_offers.retain(|o| !(o.peer_id == peer.to_string() && o.id == id));
+                                file_offers.push(FileOffer {
+                                    peer_id: peer.to_string(),
+                                    id,
+                                    name,
+                                    size,
+                                });
+                                TransferResponse::Ack
+                            }
+                            TransferRequest::Fetch { id } => match offered_files.remove(&id) {
+                                Some((to, name, data)) if to == peer => {
+                                    info!("Sending {} to {}", name, peer);
+                                    TransferResponse::file(name, data)
+                                }
+                                other => {
+                                    // Someone else's file stays offered to them
+                                    if let Some(offer) = other {
+                                        offered_files.insert(id, offer);
+                                    }
+                                    TransferResponse::Refused {
+                                        reason: format!("No file {} offered", id),
+                                    }
+                                }
+                            },
+                            TransferRequest::Decline { id } => {
+                                let declined = offered_files.get(&id).filter(|(to, _, _)| *to == peer);
+                                if let Some((_, name, _)) = declined {
+                                    reporter.report(Report::FileDeclined {
+                                        peer_id: peer.to_string(),
+                                        name: name.clone(),
+                                    });
+                                    offered_files.remove(&id);
+                                }
+                                TransferResponse::Ack
+                            }
+                        };
+                        let _ = swarm.behaviour_mut().transfer.send_response(channel, response);
+                    }
+                    SwarmEvent::Behaviour(Event::Transfer(request_response::Event::Message {
+                        peer,
+                        message: request_response::Message::Response { response, .. },
+                    })) => match response {
+                        TransferResponse::File { name, data, .. } => {
+                            match transfer::save_file(&storage.received_dir(), &name, &data) {
+                                Ok(path) => {
+                                    info!(
+                                        "Received {} from {}, saved to {}",
+                                        name,
+                                        peer,
+                                        path.display()
+                                    );
+                                    reporter.report(Report::FileReceived {
+                                        peer_id: peer.to_string(),
+                                        name,
+                                        path,
+                                    });
+                                }
+                                Err(e) => reporter.report(Report::Error {
+                                    message: format!("Could not save {} from {}: {}", name, peer, e),
+                                }),
+                            }
+                        }
+                        TransferResponse::Refused { reason } => {
+                            warn!("{} refused the file: {}", peer, reason);
+                            reporter.report(Report::Error {
+                                message: format!("{} refused the file: {}", peer, reason),
+                            });
+                        }
+                        TransferResponse::Ack => {}
+                    },
+                    SwarmEvent::Behaviour(Event::Transfer(request_response::Event::OutboundFailure {
+                        peer,
+                        error,
+                        ..
+                    })) => {
+                        warn!("Error sending a file request to {}: {}", peer, error);
+                    }
+                    SwarmEvent::Behaviour(Event::Transfer(event)) => {
+                        debug!("{:?}", event)
+                    }
+                    SwarmEvent::Behaviour(Event::RelayHint(event)) => {
+                        let (peer, theirs) = match event {
+                            request_response::Event::Message {
+                                peer,
+                                message: request_response::Message::Request { request, channel, .. },
+                            } => {
+                                // Only peers let in hear about our relays
+                                if !connected_peers.contains(&peer) {
+                                    continue;
+                                }
+                                let hint = RelayHint::measure(relays.keys(), &rtts, &reserved);
+                                let _ = swarm.behaviour_mut().relay_hint.send_response(channel, hint);
+                                (peer, request)
+                            }
+                            request_response::Event::Message {
+                                peer,
+                                message: request_response::Message::Response { response, .. },
+                            } => (peer, response),
+                            event => {
+                                debug!("{:?}", event);
+                                continue;
+                            }
+                        };
+                        let ours = RelayHint::measure(relays.keys(), &rtts, &reserved);
+                        let relay = match relay_hint::best_relay(&ours, &theirs) {
+                            Some(relay) if relays.contains_key(&relay) => relay,
+                            _ => {
+                                debug!("No relay measured by both us and {}", peer);
+                                continue;
+                            }
+                        };
+                        if agreed_relays.insert(peer, relay) != Some(relay) {
+                            info!("Going through relay {} to reach {}", relay, peer);
+                        }
+                        // Only a peer reached through another relay alone moves to this one
+                        let peer_connections = connections.get(&peer).map(|c| c.as_slice()).unwrap_or_default();
+                        let through_best = peer_connections.iter().any(|(id, _)| {
+                            paths.get(id).and_then(|(_, path)| relay_hint::path_relay(path)) == Some(relay)
+                        });
+                        if through_best
+                            || !peer_connections.iter().all(|(_, t)| *t == "relayed")
+                            || !relay_hint::dials_through(&relay, &local_peer_id, &peer, &ours, &theirs)
+                        {
+                            continue;
+                        }
+                        let opts = DialOpts::from(
+                            relays[&relay].clone().with(Protocol::P2pCircuit).with(Protocol::P2p(peer)),
+                        );
+                        let connection_id = opts.connection_id();
+                        match swarm.dial(opts) {
+                            // Takes over from the relayed connections before it once up
+                            Ok(_) => {
+                                hole_punch_dials.insert(connection_id);
+                            }
+                            Err(e) => warn!("Could not dial {} through relay {}: {}", peer, relay, e),
+                        }
+                    }
+                    SwarmEvent::ConnectionEstablished {
+                        peer_id, connection_id, endpoint, ..
+                    } => {
+                        history.established(connection_id, &peer_id, &endpoint);
+                        // Peers can reach us through the other relays too once they hold a reservation
+                        if let Some(address) = dialing_relays.remove(&connection_id) {
+                            let address = relay_with_peer_id(&address, peer_id);
+                            if let Err(e) = swarm.listen_on(address.clone().with(Protocol::P2pCircuit)) {
+                                warn!("Could not ask relay {} for a reservation: {}", address, e);
+                            }
+                            relays.insert(peer_id, address);
+                        }
+                        // A relay we connect to again has forgotten the session
+                        if let (true, Some(session)) = (peer_id == relay_peer_id, &publish) {
+                            publish_session(&mut swarm, &relay_peer_id, session, &relay_token);
+                        }
+                        if let Some(address) = dialing.remove(&connection_id) {
+                            redials.insert(peer_id, address);
+                        }
+                        let resumed = match departed.remove(&peer_id) {
+                            Some(state) if state.left.elapsed() <= RECONNECT_GRACE => {
+                                if let Some(name) = state.name {
+                                    peer_names.insert(peer_id, name);
+                                }
+                                if let Some(mode) = state.auto_mode {
+                                    auto_modes.insert(peer_id, mode);
+                                }
+                                if let Some(mode) = state.latency_mode {
+                                    latency_modes.insert(peer_id, mode);
+                                }
+                                true
+                            }
+                            _ => false,
+                        };
+                        let route = router.reconnect_peer(&peer_id, RECONNECT_GRACE);
+                        let span = peer_spans.entry(peer_id).or_insert_with(|| {
+                            info_span!("peer", id = %peer_id, name = %route.display_name)
+                        });
+                        span.in_scope(|| {
+                            info!(
+                                "Established connection via {:?} ({})",
+                                endpoint,
+                                describe_transport(endpoint.get_remote_address())
+                            );
+                            info!("Routing MIDI as {}", route.display_name);
+                            if resumed {
+                                info!("Back within {:?}, resuming where it left", RECONNECT_GRACE);
+                            }
+                        });
+                        reporter.report(Report::PeerConnected {
+                            peer_id: peer_id.to_string(),
+                            name: route.display_name.clone(),
+                            verified: trust.is_verified(&peer_id.to_string()),
+                        });
+                        outbound
+                            .entry(peer_id)
+                            .or_insert_with(|| OutboundQueue::new(priority_model));
+                        let transport = describe_transport(endpoint.get_remote_address());
+                        let peer_connections = connections.entry(peer_id).or_default();
+                        peer_connections.push((connection_id, transport));
+                        let preference = router.route(&peer_id).and_then(|route| route.transport);
+                        let mut unwanted = match relays.contains_key(&peer_id) {
+                            true => Vec::new(),
+                            false => router
+                                .route(&peer_id.to_string())
+                                .map(|route| route.unwanted_connections(peer_connections))
+                                .unwrap_or_default(),
+                        };
+                        let relayed: Vec<ConnectionId> = peer_connections
+                            .iter()
+                            .filter(|(id, t)| *t == "relayed" && *id != connection_id)
+                            .map(|(id, _)| *id)
+                            .collect();
+                        let mut hole_punched = false;
+                        if hole_punch_dials.remove(&connection_id) {
+                            // The new relayed connection takes over from the ones before it
+                            unwanted.extend(relayed);
+                        } else if transport != "relayed" && !relays.contains_key(&peer_id) {
+                            hole_punched = relayed_since.remove(&peer_id).is_some();
+                            if hole_punched && preference != Some(TransportPreference::Relayed) {
+                                info!("Upgraded {} to a direct {} connection", peer_id, transport);
+                                reporter.report(Report::UpgradedToDirect {
+                                    peer_id: peer_id.to_string(),
+                                    transport: transport.to_string(),
+                                });
+                                // MIDI goes over any connection to the peer, now only the direct one
+                                unwanted.extend(relayed);
+                            }
+                        } else if peer_connections.iter().all(|(_, t)| *t == "relayed") {
+                            relayed_since.entry(peer_id).or_insert_with(Instant::now);
+                        }
+                        for (id, transport) in peer_connections.iter().filter(|(id, _)| unwanted.contains(id)) {
+                            debug!("Closing {} connection to {}", transport, peer_id);
+                            swarm.close_connection(*id);
+                        }
+                        if let Some((_, transport)) = peer_connections.iter().rev().find(|(id, _)| !unwanted.contains(id)) {
+                            transports.insert(peer_id, *transport);
+                        }
+                        paths.insert(connection_id, (peer_id, ConnectionPath::new(&endpoint, hole_punched, private_swarm)));
+                        let accepted = relays.contains_key(&peer_id)
+                            || resumed
+                            || Some(peer_id) == dial_target
+                            || dialed.contains(&peer_id)
+                            || trust.auto_accepts(&peer_id.to_string(), auto_accept);
+                        if accepted {
+                            let _ = admit.unbounded_send(peer_id);
+                        } else if !connected_peers.contains(&peer_id)
+                            && !pending.contains(&peer_id)
+                        {
+                            info!("{} is new, waiting for it to be accepted", peer_id);
+                            unnamed.insert(peer_id);
+                        }
+                    }
+                    SwarmEvent::ConnectionClosed {
+                        peer_id,
+                        connection_id,
+                        num_established: 0,
+                        ..
+                    } => {
+                        history.closed(connection_id);
+                        paths.remove(&connection_id);
+                        if let Some(span) = peer_spans.remove(&peer_id) {
+                            span.in_scope(|| info!("Connection closed"));
+                        }
+                        // A reconnecting peer may have restarted, and its clock and sequence with it
+                        transits.remove(&peer_id);
+                        rtts.remove(&peer_id);
+                        sequences.remove(&peer_id);
+                        limiters.remove(&peer_id);
+                        smoothers.remove(&peer_id);
+                        outbound.remove(&peer_id);
+                        router.disconnect_peer(&peer_id, RECONNECT_GRACE);
+                        transports.remove(&peer_id);
+                        connections.remove(&peer_id);
+                        relayed_since.remove(&peer_id);
+                        agreed_relays.remove(&peer_id);
+                        offered_files.retain(|_, (peer, _, _)| *peer != peer_id);
+                        peer_harmonizers.remove(&peer_id);
+                        arpeggiators.retain(|(peer, _), _| *peer != peer_id);
+                        file_offers.retain(|offer| offer.peer_id != peer_id.to_string());
+                        if peer_id != relay_peer_id && relays.remove(&peer_id).is_some() {
+                            warn!("Lost relay {}, peers are no longer reached through it", peer_id);
+                            reserved.remove(&peer_id);
+                            agreed_relays.retain(|_, relay| *relay != peer_id);
+                        }
+                        if let Some(address) = redials.get(&peer_id) {
+                            info!("Dialing {} again in {:?}", address, REDIAL_INTERVAL);
+                            redial_queue.push(address.clone());
+                        }
+                        let state = DepartedPeer {
+                            left: Instant::now(),
+                            name: peer_names.remove(&peer_id),
+                            auto_mode: auto_modes.remove(&peer_id),
+                            latency_mode: latency_modes.remove(&peer_id),
+                        };
+                        departed.retain(|_, state| state.left.elapsed() <= RECONNECT_GRACE);
+                        // Only peers that were let in come back without asking
+                        if connected_peers.contains(&peer_id) {
+                            departed.insert(peer_id, state);
+                        }
+                        let asked = pending.front() == Some(&peer_id);
+                        if take_pending(&peer_id, &mut pending, &mut unnamed) && asked {
+                            if let Some(next) = pending.front() {
+                                reporter.report(Report::UntrustedPeer {
+                                    peer_id: next.to_string(),
+                                    name: peer_names.get(next).cloned(),
+                                });
+                            }
+                        }
+                        if connected_peers.remove(&peer_id) {
+                            bridges.send(BridgeEvent::PeerLeft {
+                                peer_id: peer_id.to_string(),
+                            });
+                            status.peer_left(&peer_id.to_string());
+                            metrics.peer_disconnected(&peer_id.to_string());
+                            reporter.report(Report::PeerDisconnected {
+                                peer_id: peer_id.to_string(),
+                            });
+                            reporter.report(Report::Peers {
+                                peers: connected_peers.iter().map(|p| p.to_string()).collect(),
+                            });
+                        }
+                    }
+                    SwarmEvent::ConnectionClosed { peer_id, connection_id, .. } => {
+                        history.closed(connection_id);
+                        paths.remove(&connection_id);
+                        if let Some(peer_connections) = connections.get_mut(&peer_id) {
+                            peer_connections.retain(|(id, _)| *id != connection_id);
+                            if let Some((_, transport)) = peer_connections.last() {
+                                transports.insert(peer_id, *transport);
+                            }
+                            if !relays.contains_key(&peer_id) && peer_connections.iter().all(|(_, t)| *t == "relayed") {
+                                relayed_since.entry(peer_id).or_insert_with(Instant::now);
+                            }
+                        }
+                    }
+                    SwarmEvent::OutgoingConnectionError { connection_id, peer_id, error } => {
+                        warn!("Outgoing connection error to {:?}: {:?}", peer_id, error);
+                        history.failed(peer_id, Direction::Outbound, None, error.to_string());
+                        // Peers that were here keep being dialed until they are back
+                        if let Some(address) = dialing.remove(&connection_id) {
+                            if redials.values().any(|a| *a == address) {
+                                redial_queue.push(address);
+                            }
+                        }
+                        hole_punch_dials.remove(&connection_id);
+                        if let Some(address) = dialing_relays.remove(&connection_id) {
+                            warn!("Could not connect to relay {}: {}", address, error);
+                        }
+                        // Nothing left to do when the peer we were asked to connect to can't be reached
+                        if peer_id.is_some() && peer_id == dial_target && connected_peers.is_empty() {
+                            return Err(Failure::PeerUnreachable(format!("{:?}: {}", peer_id, error)));
+                        }
+                        reporter.report(Report::Error {
+                            message: format!("Could not connect to {:?}: {}", peer_id, error),
+                        });
+                    }
+                    SwarmEvent::IncomingConnectionError { send_back_addr, error, .. } => {
+                        debug!("Incoming connection error from {}: {}", send_back_addr, error);
+                        history.failed(None, Direction::Inbound, Some(&send_back_addr), error.to_string());
+                    }
+                    _ => {}
+                },
+                ((peer, request_id), request) = delivered.select_next_some() => {
+                    // Nothing is heard from peers until they are accepted, or show an invite token
+                    if !connected_peers.contains(&peer) {
+                        let token = request
+                            .iter()
+                            .find_map(|frame| InviteToken::from_sysex(&frame.message));
+                        if let Some(token) = token {
+                            let asked = pending.front() == Some(&peer);
+                            if !take_pending(&peer, &mut pending, &mut unnamed) {
+                                continue;
+                            }
+                            match tokens.redeem(&token) {
+                                Ok(_) => {
+                                    info!("{} joined with an invite token", peer);
+                                    let _ = admit.unbounded_send(peer);
+                                }
+                                Err(e) => {
+                                    warn!("Turned away {}: {}", peer, e);
+                                    let _ = swarm.disconnect_peer_id(peer);
+                                }
+                            }
+                            if asked {
+                                if let Some(next) = pending.front() {
+                                    reporter.report(Report::UntrustedPeer {
+                                        peer_id: next.to_string(),
+                                        name: peer_names.get(next).cloned(),
+                                    });
+                                }
+                            }
+                        }
+                        continue;
+                    }
+                    let _peer = peer_spans.get(&peer).map(|span| span.enter());
+                    let _stream = tracing::debug_span!(
+                        "midi_stream",
+                        %request_id,
+                        frames = request.len()
+                    )
+                    .entered();
+                    metrics.midi_received(request.len());
+                    summary.received(request.len());
+                    let sequence = sequences.entry(peer).or_default();
+                    for frame in &request {
+                        sequence.observe(frame.seq);
+                    }
+                    let received = Instant::now();
+                    let received_us = (received - session_start).as_micros() as u64;
+                    let rtt = rtts.get(&peer).copied().unwrap_or_default();
+                    let transit = transits.entry(peer).or_default();
+                    // Frames stamped too far off would throw the clock of the peer off and land
+                    // out of reach
+                    let request: Vec<MidiFrame> = request
+                        .into_iter()
+                        .filter(|frame| {
+                            let delay = transit.observe(received_us, frame.timestamp_us, rtt);
+                            match delay {
+                                Some(delay) if measure_latency => {
+                                    latency.record(Stage::Network, delay)
+                                }
+                                Some(_) => {}
+                                None => {
+                                    debug!("{} sent a bogus timestamp", peer);
+                                    metrics.midi_dropped(1);
+                                }
+                            }
+                            delay.is_some()
+                        })
+                        .collect();
+                    if let Some(route) = router.route(&peer) {
+                        let mode = route
+                            .latency_mode
+                            .or(latency_mode)
+                            .or(auto_modes.get(&peer).copied())
+                            .unwrap_or(LatencyMode::Live);
+                        let bars = route.delay_bars.unwrap_or(delay_bars);
+                        let transport_state = transport.state();
+                        for frame in request {
+                            if let Some(start) = ScheduledStart::from_sysex(&frame.message) {
+                                if !route.permissions.control_transport {
+                                    debug!("{} may not control the transport", peer);
+                                    continue;
+                                }
+                                // When it was sent, on our clock
+                                let sent_us = transit
+                                    .arrival_us(frame.timestamp_us)
+                                    .saturating_sub(rtt.as_micros() as u64 / 2);
+                                let delay_us = sent_us.saturating_add(start.delay_us);
+                                let at = session_start.checked_add(Duration::from_micros(delay_us));
+                                let at = match at {
+                                    Some(at) => at,
+                                    None => {
+                                        debug!("{} scheduled a start out of reach", peer);
+                                        continue;
+                                    }
+                                };
+                                let schedule = CountIn {
+                                    start: at,
+                                    beats: start.count_in.min(MAX_COUNT_IN),
+                                    tempo: start.tempo,
+                                };
+                                info!("{} starts the session at {:.1} BPM", peer, start.tempo);
+                                count_in_timer = next_count_in(&schedule);
+                                count_in = Some(schedule);
+                                continue;
+                            }
+                            if let Some(state) = TransportState::from_sysex(&frame.message) {
+                                match route.permissions.control_transport {
+                                    true => transport.set(state, Source::Peer),
+                                    false => debug!("{} may not control the transport", peer),
+                                }
+                                continue;
+                            }
+                            // Tokens of peers let in anyway
+                            if InviteToken::from_sysex(&frame.message).is_some() {
+                                continue;
+                            }
+                            if let Some(rate) = route.max_rate.or(max_inbound_rate) {
+                                let limiter = limiters
+                                    .entry(peer)
+                                    .or_insert_with(|| RateLimiter::new(rate_limit_policy, received));
+                                match limiter.check(&frame.message, rate, received) {
+                                    Verdict::Pass => {}
+                                    Verdict::Drop => {
+                                        metrics.midi_dropped(1);
+                                        continue;
+                                    }
+                                    Verdict::Alert => {
+                                        warn!("{} is over its rate limit of {} events a second", peer, rate);
+                                        reporter.report(Report::RateLimited {
+                                            peer_id: peer.to_string(),
+                                            rate,
+                                        });
+                                        status.publish(StatusEvent::RateLimited {
+                                            peer_id: peer.to_string(),
+                                            rate,
+                                        });
+                                        metrics.midi_dropped(1);
+                                        continue;
+                                    }
+                                }
+                            }
+                            match route.apply(&frame.message) {
+                                Some(message) => {
+                                    trace!(seq = frame.seq, "MIDI {:?}", message);
+                                    let arrival_us = transit.arrival_us(frame.timestamp_us);
+                                    let arrival = Duration::from_micros(arrival_us);
+                                    let arrival = match session_start.checked_add(arrival) {
+                                        Some(arrival) => arrival,
+                                        None => {
+                                            metrics.midi_dropped(1);
+                                            continue;
+                                        }
+                                    };
+                                    // Recorded as played here, after permissions, limits and
+                                    // the route
+                                    let message = Bytes::from(message);
+                                    let recorders = recording.iter_mut().chain(&mut auto_recording);
+                                    for (recorder, _) in recorders {
+                                        let played = MidiFrame {
+                                            message: message.clone(),
+                                            ..frame.clone()
+                                        };
+                                        let name = &route.display_name;
+                                        recorder.record(&peer.to_string(), name, &played);
+                                    }
+                                    let event = BridgeEvent::Midi {
+                                        peer_id: peer.to_string(),
+                                        message,
+                                        track: frame.track_label().map(|t| t.to_string()),
+                                    };
+                                    let mut due = mode.due(arrival, rtt, &transport_state, bars);
+                                    if route.timing == Timing::Forgiving {
+                                        let smoother = smoothers.entry(peer).or_default();
+                                        let at = due.unwrap_or(received);
+                                        due = Some(smoother.place(at, &transport_state));
+                                    }
+                                    match due.filter(|at| *at > received) {
+                                        Some(at) => playout.play_at(at, Route::Peer(peer, event)),
+                                        None => deliver(&mut bridges, &mut arpeggiators, &router, peer, event),
+                                    }
+                                }
+                                None => metrics.midi_dropped(1),
+                            }
+                            if measure_latency {
+                                latency.record(Stage::Output, received.elapsed());
+                            }
+                        }
+                    }
+                },
+                event = midi_input.select_next_some() => {
+                    if measure_latency {
+                        latency.record(Stage::Send, event.at.elapsed());
+                    }
+                    input_batch.clear();
+                    input_batch.push(event);
+                    // Whatever else was played meanwhile goes in the same batch
+                    while let Some(event) = midi_input.pop() {
+                        if measure_latency {
+                            latency.record(Stage::Send, event.at.elapsed());
+                        }
+                        input_batch.push(event);
+                    }
+                    if let Some(curve) = &velocity_curve {
+                        for event in &mut input_batch {
+                            curve.apply(event.message_mut());
+                        }
+                    }
+                    let frames = frame_played(
+                        &input_batch,
+                        &mut harmonizer,
+                        &mut sequencer,
+                        &mut arena,
+                        &input_track,
+                    );
+                    if let Some(session) = &archive {
+                        swarm.behaviour_mut().archive.send_request(
+                            &relay_peer_id,
+                            ArchiveRequest::Store {
+                                session: session.clone(),
+                                name: archive_name.clone(),
+                                consent,
+                                frames: frames.clone(),
+                            },
+                        );
+                    }
+                    if let Some(thru) = thru {
+                        let latency = match thru {
+                            Thru::Live => Duration::ZERO,
+                            Thru::Matched => session_latency(connected_peers.iter().filter_map(
+                                |peer| Some((*rtts.get(peer)?, latency_modes.get(peer)?.0)),
+                            )),
+                        };
+                        let due = Instant::now() + latency;
+                        for frame in &frames {
+                            match latency.is_zero() {
+                                true => play_thru(&mut thru_output, &frame.message),
+                                false => playout.play_at(due, Route::Thru(frame.message.clone())),
+                            }
+                        }
+                    }
+                    let overflows = midi_input.new_overflows();
+                    if overflows > 0 {
+                        warn!("MIDI input queue overflowed, dropped {} messages", overflows);
+                        metrics.midi_dropped(overflows as usize);
+                    }
+                    let too_long = midi_input.new_too_long();
+                    if too_long > 0 {
+                        warn!(
+                            "Dropped {} SysEx messages longer than {} bytes from the MIDI input",
+                            too_long,
+                            ring::SYSEX_CAPACITY
+                        );
+                        metrics.midi_dropped(too_long as usize);
+                    }
+                    if muted {
+                        continue;
+                    }
+                    for peer in connected_peers.iter().filter(|p| router.may_receive(p)) {
+                        let route = router.route(peer);
+                        let zones = route.and_then(|r| r.harmony.as_ref());
+                        let own = peer_harmonizer(&mut peer_harmonizers, peer, zones).map(|h| {
+                            frame_played(&input_batch, h, &mut sequencer, &mut arena, &input_track)
+                        });
+                        let frames = own.as_deref().unwrap_or(&frames);
+                        broadcast(
+                            &mut swarm,
+                            &mut outbound,
+                            [peer],
+                            frames,
+                            &metrics,
+                            &mut summary,
+                            &reporter,
+                        );
+                    }
+                },
+                midi = bridged.select_next_some() => {
+                    let frames = [sequencer.frame(midi.message)];
+                    let peers = connected_peers
+                        .iter()
+                        .filter(|peer| router.may_receive(peer))
+                        .filter(|peer| match &midi.to {
+                            Some(to) => {
+                                let name = router.route(peer).map(|r| &r.display_name);
+                                *to == peer.to_string() || name == Some(to)
+                            }
+                            None => true,
+                        });
+                    broadcast(
+                        &mut swarm,
+                        &mut outbound,
+                        peers,
+                        &frames,
+                        &metrics,
+                        &mut summary,
+                        &reporter,
+                    );
+                },
+                change = transport_changes.select_next_some() => {
+                    reporter.report(Report::Transport {
+                        tempo: change.state.tempo,
+                        playing: change.state.playing,
+                        source: change.source,
+                    });
+                    status.publish(StatusEvent::Transport {
+                        tempo: change.state.tempo,
+                        playing: change.state.playing,
+                    });
+                    // Changes made here or by Link go to the peers, theirs already went around
+                    if !matches!(change.source, Source::Peer | Source::Scheduled) {
+                        let frames = [sequencer.frame(change.state.to_sysex())];
+                        broadcast(
+                            &mut swarm,
+                            &mut outbound,
+                            &connected_peers,
+                            &frames,
+                            &metrics,
+                            &mut summary,
+                            &reporter,
+                        );
+                    }
+                },
+                _ = config_changes.select_next_some() => match reloader.reload() {
+                    Ok((reloaded, change)) if !change.is_empty() => {
+                        harmonizer.set_zones(&reloaded.harmony);
+                        velocity_curve = reloaded.velocity_curve;
+                        router.set_configs(reloaded.peers);
+                        if !change.applied.is_empty() {
+                            info!("Applied config changes: {}", change.applied.join(", "));
+                        }
+                        if !change.needs_reconnect.is_empty() {
+                            warn!(
+                                "Reconnect to apply config changes: {}",
+                                change.needs_reconnect.join(", ")
+                            );
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => warn!("Error reloading config file: {}", e),
+                },
+                _ = latency_timer => {
+                    latency_timer = futures_timer::Delay::new(LATENCY_REPORT_INTERVAL).fuse();
+                    for report in latency.reports() {
+                        reporter.report(report);
+                    }
+                    let now_us = (Instant::now() - session_start).as_micros() as u64;
+                    for (peer, transit) in &transits {
+                        debug!("Clock of {}: {}", peer, transit.quality(now_us));
+                    }
+                },
+                _ = loss_timer => {
+                    loss_timer = futures_timer::Delay::new(LOSS_REPORT_INTERVAL).fuse();
+                    if !sequences.is_empty() {
+                        reporter.report(Report::Loss {
+                            peers: loss_by_name(&sequences, &router),
+                        });
+                    }
+                    for (peer, sequence) in sequences.iter_mut() {
+                        let lost = sequence.new_losses();
+                        if lost > 0 {
+                            status.publish(StatusEvent::Dropout {
+                                peer_id: peer.to_string(),
+                                lost,
+                            });
+                        }
+                    }
+                },
+                _ = save_timer => {
+                    save_timer = futures_timer::Delay::new(RECORD_SAVE_INTERVAL).fuse();
+                    if let Some((recorder, path)) = &recording {
+                        save_recording(recorder, path, &mut saved_events, &reporter);
+                    }
+                    if let Some((recorder, path)) = &auto_recording {
+                        save_auto_recording(
+                            &storage,
+                            recorder,
+                            path,
+                            &mut auto_saved_events,
+                            &reporter,
+                        );
+                    }
+                },
+                _ = status_line_timer => {
+                    status_line_timer = futures_timer::Delay::new(STATUS_LINE_INTERVAL).fuse();
+                    let events = metrics.midi_events();
+                    let transport_state = transport.state();
+                    reporter.status(&StatusLine {
+                        peers: connected_peers.len(),
+                        tempo: transport_state.tempo,
+                        playing: transport_state.playing,
+                        rtt_ms: connected_peers
+                            .iter()
+                            .filter_map(|peer| rtts.get(peer))
+                            .max()
+                            .map(|rtt| rtt.as_secs_f64() * 1000.0),
+                        events_per_s: (events - status_line_events) as f64
+                            / STATUS_LINE_INTERVAL.as_secs_f64(),
+                    });
+                    status_line_events = events;
+                },
+                _ = hole_punch_timer => {
+                    hole_punch_timer = futures_timer::Delay::new(HOLE_PUNCH_RETRY_INTERVAL).fuse();
+                    for (peer, since) in relayed_since.iter_mut() {
+                        // Only one side retries, so neither closes the relayed connection the
+                        // other just opened
+                        if since.elapsed() < HOLE_PUNCH_RETRY_INTERVAL
+                            || local_peer_id > *peer
+                            || router.route(peer).and_then(|route| route.transport)
+                                == Some(TransportPreference::Relayed)
+                        {
+                            continue;
+                        }
+                        // A new relayed connection has the peer start hole punching again
+                        debug!("Trying to hole punch to {} again", peer);
+                        *since = Instant::now();
+                        let relay = agreed_relays.get(peer).and_then(|r| relays.get(r)).unwrap_or(&relay_address);
+                        let opts = DialOpts::from(
+                            relay.clone().with(Protocol::P2pCircuit).with(Protocol::P2p(*peer)),
+                        );
+                        let connection_id = opts.connection_id();
+                        match swarm.dial(opts) {
+                            Ok(_) => {
+                                hole_punch_dials.insert(connection_id);
+                            }
+                            Err(e) => warn!("Could not dial {} through the relay: {}", peer, e),
+                        }
+                    }
+                },
+                _ = count_in_timer => {
+                    if let Some(schedule) = count_in {
+                        let now = Instant::now();
+                        let beats_left = match now >= schedule.start {
+                            true => Some(0),
+                            false => schedule.beats_left(now),
+                        };
+                        if let Some(beats_left) = beats_left {
+                            reporter.report(Report::CountIn { beats_left });
+                            bridges.send(BridgeEvent::CountIn { beats_left });
+                        }
+                        match beats_left {
+                            Some(0) => {
+                                transport.set(schedule.started(), Source::Scheduled);
+                                count_in = None;
+                            }
+                            _ => count_in_timer = next_count_in(&schedule),
+                        }
+                    }
                 }
-                SwarmEvent::Behaviour(Event::Dcutr(event)) => {
-                    println!("{:?}", event)
+                _ = tempo_timer => {
+                    // Anything else changing the transport meanwhile wins over the change
+                    if let Some((from, to)) = tempo_change.take() {
+                        if transport.state() == from {
+                            info!("Tempo {:.1} BPM", to.tempo);
+                            transport.set(to, Source::Local);
+                        }
+                    }
                 }
-                SwarmEvent::Behaviour(Event::Identify(event)) => {
-                    println!("{:?}", event)
+                _ = arp_timer => {
+                    arp_timer = futures_timer::Delay::new(ARP_INTERVAL).fuse();
+                    if !arpeggiators.is_empty() {
+                        schedule_arpeggios(&mut arpeggiators, &transport.state(), &playout);
+                    }
                 }
-                SwarmEvent::Behaviour(Event::Ping(_)) => {}
-                SwarmEvent::ConnectionEstablished {
-                    peer_id, endpoint, ..
-                } => {
-                    println!("Established connection to {:?} via {:?}", peer_id, endpoint);
+                _ = redial_timer => {
+                    redial_timer = futures_timer::Delay::new(REDIAL_INTERVAL).fuse();
+                    for address in redial_queue.drain(..) {
+                        let opts = DialOpts::from(address.clone());
+                        let connection_id = opts.connection_id();
+                        dialing.insert(connection_id, address.clone());
+                        if let Err(e) = swarm.dial(opts) {
+                            warn!("Could not dial {} again: {}", address, e);
+                            dialing.remove(&connection_id);
+                        }
+                    }
+                },
+                route = played.select_next_some() => match route {
+                    Route::Peer(peer, event) => {
+                        if connected_peers.contains(&peer) {
+                            deliver(&mut bridges, &mut arpeggiators, &router, peer, event);
+                        }
+                    }
+                    Route::Arpeggio(_, event) => bridges.send(event),
+                    Route::Thru(message) => play_thru(&mut thru_output, &message),
+                },
+                (request, reply) = control_requests.select_next_some() => {
+                    let response = match request {
+                        ControlRequest::Status => ControlResponse::ok(serde_json::json!({
+                            "peer_id": local_peer_id.to_string(),
+                            "mode": format!("{:?}", mode),
+                            "peers": connected_peers
+                                .iter()
+                                .map(|p| p.to_string())
+                                .collect::<Vec<String>>(),
+                            "verified": connected_peers
+                                .iter()
+                                .map(|p| p.to_string())
+                                .filter(|p| trust.is_verified(p))
+                                .collect::<Vec<String>>(),
+                            "pending": pending
+                                .iter()
+                                .chain(unnamed.iter())
+                                .map(|p| p.to_string())
+                                .collect::<Vec<String>>(),
+                            "listen_addresses": swarm
+                                .listeners()
+                                .map(|a| a.to_string())
+                                .collect::<Vec<String>>(),
+                            "ports": connected_peers
+                                .iter()
+                                .filter(|_| bridge_options.virtual_ports)
+                                .filter_map(|p| {
+                                    let route = router.route(p)?;
+                                    let (to, from) = port_names(&route.display_name);
+                                    Some((p.to_string(), serde_json::json!({"to": to, "from": from})))
+                                })
+                                .collect::<BTreeMap<String, serde_json::Value>>(),
+                            "transports": connected_peers
+                                .iter()
+                                .filter_map(|p| Some((p.to_string(), *transports.get(p)?)))
+                                .collect::<BTreeMap<String, &str>>(),
+                            "connections": paths
+                                .values()
+                                .filter(|(p, _)| connected_peers.contains(p))
+                                .fold(BTreeMap::new(), |mut by_peer: BTreeMap<String, Vec<&ConnectionPath>>, (p, path)| {
+                                    by_peer.entry(p.to_string()).or_default().push(path);
+                                    by_peer
+                                }),
+                            "relays": agreed_relays
+                                .iter()
+                                .map(|(p, relay)| (p.to_string(), relay.to_string()))
+                                .collect::<BTreeMap<String, String>>(),
+                            "latency_modes": latency_modes
+                                .iter()
+                                .map(|(p, (mode, bars))| {
+                                    (p.to_string(), serde_json::json!({"mode": mode, "delay_bars": bars}))
+                                })
+                                .collect::<BTreeMap<String, serde_json::Value>>(),
+                            "clocks": transits
+                                .iter()
+                                .filter(|(p, _)| connected_peers.contains(p))
+                                .map(|(p, transit)| {
+                                    let now_us =
+                                        (Instant::now() - session_start).as_micros() as u64;
+                                    (p.to_string(), transit.quality(now_us))
+                                })
+                                .collect::<BTreeMap<String, ClockQuality>>(),
+                        })),
+                        ControlRequest::Stats => ControlResponse::ok(
+                            serde_json::to_value(loss_by_peer_id(&sequences)).unwrap_or_default(),
+                        ),
+                        ControlRequest::Dial { address } => {
+                            match dial_address(&relay_address, &address, port) {
+                                Ok(address) => match swarm.dial(address) {
+                                    Ok(_) => ControlResponse::ok(serde_json::Value::Null),
+                                    Err(e) => ControlResponse::error(e.to_string()),
+                                },
+                                Err(e) => ControlResponse::error(e),
+                            }
+                        }
+                        ControlRequest::Disconnect { peer_id } => match PeerId::from_str(&peer_id) {
+                            Ok(peer) => match swarm.disconnect_peer_id(peer) {
+                                Ok(_) => ControlResponse::ok(serde_json::Value::Null),
+                                Err(_) => ControlResponse::error(format!("Not connected to {}", peer)),
+                            },
+                            Err(e) => ControlResponse::error(format!("Invalid PeerId: {}", e)),
+                        },
+                        ControlRequest::Renegotiate { peer_id } => match PeerId::from_str(&peer_id) {
+                            Ok(peer) if connected_peers.contains(&peer) => {
+                                // Back within the reconnect grace, it resumes where it was
+                                let _ = swarm.disconnect_peer_id(peer);
+                                let relay = agreed_relays.get(&peer).and_then(|r| relays.get(r));
+                                let address = relay
+                                    .unwrap_or(&relay_address)
+                                    .clone()
+                                    .with(Protocol::P2pCircuit)
+                                    .with(Protocol::P2p(peer));
+                                match swarm.dial(address) {
+                                    Ok(_) => ControlResponse::ok(serde_json::Value::Null),
+                                    Err(e) => ControlResponse::error(e.to_string()),
+                                }
+                            }
+                            Ok(peer) => ControlResponse::error(format!("Not connected to {}", peer)),
+                            Err(e) => ControlResponse::error(format!("Invalid PeerId: {}", e)),
+                        },
+                        ControlRequest::Accept { peer_id } => match PeerId::from_str(&peer_id) {
+                            Ok(peer) if take_pending(&peer, &mut pending, &mut unnamed) => {
+                                let _ = admit.unbounded_send(peer);
+                                ControlResponse::ok(serde_json::Value::Null)
+                            }
+                            Ok(peer) => ControlResponse::error(format!(
+                                "{} is not waiting to be accepted",
+                                peer
+                            )),
+                            Err(e) => ControlResponse::error(format!("Invalid PeerId: {}", e)),
+                        },
+                        ControlRequest::Reject { peer_id } => match PeerId::from_str(&peer_id) {
+                            Ok(peer) if take_pending(&peer, &mut pending, &mut unnamed) => {
+                                info!("Rejected {}", peer);
+                                let _ = swarm.disconnect_peer_id(peer);
+                                ControlResponse::ok(serde_json::Value::Null)
+                            }
+                            Ok(peer) => ControlResponse::error(format!(
+                                "{} is not waiting to be accepted",
+                                peer
+                            )),
+                            Err(e) => ControlResponse::error(format!("Invalid PeerId: {}", e)),
+                        },
+                        ControlRequest::Sas { peer_id } => match PeerId::from_str(&peer_id) {
+                            Ok(peer) => {
+                                let sas = ShortAuthString::new(&local_peer_id, &peer);
+                                ControlResponse::ok(serde_json::json!({
+                                    "emoji": sas.emoji(),
+                                    "words": sas.words(),
+                                    "verified": trust.is_verified(&peer_id),
+                                }))
+                            }
+                            Err(e) => ControlResponse::error(format!("Invalid PeerId: {}", e)),
+                        },
+                        ControlRequest::Verify { peer_id } => match trust.verify(&peer_id) {
+                            Ok(_) => {
+                                info!("Verified {}", peer_id);
+                                ControlResponse::ok(serde_json::Value::Null)
+                            }
+                            Err(e) => ControlResponse::error(e.to_string()),
+                        },
+                        ControlRequest::Invite { expires_in_secs, once } => {
+                            match make_invite(
+                                &local_key,
+                                &invite_relay_address,
+                                relay_port,
+                                expires_in_secs.map(Duration::from_secs),
+                                once,
+                            ) {
+                                Ok(invite) => ControlResponse::ok(serde_json::json!({
+                                    "invite": invite.to_string(),
+                                    "expires": invite.token.map(|t| t.expires),
+                                })),
+                                Err(e) => ControlResponse::error(e.to_string()),
+                            }
+                        }
+                        ControlRequest::History { limit } => match storage.history() {
+                            Ok(records) => {
+                                let skip = records.len().saturating_sub(limit);
+                                ControlResponse::ok(serde_json::json!(records[skip..]))
+                            }
+                            Err(e) => ControlResponse::error(e.to_string()),
+                        },
+                        ControlRequest::Panic => {
+                            let frames: Vec<MidiFrame> = midi::all_notes_off()
+                                .into_iter()
+                                .map(|m| sequencer.frame(m))
+                                .collect();
+                            broadcast(
+                                &mut swarm,
+                                &mut outbound,
+                                &connected_peers,
+                                &frames,
+                                &metrics,
+                                &mut summary,
+                                &reporter,
+                            );
+                            status.publish(StatusEvent::Panic);
+                            ControlResponse::ok(serde_json::Value::Null)
+                        }
+                        ControlRequest::SetTempo { tempo } if !TEMPO_RANGE.contains(&tempo) => {
+                            ControlResponse::error(format!(
+                                "Tempo must be between {} and {} BPM",
+                                TEMPO_RANGE.start(),
+                                TEMPO_RANGE.end()
+                            ))
+                        }
+                        ControlRequest::SetTempo { tempo } => {
+                            let (change, timer) = change_tempo(&transport, tempo);
+                            tempo_change = change;
+                            tempo_timer = timer;
+                            ControlResponse::ok(serde_json::json!({ "tempo": tempo }))
+                        }
+                        ControlRequest::StartTogether { count_in: beats } => {
+                            let tempo = transport.state().tempo;
+                            let beats = beats.min(MAX_COUNT_IN);
+                            let delay =
+                                START_LEAD + Duration::from_secs_f64(beats as f64 * 60.0 / tempo);
+                            let start = ScheduledStart {
+                                delay_us: delay.as_micros() as u64,
+                                count_in: beats,
+                                tempo,
+                            };
+                            let frames = [sequencer.frame(start.to_sysex())];
+                            broadcast(
+                                &mut swarm,
+                                &mut outbound,
+                                &connected_peers,
+                                &frames,
+                                &metrics,
+                                &mut summary,
+                                &reporter,
+                            );
+                            let schedule = CountIn {
+                                start: Instant::now() + delay,
+                                beats,
+                                tempo,
+                            };
+                            count_in_timer = next_count_in(&schedule);
+                            count_in = Some(schedule);
+                            ControlResponse::ok(serde_json::json!({
+                                "starts_in_secs": delay.as_secs_f64()
+                            }))
+                        }
+                        ControlRequest::TapTempo => match tap_tempo.tap(Instant::now()) {
+                            Some(tempo) => {
+                                let (change, timer) = change_tempo(&transport, tempo);
+                                tempo_change = change;
+                                tempo_timer = timer;
+                                ControlResponse::ok(serde_json::json!({ "tempo": tempo }))
+                            }
+                            None => ControlResponse::ok(serde_json::Value::Null),
+                        },
+                        ControlRequest::RecordStart { path } => {
+                            if let Some((recorder, path)) = &recording {
+                                save_recording(recorder, path, &mut saved_events, &reporter);
+                            }
+                            status.publish(StatusEvent::Recording {
+                                recording: true,
+                                path: Some(path.clone()),
+                            });
+                            recording = Some(start_recording(&storage, path));
+                            saved_events = 0;
+                            ControlResponse::ok(serde_json::Value::Null)
+                        }
+                        ControlRequest::RecordStop => match recording.take() {
+                            Some((recorder, path)) => {
+                                save_recording(&recorder, &path, &mut saved_events, &reporter);
+                                info!("Stopped recording to {:?}", path);
+                                status.publish(StatusEvent::Recording {
+                                    recording: false,
+                                    path: None,
+                                });
+                                saved_events = 0;
+                                ControlResponse::ok(serde_json::Value::Null)
+                            }
+                            None => ControlResponse::error("Not recording"),
+                        },
+                        ControlRequest::PresetSave { name } => {
+                            let saved = storage.presets().and_then(|mut presets| {
+                                presets.insert(name.clone(), router.configs().clone());
+                                storage.save_presets(&presets)
+                            });
+                            match saved {
+                                Ok(()) => {
+                                    info!("Saved preset {:?}", name);
+                                    ControlResponse::ok(serde_json::Value::Null)
+                                }
+                                Err(e) => ControlResponse::error(e.to_string()),
+                            }
+                        }
+                        ControlRequest::PresetLoad { name } => match storage.presets() {
+                            Ok(mut presets) => match presets.remove(&name) {
+                                Some(configs) => {
+                                    router.set_configs(configs);
+                                    info!("Switched to preset {:?}", name);
+                                    ControlResponse::ok(serde_json::Value::Null)
+                                }
+                                None => {
+                                    ControlResponse::error(format!("No preset named {:?}", name))
+                                }
+                            },
+                            Err(e) => ControlResponse::error(e.to_string()),
+                        },
+                        ControlRequest::PresetList => match storage.presets() {
+                            Ok(presets) => ControlResponse::ok(serde_json::json!(presets
+                                .keys()
+                                .collect::<Vec<&String>>())),
+                            Err(e) => ControlResponse::error(e.to_string()),
+                        },
+                        ControlRequest::FileSend { peer_id, path } => match PeerId::from_str(&peer_id) {
+                            Ok(peer) if connected_peers.contains(&peer) => {
+                                match transfer::read_file(&path) {
+                                    Ok(_) if offered_files.len() >= transfer::MAX_PENDING_OFFERS => {
+                                        ControlResponse::error("Too many files waiting to be accepted")
+                                    }
+                                    Ok((name, data)) => {
+                                        next_file_id += 1;
+                                        let id = next_file_id;
+                                        info!("Offering {} to {}", name, peer);
+                                        swarm.behaviour_mut().transfer.send_request(
+                                            &peer,
+                                            TransferRequest::Offer {
+                                                id,
+                                                name: name.clone(),
+                                                size: data.len() as u64,
+                                            },
+                                        );
+                                        offered_files.insert(id, (peer, name, data));
+                                        ControlResponse::ok(serde_json::json!({ "id": id }))
+                                    }
+                                    Err(e) => ControlResponse::error(e),
+                                }
+                            }
+                            Ok(peer) => ControlResponse::error(format!("Not connected to {}", peer)),
+                            Err(e) => ControlResponse::error(format!("Invalid PeerId: {}", e)),
+                        },
+                        ControlRequest::FileAccept { peer_id, id } => {
+                            match take_offer(&mut file_offers, &peer_id, id) {
+                                Some(peer) => {
+                                    let request = TransferRequest::Fetch { id };
+                                    swarm.behaviour_mut().transfer.send_request(&peer, request);
+                                    ControlResponse::ok(serde_json::Value::Null)
+                                }
+                                None => ControlResponse::error(format!(
+                                    "{} offered no file {}",
+                                    peer_id, id
+                                )),
+                            }
+                        }
+                        ControlRequest::FileDecline { peer_id, id } => {
+                            match take_offer(&mut file_offers, &peer_id, id) {
+                                Some(peer) => {
+                                    let request = TransferRequest::Decline { id };
+                                    swarm.behaviour_mut().transfer.send_request(&peer, request);
+                                    ControlResponse::ok(serde_json::Value::Null)
+                                }
+                                None => ControlResponse::error(format!(
+                                    "{} offered no file {}",
+                                    peer_id, id
+                                )),
+                            }
+                        }
+                        ControlRequest::FileList => {
+                            ControlResponse::ok(serde_json::json!(file_offers))
+                        }
+                        ControlRequest::PeerSet {
+                            peer_id,
+                            volume,
+                            muted,
+                            timing,
+                        } => {
+                            match router.resolve(&peer_id) {
+                                Some(peer_id) => {
+                                    router.update_peer(&peer_id, |config| {
+                                        if volume.is_some() {
+                                            config.volume = volume;
+                                        }
+                                        if let Some(muted) = muted {
+                                            config.muted = muted;
+                                        }
+                                        if timing.is_some() {
+                                            config.timing = timing;
+                                        }
+                                    });
+                                    ControlResponse::ok(serde_json::Value::Null)
+                                }
+                                None => ControlResponse::error(format!(
+                                    "Not connected to {}",
+                                    peer_id
+                                )),
+                            }
+                        }
+                        ControlRequest::PresetDelete { name } => {
+                            let deleted = storage.presets().and_then(|mut presets| {
+                                match presets.remove(&name) {
+                                    Some(_) => storage.save_presets(&presets).map(|_| true),
+                                    None => Ok(false),
+                                }
+                            });
+                            match deleted {
+                                Ok(true) => ControlResponse::ok(serde_json::Value::Null),
+                                Ok(false) => {
+                                    ControlResponse::error(format!("No preset named {:?}", name))
+                                }
+                                Err(e) => ControlResponse::error(e.to_string()),
+                            }
+                        }
+                    };
+                    let _ = reply.send(response);
                 }
-                SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
-                    println!("Outgoing connection error to {:?}: {:?}", peer_id, error);
+                peer_id = admissions.select_next_some() => {
+                    let name = match router.route(&peer_id) {
+                        Some(route) if swarm.is_connected(&peer_id) => route.display_name.clone(),
+                        _ => continue,
+                    };
+                    let relay = relays.contains_key(&peer_id);
+                    if !relay {
+                        let announced = peer_names.get(&peer_id).map(|n| n.as_str());
+                        if let Err(e) = trust.accept(&peer_id.to_string(), announced) {
+                            warn!("Could not save {} as a known peer: {}", peer_id, e);
+                        }
+                    }
+                    if !relay && !trust.is_verified(&peer_id.to_string()) {
+                        let sas = ShortAuthString::new(&local_peer_id, &peer_id);
+                        reporter.report(Report::Verify {
+                            peer_id: peer_id.to_string(),
+                            name: name.clone(),
+                            emoji: sas.emoji(),
+                            words: sas.words(),
+                        });
+                    }
+                    // The peer we dialed with an invite token expects it first thing
+                    if let (Some(token), true) = (&invite_token, Some(peer_id) == dial_target) {
+                        if let Some(queue) = outbound.get_mut(&peer_id) {
+                            let frames = [sequencer.frame(token.to_sysex())];
+                            send_midi(&mut swarm, queue, &peer_id, &frames, &reporter);
+                        }
+                    }
+                    // With several relays, agree with the peer on which to go through
+                    if !relay && relays.len() > 1 {
+                        let hint = RelayHint::measure(relays.keys(), &rtts, &reserved);
+                        swarm.behaviour_mut().relay_hint.send_request(&peer_id, hint);
+                    }
+                    if connected_peers.insert(peer_id) {
+                        bridges.send(BridgeEvent::PeerJoined {
+                            peer_id: peer_id.to_string(),
+                            name: name.clone(),
+                        });
+                        status.publish(StatusEvent::PeerJoined {
+                            peer_id: peer_id.to_string(),
+                            name: name.clone(),
+                        });
+                        if !recent.peers.contains(&name) {
+                            recent.peers.push(name.clone());
+                            remember_session(&storage, &recent);
+                        }
+                        metrics.peer_connected();
+                        summary.peer_connected(
+                            &peer_id.to_string(),
+                            &name,
+                            transports.get(&peer_id).copied().unwrap_or("unknown"),
+                        );
+                    }
+                    reporter.report(Report::Peers {
+                        peers: connected_peers.iter().map(|p| p.to_string()).collect(),
+                    });
+                },
+                line = typed.select_next_some() => {
+                    let action = KeyBinding::from_terminal(&line)
+                        .and_then(|key| actions.action_for(&key));
+                    match action {
+                        Some(Action::Panic) => {
+                            let frames: Vec<MidiFrame> = midi::all_notes_off()
+                                .into_iter()
+                                .map(|m| sequencer.frame(m))
+                                .collect();
+                            broadcast(
+                                &mut swarm,
+                                &mut outbound,
+                                &connected_peers,
+                                &frames,
+                                &metrics,
+                                &mut summary,
+                                &reporter,
+                            );
+                            status.publish(StatusEvent::Panic);
+                            info!("Sent all notes off");
+                            continue;
+                        }
+                        Some(Action::Mute) => {
+                            muted = !muted;
+                            info!("Muted: {}", muted);
+                            continue;
+                        }
+                        Some(Action::Connect) => {
+                            if target.is_none() && addresses.is_empty() {
+                                info!("No peer to connect to");
+                            }
+                            for target in target.iter().chain(&addresses) {
+                                let dialed = dial_address(&relay_address, target, port).and_then(
+                                    |address| swarm.dial(address).map_err(|e| e.to_string()),
+                                );
+                                if let Err(e) = dialed {
+                                    warn!("Could not dial {}: {}", target, e);
+                                }
+                            }
+                            continue;
+                        }
+                        Some(action) => {
+                            debug!("{} is not available on the terminal", action.name());
+                            continue;
+                        }
+                        None => {}
+                    }
+                    let peer_id = match pending.pop_front() {
+                        Some(peer_id) => peer_id,
+                        None => continue,
+                    };
+                    match line.trim().to_lowercase().as_str() {
+                        "y" | "yes" => {
+                            let _ = admit.unbounded_send(peer_id);
+                        }
+                        _ => {
+                            info!("Rejected {}", peer_id);
+                            let _ = swarm.disconnect_peer_id(peer_id);
+                        }
+                    }
+                    if let Some(next) = pending.front() {
+                        reporter.report(Report::UntrustedPeer {
+                            peer_id: next.to_string(),
+                            name: peer_names.get(next).cloned(),
+                        });
+                    }
+                },
+                _ = shutdown.select_next_some() => {
+                    info!("Shutting down");
+                    break;
                 }
-                _ => {}
             }
         }
-    })
-}
 
-fn generate_ed25519(secret_key_seed: u8) -> identity::Keypair {
-    let mut bytes = [0u8; 32];
-    bytes[0] = secret_key_seed;
+        history.close_all();
 
-    identity::Keypair::ed25519_from_bytes(bytes).expect("only errors on wrong length")
+        if let Some((recorder, path)) = &recording {
+            save_recording(recorder, path, &mut saved_events, &reporter);
+            status.publish(StatusEvent::Recording {
+                recording: false,
+                path: None,
+            });
+        }
+        if let (Some((recorder, path)), Some(retention)) = (&auto_recording, &auto_record) {
+            save_auto_recording(&storage, recorder, path, &mut auto_saved_events, &reporter);
+            prune_auto_recordings(&storage, retention);
+        }
+        let report = summary.report();
+        if let Some(path) = &session_report {
+            let written = serde_json::to_string_pretty(&report)
+                .map_err(|e| e.to_string())
+                .and_then(|json| std::fs::write(path, json).map_err(|e| e.to_string()));
+            if let Err(e) = written {
+                reporter.report(Report::Error {
+                    message: format!("Could not write session report to {:?}: {}", path, e),
+                });
+            }
+        }
+        reporter.report(Report::Session(report));
+        Ok(())
+    });
+    match runtime::block_on(session) {
+        Ok(result) => Ok(result?),
+        Err(e) => Err(Failure::Runtime(format!("Session ended unexpectedly: {}", e)).into()),
+    }
 }
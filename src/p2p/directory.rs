@@ -0,0 +1,310 @@
+//! Sessions open to join on a relay. Peers that publish their session register its name with the
+//! relay while they are connected to it, and anyone can list the sessions registered, with the
+//! peers in each to dial. Relays only keep a directory when started with it, and can ask for a
+//! token before answering.
+
+use async_trait::async_trait;
+use futures::{future::FutureExt, stream::StreamExt};
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use libp2p::{
+    identity, ping, pnet::PreSharedKey, request_response, swarm::SwarmEvent, PeerId, StreamProtocol,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::time::Duration;
+use tracing::info_span;
+
+use super::archive::valid_session;
+use super::client::{bootstrap, build_swarm, relay_multiaddr, Event};
+use super::trust::agent_version;
+use crate::output::{Report, Reporter};
+use crate::runtime;
+
+/// Protocol sessions are registered and listed over.
+pub const DIRECTORY_PROTOCOL: StreamProtocol = StreamProtocol::new("/p2pmidi/directory/1.0.0");
+
+/// Largest request or listing accepted.
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/// Most peers listed in one session, and most sessions listed.
+const MAX_LISTED: usize = 256;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "request", rename_all = "snake_case")]
+pub enum DirectoryRequest {
+    /// List the sender in `session` for as long as it stays connected to the relay.
+    Register {
+        session: String,
+        token: Option<String>,
+    },
+    List {
+        token: Option<String>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "response", rename_all = "snake_case")]
+pub enum DirectoryResponse {
+    Registered,
+    Sessions { sessions: Vec<OpenSession> },
+    Refused { reason: String },
+}
+
+/// A session registered on the relay and who is in it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OpenSession {
+    pub session: String,
+    pub participants: usize,
+    /// PeerIds to dial through the relay to join.
+    pub peers: Vec<String>,
+}
+
+impl fmt::Display for OpenSession {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.participants {
+            1 => write!(f, "{} (1 peer)", self.session),
+            n => write!(f, "{} ({} peers)", self.session, n),
+        }
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+pub(crate) async fn read_json<T, M>(io: &mut T) -> io::Result<M>
+where
+    T: AsyncRead + Unpin + Send,
+    M: serde::de::DeserializeOwned,
+{
+    let mut len = [0u8; 4];
+    io.read_exact(&mut len).await?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_MESSAGE_SIZE {
+        return Err(invalid(&format!("Message of {} bytes is too large", len)));
+    }
+    let mut buffer = vec![0; len];
+    io.read_exact(&mut buffer).await?;
+    serde_json::from_slice(&buffer).map_err(|e| invalid(&e.to_string()))
+}
+
+pub(crate) async fn write_json<T, M>(io: &mut T, message: &M) -> io::Result<()>
+where
+    T: AsyncWrite + Unpin + Send,
+    M: Serialize,
+{
+    let body = serde_json::to_vec(message).map_err(|e| invalid(&e.to_string()))?;
+    io.write_all(&(body.len() as u32).to_be_bytes()).await?;
+    io.write_all(&body).await
+}
+
+/// Length prefixed JSON requests and responses, they are small and rare.
+#[derive(Debug, Clone, Default)]
+pub struct DirectoryCodec;
+
+#[async_trait]
+impl request_response::Codec for DirectoryCodec {
+    type Protocol = StreamProtocol;
+    type Request = DirectoryRequest;
+    type Response = DirectoryResponse;
+
+    async fn read_request<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+    ) -> io::Result<DirectoryRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_json(io).await
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+    ) -> io::Result<DirectoryResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_json(io).await
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        request: DirectoryRequest,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_json(io, &request).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        response: DirectoryResponse,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_json(io, &response).await
+    }
+}
+
+/// The sessions registered on a relay, by name.
+pub struct RelayDirectory {
+    token: Option<String>,
+    sessions: BTreeMap<String, BTreeSet<PeerId>>,
+}
+
+impl RelayDirectory {
+    pub fn new(token: Option<String>) -> Self {
+        RelayDirectory {
+            token,
+            sessions: BTreeMap::new(),
+        }
+    }
+
+    pub fn handle(&mut self, peer: &PeerId, request: DirectoryRequest) -> DirectoryResponse {
+        let token = match &request {
+            DirectoryRequest::Register { token, .. } | DirectoryRequest::List { token } => token,
+        };
+        if self.token.is_some() && *token != self.token {
+            return DirectoryResponse::Refused {
+                reason: "Wrong or missing relay token".to_string(),
+            };
+        }
+        match request {
+            DirectoryRequest::Register { session, .. } => self.register(peer, session),
+            DirectoryRequest::List { .. } => DirectoryResponse::Sessions {
+                sessions: self.list(),
+            },
+        }
+    }
+
+    fn register(&mut self, peer: &PeerId, session: String) -> DirectoryResponse {
+        if !valid_session(&session) {
+            return DirectoryResponse::Refused {
+                reason: format!("Invalid session name {:?}", session),
+            };
+        }
+        let full = match self.sessions.get(&session) {
+            Some(peers) => peers.len() >= MAX_LISTED,
+            None => self.sessions.len() >= MAX_LISTED,
+        };
+        if full {
+            return DirectoryResponse::Refused {
+                reason: "The directory is full".to_string(),
+            };
+        }
+        // A peer is in one session at a time
+        self.peer_left(peer);
+        self.sessions.entry(session).or_default().insert(*peer);
+        DirectoryResponse::Registered
+    }
+
+    fn list(&self) -> Vec<OpenSession> {
+        self.sessions
+            .iter()
+            .map(|(session, peers)| OpenSession {
+                session: session.clone(),
+                participants: peers.len(),
+                peers: peers.iter().map(|p| p.to_string()).collect(),
+            })
+            .collect()
+    }
+
+    /// Drop a peer that disconnected from the relay from its session.
+    pub fn peer_left(&mut self, peer: &PeerId) {
+        self.sessions.retain(|_, peers| {
+            peers.remove(peer);
+            !peers.is_empty()
+        });
+    }
+}
+
+/// Settings of a `p2pmidi sessions` run.
+#[derive(Clone, Debug)]
+pub struct ListOptions {
+    pub relay_address: String,
+    pub relay_port: u16,
+    pub relay_peer_id: Option<PeerId>,
+    pub use_ipv6: bool,
+    pub bind_address: Option<IpAddr>,
+    pub swarm_key: Option<PreSharedKey>,
+    pub token: Option<String>,
+}
+
+/// Ask the relay for the sessions registered on it.
+pub fn list_sessions(
+    options: ListOptions,
+    local_key: identity::Keypair,
+) -> Result<Vec<OpenSession>, Box<dyn Error>> {
+    let _list = info_span!("directory").entered();
+    let _runtime = runtime::enter();
+    let relay_address = relay_multiaddr(
+        &options.relay_address,
+        options.relay_port,
+        options.use_ipv6,
+        options.relay_peer_id,
+    )?;
+    let mut swarm = build_swarm(
+        &local_key,
+        ping::Config::new(),
+        agent_version(None),
+        options.swarm_key,
+    )?;
+    let relay_peer_id = bootstrap(&mut swarm, &relay_address, options.bind_address)?;
+    swarm.behaviour_mut().directory.send_request(
+        &relay_peer_id,
+        DirectoryRequest::List {
+            token: options.token,
+        },
+    );
+
+    let sessions: Result<Vec<OpenSession>, String> = runtime::block_on(async {
+        let mut deadline = futures_timer::Delay::new(Duration::from_secs(30)).fuse();
+        loop {
+            futures::select! {
+                event = swarm.select_next_some() => match event {
+                    SwarmEvent::Behaviour(Event::Directory(request_response::Event::Message {
+                        message: request_response::Message::Response { response, .. },
+                        ..
+                    })) => {
+                        return match response {
+                            DirectoryResponse::Sessions { sessions } => Ok(sessions),
+                            DirectoryResponse::Refused { reason } => Err(reason),
+                            DirectoryResponse::Registered => Err("Unexpected answer from the relay".to_string()),
+                        };
+                    }
+                    SwarmEvent::Behaviour(Event::Directory(request_response::Event::OutboundFailure {
+                        error,
+                        ..
+                    })) => return Err(format!("The relay keeps no session directory: {}", error)),
+                    _ => {}
+                },
+                _ = deadline => return Err("The relay did not answer".to_string()),
+            }
+        }
+    });
+    Ok(sessions?)
+}
+
+/// List the sessions registered on the relay, for `p2pmidi sessions`.
+pub fn run_list(
+    options: ListOptions,
+    local_key: identity::Keypair,
+    reporter: Reporter,
+) -> Result<(), Box<dyn Error>> {
+    let sessions = list_sessions(options, local_key)?;
+    reporter.report(Report::Sessions { sessions });
+    Ok(())
+}
@@ -0,0 +1,251 @@
+//! Append-only log of connection attempts in the data directory, to look back at who connected,
+//! how and for how long, and how the connection to each peer in the address book has been going.
+
+use libp2p::core::ConnectedPoint;
+use libp2p::multiaddr::Protocol;
+use libp2p::swarm::ConnectionId;
+use libp2p::{Multiaddr, PeerId};
+use std::collections::HashMap;
+use std::error::Error;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+use super::client::describe_transport;
+use super::invite::Invite;
+use crate::storage::{format_utc, ConnectionOutcome, ConnectionRecord, Direction, Storage};
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// A connection not logged yet, with the round trips measured over it.
+struct OpenConnection {
+    started: Instant,
+    record: ConnectionRecord,
+    rtt_total: Duration,
+    rtt_samples: u32,
+}
+
+/// Logs connections once they close, so their duration and latency are known, and failed
+/// attempts right away.
+pub struct ConnectionHistory {
+    storage: Storage,
+    open: HashMap<ConnectionId, OpenConnection>,
+}
+
+impl ConnectionHistory {
+    pub fn new(storage: &Storage) -> Self {
+        ConnectionHistory {
+            storage: storage.clone(),
+            open: HashMap::new(),
+        }
+    }
+
+    pub fn established(
+        &mut self,
+        connection_id: ConnectionId,
+        peer_id: &PeerId,
+        endpoint: &ConnectedPoint,
+    ) {
+        let direction = match endpoint {
+            ConnectedPoint::Dialer { .. } => Direction::Outbound,
+            ConnectedPoint::Listener { .. } => Direction::Inbound,
+        };
+        let address = endpoint.get_remote_address();
+        let record = ConnectionRecord {
+            at: now_secs(),
+            peer_id: Some(peer_id.to_string()),
+            direction,
+            address: Some(address.to_string()),
+            transport: describe_transport(address).to_string(),
+            outcome: ConnectionOutcome::Connected,
+            duration_secs: None,
+            rtt_ms: None,
+        };
+        self.open.insert(
+            connection_id,
+            OpenConnection {
+                started: Instant::now(),
+                record,
+                rtt_total: Duration::ZERO,
+                rtt_samples: 0,
+            },
+        );
+    }
+
+    /// Count a round trip to `peer_id` towards the average of its open connections.
+    pub fn rtt(&mut self, peer_id: &PeerId, rtt: Duration) {
+        let peer_id = peer_id.to_string();
+        for open in self.open.values_mut() {
+            if open.record.peer_id.as_deref() == Some(peer_id.as_str()) {
+                open.rtt_total += rtt;
+                open.rtt_samples += 1;
+            }
+        }
+    }
+
+    pub fn closed(&mut self, connection_id: ConnectionId) {
+        if let Some(mut open) = self.open.remove(&connection_id) {
+            open.record.duration_secs = Some(open.started.elapsed().as_secs_f64());
+            if open.rtt_samples > 0 {
+                open.record.rtt_ms =
+                    Some(open.rtt_total.as_secs_f64() * 1000.0 / open.rtt_samples as f64);
+            }
+            self.append(&open.record);
+        }
+    }
+
+    pub fn failed(
+        &mut self,
+        peer_id: Option<PeerId>,
+        direction: Direction,
+        address: Option<&Multiaddr>,
+        error: String,
+    ) {
+        self.append(&ConnectionRecord {
+            at: now_secs(),
+            peer_id: peer_id.map(|p| p.to_string()),
+            direction,
+            address: address.map(|a| a.to_string()),
+            transport: address.map_or("unknown", describe_transport).to_string(),
+            outcome: ConnectionOutcome::Failed { error },
+            duration_secs: None,
+            rtt_ms: None,
+        });
+    }
+
+    /// Log the connections still open when the session ends.
+    pub fn close_all(&mut self) {
+        let open: Vec<ConnectionId> = self.open.keys().copied().collect();
+        for connection_id in open {
+            self.closed(connection_id);
+        }
+    }
+
+    fn append(&self, record: &ConnectionRecord) {
+        if let Err(e) = self.storage.append_history(record) {
+            warn!("Could not write the connection history: {}", e);
+        }
+    }
+}
+
+/// The peer an address book entry leads to, from an invite, a PeerId or a multiaddr ending in
+/// /p2p/<PeerId>.
+fn entry_peer_id(entry: &str) -> Option<PeerId> {
+    if Invite::is_invite(entry) {
+        return entry.parse::<Invite>().ok().map(|invite| invite.peer_id);
+    }
+    if let Ok(peer_id) = entry.parse::<PeerId>() {
+        return Some(peer_id);
+    }
+    entry
+        .parse::<Multiaddr>()
+        .ok()?
+        .iter()
+        .filter_map(|p| match p {
+            Protocol::P2p(peer_id) => Some(peer_id),
+            _ => None,
+        })
+        .last()
+}
+
+fn format_duration(secs: f64) -> String {
+    let secs = secs as u64;
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3_599 => format!("{}m {:02}s", secs / 60, secs % 60),
+        _ => format!("{}h {:02}m", secs / 3_600, secs / 60 % 60),
+    }
+}
+
+/// One line summing up the connections to a peer.
+fn summary(records: &[ConnectionRecord]) -> String {
+    let connected: Vec<&ConnectionRecord> = records
+        .iter()
+        .filter(|r| r.outcome == ConnectionOutcome::Connected)
+        .collect();
+    let last = match records.last() {
+        Some(record) => record,
+        None => return "never connected".to_string(),
+    };
+    let rtts: Vec<f64> = connected.iter().filter_map(|r| r.rtt_ms).collect();
+    let rtt = match rtts.is_empty() {
+        true => String::new(),
+        false => format!(
+            ", {:.1} ms round trip on average",
+            rtts.iter().sum::<f64>() / rtts.len() as f64
+        ),
+    };
+    format!(
+        "{} connections for {}, {} failed{}, last {}",
+        connected.len(),
+        format_duration(connected.iter().filter_map(|r| r.duration_secs).sum()),
+        records.len() - connected.len(),
+        rtt,
+        format_utc(last.at)
+    )
+}
+
+/// Print the peers of the address book with how connecting to them went, or every connection to
+/// the one named `name`, to see whether it has been getting worse.
+pub fn run_peers(storage: &Storage, name: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let book = storage.address_book()?;
+    let history = storage.history()?;
+    let of_peer = |peer_id: &PeerId| -> Vec<ConnectionRecord> {
+        let peer_id = peer_id.to_string();
+        history
+            .iter()
+            .filter(|r| r.peer_id.as_deref() == Some(peer_id.as_str()))
+            .cloned()
+            .collect()
+    };
+    let name = match name {
+        Some(name) => name,
+        None => {
+            if book.is_empty() {
+                println!("No peers saved in the address book");
+            }
+            for (name, entry) in &book {
+                match entry_peer_id(entry) {
+                    Some(peer_id) => {
+                        println!("{}  {}\n    {}", name, peer_id, summary(&of_peer(&peer_id)))
+                    }
+                    None => println!("{}  {}", name, entry),
+                }
+            }
+            return Ok(());
+        }
+    };
+    let entry = book.get(name).map(String::as_str).unwrap_or(name);
+    let peer_id = entry_peer_id(entry)
+        .ok_or_else(|| format!("{:?} is not in the address book or a PeerId", name))?;
+    let records = of_peer(&peer_id);
+    println!("{}  {}\n{}\n", name, peer_id, summary(&records));
+    for record in &records {
+        let outcome = match &record.outcome {
+            ConnectionOutcome::Connected => format!(
+                "{:>8}  {}",
+                record
+                    .duration_secs
+                    .map(format_duration)
+                    .unwrap_or_default(),
+                record
+                    .rtt_ms
+                    .map(|rtt| format!("{:.1} ms", rtt))
+                    .unwrap_or_else(|| "-".to_string())
+            ),
+            ConnectionOutcome::Failed { error } => format!("failed: {}", error),
+        };
+        println!(
+            "{}  {:<8} {:<7} {}",
+            format_utc(record.at),
+            format!("{:?}", record.direction),
+            record.transport,
+            outcome
+        );
+    }
+    Ok(())
+}
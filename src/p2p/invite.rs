@@ -0,0 +1,333 @@
+use libp2p::{identity, PeerId};
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+use crate::storage::{Storage, UsedInvites};
+
+pub const INVITE_SCHEME: &str = "p2pmidi://";
+
+/// How long single use tokens stay valid when no expiry was asked for.
+pub const ONCE_VALIDITY: Duration = Duration::from_secs(24 * 3600);
+
+/// Non-commercial SysEx ID followed by `PJ`, marking the token a dialing peer joins with.
+pub(crate) const TOKEN_SYSEX_HEADER: [u8; 4] = [0xF0, 0x7D, b'P', b'J'];
+
+/// Expiry, nonce and flags, the part of a token that is signed.
+const CLAIMS_LEN: usize = 17;
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Lets whoever holds an invite in without being asked, until it expires or only once. Signed by
+/// the inviting node so it can't be forged or extended.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InviteToken {
+    /// Seconds since the unix epoch.
+    pub expires: u64,
+    pub nonce: u64,
+    pub once: bool,
+    signature: Vec<u8>,
+}
+
+impl InviteToken {
+    /// A token for the node of `keypair`, valid for `valid_for`.
+    pub fn issue(
+        keypair: &identity::Keypair,
+        valid_for: Duration,
+        once: bool,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut token = InviteToken {
+            expires: now_secs() + valid_for.as_secs(),
+            nonce: rand::random(),
+            once,
+            signature: Vec::new(),
+        };
+        let message = token.signed_message(&keypair.public().to_peer_id());
+        token.signature = keypair.sign(&message)?;
+        Ok(token)
+    }
+
+    fn claims(&self) -> [u8; CLAIMS_LEN] {
+        let mut claims = [0; CLAIMS_LEN];
+        claims[..8].copy_from_slice(&self.expires.to_be_bytes());
+        claims[8..16].copy_from_slice(&self.nonce.to_be_bytes());
+        claims[16] = self.once as u8;
+        claims
+    }
+
+    fn signed_message(&self, peer_id: &PeerId) -> Vec<u8> {
+        [
+            b"p2pmidi-invite/1".as_slice(),
+            &peer_id.to_bytes(),
+            &self.claims(),
+        ]
+        .concat()
+    }
+
+    /// Whether the node of `public` issued the token, expired or not.
+    pub fn is_signed_by(&self, public: &identity::PublicKey) -> bool {
+        public.verify(&self.signed_message(&public.to_peer_id()), &self.signature)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        now_secs() >= self.expires
+    }
+
+    /// The SysEx message presenting the token to the inviting node.
+    pub fn to_sysex(&self) -> Vec<u8> {
+        let mut message = TOKEN_SYSEX_HEADER.to_vec();
+        message.extend_from_slice(self.to_string().as_bytes());
+        message.push(0xF7);
+        message
+    }
+
+    /// Read a token presented by a peer, `None` for any other MIDI.
+    pub fn from_sysex(message: &[u8]) -> Option<Self> {
+        let body = message
+            .strip_prefix(&TOKEN_SYSEX_HEADER)?
+            .strip_suffix(&[0xF7])?;
+        std::str::from_utf8(body).ok()?.parse().ok()
+    }
+}
+
+impl fmt::Display for InviteToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in self.claims().iter().chain(&self.signature) {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for InviteToken {
+    type Err = String;
+
+    fn from_str(token: &str) -> Result<Self, Self::Err> {
+        if token.len() % 2 != 0 || token.len() <= CLAIMS_LEN * 2 {
+            return Err("Invalid invite token".to_string());
+        }
+        let bytes = (0..token.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(token.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()
+            .ok_or("Invalid invite token")?;
+        let (claims, signature) = bytes.split_at(CLAIMS_LEN);
+        Ok(InviteToken {
+            expires: u64::from_be_bytes(claims[..8].try_into().unwrap()),
+            nonce: u64::from_be_bytes(claims[8..16].try_into().unwrap()),
+            once: claims[16] != 0,
+            signature: signature.to_vec(),
+        })
+    }
+}
+
+/// Checks the tokens of joining peers, remembering single use tokens once used.
+pub struct TokenChecker {
+    public: identity::PublicKey,
+    storage: Storage,
+    used: UsedInvites,
+}
+
+impl TokenChecker {
+    pub fn load(public: identity::PublicKey, storage: &Storage) -> Result<Self, Box<dyn Error>> {
+        Ok(TokenChecker {
+            public,
+            storage: storage.clone(),
+            used: storage.used_invites()?,
+        })
+    }
+
+    /// Use up `token`, if it lets a peer in.
+    pub fn redeem(&mut self, token: &InviteToken) -> Result<(), String> {
+        if !token.is_signed_by(&self.public) {
+            return Err("Invite token was not issued by this node".to_string());
+        }
+        if token.is_expired() {
+            return Err("Invite token expired".to_string());
+        }
+        if token.once {
+            let nonce = format!("{:016x}", token.nonce);
+            if self.used.contains_key(&nonce) {
+                return Err("Invite token was already used".to_string());
+            }
+            // Expired tokens are turned away anyway
+            let now = now_secs();
+            self.used.retain(|_, expires| *expires > now);
+            self.used.insert(nonce, token.expires);
+            if let Err(e) = self.storage.save_used_invites(&self.used) {
+                warn!("Could not save used invite tokens: {}", e);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A link telling another node which relay to use and who to dial there, like
+/// `p2pmidi://p2pmidirelay.fly.dev:8040/12D3KooW...`, with `?token=...` for invites that expire.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Invite {
+    pub relay_address: String,
+    pub relay_port: u16,
+    pub peer_id: PeerId,
+    pub token: Option<InviteToken>,
+}
+
+impl Invite {
+    pub fn is_invite(target: &str) -> bool {
+        target.starts_with(INVITE_SCHEME)
+    }
+}
+
+impl FromStr for Invite {
+    type Err = String;
+
+    fn from_str(invite: &str) -> Result<Self, Self::Err> {
+        let rest = invite
+            .strip_prefix(INVITE_SCHEME)
+            .ok_or_else(|| format!("Invites start with {}", INVITE_SCHEME))?;
+        let (rest, token) = match rest.split_once('?') {
+            Some((rest, query)) => {
+                let token = query
+                    .strip_prefix("token=")
+                    .ok_or_else(|| format!("Unknown invite parameter: {}", query))?;
+                (rest, Some(token.parse()?))
+            }
+            None => (rest, None),
+        };
+        let (relay, peer_id) = rest
+            .trim_end_matches('/')
+            .split_once('/')
+            .ok_or("Invite is missing the PeerId")?;
+        let (host, port) = relay
+            .rsplit_once(':')
+            .ok_or("Invite is missing the relay port")?;
+        let relay_port = port
+            .parse()
+            .map_err(|_| format!("Invalid relay port in invite: {}", port))?;
+        let peer_id =
+            PeerId::from_str(peer_id).map_err(|e| format!("Invalid PeerId in invite: {}", e))?;
+        Ok(Invite {
+            relay_address: host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string(),
+            relay_port,
+            peer_id,
+            token,
+        })
+    }
+}
+
+impl fmt::Display for Invite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.relay_address.contains(':') {
+            write!(
+                f,
+                "{}[{}]:{}/{}",
+                INVITE_SCHEME, self.relay_address, self.relay_port, self.peer_id
+            )?;
+        } else {
+            write!(
+                f,
+                "{}{}:{}/{}",
+                INVITE_SCHEME, self.relay_address, self.relay_port, self.peer_id
+            )?;
+        }
+        match &self.token {
+            Some(token) => write!(f, "?token={}", token),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_storage() -> Storage {
+        let dir =
+            std::env::temp_dir().join(format!("p2pmidi-invite-{:016x}", rand::random::<u64>()));
+        Storage::new(Some(&dir))
+    }
+
+    #[test]
+    fn tokens_survive_text_and_sysex() {
+        let key = identity::Keypair::generate_ed25519();
+        let token = InviteToken::issue(&key, Duration::from_secs(600), true).unwrap();
+        let parsed: InviteToken = token.to_string().parse().unwrap();
+        assert_eq!(parsed, token);
+        assert_eq!(
+            InviteToken::from_sysex(&token.to_sysex()),
+            Some(token.clone())
+        );
+        assert!(parsed.is_signed_by(&key.public()));
+        assert_eq!(InviteToken::from_sysex(&[0x90, 60, 100]), None);
+
+        let invite = Invite {
+            relay_address: "::1".to_string(),
+            relay_port: 8040,
+            peer_id: key.public().to_peer_id(),
+            token: Some(token),
+        };
+        assert_eq!(invite.to_string().parse::<Invite>().unwrap(), invite);
+    }
+
+    #[test]
+    fn tampered_tokens_are_not_signed() {
+        let key = identity::Keypair::generate_ed25519();
+        let token = InviteToken::issue(&key, Duration::from_secs(600), true).unwrap();
+        let extended = InviteToken {
+            expires: token.expires + 3600,
+            ..token.clone()
+        };
+        assert!(!extended.is_signed_by(&key.public()));
+        let reusable = InviteToken {
+            once: false,
+            ..token.clone()
+        };
+        assert!(!reusable.is_signed_by(&key.public()));
+    }
+
+    #[test]
+    fn tokens_of_other_nodes_are_refused() {
+        let key = identity::Keypair::generate_ed25519();
+        let other = identity::Keypair::generate_ed25519();
+        let token = InviteToken::issue(&other, Duration::from_secs(600), false).unwrap();
+        let mut checker = TokenChecker::load(key.public(), &temp_storage()).unwrap();
+        assert!(checker.redeem(&token).is_err());
+    }
+
+    #[test]
+    fn expired_tokens_are_refused() {
+        let key = identity::Keypair::generate_ed25519();
+        let token = InviteToken::issue(&key, Duration::ZERO, false).unwrap();
+        assert!(token.is_expired());
+        let mut checker = TokenChecker::load(key.public(), &temp_storage()).unwrap();
+        assert!(checker.redeem(&token).is_err());
+    }
+
+    #[test]
+    fn single_use_tokens_work_once() {
+        let key = identity::Keypair::generate_ed25519();
+        let storage = temp_storage();
+        let once = InviteToken::issue(&key, Duration::from_secs(600), true).unwrap();
+        let reusable = InviteToken::issue(&key, Duration::from_secs(600), false).unwrap();
+        let mut checker = TokenChecker::load(key.public(), &storage).unwrap();
+        assert_eq!(checker.redeem(&once), Ok(()));
+        assert!(checker.redeem(&once).is_err());
+        assert_eq!(checker.redeem(&reusable), Ok(()));
+        assert_eq!(checker.redeem(&reusable), Ok(()));
+
+        // Also after a restart
+        let mut checker = TokenChecker::load(key.public(), &storage).unwrap();
+        assert!(checker.redeem(&once).is_err());
+        let _ = std::fs::remove_dir_all(storage.dir());
+    }
+}
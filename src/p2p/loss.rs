@@ -0,0 +1,73 @@
+use serde::Serialize;
+use std::collections::BTreeSet;
+
+/// How far back a late frame can still fill a gap. Older frames are counted as out of window.
+const REORDER_WINDOW: u32 = 256;
+
+/// Counts frames lost or delayed on the way from one peer, from their sequence numbers.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct LossStats {
+    pub received: u64,
+    /// Frames skipped in the sequence that have not shown up since.
+    pub lost: u64,
+    /// Frames that arrived after newer ones, filling a gap.
+    pub late: u64,
+    /// Frames too far behind the newest one to fill a gap.
+    pub out_of_window: u64,
+    pub duplicates: u64,
+}
+
+/// Follows the sequence numbers of the frames of one peer.
+#[derive(Debug, Clone, Default)]
+pub struct SequenceTracker {
+    next: Option<u32>,
+    missing: BTreeSet<u32>,
+    stats: LossStats,
+    /// Frames lost when last asked for the new losses.
+    reported_lost: u64,
+}
+
+impl SequenceTracker {
+    pub fn observe(&mut self, seq: u32) {
+        self.stats.received += 1;
+        let next = match self.next {
+            Some(next) => next,
+            None => {
+                self.next = Some(seq.wrapping_add(1));
+                return;
+            }
+        };
+
+        // Sequence numbers wrap around, so compare them by their distance
+        let ahead = seq.wrapping_sub(next) as i32;
+        if ahead >= 0 {
+            let ahead = ahead as u32;
+            for missing in ahead.saturating_sub(REORDER_WINDOW)..ahead {
+                self.missing.insert(next.wrapping_add(missing));
+            }
+            self.stats.lost += ahead as u64;
+            self.next = Some(seq.wrapping_add(1));
+            // Gaps too old to be filled stay lost
+            self.missing
+                .retain(|&m| seq.wrapping_sub(m) <= REORDER_WINDOW);
+        } else if self.missing.remove(&seq) {
+            self.stats.lost -= 1;
+            self.stats.late += 1;
+        } else if ahead.unsigned_abs() > REORDER_WINDOW {
+            self.stats.out_of_window += 1;
+        } else {
+            self.stats.duplicates += 1;
+        }
+    }
+
+    pub fn stats(&self) -> &LossStats {
+        &self.stats
+    }
+
+    /// Frames lost since the last call.
+    pub fn new_losses(&mut self) -> u64 {
+        let lost = self.stats.lost.saturating_sub(self.reported_lost);
+        self.reported_lost = self.stats.lost;
+        lost
+    }
+}
@@ -1,2 +1,31 @@
+pub mod archive;
+pub mod backpressure;
 pub mod client;
+#[cfg(test)]
+mod compat;
+pub mod directory;
+pub mod history;
+pub mod invite;
+pub mod loss;
+pub mod paths;
+pub mod play;
+pub mod playout;
+pub mod probe;
+pub mod protocol;
+#[cfg(test)]
+mod protocol_harness;
+pub mod ratelimit;
+#[cfg(feature = "relay")]
 pub mod relay;
+pub mod relay_config;
+pub mod relay_hint;
+pub mod sas;
+pub mod schema;
+pub mod selftest;
+pub mod simulate;
+pub mod summary;
+pub mod swarm_key;
+pub mod transfer;
+pub mod troubleshoot;
+pub mod trust;
+pub mod webhook;
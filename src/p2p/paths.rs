@@ -0,0 +1,108 @@
+//! How each connection to a peer gets there: its transport, whether it goes through the relay or
+//! was hole punched, the addresses at both ends and what encrypts it.
+
+use libp2p::core::ConnectedPoint;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use super::client::describe_transport;
+
+/// Route a connection takes to the peer.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Path {
+    Direct,
+    /// Through the relay, which passes the bytes along without being able to read them.
+    Relayed,
+    /// Direct, after starting relayed and punching through NAT.
+    HolePunched,
+}
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Path::Direct => write!(f, "direct"),
+            Path::Relayed => write!(f, "relayed"),
+            Path::HolePunched => write!(f, "hole punched"),
+        }
+    }
+}
+
+/// What encrypts a connection end to end. Every connection has it, there is no way to connect
+/// without.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Encryption {
+    /// Noise XX, on TCP and relayed connections.
+    Noise,
+    /// TLS 1.3, built into QUIC.
+    Tls,
+}
+
+impl fmt::Display for Encryption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Encryption::Noise => write!(f, "Noise"),
+            Encryption::Tls => write!(f, "TLS 1.3"),
+        }
+    }
+}
+
+/// One connection to a peer, as `ctl status` reports it under `connections`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ConnectionPath {
+    /// QUIC, TCP or relayed.
+    pub transport: String,
+    pub path: Path,
+    pub outbound: bool,
+    /// Address this end listens on, not known for connections we dialed.
+    pub local_address: Option<String>,
+    pub remote_address: String,
+    pub encryption: Encryption,
+    /// The connection is also wrapped in the swarm key of a private swarm.
+    pub private_swarm: bool,
+}
+
+impl ConnectionPath {
+    pub fn new(endpoint: &ConnectedPoint, hole_punched: bool, private_swarm: bool) -> Self {
+        let (local_address, remote_address) = match endpoint {
+            ConnectedPoint::Dialer { address, .. } => (None, address),
+            ConnectedPoint::Listener {
+                local_addr,
+                send_back_addr,
+            } => (Some(local_addr.to_string()), send_back_addr),
+        };
+        let transport = describe_transport(remote_address);
+        let path = match (transport, hole_punched) {
+            ("relayed", _) => Path::Relayed,
+            (_, true) => Path::HolePunched,
+            (_, false) => Path::Direct,
+        };
+        ConnectionPath {
+            transport: transport.to_string(),
+            path,
+            outbound: endpoint.is_dialer(),
+            local_address,
+            remote_address: remote_address.to_string(),
+            encryption: match transport {
+                "QUIC" => Encryption::Tls,
+                _ => Encryption::Noise,
+            },
+            private_swarm,
+        }
+    }
+}
+
+impl fmt::Display for ConnectionPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.path {
+            Path::Relayed => write!(f, "relayed")?,
+            path => write!(f, "{} {}", path, self.transport)?,
+        }
+        write!(f, ", {}", self.encryption)?;
+        if self.private_swarm {
+            write!(f, " in a private swarm")?;
+        }
+        Ok(())
+    }
+}
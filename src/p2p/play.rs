@@ -0,0 +1,206 @@
+use futures::{future::FutureExt, stream::StreamExt};
+use libp2p::{
+    core::multiaddr::Protocol, identity, ping, pnet::PreSharedKey, request_response,
+    swarm::SwarmEvent, PeerId,
+};
+use std::error::Error;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tracing::{info, info_span, warn};
+
+use super::client::{
+    bootstrap, build_swarm, describe_transport, dial_address, relay_multiaddr, Event,
+};
+use super::protocol::{FrameSequencer, MidiFrame, MAX_BATCH_FRAMES};
+use super::trust::agent_version;
+use crate::latency::{LatencyStats, Stage};
+use crate::midi;
+use crate::output::{Report, Reporter};
+use crate::runtime;
+use crate::smf;
+
+/// Settings of a `p2pmidi play` run.
+#[derive(Clone, Debug)]
+pub struct PlayOptions {
+    pub file: PathBuf,
+    /// A PeerId reached through the relay or a multiaddr ending in `/p2p/<PeerId>`.
+    pub target: String,
+    /// Report how late events were handed to the network.
+    pub measure_latency: bool,
+    pub relay_address: String,
+    pub relay_port: u16,
+    pub relay_peer_id: Option<PeerId>,
+    pub use_ipv6: bool,
+    pub bind_address: Option<IpAddr>,
+    pub swarm_key: Option<PreSharedKey>,
+}
+
+/// Stream a MIDI file to a peer in real time and return once it is done.
+pub fn run_play(options: PlayOptions, reporter: Reporter) -> Result<(), Box<dyn Error>> {
+    let _play = info_span!("play", to = %options.target).entered();
+    let _runtime = runtime::enter();
+    let events = smf::load_events(&options.file)?;
+    let total = events.last().map(|e| e.at).unwrap_or_default();
+    info!(
+        "Loaded {} MIDI messages ({:.1}s) from {}",
+        events.len(),
+        total.as_secs_f64(),
+        options.file.display()
+    );
+
+    let relay_address = relay_multiaddr(
+        &options.relay_address,
+        options.relay_port,
+        options.use_ipv6,
+        options.relay_peer_id,
+    )?;
+    let local_key = identity::Keypair::generate_ed25519();
+    let mut swarm = build_swarm(
+        &local_key,
+        ping::Config::new(),
+        agent_version(None),
+        options.swarm_key,
+    )?;
+    bootstrap(&mut swarm, &relay_address, options.bind_address)?;
+
+    let address = dial_address(&relay_address, &options.target, None)?;
+    let target_peer = address
+        .iter()
+        .filter_map(|p| match p {
+            Protocol::P2p(id) => Some(id),
+            _ => None,
+        })
+        .last()
+        .ok_or("The address must end with /p2p/<PeerId>")?;
+    swarm.dial(address)?;
+
+    let result: Result<(), String> = runtime::block_on(async {
+        loop {
+            match swarm.select_next_some().await {
+                SwarmEvent::ConnectionEstablished {
+                    peer_id, endpoint, ..
+                } if peer_id == target_peer => {
+                    info!(
+                        "Connected to {} via {}",
+                        peer_id,
+                        describe_transport(endpoint.get_remote_address())
+                    );
+                    break;
+                }
+                SwarmEvent::OutgoingConnectionError {
+                    peer_id: Some(peer_id),
+                    error,
+                    ..
+                } if peer_id == target_peer => {
+                    return Err(format!("Could not connect to {}: {}", peer_id, error));
+                }
+                _ => {}
+            }
+        }
+
+        let mut sequencer = FrameSequencer::default();
+        let mut stats = LatencyStats::default();
+        let mut pending = 0;
+        let start = Instant::now();
+        let mut last_progress = Duration::ZERO;
+        let mut next = 0;
+        while next < events.len() {
+            let now = start.elapsed();
+            if events[next].at <= now {
+                // Everything that is due goes out in one batch, as long as it fits
+                let mut frames: Vec<MidiFrame> = Vec::new();
+                while next < events.len()
+                    && events[next].at <= now
+                    && frames.len() < MAX_BATCH_FRAMES
+                {
+                    if options.measure_latency {
+                        stats.record(Stage::Send, start.elapsed() - events[next].at);
+                    }
+                    frames.push(sequencer.frame(events[next].message.clone()));
+                    next += 1;
+                }
+                swarm
+                    .behaviour_mut()
+                    .midi
+                    .send_request(&target_peer, frames);
+                pending += 1;
+
+                if now - last_progress >= Duration::from_secs(1) {
+                    last_progress = now;
+                    reporter.report(Report::Progress {
+                        position_s: now.as_secs_f64(),
+                        duration_s: total.as_secs_f64(),
+                    });
+                }
+                continue;
+            }
+
+            let mut wait = futures_timer::Delay::new(events[next].at - now).fuse();
+            futures::select! {
+                _ = wait => {}
+                event = swarm.select_next_some() => match event {
+                    SwarmEvent::Behaviour(Event::Midi(request_response::Event::Message {
+                        message: request_response::Message::Response { .. },
+                        ..
+                    })) => pending -= 1,
+                    SwarmEvent::Behaviour(Event::Midi(request_response::Event::OutboundFailure {
+                        error,
+                        ..
+                    })) => {
+                        pending -= 1;
+                        warn!("Error sending MIDI: {}", error);
+                    }
+                    SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. }
+                        if peer_id == target_peer =>
+                    {
+                        return Err(format!("Lost connection to {}", peer_id));
+                    }
+                    _ => {}
+                },
+            }
+        }
+
+        // Leave no notes hanging, then wait for the peer to get everything
+        let frames = midi::all_notes_off()
+            .into_iter()
+            .map(|m| sequencer.frame(m))
+            .collect();
+        swarm
+            .behaviour_mut()
+            .midi
+            .send_request(&target_peer, frames);
+        pending += 1;
+        let mut deadline = futures_timer::Delay::new(Duration::from_secs(10)).fuse();
+        while pending > 0 {
+            futures::select! {
+                event = swarm.select_next_some() => {
+                    if let SwarmEvent::Behaviour(Event::Midi(
+                        request_response::Event::Message {
+                            message: request_response::Message::Response { .. },
+                            ..
+                        }
+                        | request_response::Event::OutboundFailure { .. },
+                    )) = event
+                    {
+                        pending -= 1;
+                    }
+                }
+                _ = deadline => {
+                    warn!("{} MIDI batches were not acknowledged", pending);
+                    break;
+                }
+            }
+        }
+        reporter.report(Report::Progress {
+            position_s: total.as_secs_f64(),
+            duration_s: total.as_secs_f64(),
+        });
+        for report in stats.reports() {
+            reporter.report(report);
+        }
+        Ok(())
+    });
+    result?;
+    Ok(())
+}
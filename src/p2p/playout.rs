@@ -0,0 +1,264 @@
+//! How MIDI received from a peer is played, picked by the round trip time to it. Peers close by
+//! play live, further ones through a jitter buffer so their timing stays even, and far ones whole
+//! bars of the session tempo late so everyone plays along with the previous bar, like NINJAM.
+//! Local thru goes through here too when it is matched to what the peers hear.
+
+use bytes::Bytes;
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures::StreamExt;
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::bridge::BridgeEvent;
+use crate::runtime;
+use crate::transport::{unix_micros, TransportState};
+
+/// Round trip times under this play live.
+const LIVE_BELOW: Duration = Duration::from_millis(15);
+
+/// Round trip times under this go through the jitter buffer, longer ones are bars late.
+const BUFFERED_BELOW: Duration = Duration::from_millis(60);
+
+/// How far past a threshold the round trip time must go to switch modes, so they don't flap.
+const HYSTERESIS: Duration = Duration::from_millis(3);
+
+/// Delay on top of the fastest transit in buffered mode, taking up the jitter.
+pub const JITTER_BUFFER: Duration = Duration::from_millis(20);
+
+/// Bars are counted in 4/4.
+pub const BEATS_PER_BAR: f64 = 4.0;
+
+pub const DEFAULT_DELAY_BARS: u32 = 1;
+
+/// MIDI this close after the previous message of a peer plays with it in forgiving timing.
+const CHORD_WINDOW: Duration = Duration::from_millis(8);
+
+/// MIDI this close to a sixteenth of the session beat grid is pulled halfway to it in forgiving
+/// timing.
+const GRID_WINDOW: Duration = Duration::from_millis(20);
+
+#[derive(clap::ValueEnum, Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LatencyMode {
+    /// As soon as it arrives.
+    Live,
+    /// Late by the same amount every time, evening out the jitter.
+    Buffered,
+    /// Whole bars of the session tempo after it was played.
+    Bar,
+}
+
+impl LatencyMode {
+    /// The mode for a round trip time, sticking to `current` around the thresholds.
+    pub fn for_rtt(rtt: Duration, current: Option<LatencyMode>) -> Self {
+        let (live_below, buffered_below) = match current {
+            None => (LIVE_BELOW, BUFFERED_BELOW),
+            Some(LatencyMode::Live) => (LIVE_BELOW + HYSTERESIS, BUFFERED_BELOW + HYSTERESIS),
+            Some(LatencyMode::Buffered) => (LIVE_BELOW - HYSTERESIS, BUFFERED_BELOW + HYSTERESIS),
+            Some(LatencyMode::Bar) => (LIVE_BELOW - HYSTERESIS, BUFFERED_BELOW - HYSTERESIS),
+        };
+        if rtt < live_below {
+            LatencyMode::Live
+        } else if rtt < buffered_below {
+            LatencyMode::Buffered
+        } else {
+            LatencyMode::Bar
+        }
+    }
+
+    /// When to play a message that would have arrived at `arrival` without any delay on the way,
+    /// or `None` to play it right away. In bar mode it is `bars` bars late.
+    pub fn due(
+        &self,
+        arrival: Instant,
+        rtt: Duration,
+        transport: &TransportState,
+        bars: u32,
+    ) -> Option<Instant> {
+        match self {
+            LatencyMode::Live => None,
+            LatencyMode::Buffered => Some(arrival + JITTER_BUFFER),
+            LatencyMode::Bar => {
+                // Arrival is half a round trip after it was played
+                let played = arrival.checked_sub(rtt / 2).unwrap_or(arrival);
+                let beats = bars.max(1) as f64 * BEATS_PER_BAR;
+                match transport.playing {
+                    // The same place on the beat grid, even across tempo changes
+                    true => {
+                        let now = Instant::now();
+                        let now_us = unix_micros();
+                        let played_us = now_us.saturating_sub(
+                            now.saturating_duration_since(played).as_micros() as u64,
+                        );
+                        let due_us = transport.time_of_beat(transport.beat_at(played_us) + beats);
+                        Some(now + Duration::from_micros(due_us.saturating_sub(now_us)))
+                    }
+                    false => Some(played + Duration::from_secs_f64(beats * 60.0 / transport.tempo)),
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for LatencyMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LatencyMode::Live => write!(f, "live"),
+            LatencyMode::Buffered => write!(f, "buffered"),
+            LatencyMode::Bar => write!(f, "bar"),
+        }
+    }
+}
+
+/// How closely MIDI from a peer follows the timing it was played with.
+#[derive(clap::ValueEnum, Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Timing {
+    /// Exactly as the timestamps of the peer say.
+    #[default]
+    Tight,
+    /// Notes played together land together, and while the transport plays, near the beat grid.
+    Forgiving,
+}
+
+impl fmt::Display for Timing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Timing::Tight => write!(f, "tight"),
+            Timing::Forgiving => write!(f, "forgiving"),
+        }
+    }
+}
+
+/// Evens out the micro-timing of MIDI from a peer in forgiving timing.
+#[derive(Debug, Default)]
+pub struct TimingSmoother {
+    /// When the previous message was placed.
+    last: Option<Instant>,
+}
+
+impl TimingSmoother {
+    /// When to play MIDI due at `at`. Never earlier than the message before, so notes of the peer
+    /// keep their order.
+    pub fn place(&mut self, at: Instant, transport: &TransportState) -> Instant {
+        let at = match transport.playing {
+            true => pull_to_grid(at, transport),
+            false => at,
+        };
+        let at = match self.last {
+            Some(last) if at < last + CHORD_WINDOW => last,
+            _ => at,
+        };
+        self.last = Some(at);
+        at
+    }
+}
+
+/// `at` moved halfway to the nearest sixteenth of the beat grid, if it is close to one.
+fn pull_to_grid(at: Instant, transport: &TransportState) -> Instant {
+    let now = Instant::now();
+    let now_us = unix_micros();
+    let at_us = match at.checked_duration_since(now) {
+        Some(ahead) => now_us + ahead.as_micros() as u64,
+        None => now_us.saturating_sub(now.duration_since(at).as_micros() as u64),
+    };
+    let sixteenth = (transport.beat_at(at_us) * 4.0).round() / 4.0;
+    let offset = transport.time_of_beat(sixteenth) as i64 - at_us as i64;
+    if offset.unsigned_abs() > GRID_WINDOW.as_micros() as u64 {
+        return at;
+    }
+    let nudge = Duration::from_micros(offset.unsigned_abs() / 2);
+    match offset >= 0 {
+        true => at + nudge,
+        false => at.checked_sub(nudge).unwrap_or(at),
+    }
+}
+
+/// Whether the input device is also played on the monitoring output, and when.
+#[derive(clap::ValueEnum, Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Thru {
+    /// As soon as it is played.
+    Live,
+    /// As late as the peers hear it, so playing along sounds here like it does there.
+    Matched,
+}
+
+/// How late peers hear what is played here, the latest of them not playing it bars late. Each
+/// peer is taken to play us the way we play it, the round trip being the same both ways.
+pub fn session_latency(peers: impl Iterator<Item = (Duration, LatencyMode)>) -> Duration {
+    peers
+        .filter_map(|(rtt, mode)| match mode {
+            LatencyMode::Live => Some(rtt / 2),
+            LatencyMode::Buffered => Some(rtt / 2 + JITTER_BUFFER),
+            LatencyMode::Bar => None,
+        })
+        .max()
+        .unwrap_or_default()
+}
+
+/// Where MIDI goes once it is due.
+#[derive(Debug)]
+pub enum Route {
+    /// To the bridges, from a peer.
+    Peer(PeerId, BridgeEvent),
+    /// To the bridges, from the arpeggiator of a peer.
+    Arpeggio(PeerId, BridgeEvent),
+    /// Out of the monitoring output, from the input device.
+    Thru(Bytes),
+}
+
+/// Holds MIDI until it is due.
+pub struct Playout {
+    schedule: UnboundedSender<(Instant, Route)>,
+}
+
+impl Playout {
+    /// Messages come out of the receiver when they are due.
+    pub fn new() -> (Self, UnboundedReceiver<Route>) {
+        let (schedule, mut scheduled) = mpsc::unbounded::<(Instant, Route)>();
+        let (due, played) = mpsc::unbounded();
+        runtime::spawn(async move {
+            // By when they are due, then in the order they came
+            let mut queue: BTreeMap<(Instant, u64), Route> = BTreeMap::new();
+            let mut arrivals = 0u64;
+            loop {
+                let next = queue.keys().next().map(|(at, _)| *at);
+                let wait = async move {
+                    match next {
+                        Some(at) => tokio::time::sleep_until(at.into()).await,
+                        None => futures::future::pending().await,
+                    }
+                };
+                tokio::select! {
+                    item = scheduled.next() => match item {
+                        Some((at, route)) => {
+                            queue.insert((at, arrivals), route);
+                            arrivals += 1;
+                        }
+                        None => break,
+                    },
+                    _ = wait => {
+                        let now = Instant::now();
+                        while let Some(entry) = queue.first_entry() {
+                            if entry.key().0 > now {
+                                break;
+                            }
+                            if due.unbounded_send(entry.remove()).is_err() {
+                                return;
+                            }
+                        }
+                    }
+                }
+            }
+        });
+        (Playout { schedule }, played)
+    }
+
+    pub fn play_at(&self, at: Instant, route: Route) {
+        let _ = self.schedule.unbounded_send((at, route));
+    }
+}
@@ -0,0 +1,142 @@
+use futures::{future::FutureExt, stream::StreamExt};
+use libp2p::{
+    core::multiaddr::Protocol, dcutr, identity, ping, pnet::PreSharedKey, swarm::SwarmEvent, PeerId,
+};
+use std::error::Error;
+use std::net::IpAddr;
+use std::time::Duration;
+use tracing::{info, info_span, warn};
+
+use super::client::{
+    bootstrap, build_swarm, describe_transport, dial_address, relay_multiaddr, Event,
+};
+use super::trust::agent_version;
+use crate::output::{Report, Reporter};
+use crate::runtime;
+
+/// Settings of a `p2pmidi ping` run.
+#[derive(Clone, Debug)]
+pub struct ProbeOptions {
+    /// `relay`, a PeerId reached through the relay or a multiaddr ending in `/p2p/<PeerId>`.
+    pub target: String,
+    pub count: usize,
+    /// Wait for a direct connection before measuring.
+    pub hole_punch: bool,
+    pub timeout: Duration,
+    pub relay_address: String,
+    pub relay_port: u16,
+    pub relay_peer_id: Option<PeerId>,
+    pub use_ipv6: bool,
+    pub bind_address: Option<IpAddr>,
+    pub swarm_key: Option<PreSharedKey>,
+}
+
+/// Connect to a peer or the relay and measure round trip times, as a quick check before a session.
+pub fn run_probe(options: ProbeOptions, reporter: Reporter) -> Result<(), Box<dyn Error>> {
+    let _probe = info_span!("probe", target = %options.target).entered();
+    let _runtime = runtime::enter();
+    let relay_address = relay_multiaddr(
+        &options.relay_address,
+        options.relay_port,
+        options.use_ipv6,
+        options.relay_peer_id,
+    )?;
+    // A random identity so probing does not clash with a running session
+    let local_key = identity::Keypair::generate_ed25519();
+    let mut swarm = build_swarm(
+        &local_key,
+        ping::Config::new().with_interval(Duration::from_secs(1)),
+        agent_version(None),
+        options.swarm_key,
+    )?;
+
+    info!("Connecting to relay at {}", relay_address);
+    let relay_peer_id = bootstrap(&mut swarm, &relay_address, options.bind_address)?;
+    let to_relay = options.target == "relay";
+    let target_peer = match to_relay {
+        true => relay_peer_id,
+        false => {
+            let address = dial_address(&relay_address, &options.target, None)?;
+            let peer = address
+                .iter()
+                .filter_map(|p| match p {
+                    Protocol::P2p(id) => Some(id),
+                    _ => None,
+                })
+                .last()
+                .ok_or("The address must end with /p2p/<PeerId>")?;
+            swarm.dial(address)?;
+            peer
+        }
+    };
+
+    let mut transport = match to_relay {
+        true => describe_transport(&relay_address).to_string(),
+        false => "none".to_string(),
+    };
+    let mut measuring = to_relay || !options.hole_punch;
+    let mut rtts: Vec<Duration> = Vec::new();
+    let result: Result<(), String> = runtime::block_on(async {
+        let mut deadline = futures_timer::Delay::new(options.timeout).fuse();
+        while rtts.len() < options.count {
+            futures::select! {
+                event = swarm.select_next_some() => match event {
+                    SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. }
+                        if peer_id == target_peer =>
+                    {
+                        transport = describe_transport(endpoint.get_remote_address()).to_string();
+                        info!("Connected to {} via {}", peer_id, transport);
+                    }
+                    SwarmEvent::OutgoingConnectionError { peer_id: Some(peer_id), error, .. }
+                        if peer_id == target_peer =>
+                    {
+                        return Err(format!("Could not connect to {}: {}", peer_id, error));
+                    }
+                    SwarmEvent::Behaviour(Event::Dcutr(
+                        dcutr::Event::DirectConnectionUpgradeSucceeded { remote_peer_id },
+                    )) if remote_peer_id == target_peer => {
+                        info!("Hole punch to {} succeeded", remote_peer_id);
+                        measuring = true;
+                    }
+                    SwarmEvent::Behaviour(Event::Dcutr(
+                        dcutr::Event::DirectConnectionUpgradeFailed { remote_peer_id, error },
+                    )) if remote_peer_id == target_peer => {
+                        warn!("Hole punch to {} failed, measuring the relayed path: {}", remote_peer_id, error);
+                        measuring = true;
+                    }
+                    SwarmEvent::Behaviour(Event::Ping(ping::Event { peer, result, .. }))
+                        if peer == target_peer && measuring =>
+                    {
+                        match result {
+                            Ok(rtt) => rtts.push(rtt),
+                            Err(e) => warn!("Ping to {} failed: {}", peer, e),
+                        }
+                    }
+                    _ => {}
+                },
+                _ = deadline => break,
+            }
+        }
+        Ok(())
+    });
+    result?;
+
+    if rtts.is_empty() {
+        return Err(format!(
+            "No ping replies from {} within {}s",
+            options.target,
+            options.timeout.as_secs()
+        )
+        .into());
+    }
+    let millis: Vec<f64> = rtts.iter().map(|r| r.as_secs_f64() * 1000.0).collect();
+    reporter.report(Report::Ping {
+        target: options.target,
+        transport,
+        received: millis.len(),
+        min_ms: millis.iter().cloned().fold(f64::INFINITY, f64::min),
+        avg_ms: millis.iter().sum::<f64>() / millis.len() as f64,
+        max_ms: millis.iter().cloned().fold(0.0, f64::max),
+    });
+    Ok(())
+}
@@ -0,0 +1,429 @@
+use async_trait::async_trait;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use libp2p::{request_response, StreamProtocol};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::midi::MessageKind;
+
+/// Protocol used to stream MIDI between peers.
+pub const PROTOCOL: StreamProtocol = StreamProtocol::new("/p2pmidi/midi/1.0.0");
+
+/// The same protocol with a track label on every frame, preferred when both peers support it.
+pub const TRACKS_PROTOCOL: StreamProtocol = StreamProtocol::new("/p2pmidi/midi/2.0.0");
+
+/// Version byte leading every batch of frames.
+pub const WIRE_VERSION: u8 = 1;
+
+/// Version of batches carrying track labels.
+pub const TRACKS_WIRE_VERSION: u8 = 2;
+
+/// Largest encoded batch accepted from a peer.
+pub(crate) const MAX_BATCH_SIZE: usize = 1024 * 1024;
+
+/// Most frames in a batch, the count is sent as a `u16`.
+pub(crate) const MAX_BATCH_FRAMES: usize = u16::MAX as usize;
+
+/// Byte a receiver answers with once it got a batch.
+pub(crate) const ACK: u8 = 0x06;
+
+/// Buffers kept around for reuse by a codec and its clones.
+const MAX_POOLED_BUFFERS: usize = 16;
+
+/// A MIDI message as sent over the wire.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MidiFrame {
+    /// Increases by one for every frame a peer sends, to spot losses and reordering.
+    pub seq: u32,
+    /// Microseconds since the sender started its session.
+    pub timestamp_us: u64,
+    /// Usually a slice of a larger buffer shared with the other frames of its batch.
+    pub message: Bytes,
+    /// Label of the instrument or source the message was played on, empty for none.
+    pub track: Bytes,
+}
+
+impl MidiFrame {
+    pub fn on_track(self, track: &Bytes) -> Self {
+        MidiFrame {
+            track: track.clone(),
+            ..self
+        }
+    }
+
+    pub fn class(&self) -> EventClass {
+        EventClass::of(&self.message)
+    }
+
+    /// The track label, if there is one and it is text.
+    pub fn track_label(&self) -> Option<&str> {
+        match self.track.is_empty() {
+            true => None,
+            false => std::str::from_utf8(&self.track).ok(),
+        }
+    }
+}
+
+/// Classes of MIDI queued and dropped apart when a link can't carry all of it. The class is worked
+/// out from the message, so nothing more goes on the wire.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum EventClass {
+    /// Note on, note off and polyphonic aftertouch.
+    Note,
+    /// The sustain pedal.
+    Sustain,
+    PitchBend,
+    /// Other controllers, program changes and channel aftertouch.
+    Controller,
+    #[serde(rename = "sysex")]
+    SysEx,
+    /// MIDI clock, start and stop and the other system messages.
+    Clock,
+}
+
+impl EventClass {
+    pub const ALL: [EventClass; 6] = [
+        EventClass::Note,
+        EventClass::Sustain,
+        EventClass::PitchBend,
+        EventClass::Controller,
+        EventClass::SysEx,
+        EventClass::Clock,
+    ];
+
+    pub fn of(message: &[u8]) -> Self {
+        match MessageKind::of(message) {
+            Some(MessageKind::NoteOn | MessageKind::NoteOff | MessageKind::PolyAftertouch) => {
+                EventClass::Note
+            }
+            Some(MessageKind::ControlChange) if message.get(1) == Some(&64) => EventClass::Sustain,
+            Some(MessageKind::PitchBend) => EventClass::PitchBend,
+            Some(MessageKind::SysEx) => EventClass::SysEx,
+            Some(MessageKind::Clock | MessageKind::System) => EventClass::Clock,
+            _ => EventClass::Controller,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    UnsupportedVersion(u8),
+    Truncated,
+    TrailingBytes,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::UnsupportedVersion(v) => write!(f, "unsupported wire version {}", v),
+            DecodeError::Truncated => write!(f, "truncated frame"),
+            DecodeError::TrailingBytes => write!(f, "unexpected bytes after the last frame"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EncodeError {
+    TooManyFrames(usize),
+    MessageTooLong(usize),
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EncodeError::TooManyFrames(n) => {
+                write!(
+                    f,
+                    "{} frames don't fit in a batch of {}",
+                    n, MAX_BATCH_FRAMES
+                )
+            }
+            EncodeError::MessageTooLong(n) => {
+                write!(f, "MIDI message of {} bytes is longer than {}", n, u16::MAX)
+            }
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+/// Longest track label sent, longer ones are cut.
+const MAX_TRACK_LEN: usize = u8::MAX as usize;
+
+/// Size of one frame once encoded in `version`.
+pub(crate) fn frame_len(frame: &MidiFrame, version: u8) -> usize {
+    let track_len = match version {
+        TRACKS_WIRE_VERSION => 1 + frame.track.len().min(MAX_TRACK_LEN),
+        _ => 0,
+    };
+    14 + track_len + frame.message.len()
+}
+
+/// Size of a batch of frames once encoded in `version`.
+pub fn encoded_len(frames: &[MidiFrame], version: u8) -> usize {
+    3 + frames.iter().map(|f| frame_len(f, version)).sum::<usize>()
+}
+
+/// Encode a batch of frames at the end of `buffer`:
+/// `version: u8, count: u16, (seq: u32, timestamp_us: u64, len: u16, message: [u8; len])*`,
+/// all integers big endian. Version 2 frames end with `track_len: u8, track: [u8; track_len]`.
+///
+/// Fails with nothing written for more than [`MAX_BATCH_FRAMES`] frames or a message longer
+/// than `u16::MAX` bytes.
+pub fn encode_frames_into(
+    frames: &[MidiFrame],
+    version: u8,
+    buffer: &mut BytesMut,
+) -> Result<(), EncodeError> {
+    if frames.len() > MAX_BATCH_FRAMES {
+        return Err(EncodeError::TooManyFrames(frames.len()));
+    }
+    if let Some(frame) = frames.iter().find(|f| f.message.len() > u16::MAX as usize) {
+        return Err(EncodeError::MessageTooLong(frame.message.len()));
+    }
+    buffer.reserve(encoded_len(frames, version));
+    buffer.put_u8(version);
+    buffer.put_u16(frames.len() as u16);
+    for frame in frames {
+        buffer.put_u32(frame.seq);
+        buffer.put_u64(frame.timestamp_us);
+        buffer.put_u16(frame.message.len() as u16);
+        buffer.put_slice(&frame.message);
+        if version == TRACKS_WIRE_VERSION {
+            let track = &frame.track[..frame.track.len().min(MAX_TRACK_LEN)];
+            buffer.put_u8(track.len() as u8);
+            buffer.put_slice(track);
+        }
+    }
+    Ok(())
+}
+
+/// Encode a batch of frames into a buffer of its own.
+pub fn encode_frames(frames: &[MidiFrame], version: u8) -> Result<Bytes, EncodeError> {
+    let mut buffer = BytesMut::new();
+    encode_frames_into(frames, version, &mut buffer)?;
+    Ok(buffer.freeze())
+}
+
+fn need(bytes: &Bytes, n: usize) -> Result<(), DecodeError> {
+    match bytes.remaining() < n {
+        true => Err(DecodeError::Truncated),
+        false => Ok(()),
+    }
+}
+
+/// Decode a batch of frames written by `encode_frames` in either version. Messages and tracks are
+/// slices of `bytes`, nothing is copied.
+pub fn decode_frames(mut bytes: Bytes) -> Result<Vec<MidiFrame>, DecodeError> {
+    need(&bytes, 3)?;
+    let version = bytes.get_u8();
+    if version != WIRE_VERSION && version != TRACKS_WIRE_VERSION {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+    let count = bytes.get_u16();
+    // Never trust the count for preallocation, each frame takes at least 14 bytes
+    let mut frames = Vec::with_capacity((count as usize).min(bytes.len() / 14));
+    for _ in 0..count {
+        need(&bytes, 14)?;
+        let seq = bytes.get_u32();
+        let timestamp_us = bytes.get_u64();
+        let len = bytes.get_u16() as usize;
+        need(&bytes, len)?;
+        let message = bytes.split_to(len);
+        let track = match version {
+            TRACKS_WIRE_VERSION => {
+                need(&bytes, 1)?;
+                let len = bytes.get_u8() as usize;
+                need(&bytes, len)?;
+                bytes.split_to(len)
+            }
+            _ => Bytes::new(),
+        };
+        frames.push(MidiFrame {
+            seq,
+            timestamp_us,
+            message,
+            track,
+        });
+    }
+    if !bytes.is_empty() {
+        return Err(DecodeError::TrailingBytes);
+    }
+    Ok(frames)
+}
+
+/// Buffers reused across batches so steady streaming does not allocate for every one. A buffer
+/// handed out again reclaims its memory once every slice taken from it was dropped.
+#[derive(Debug, Clone, Default)]
+pub struct BufferPool {
+    buffers: Arc<Mutex<Vec<BytesMut>>>,
+}
+
+impl BufferPool {
+    /// An empty buffer with room for at least `capacity` bytes.
+    pub fn take(&self, capacity: usize) -> BytesMut {
+        let mut buffer = self
+            .buffers
+            .lock()
+            .ok()
+            .and_then(|mut buffers| buffers.pop())
+            .unwrap_or_default();
+        buffer.clear();
+        buffer.reserve(capacity);
+        buffer
+    }
+
+    pub fn put(&self, buffer: BytesMut) {
+        if let Ok(mut buffers) = self.buffers.lock() {
+            if buffers.len() < MAX_POOLED_BUFFERS {
+                buffers.push(buffer);
+            }
+        }
+    }
+}
+
+/// Copies small messages into shared blocks, so framing them allocates once per block rather
+/// than once per message.
+#[derive(Debug, Default)]
+pub struct MessageArena {
+    block: BytesMut,
+}
+
+/// Room taken whenever the arena needs a new block.
+const ARENA_BLOCK_SIZE: usize = 4096;
+
+impl MessageArena {
+    pub fn copy(&mut self, message: &[u8]) -> Bytes {
+        if self.block.capacity() < message.len() {
+            self.block.reserve(ARENA_BLOCK_SIZE.max(message.len()));
+        }
+        self.block.put_slice(message);
+        self.block.split().freeze()
+    }
+}
+
+/// Numbers and timestamps outgoing MIDI messages.
+pub struct FrameSequencer {
+    next_seq: u32,
+    start: Instant,
+}
+
+impl Default for FrameSequencer {
+    fn default() -> Self {
+        FrameSequencer {
+            next_seq: 0,
+            start: Instant::now(),
+        }
+    }
+}
+
+impl FrameSequencer {
+    pub fn frame(&mut self, message: impl Into<Bytes>) -> MidiFrame {
+        self.frame_at(message, Instant::now())
+    }
+
+    /// Frame a message played at `at`, which can be a little earlier than now.
+    pub fn frame_at(&mut self, message: impl Into<Bytes>, at: Instant) -> MidiFrame {
+        let frame = MidiFrame {
+            seq: self.next_seq,
+            timestamp_us: at.saturating_duration_since(self.start).as_micros() as u64,
+            message: message.into(),
+            track: Bytes::new(),
+        };
+        self.next_seq = self.next_seq.wrapping_add(1);
+        frame
+    }
+}
+
+/// Length prefixed batches of MIDI frames, acknowledged with a single byte.
+#[derive(Debug, Clone, Default)]
+pub struct MidiCodec {
+    buffers: BufferPool,
+}
+
+#[async_trait]
+impl request_response::Codec for MidiCodec {
+    type Protocol = StreamProtocol;
+    type Request = Vec<MidiFrame>;
+    type Response = ();
+
+    async fn read_request<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+    ) -> io::Result<Vec<MidiFrame>>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut len = [0u8; 4];
+        io.read_exact(&mut len).await?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_BATCH_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("MIDI batch of {} bytes is too large", len),
+            ));
+        }
+        let mut buffer = self.buffers.take(len);
+        buffer.resize(len, 0);
+        let read = io.read_exact(&mut buffer).await;
+        let bytes = buffer.split().freeze();
+        self.buffers.put(buffer);
+        read?;
+        decode_frames(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    async fn read_response<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<()>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut ack = [0u8; 1];
+        io.read_exact(&mut ack).await?;
+        match ack[0] {
+            ACK => Ok(()),
+            b => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unexpected acknowledgement byte {}", b),
+            )),
+        }
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        protocol: &StreamProtocol,
+        io: &mut T,
+        frames: Vec<MidiFrame>,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        // Peers on the first version get no track labels
+        let version = match *protocol == TRACKS_PROTOCOL {
+            true => TRACKS_WIRE_VERSION,
+            false => WIRE_VERSION,
+        };
+        let len = encoded_len(&frames, version);
+        let mut buffer = self.buffers.take(4 + len);
+        buffer.put_u32(len as u32);
+        let written = match encode_frames_into(&frames, version, &mut buffer) {
+            Ok(()) => io.write_all(&buffer).await,
+            Err(e) => Err(io::Error::new(io::ErrorKind::InvalidInput, e)),
+        };
+        self.buffers.put(buffer);
+        written
+    }
+
+    async fn write_response<T>(&mut self, _: &StreamProtocol, io: &mut T, _: ()) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        io.write_all(&[ACK]).await
+    }
+}
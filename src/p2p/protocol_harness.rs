@@ -0,0 +1,409 @@
+//! Runs MIDI through the real codec and protocol between swarms connected over the in-memory
+//! transport, without networking or MIDI hardware. The swarms only speak the MIDI protocol and
+//! frames are queued and routed by the tests themselves, the client loop behind `Session::start`
+//! isn't run.
+
+use futures::{
+    executor::block_on,
+    future::{select, select_all, Either},
+    FutureExt, StreamExt,
+};
+use libp2p::{
+    core::{transport::MemoryTransport, upgrade, Multiaddr, Transport},
+    identity, noise, request_response,
+    swarm::{NetworkBehaviour, Swarm, SwarmBuilder, SwarmEvent},
+    yamux, PeerId,
+};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use super::backpressure::{
+    BackpressurePolicy, ClassPolicy, DropPolicy, OutboundQueue, PriorityModel,
+};
+use super::loss::SequenceTracker;
+use super::protocol::{self, EncodeError, EventClass, FrameSequencer, MidiCodec, MidiFrame};
+use crate::midi;
+use crate::routing::{ChannelMapping, PeerConfig, PeerRoute};
+
+/// How long a test waits on the network before failing.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How late a batch may arrive after it was sent.
+const DELIVERY_TOLERANCE: Duration = Duration::from_millis(500);
+
+#[derive(NetworkBehaviour)]
+struct TestBehaviour {
+    midi: request_response::Behaviour<MidiCodec>,
+}
+
+/// A frame as it reached a node.
+struct Received {
+    from: PeerId,
+    /// Batches are numbered in the order they arrived on a node.
+    batch: usize,
+    at: Instant,
+    frame: MidiFrame,
+}
+
+/// What polling the network led to.
+enum Step {
+    Connected,
+    Received,
+    Other,
+}
+
+/// Nodes on the memory transport, all connected to each other, answering MIDI requests with the
+/// empty response clients give.
+struct TestNetwork {
+    nodes: Vec<Swarm<TestBehaviour>>,
+    received: HashMap<usize, Vec<Received>>,
+    batches: HashMap<usize, usize>,
+}
+
+fn memory_node(port: u64) -> Swarm<TestBehaviour> {
+    let key = identity::Keypair::generate_ed25519();
+    let peer_id = PeerId::from(key.public());
+    let transport = MemoryTransport::default()
+        .upgrade(upgrade::Version::V1)
+        .authenticate(noise::Config::new(&key).unwrap())
+        .multiplex(yamux::Config::default())
+        .boxed();
+    let behaviour = TestBehaviour {
+        midi: request_response::Behaviour::new(
+            [(protocol::PROTOCOL, request_response::ProtocolSupport::Full)],
+            request_response::Config::default(),
+        ),
+    };
+    let mut swarm = SwarmBuilder::without_executor(transport, behaviour, peer_id).build();
+    swarm
+        .listen_on(format!("/memory/{}", port).parse().unwrap())
+        .unwrap();
+    swarm
+}
+
+impl TestNetwork {
+    fn connected(size: usize) -> Self {
+        // Memory ports are shared by the whole process, keep tests running in parallel apart
+        let base = 1 + rand::random::<u32>() as u64 * 64;
+        let mut network = TestNetwork {
+            nodes: (0..size).map(|i| memory_node(base + i as u64)).collect(),
+            received: HashMap::new(),
+            batches: HashMap::new(),
+        };
+        for from in 0..size {
+            for to in from + 1..size {
+                let address: Multiaddr = format!("/memory/{}", base + to as u64).parse().unwrap();
+                network.nodes[from].dial(address).unwrap();
+            }
+        }
+        // Both ends see every connection
+        let mut connections = 0;
+        while connections < size * (size - 1) {
+            if let Step::Connected = network.step() {
+                connections += 1;
+            }
+        }
+        network
+    }
+
+    fn peer_id(&self, node: usize) -> PeerId {
+        *self.nodes[node].local_peer_id()
+    }
+
+    fn send(&mut self, from: usize, to: usize, frames: Vec<MidiFrame>) {
+        let peer = self.peer_id(to);
+        self.nodes[from]
+            .behaviour_mut()
+            .midi
+            .send_request(&peer, frames);
+    }
+
+    /// Poll every node until one of them has an event.
+    fn step(&mut self) -> Step {
+        let deadline = Instant::now() + TIMEOUT;
+        let (event, node) = block_on(async {
+            loop {
+                assert!(
+                    Instant::now() < deadline,
+                    "timed out waiting on the network"
+                );
+                let events = select_all(
+                    self.nodes
+                        .iter_mut()
+                        .map(|swarm| swarm.select_next_some().boxed_local()),
+                );
+                let wait = futures_timer::Delay::new(Duration::from_millis(100));
+                if let Either::Left(((event, node, _), _)) = select(events, wait).await {
+                    break (event, node);
+                }
+            }
+        });
+        match event {
+            SwarmEvent::ConnectionEstablished { .. } => Step::Connected,
+            SwarmEvent::Behaviour(TestBehaviourEvent::Midi(request_response::Event::Message {
+                peer,
+                message:
+                    request_response::Message::Request {
+                        request, channel, ..
+                    },
+            })) => {
+                let batch = self.batches.entry(node).or_default();
+                let at = Instant::now();
+                self.received
+                    .entry(node)
+                    .or_default()
+                    .extend(request.into_iter().map(|frame| Received {
+                        from: peer,
+                        batch: *batch,
+                        at,
+                        frame,
+                    }));
+                *batch += 1;
+                let _ = self.nodes[node]
+                    .behaviour_mut()
+                    .midi
+                    .send_response(channel, ());
+                Step::Received
+            }
+            _ => Step::Other,
+        }
+    }
+
+    /// Run until `node` got `count` frames and return them in arrival order.
+    fn receive(&mut self, node: usize, count: usize) -> &[Received] {
+        while self.received.get(&node).map_or(0, |r| r.len()) < count {
+            self.step();
+        }
+        &self.received[&node]
+    }
+}
+
+fn note_on(channel: u8, note: u8) -> Vec<u8> {
+    vec![0x90 | channel, note, 100]
+}
+
+/// Notes held on each channel of an output, following what a synth would do.
+#[derive(Default)]
+struct HeldNotes {
+    notes: HashSet<(u8, u8)>,
+    sustain: HashSet<u8>,
+    sustained: HashSet<(u8, u8)>,
+}
+
+impl HeldNotes {
+    fn play(&mut self, message: &[u8]) {
+        let channel = message[0] & 0x0F;
+        match (message[0] & 0xF0, message[1], message[2]) {
+            (0x90, note, velocity) if velocity > 0 => {
+                self.notes.insert((channel, note));
+            }
+            (0x80 | 0x90, note, _) => {
+                if self.notes.remove(&(channel, note)) && self.sustain.contains(&channel) {
+                    self.sustained.insert((channel, note));
+                }
+            }
+            (0xB0, 64, value) if value >= 64 => {
+                self.sustain.insert(channel);
+            }
+            (0xB0, 64, _) => {
+                self.sustain.remove(&channel);
+                self.sustained.retain(|(c, _)| *c != channel);
+            }
+            (0xB0, 120 | 123, _) => {
+                self.notes.retain(|(c, _)| *c != channel);
+                self.sustained.retain(|(c, _)| *c != channel);
+            }
+            _ => {}
+        }
+    }
+
+    fn is_silent(&self) -> bool {
+        self.notes.is_empty() && self.sustained.is_empty()
+    }
+}
+
+#[test]
+fn frames_arrive_once_and_in_order() {
+    let mut network = TestNetwork::connected(2);
+    let mut sequencer = FrameSequencer::default();
+    let batches = 50;
+    let per_batch = 8;
+    for batch in 0..batches {
+        let frames = (0..per_batch)
+            .map(|i| sequencer.frame(note_on(0, (batch * per_batch + i) as u8 % 128)))
+            .collect();
+        network.send(0, 1, frames);
+    }
+
+    let sender = network.peer_id(0);
+    let received = network.receive(1, batches * per_batch);
+    let mut tracker = SequenceTracker::default();
+    let mut by_batch: HashMap<usize, Vec<u32>> = HashMap::new();
+    for r in received {
+        assert_eq!(r.from, sender);
+        tracker.observe(r.frame.seq);
+        by_batch.entry(r.batch).or_default().push(r.frame.seq);
+    }
+    let stats = tracker.stats();
+    assert_eq!(stats.received, (batches * per_batch) as u64);
+    assert_eq!(stats.lost, 0);
+    assert_eq!(stats.duplicates, 0);
+    assert_eq!(stats.out_of_window, 0);
+    // Batches travel on their own streams, only the frames within one keep their order
+    for seqs in by_batch.values() {
+        assert_eq!(seqs.len(), per_batch);
+        assert!(seqs.windows(2).all(|w| w[1] == w[0] + 1), "{:?}", seqs);
+    }
+}
+
+#[test]
+fn frames_arrive_within_tolerance() {
+    let mut network = TestNetwork::connected(2);
+    // Taken before the sequencer starts, so frames look sent no later than they were
+    let start = Instant::now();
+    let mut sequencer = FrameSequencer::default();
+    let count = 20;
+    for i in 0..count {
+        network.send(0, 1, vec![sequencer.frame(note_on(0, 60 + i as u8))]);
+    }
+
+    for r in network.receive(1, count) {
+        let sent = start + Duration::from_micros(r.frame.timestamp_us);
+        let delay = r.at.saturating_duration_since(sent);
+        assert!(
+            delay < DELIVERY_TOLERANCE,
+            "frame {} took {:?}",
+            r.frame.seq,
+            delay
+        );
+    }
+}
+
+#[test]
+fn every_peer_hears_every_other() {
+    let size = 4;
+    let mut network = TestNetwork::connected(size);
+    let mut sequencers: Vec<FrameSequencer> =
+        (0..size).map(|_| FrameSequencer::default()).collect();
+    for from in 0..size {
+        for to in (0..size).filter(|to| *to != from) {
+            let frame = sequencers[from].frame(note_on(from as u8, 60));
+            network.send(from, to, vec![frame]);
+        }
+    }
+
+    for node in 0..size {
+        let senders: HashSet<PeerId> = network
+            .receive(node, size - 1)
+            .iter()
+            .map(|r| r.from)
+            .collect();
+        let expected: HashSet<PeerId> = (0..size)
+            .filter(|n| *n != node)
+            .map(|n| network.peer_id(n))
+            .collect();
+        assert_eq!(senders, expected);
+    }
+}
+
+#[test]
+fn all_notes_off_silences_routed_notes() {
+    let mut network = TestNetwork::connected(2);
+    let mut sequencer = FrameSequencer::default();
+    let mut frames: Vec<MidiFrame> = (0..16)
+        .flat_map(|channel| [note_on(channel, 60), vec![0xB0 | channel, 64, 127]])
+        .map(|m| sequencer.frame(m))
+        .collect();
+    frames.push(sequencer.frame(vec![0x80, 60, 0]));
+    network.send(0, 1, frames);
+    let panic = midi::all_notes_off()
+        .into_iter()
+        .map(|m| sequencer.frame(m))
+        .collect();
+    network.send(0, 1, panic);
+
+    // A route that moves everything around and filters what it can must still let notes end
+    let route = PeerRoute::new(
+        "sender",
+        &PeerConfig {
+            channel_map: (1..=16)
+                .map(|from| ChannelMapping {
+                    from,
+                    to: 17 - from,
+                })
+                .collect(),
+            transpose: 12,
+            filters: vec![midi::MessageKind::NoteOff, midi::MessageKind::ControlChange],
+            ..Default::default()
+        },
+    );
+    let mut received: Vec<&Received> = network.receive(1, 16 * 2 + 1 + 32).iter().collect();
+    received.sort_by_key(|r| r.frame.seq);
+    let mut held = HeldNotes::default();
+    for r in received {
+        if let Some(message) = route.apply(&r.frame.message) {
+            held.play(&message);
+        }
+    }
+    assert!(held.is_silent(), "{:?}", held.notes);
+}
+
+#[test]
+fn long_backlogs_go_out_in_batches_that_fit() {
+    let mut network = TestNetwork::connected(2);
+    let mut sequencer = FrameSequencer::default();
+    // Clock that is never dropped piles up past what a single batch can count
+    let overrides = BTreeMap::from([(
+        EventClass::Clock,
+        ClassPolicy {
+            drop: Some(DropPolicy::Never),
+            ..Default::default()
+        },
+    )]);
+    let mut queue = OutboundQueue::new(PriorityModel::new(
+        BackpressurePolicy::default(),
+        &overrides,
+    ));
+    let count = protocol::MAX_BATCH_FRAMES + 1000;
+    let clock: Vec<MidiFrame> = (0..count).map(|_| sequencer.frame(vec![0xF8])).collect();
+    queue.push(&clock);
+    let mut sizes = Vec::new();
+    while let Some(frames) = queue.take_batch() {
+        sizes.push(frames.len());
+        network.send(0, 1, frames);
+        queue.completed();
+    }
+    assert_eq!(sizes, [protocol::MAX_BATCH_FRAMES, 1000]);
+
+    let mut seqs: Vec<u32> = network
+        .receive(1, count)
+        .iter()
+        .map(|r| r.frame.seq)
+        .collect();
+    seqs.sort_unstable();
+    assert_eq!(seqs.len(), count);
+    assert!(seqs.windows(2).all(|w| w[1] == w[0] + 1));
+}
+
+#[test]
+fn messages_too_long_for_a_frame_are_refused_not_cut() {
+    let mut network = TestNetwork::connected(2);
+    let mut sequencer = FrameSequencer::default();
+    let mut sysex = vec![0x00; u16::MAX as usize + 2];
+    sysex[0] = 0xF0;
+    *sysex.last_mut().unwrap() = 0xF7;
+    let len = sysex.len();
+    let long = sequencer.frame(sysex);
+    assert_eq!(
+        protocol::encode_frames(&[long.clone()], protocol::WIRE_VERSION),
+        Err(EncodeError::MessageTooLong(len))
+    );
+
+    // The peer never gets a cut copy, and still gets what comes next
+    network.send(0, 1, vec![long]);
+    let next = sequencer.frame(note_on(0, 60));
+    network.send(0, 1, vec![next.clone()]);
+    let received = network.receive(1, 1);
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].frame, next);
+}
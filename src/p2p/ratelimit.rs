@@ -0,0 +1,129 @@
+//! Inbound MIDI rate limiting, protecting local synths from a malfunctioning or malicious peer
+//! flooding them.
+
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+
+use super::backpressure::is_continuous;
+use crate::midi::{self, MessageKind};
+
+/// What happens to MIDI from a peer going over its rate limit. Messages ending notes the peer
+/// holds always get through.
+#[derive(clap::ValueEnum, Debug, Default, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RateLimitPolicy {
+    /// Drop everything over the limit.
+    #[default]
+    Drop,
+    /// Clamp controllers, aftertouch and pitch bend to the limit, letting notes through.
+    Clamp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Pass,
+    Drop,
+    /// The first message dropped since the peer went over its limit.
+    Alert,
+}
+
+/// Token bucket holding up to a second worth of events.
+#[derive(Debug)]
+pub struct RateLimiter {
+    policy: RateLimitPolicy,
+    tokens: f64,
+    last: Instant,
+    /// Over the limit, until a quiet second refills the bucket.
+    limited: bool,
+    /// Notes held on each channel by what got through, the only ones ended over the limit.
+    held: [u128; 16],
+    /// Channels with the sustain pedal down.
+    sustained: u16,
+}
+
+impl RateLimiter {
+    pub fn new(policy: RateLimitPolicy, now: Instant) -> Self {
+        RateLimiter {
+            policy,
+            tokens: f64::MAX,
+            last: now,
+            limited: false,
+            held: [0; 16],
+            sustained: 0,
+        }
+    }
+
+    /// Whether `message` received at `now` gets through, with at most `rate` events a second.
+    pub fn check(&mut self, message: &[u8], rate: u32, now: Instant) -> Verdict {
+        let rate = rate as f64;
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        self.last = now;
+        if self.limited && self.tokens >= rate {
+            self.limited = false;
+        }
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            self.passed(message);
+            return Verdict::Pass;
+        }
+        let keep = match self.policy {
+            RateLimitPolicy::Drop => self.ends_held(message),
+            RateLimitPolicy::Clamp => {
+                self.ends_held(message) || (!is_continuous(message) && !midi::is_silencing(message))
+            }
+        };
+        if keep {
+            self.passed(message);
+            return Verdict::Pass;
+        }
+        match std::mem::replace(&mut self.limited, true) {
+            true => Verdict::Drop,
+            false => Verdict::Alert,
+        }
+    }
+
+    /// Follow the notes and pedals held as `message` gets through.
+    fn passed(&mut self, message: &[u8]) {
+        let channel = match midi::channel(message) {
+            Some(channel) => channel as usize,
+            None => return,
+        };
+        let silencing = midi::is_silencing(message);
+        match (MessageKind::of(message), message.get(1)) {
+            (Some(MessageKind::NoteOn | MessageKind::NoteOff), Some(note)) => match silencing {
+                true => self.held[channel] &= !(1 << (note & 0x7F)),
+                false => self.held[channel] |= 1 << (note & 0x7F),
+            },
+            (Some(MessageKind::ControlChange), Some(64)) => match silencing {
+                true => self.sustained &= !(1 << channel),
+                false => self.sustained |= 1 << channel,
+            },
+            (Some(MessageKind::ControlChange), Some(120 | 123)) => {
+                self.held[channel] = 0;
+                self.sustained &= !(1 << channel);
+            }
+            _ => {}
+        }
+    }
+
+    /// Whether `message` ends a note or pedal held on its channel.
+    fn ends_held(&self, message: &[u8]) -> bool {
+        if !midi::is_silencing(message) {
+            return false;
+        }
+        let channel = match midi::channel(message) {
+            Some(channel) => channel as usize,
+            None => return false,
+        };
+        let sustained = self.sustained & (1 << channel) != 0;
+        match (MessageKind::of(message), message.get(1)) {
+            (Some(MessageKind::NoteOn | MessageKind::NoteOff), Some(note)) => {
+                self.held[channel] & (1 << (note & 0x7F)) != 0
+            }
+            (Some(MessageKind::ControlChange), Some(64)) => sustained,
+            (Some(MessageKind::ControlChange), _) => self.held[channel] != 0 || sustained,
+            _ => false,
+        }
+    }
+}
@@ -1,81 +1,296 @@
+use futures::future::Either;
 use futures::stream::StreamExt;
-use futures::{executor::block_on, future::Either};
 use libp2p::{
     core::multiaddr::Protocol,
     core::muxing::StreamMuxerBox,
     core::upgrade,
-    core::{Multiaddr, Transport},
+    core::{transport::OptionalTransport, Multiaddr, Transport},
     identify, identity,
     identity::PeerId,
-    noise, ping, relay,
-    swarm::{NetworkBehaviour, SwarmBuilder, SwarmEvent},
-    tcp,
+    noise, ping, relay, request_response,
+    swarm::{behaviour::toggle::Toggle, NetworkBehaviour, SwarmBuilder, SwarmEvent},
+    tcp, TransportExt,
 };
 use libp2p_quic as quic;
+use std::collections::HashMap;
 use std::error::Error;
 use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, info_span, warn};
 
+use super::archive::{ArchiveCodec, RelayArchive, ARCHIVE_PROTOCOL};
+use super::directory::{DirectoryCodec, RelayDirectory, DIRECTORY_PROTOCOL};
+use super::relay_config::{RelayLimits, RelayOptions};
+use super::swarm_key;
+use super::webhook::{self, RelayEvent};
+use crate::metrics::{self, Metrics};
+use crate::runtime;
+
+/// How often archived sessions are written to disk.
+const ARCHIVE_SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The libp2p relay config with the limits that are set.
+fn relay_config(limits: &RelayLimits) -> relay::Config {
+    let mut config = relay::Config::default();
+    if let Some(max) = limits.max_reservations {
+        config.max_reservations = max;
+    }
+    if let Some(max) = limits.max_reservations_per_peer {
+        config.max_reservations_per_peer = max;
+    }
+    if let Some(secs) = limits.reservation_duration_secs {
+        config.reservation_duration = Duration::from_secs(secs);
+    }
+    if let Some(max) = limits.max_circuits {
+        config.max_circuits = max;
+    }
+    if let Some(max) = limits.max_circuits_per_peer {
+        config.max_circuits_per_peer = max;
+    }
+    if let Some(secs) = limits.max_circuit_duration_secs {
+        config.max_circuit_duration = Duration::from_secs(secs);
+    }
+    if let Some(max) = limits.max_circuit_bytes {
+        config.max_circuit_bytes = max;
+    }
+    config
+}
+
+/// Run the relay, recording and listing sessions when asked to, until the process ends.
 pub fn start_relay_loop(
-    port: u16,
-    secret_key_seed: u8,
-    use_ipv6: bool,
+    options: RelayOptions,
+    local_key: identity::Keypair,
 ) -> Result<(), Box<dyn Error>> {
-    // Create a static known PeerId based on given secret
-    let local_key: identity::Keypair = generate_ed25519(secret_key_seed);
+    let RelayOptions {
+        port,
+        listen_addresses,
+        use_ipv6,
+        swarm_key,
+        archive_dir,
+        directory,
+        token,
+        limits,
+        allow_peers,
+        metrics_address,
+        webhook,
+    } = options;
     let local_peer_id = PeerId::from(local_key.public());
-    println!("Local peer id: {local_peer_id:?}");
+    let _relay = info_span!("relay", id = %local_peer_id, port).entered();
+    info!("Local peer id: {local_peer_id:?}");
+    let _runtime = runtime::enter();
 
-    let tcp_transport = tcp::async_io::Transport::default();
-
-    let tcp_transport = tcp_transport
+    let tcp_transport = swarm_key::tcp_transport(tcp::Config::default(), swarm_key)
         .upgrade(upgrade::Version::V1Lazy)
         .authenticate(
-            noise::Config::new(&local_key).expect("Signing libp2p-noise static DH keypair failed."),
+            noise::Config::new(&local_key).map_err(|e| format!("Error setting up noise: {}", e))?,
         )
         .multiplex(libp2p::yamux::Config::default());
 
-    let quic_transport = quic::async_std::Transport::new(quic::Config::new(&local_key));
+    // QUIC can't carry the swarm key of a private swarm
+    let quic_transport = match swarm_key {
+        Some(_) => OptionalTransport::none(),
+        None => OptionalTransport::some(quic::tokio::Transport::new(quic::Config::new(&local_key))),
+    };
 
-    let transport = quic_transport
+    let (transport, bandwidth) = quic_transport
         .or_transport(tcp_transport)
         .map(|either_output, _| match either_output {
             Either::Left((peer_id, muxer)) => (peer_id, StreamMuxerBox::new(muxer)),
             Either::Right((peer_id, muxer)) => (peer_id, StreamMuxerBox::new(muxer)),
         })
-        .boxed();
+        .with_bandwidth_logging();
 
     let behaviour = Behaviour {
-        relay: relay::Behaviour::new(local_peer_id, Default::default()),
+        relay: relay::Behaviour::new(local_peer_id, relay_config(&limits)),
         ping: ping::Behaviour::new(ping::Config::new()),
         identify: identify::Behaviour::new(identify::Config::new(
             "/TODO/0.0.1".to_string(),
             local_key.public(),
         )),
+        archive: Toggle::from(archive_dir.as_ref().map(|_| {
+            request_response::Behaviour::new(
+                [(ARCHIVE_PROTOCOL, request_response::ProtocolSupport::Inbound)],
+                request_response::Config::default(),
+            )
+        })),
+        directory: Toggle::from(directory.then(|| {
+            request_response::Behaviour::new(
+                [(
+                    DIRECTORY_PROTOCOL,
+                    request_response::ProtocolSupport::Inbound,
+                )],
+                request_response::Config::default(),
+            )
+        })),
+    };
+    let mut archive = match archive_dir {
+        Some(dir) => {
+            std::fs::create_dir_all(&dir)?;
+            info!("Archiving sessions to {}", dir.display());
+            Some(RelayArchive::new(dir))
+        }
+        None => None,
     };
+    let mut directory = directory.then(|| {
+        info!("Keeping a directory of open sessions");
+        RelayDirectory::new(token)
+    });
 
-    let mut swarm = SwarmBuilder::without_executor(transport, behaviour, local_peer_id).build();
+    let metrics = Arc::new(Metrics::default());
+    if let Some(address) = metrics_address {
+        metrics::serve(address, metrics.clone())?;
+    }
+    if !allow_peers.is_empty() {
+        info!("Only letting {} peers connect", allow_peers.len());
+    }
+    let webhook = match webhook {
+        Some(url) => Some(webhook::start(&url, local_peer_id.to_string())?),
+        None => None,
+    };
+    let post = |event: RelayEvent| {
+        if let Some(webhook) = &webhook {
+            let _ = webhook.unbounded_send(event);
+        }
+    };
+    // When each circuit between two peers opened, to tell how long it lasted
+    let mut circuits: HashMap<(PeerId, PeerId), Vec<Instant>> = HashMap::new();
+    let allowed = |peer: &PeerId| allow_peers.is_empty() || allow_peers.contains(peer);
 
-    // Listen on all interfaces
-    let listen_addr_tcp = Multiaddr::empty()
-        .with(match use_ipv6 {
-            true => Protocol::from(Ipv6Addr::UNSPECIFIED),
-            _ => Protocol::from(Ipv4Addr::UNSPECIFIED),
-        })
-        .with(Protocol::Tcp(port));
-    swarm.listen_on(listen_addr_tcp)?;
+    let mut swarm = SwarmBuilder::with_tokio_executor(transport, behaviour, local_peer_id).build();
 
-    let listen_addr_quic = Multiaddr::empty()
-        .with(match use_ipv6 {
-            true => Protocol::from(Ipv6Addr::UNSPECIFIED),
-            _ => Protocol::from(Ipv4Addr::UNSPECIFIED),
-        })
-        .with(Protocol::Udp(port))
-        .with(Protocol::QuicV1);
-    swarm.listen_on(listen_addr_quic)?;
+    // Listen on all interfaces unless told where
+    let listen_addresses = match listen_addresses.is_empty() {
+        false => listen_addresses,
+        true => {
+            let ip = match use_ipv6 {
+                true => Protocol::from(Ipv6Addr::UNSPECIFIED),
+                _ => Protocol::from(Ipv4Addr::UNSPECIFIED),
+            };
+            let mut addresses = vec![Multiaddr::empty()
+                .with(ip.clone())
+                .with(Protocol::Tcp(port))];
+            if swarm_key.is_none() {
+                addresses.push(
+                    Multiaddr::empty()
+                        .with(ip)
+                        .with(Protocol::Udp(port))
+                        .with(Protocol::QuicV1),
+                );
+            }
+            addresses
+        }
+    };
+    for address in listen_addresses {
+        swarm.listen_on(address)?;
+    }
 
-    block_on(async {
+    runtime::block_on(async {
+        let mut save_timer = tokio::time::interval(ARCHIVE_SAVE_INTERVAL);
         loop {
-            match swarm.next().await.expect("Infinite Stream.") {
+            let event = tokio::select! {
+                event = swarm.select_next_some() => event,
+                _ = save_timer.tick() => {
+                    if let Some(archive) = &mut archive {
+                        archive.save();
+                    }
+                    continue;
+                }
+            };
+            match event {
+                SwarmEvent::Behaviour(BehaviourEvent::Archive(
+                    request_response::Event::Message {
+                        peer,
+                        message:
+                            request_response::Message::Request {
+                                request, channel, ..
+                            },
+                    },
+                )) => {
+                    if let Some(archive) = &mut archive {
+                        let response = archive.handle(&peer, request);
+                        if let Some(behaviour) = swarm.behaviour_mut().archive.as_mut() {
+                            if behaviour.send_response(channel, response).is_err() {
+                                warn!("Could not answer the archive request of {}", peer);
+                            }
+                        }
+                    }
+                }
+                SwarmEvent::Behaviour(BehaviourEvent::Directory(
+                    request_response::Event::Message {
+                        peer,
+                        message:
+                            request_response::Message::Request {
+                                request, channel, ..
+                            },
+                    },
+                )) => {
+                    if let Some(directory) = &mut directory {
+                        let response = directory.handle(&peer, request);
+                        if let Some(behaviour) = swarm.behaviour_mut().directory.as_mut() {
+                            if behaviour.send_response(channel, response).is_err() {
+                                warn!("Could not answer the directory request of {}", peer);
+                            }
+                        }
+                    }
+                }
+                SwarmEvent::Behaviour(BehaviourEvent::Relay(event)) => {
+                    debug!("{event:?}");
+                    match event {
+                        relay::Event::ReservationReqAccepted {
+                            src_peer_id,
+                            renewed,
+                        } => post(RelayEvent::ReservationCreated {
+                            peer_id: src_peer_id.to_string(),
+                            renewed,
+                        }),
+                        relay::Event::ReservationTimedOut { src_peer_id } => {
+                            post(RelayEvent::ReservationExpired {
+                                peer_id: src_peer_id.to_string(),
+                            })
+                        }
+                        relay::Event::CircuitReqAccepted {
+                            src_peer_id,
+                            dst_peer_id,
+                        } => {
+                            circuits
+                                .entry((src_peer_id, dst_peer_id))
+                                .or_default()
+                                .push(Instant::now());
+                            if let Some(archive) = &mut archive {
+                                archive.circuit_opened(&src_peer_id, &dst_peer_id);
+                            }
+                            post(RelayEvent::CircuitOpened {
+                                src_peer_id: src_peer_id.to_string(),
+                                dst_peer_id: dst_peer_id.to_string(),
+                            });
+                        }
+                        relay::Event::CircuitClosed {
+                            src_peer_id,
+                            dst_peer_id,
+                            error,
+                        } => {
+                            let key = (src_peer_id, dst_peer_id);
+                            let opened = circuits
+                                .get_mut(&key)
+                                .and_then(|opened| (!opened.is_empty()).then(|| opened.remove(0)));
+                            if circuits.get(&key).map_or(false, |opened| opened.is_empty()) {
+                                circuits.remove(&key);
+                            }
+                            post(RelayEvent::CircuitClosed {
+                                src_peer_id: src_peer_id.to_string(),
+                                dst_peer_id: dst_peer_id.to_string(),
+                                duration_secs: opened
+                                    .map(|opened| opened.elapsed().as_secs_f64())
+                                    .unwrap_or_default(),
+                                relay_bytes_in: bandwidth.total_inbound(),
+                                relay_bytes_out: bandwidth.total_outbound(),
+                                error: error.map(|e| e.to_string()),
+                            });
+                        }
+                        _ => {}
+                    }
+                }
                 SwarmEvent::Behaviour(event) => {
                     if let BehaviourEvent::Identify(identify::Event::Received {
                         info: identify::Info { observed_addr, .. },
@@ -85,10 +300,40 @@ pub fn start_relay_loop(
                         swarm.add_external_address(observed_addr.clone());
                     }
 
-                    println!("{event:?}")
+                    debug!("{event:?}")
                 }
                 SwarmEvent::NewListenAddr { address, .. } => {
-                    println!("Listening on {address:?}");
+                    info!("Listening on {address:?}");
+                }
+                SwarmEvent::ConnectionEstablished {
+                    peer_id,
+                    endpoint,
+                    num_established,
+                    ..
+                } => {
+                    info_span!("connection", peer = %peer_id)
+                        .in_scope(|| debug!("Established via {:?}", endpoint));
+                    if !allowed(&peer_id) {
+                        info!("Turned away {}, it is not in allow_peers", peer_id);
+                        let _ = swarm.disconnect_peer_id(peer_id);
+                    } else if num_established.get() == 1 {
+                        metrics.peer_connected();
+                    }
+                }
+                SwarmEvent::ConnectionClosed {
+                    peer_id,
+                    cause,
+                    num_established,
+                    ..
+                } => {
+                    info_span!("connection", peer = %peer_id)
+                        .in_scope(|| debug!("Closed: {:?}", cause));
+                    if num_established == 0 && allowed(&peer_id) {
+                        metrics.peer_disconnected(&peer_id.to_string());
+                    }
+                    if let (0, Some(directory)) = (num_established, &mut directory) {
+                        directory.peer_left(&peer_id);
+                    }
                 }
                 _ => {}
             }
@@ -101,11 +346,6 @@ struct Behaviour {
     relay: relay::Behaviour,
     ping: ping::Behaviour,
     identify: identify::Behaviour,
-}
-
-fn generate_ed25519(secret_key_seed: u8) -> identity::Keypair {
-    let mut bytes = [0u8; 32];
-    bytes[0] = secret_key_seed;
-
-    identity::Keypair::ed25519_from_bytes(bytes).expect("only errors on wrong length")
+    archive: Toggle<request_response::Behaviour<ArchiveCodec>>,
+    directory: Toggle<request_response::Behaviour<DirectoryCodec>>,
 }
@@ -0,0 +1,276 @@
+//! Settings of a relay run with `--as-relay`, from the file given with `--relay-config` and
+//! `P2PMIDI_RELAY_*` environment variables, which win over the file. Settings the relay shares with
+//! clients, like the port and the log, win over the main config file too.
+
+use libp2p::{pnet::PreSharedKey, Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use super::webhook::parse_webhook_url;
+use crate::settings::{ConfigFormat, LogFormat, LogLevel, Settings};
+use crate::validation::SettingsError;
+
+/// Everything needed to run a relay.
+#[derive(Clone, Debug)]
+pub struct RelayOptions {
+    pub port: u16,
+    /// Listen on these instead of TCP and QUIC on `port` on every interface.
+    pub listen_addresses: Vec<Multiaddr>,
+    pub use_ipv6: bool,
+    pub swarm_key: Option<PreSharedKey>,
+    /// Record the sessions peers consent to here.
+    pub archive_dir: Option<PathBuf>,
+    /// Keep a directory of open sessions, behind `token` if there is one.
+    pub directory: bool,
+    pub token: Option<String>,
+    pub limits: RelayLimits,
+    /// Turn away peers not listed, unless empty.
+    pub allow_peers: Vec<PeerId>,
+    pub metrics_address: Option<SocketAddr>,
+    /// POST relay events to this `http://` URL.
+    pub webhook: Option<String>,
+}
+
+/// Prefix of the environment variables overriding the relay config file.
+pub const ENV_PREFIX: &str = "P2PMIDI_RELAY_";
+
+/// How much the relay takes on. Unset limits keep the libp2p defaults.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct RelayLimits {
+    pub max_reservations: Option<usize>,
+    pub max_reservations_per_peer: Option<usize>,
+    /// Seconds a reservation lasts before the peer has to renew it.
+    pub reservation_duration_secs: Option<u64>,
+    pub max_circuits: Option<usize>,
+    pub max_circuits_per_peer: Option<usize>,
+    /// Seconds a relayed connection lasts before the relay closes it.
+    pub max_circuit_duration_secs: Option<u64>,
+    /// Bytes a relayed connection carries before the relay closes it.
+    pub max_circuit_bytes: Option<u64>,
+}
+
+/// Who the relay talks to.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct RelayAuth {
+    /// Token the session directory asks for, like `relay_token`.
+    pub token: Option<String>,
+    /// Only these PeerIds may connect. Everyone may when empty.
+    pub allow_peers: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct RelayLog {
+    pub level: Option<LogLevel>,
+    pub file: Option<PathBuf>,
+    pub format: Option<LogFormat>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct RelayConfig {
+    /// Identity key of the relay, instead of relay_identity.key in the data directory. Has to
+    /// exist, so a wrong path can't give the relay a new PeerId.
+    pub identity_path: Option<PathBuf>,
+    /// Multiaddrs to listen on, instead of TCP and QUIC on the port on every interface.
+    pub listen_addresses: Vec<String>,
+    pub port: Option<u16>,
+    pub swarm_key: Option<PathBuf>,
+    pub archive_dir: Option<PathBuf>,
+    pub directory: Option<bool>,
+    pub limits: RelayLimits,
+    pub auth: RelayAuth,
+    /// Serve Prometheus metrics on this address, apart from those of a session run alongside.
+    pub metrics_address: Option<SocketAddr>,
+    /// URL relay events are POSTed to as JSON: reservations created and expired, and circuits
+    /// opened and closed.
+    pub webhook: Option<String>,
+    pub log: RelayLog,
+}
+
+/// The environment variable `P2PMIDI_RELAY_<name>`, parsed.
+fn env<T>(name: &str) -> Result<Option<T>, String>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    let name = format!("{}{}", ENV_PREFIX, name);
+    match std::env::var(&name) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|e| format!("Invalid {}: {}", name, e)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Like [`env`], for the lowercase names of enum settings.
+fn env_enum<T: clap::ValueEnum>(name: &str) -> Result<Option<T>, String> {
+    let name = format!("{}{}", ENV_PREFIX, name);
+    match std::env::var(&name) {
+        Ok(value) => T::from_str(&value, true)
+            .map(Some)
+            .map_err(|e| format!("Invalid {}: {}", name, e)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Comma separated lists.
+fn env_list(name: &str) -> Option<Vec<String>> {
+    std::env::var(format!("{}{}", ENV_PREFIX, name))
+        .ok()
+        .map(|value| {
+            value
+                .split(',')
+                .map(|item| item.trim().to_string())
+                .filter(|item| !item.is_empty())
+                .collect()
+        })
+}
+
+impl RelayConfig {
+    /// Read the config file, YAML, TOML or JSON by its extension, then apply the environment.
+    pub fn load(path: Option<&Path>) -> Result<Self, Box<dyn Error>> {
+        let mut config: RelayConfig = match path {
+            Some(path) => {
+                let contents = std::fs::read_to_string(path)
+                    .map_err(|e| format!("Error reading {}: {}", path.display(), e))?;
+                ConfigFormat::from_path(path)?
+                    .deserialize(&contents)
+                    .map_err(|e| format!("Invalid relay config {}: {}", path.display(), e))?
+            }
+            None => RelayConfig::default(),
+        };
+        config.apply_env()?;
+        config.listen_multiaddrs()?;
+        config.allowed_peers()?;
+        if let Some(path) = &config.identity_path {
+            if !path.exists() {
+                return Err(SettingsError::MissingIdentity {
+                    field: "identity_path",
+                    path: path.clone(),
+                }
+                .into());
+            }
+        }
+        if let Some(path) = &config.swarm_key {
+            if !path.exists() {
+                return Err(SettingsError::MissingSwarmKey { path: path.clone() }.into());
+            }
+        }
+        if let Some(url) = &config.webhook {
+            parse_webhook_url(url)?;
+        }
+        Ok(config)
+    }
+
+    fn apply_env(&mut self) -> Result<(), String> {
+        if let Some(path) = env("IDENTITY_PATH")? {
+            self.identity_path = Some(path);
+        }
+        if let Some(addresses) = env_list("LISTEN_ADDRESSES") {
+            self.listen_addresses = addresses;
+        }
+        if let Some(port) = env("PORT")? {
+            self.port = Some(port);
+        }
+        if let Some(path) = env("SWARM_KEY")? {
+            self.swarm_key = Some(path);
+        }
+        if let Some(dir) = env("ARCHIVE_DIR")? {
+            self.archive_dir = Some(dir);
+        }
+        if let Some(directory) = env("DIRECTORY")? {
+            self.directory = Some(directory);
+        }
+        let limits = &mut self.limits;
+        limits.max_reservations = env("MAX_RESERVATIONS")?.or(limits.max_reservations);
+        limits.max_reservations_per_peer =
+            env("MAX_RESERVATIONS_PER_PEER")?.or(limits.max_reservations_per_peer);
+        limits.reservation_duration_secs =
+            env("RESERVATION_DURATION_SECS")?.or(limits.reservation_duration_secs);
+        limits.max_circuits = env("MAX_CIRCUITS")?.or(limits.max_circuits);
+        limits.max_circuits_per_peer =
+            env("MAX_CIRCUITS_PER_PEER")?.or(limits.max_circuits_per_peer);
+        limits.max_circuit_duration_secs =
+            env("MAX_CIRCUIT_DURATION_SECS")?.or(limits.max_circuit_duration_secs);
+        limits.max_circuit_bytes = env("MAX_CIRCUIT_BYTES")?.or(limits.max_circuit_bytes);
+        if let Some(token) = env("TOKEN")? {
+            self.auth.token = Some(token);
+        }
+        if let Some(peers) = env_list("ALLOW_PEERS") {
+            self.auth.allow_peers = peers;
+        }
+        if let Some(address) = env("METRICS_ADDRESS")? {
+            self.metrics_address = Some(address);
+        }
+        if let Some(url) = env("WEBHOOK")? {
+            self.webhook = Some(url);
+        }
+        if let Some(level) = env_enum("LOG_LEVEL")? {
+            self.log.level = Some(level);
+        }
+        if let Some(file) = env("LOG_FILE")? {
+            self.log.file = Some(file);
+        }
+        if let Some(format) = env_enum("LOG_FORMAT")? {
+            self.log.format = Some(format);
+        }
+        Ok(())
+    }
+
+    /// The addresses to listen on, empty to listen on the port.
+    pub fn listen_multiaddrs(&self) -> Result<Vec<Multiaddr>, String> {
+        self.listen_addresses
+            .iter()
+            .map(|address| {
+                Multiaddr::from_str(address)
+                    .map_err(|e| format!("Invalid listen address {:?}: {}", address, e))
+            })
+            .collect()
+    }
+
+    pub fn allowed_peers(&self) -> Result<Vec<PeerId>, String> {
+        self.auth
+            .allow_peers
+            .iter()
+            .map(|peer| {
+                PeerId::from_str(peer).map_err(|e| format!("Invalid PeerId {:?}: {}", peer, e))
+            })
+            .collect()
+    }
+
+    /// Override the settings the relay shares with the main config file.
+    pub fn apply_to(&self, settings: &mut Settings) {
+        if let Some(port) = self.port {
+            settings.relay_port = Some(port);
+        }
+        if let Some(path) = &self.swarm_key {
+            settings.swarm_key = Some(path.clone());
+        }
+        if let Some(dir) = &self.archive_dir {
+            settings.archive_dir = Some(dir.clone());
+        }
+        if let Some(directory) = self.directory {
+            settings.directory = Some(directory);
+        }
+        if let Some(token) = &self.auth.token {
+            settings.relay_token = Some(token.clone());
+        }
+        if let Some(level) = self.log.level {
+            settings.log_level = Some(level);
+        }
+        if let Some(file) = &self.log.file {
+            settings.log_file = Some(file.clone());
+        }
+        if let Some(format) = self.log.format {
+            settings.log_format = Some(format);
+        }
+    }
+}
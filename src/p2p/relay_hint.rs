@@ -0,0 +1,139 @@
+//! Picking the relay two peers talk through when there are several. Once a peer is let in, both
+//! ends tell each other their round trip time to every relay they are connected to and the relays
+//! they hold a reservation on, and each picks the same one: the relay closest to both of them.
+
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncWrite};
+use libp2p::{core::multiaddr::Protocol, request_response, Multiaddr, PeerId, StreamProtocol};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io;
+use std::str::FromStr;
+use std::time::Duration;
+
+use super::directory::{read_json, write_json};
+use super::paths::ConnectionPath;
+
+/// Protocol peers exchange their relay round trip times over.
+pub const RELAY_HINT_PROTOCOL: StreamProtocol = StreamProtocol::new("/p2pmidi/relay-hint/1.0.0");
+
+/// What one end knows of the relays, sent as both the request and the response.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct RelayHint {
+    /// Round trip time in ms to each relay measured, by PeerId.
+    pub rtts_ms: BTreeMap<String, f64>,
+    /// Relays holding a reservation for this end, which it can be dialed through.
+    pub reserved: BTreeSet<String>,
+}
+
+impl RelayHint {
+    pub fn measure<'a>(
+        relays: impl Iterator<Item = &'a PeerId>,
+        rtts: &HashMap<PeerId, Duration>,
+        reserved: &HashSet<PeerId>,
+    ) -> Self {
+        RelayHint {
+            rtts_ms: relays
+                .filter_map(|relay| {
+                    let rtt = rtts.get(relay)?;
+                    Some((relay.to_string(), rtt.as_secs_f64() * 1000.0))
+                })
+                .collect(),
+            reserved: reserved.iter().map(|relay| relay.to_string()).collect(),
+        }
+    }
+}
+
+/// The relay both ends measured with the lowest round trip times added up, which is the round
+/// trip between them through it. Ties go to the lowest PeerId, so both ends pick the same.
+pub fn best_relay(ours: &RelayHint, theirs: &RelayHint) -> Option<PeerId> {
+    ours.rtts_ms
+        .iter()
+        .filter_map(|(relay, rtt)| Some((rtt + theirs.rtts_ms.get(relay)?, relay)))
+        .min_by(|(a, relay_a), (b, relay_b)| a.total_cmp(b).then(relay_a.cmp(relay_b)))
+        .and_then(|(_, relay)| PeerId::from_str(relay).ok())
+}
+
+/// Whether this end dials the other through `relay`. Only an end with a reservation there can be
+/// dialed, when both have one the lower PeerId dials.
+pub fn dials_through(
+    relay: &PeerId,
+    local_peer_id: &PeerId,
+    peer: &PeerId,
+    ours: &RelayHint,
+    theirs: &RelayHint,
+) -> bool {
+    let relay = relay.to_string();
+    theirs.reserved.contains(&relay) && (local_peer_id < peer || !ours.reserved.contains(&relay))
+}
+
+/// The relay a circuit address goes through.
+fn circuit_relay(address: &Multiaddr) -> Option<PeerId> {
+    let mut relay = None;
+    for protocol in address.iter() {
+        match protocol {
+            Protocol::P2p(peer_id) => relay = Some(peer_id),
+            Protocol::P2pCircuit => return relay,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// The relay a relayed connection goes through, found in the address of whichever end dialed
+/// through it.
+pub fn path_relay(path: &ConnectionPath) -> Option<PeerId> {
+    std::iter::once(&path.remote_address)
+        .chain(&path.local_address)
+        .filter_map(|address| Multiaddr::from_str(address).ok())
+        .find_map(|address| circuit_relay(&address))
+}
+
+/// Length prefixed JSON, like the directory.
+#[derive(Debug, Clone, Default)]
+pub struct RelayHintCodec;
+
+#[async_trait]
+impl request_response::Codec for RelayHintCodec {
+    type Protocol = StreamProtocol;
+    type Request = RelayHint;
+    type Response = RelayHint;
+
+    async fn read_request<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<RelayHint>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_json(io).await
+    }
+
+    async fn read_response<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<RelayHint>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_json(io).await
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        request: RelayHint,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_json(io, &request).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        response: RelayHint,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_json(io, &response).await
+    }
+}
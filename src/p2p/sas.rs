@@ -0,0 +1,132 @@
+//! Short authentication strings. Both ends of a connection derive the same seven emoji from the
+//! two PeerIds, so musicians can read them to each other over voice chat and be sure nobody sits
+//! in the middle. The PeerIds are what the encrypted connection authenticated, so a relay swapping
+//! keys ends up with different emoji on each side.
+
+use libp2p::PeerId;
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+/// Emoji and their names, the same table Matrix clients use for verification.
+const EMOJI: [(&str, &str); 64] = [
+    ("🐶", "Dog"),
+    ("🐱", "Cat"),
+    ("🦁", "Lion"),
+    ("🐎", "Horse"),
+    ("🦄", "Unicorn"),
+    ("🐷", "Pig"),
+    ("🐘", "Elephant"),
+    ("🐰", "Rabbit"),
+    ("🐼", "Panda"),
+    ("🐓", "Rooster"),
+    ("🐧", "Penguin"),
+    ("🐢", "Turtle"),
+    ("🐟", "Fish"),
+    ("🐙", "Octopus"),
+    ("🦋", "Butterfly"),
+    ("🌷", "Flower"),
+    ("🌳", "Tree"),
+    ("🌵", "Cactus"),
+    ("🍄", "Mushroom"),
+    ("🌏", "Globe"),
+    ("🌙", "Moon"),
+    ("☁️", "Cloud"),
+    ("🔥", "Fire"),
+    ("🍌", "Banana"),
+    ("🍎", "Apple"),
+    ("🍓", "Strawberry"),
+    ("🌽", "Corn"),
+    ("🍕", "Pizza"),
+    ("🎂", "Cake"),
+    ("❤️", "Heart"),
+    ("😀", "Smiley"),
+    ("🤖", "Robot"),
+    ("🎩", "Hat"),
+    ("👓", "Glasses"),
+    ("🔧", "Spanner"),
+    ("🎅", "Santa"),
+    ("👍", "Thumbs Up"),
+    ("☂️", "Umbrella"),
+    ("⌛", "Hourglass"),
+    ("⏰", "Clock"),
+    ("🎁", "Gift"),
+    ("💡", "Light Bulb"),
+    ("📕", "Book"),
+    ("✏️", "Pencil"),
+    ("📎", "Paperclip"),
+    ("✂️", "Scissors"),
+    ("🔒", "Lock"),
+    ("🔑", "Key"),
+    ("🔨", "Hammer"),
+    ("☎️", "Telephone"),
+    ("🏁", "Flag"),
+    ("🚂", "Train"),
+    ("🚲", "Bicycle"),
+    ("✈️", "Aeroplane"),
+    ("🚀", "Rocket"),
+    ("🏆", "Trophy"),
+    ("⚽", "Ball"),
+    ("🎸", "Guitar"),
+    ("🎺", "Trumpet"),
+    ("🔔", "Bell"),
+    ("⚓", "Anchor"),
+    ("🎧", "Headphones"),
+    ("📁", "Folder"),
+    ("📌", "Pin"),
+];
+
+/// Emoji read out to compare.
+const LENGTH: usize = 7;
+
+/// The emoji of a connection, the same whichever end computes them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShortAuthString {
+    indices: [usize; LENGTH],
+}
+
+impl ShortAuthString {
+    pub fn new(local: &PeerId, remote: &PeerId) -> Self {
+        let (mut a, mut b) = (local.to_bytes(), remote.to_bytes());
+        if a > b {
+            std::mem::swap(&mut a, &mut b);
+        }
+        let mut hasher = Sha256::new();
+        hasher.update(b"p2pmidi-sas");
+        hasher.update((a.len() as u32).to_be_bytes());
+        hasher.update(&a);
+        hasher.update(&b);
+        let digest = hasher.finalize();
+        // Six bits per emoji out of the first 42 bits
+        let bits = u64::from_be_bytes([
+            0, 0, digest[0], digest[1], digest[2], digest[3], digest[4], digest[5],
+        ]);
+        let mut indices = [0; LENGTH];
+        for (i, index) in indices.iter_mut().enumerate() {
+            *index = ((bits >> (42 - 6 * (i + 1))) & 0x3F) as usize;
+        }
+        ShortAuthString { indices }
+    }
+
+    pub fn emoji(&self) -> String {
+        self.indices
+            .iter()
+            .map(|i| EMOJI[*i].0)
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// The names of the emoji, for reading out loud.
+    pub fn words(&self) -> String {
+        self.indices
+            .iter()
+            .map(|i| EMOJI[*i].1)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+impl fmt::Display for ShortAuthString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.emoji(), self.words())
+    }
+}
@@ -0,0 +1,205 @@
+//! The wire format described in the code, so it stays next to what it describes. `p2pmidi protocol
+//! dump` prints it for anyone writing another implementation, and the compatibility tests check the
+//! recorded byte streams of every version against it.
+
+use serde::Serialize;
+
+use super::archive::ARCHIVE_PROTOCOL;
+use super::directory::DIRECTORY_PROTOCOL;
+use super::invite::TOKEN_SYSEX_HEADER;
+use super::protocol::{
+    EventClass, ACK, MAX_BATCH_SIZE, PROTOCOL, TRACKS_PROTOCOL, TRACKS_WIRE_VERSION, WIRE_VERSION,
+};
+use super::relay_hint::RELAY_HINT_PROTOCOL;
+use super::transfer::TRANSFER_PROTOCOL;
+use crate::transport::{START_SYSEX_HEADER, SYSEX_HEADER};
+
+/// A field of a message, in the order it is written. Integers are big endian unless said
+/// otherwise.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Field {
+    pub name: &'static str,
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub description: &'static str,
+}
+
+const fn field(name: &'static str, kind: &'static str, description: &'static str) -> Field {
+    Field {
+        name,
+        kind,
+        description,
+    }
+}
+
+/// How a batch of MIDI frames is encoded in one version.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct BatchEncoding {
+    pub version: u8,
+    /// Stream protocol the version is spoken on.
+    pub protocol: String,
+    pub batch: Vec<Field>,
+    /// Repeated `count` times after the batch fields.
+    pub frame: Vec<Field>,
+}
+
+/// Control messages sent between peers as SysEx inside MIDI frames.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SysExMessage {
+    pub name: &'static str,
+    /// Bytes every message starts with, in hex.
+    pub header: String,
+    pub fields: Vec<Field>,
+    pub description: &'static str,
+}
+
+/// Another stream protocol between nodes, carrying something else than MIDI.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Stream {
+    pub protocol: String,
+    pub description: &'static str,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Schema {
+    pub crate_version: &'static str,
+    /// Written before every batch on the MIDI streams.
+    pub framing: Vec<Field>,
+    /// Largest batch a node reads, in bytes.
+    pub max_batch_size: usize,
+    /// Byte the receiver answers every batch with.
+    pub ack: u8,
+    pub encodings: Vec<BatchEncoding>,
+    pub sysex: Vec<SysExMessage>,
+    /// Classes MIDI is queued and dropped by, worked out from each message.
+    pub event_classes: Vec<EventClass>,
+    pub streams: Vec<Stream>,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+fn frame_fields() -> Vec<Field> {
+    vec![
+        field(
+            "seq",
+            "u32",
+            "Increases by one for every frame a peer sends, wrapping",
+        ),
+        field(
+            "timestamp_us",
+            "u64",
+            "Microseconds since the sender started its session",
+        ),
+        field("len", "u16", "Length of the message"),
+        field("message", "[u8; len]", "One complete MIDI message"),
+    ]
+}
+
+/// The wire format of this build.
+pub fn schema() -> Schema {
+    let batch = vec![
+        field("version", "u8", "Version of the encoding"),
+        field("count", "u16", "Number of frames following"),
+    ];
+    let mut tracks_frame = frame_fields();
+    tracks_frame.extend([
+        field("track_len", "u8", "Length of the track label"),
+        field(
+            "track",
+            "[u8; track_len]",
+            "UTF-8 label of the instrument played, empty for none",
+        ),
+    ]);
+    let seven_bit = "u64 as ten 7-bit bytes, most significant first";
+    Schema {
+        crate_version: env!("CARGO_PKG_VERSION"),
+        framing: vec![field("len", "u32", "Length of the batch following")],
+        max_batch_size: MAX_BATCH_SIZE,
+        ack: ACK,
+        encodings: vec![
+            BatchEncoding {
+                version: WIRE_VERSION,
+                protocol: PROTOCOL.to_string(),
+                batch: batch.clone(),
+                frame: frame_fields(),
+            },
+            BatchEncoding {
+                version: TRACKS_WIRE_VERSION,
+                protocol: TRACKS_PROTOCOL.to_string(),
+                batch,
+                frame: tracks_frame,
+            },
+        ],
+        sysex: vec![
+            SysExMessage {
+                name: "transport",
+                header: hex(&SYSEX_HEADER),
+                fields: vec![
+                    field("version", "u8", "Always 1"),
+                    field("tempo", seven_bit, "Beats per minute times 1000"),
+                    field("playing", "u8", "1 while playing"),
+                    field(
+                        "beat",
+                        seven_bit,
+                        "Beat at `at_us` times 1000, two's complement",
+                    ),
+                    field("at_us", seven_bit, "Microseconds since the unix epoch"),
+                    field("end", "u8", "F7"),
+                ],
+                description: "The session transport of the sender changed",
+            },
+            SysExMessage {
+                name: "scheduled_start",
+                header: hex(&START_SYSEX_HEADER),
+                fields: vec![
+                    field("version", "u8", "Always 1"),
+                    field(
+                        "delay_us",
+                        seven_bit,
+                        "Microseconds from the timestamp of the frame to the start",
+                    ),
+                    field("count_in", seven_bit, "Beats counted in before the start"),
+                    field("tempo", seven_bit, "Beats per minute times 1000"),
+                    field("end", "u8", "F7"),
+                ],
+                description: "Start every node together after counting in",
+            },
+            SysExMessage {
+                name: "invite_token",
+                header: hex(&TOKEN_SYSEX_HEADER),
+                fields: vec![
+                    field("token", "hex text", "Signed claims of the invite"),
+                    field("end", "u8", "F7"),
+                ],
+                description: "The invite a dialing peer joins with, its first frame",
+            },
+        ],
+        event_classes: EventClass::ALL.to_vec(),
+        streams: vec![
+            Stream {
+                protocol: ARCHIVE_PROTOCOL.to_string(),
+                description: "Sessions recorded on the relay, length prefixed binary",
+            },
+            Stream {
+                protocol: DIRECTORY_PROTOCOL.to_string(),
+                description: "Sessions published on the relay, length prefixed JSON",
+            },
+            Stream {
+                protocol: RELAY_HINT_PROTOCOL.to_string(),
+                description: "Round trip times to the relays, length prefixed JSON",
+            },
+            Stream {
+                protocol: TRANSFER_PROTOCOL.to_string(),
+                description: "Files sent between peers, length prefixed JSON then raw bytes",
+            },
+        ],
+    }
+}
+
+/// Print the schema as JSON.
+pub fn dump() -> Result<(), Box<dyn std::error::Error>> {
+    println!("{}", serde_json::to_string_pretty(&schema())?);
+    Ok(())
+}
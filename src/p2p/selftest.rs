@@ -0,0 +1,292 @@
+use futures::{future::FutureExt, stream::StreamExt};
+use libp2p::{
+    core::{muxing::StreamMuxerBox, transport::Boxed, transport::MemoryTransport, upgrade},
+    identity, noise, request_response,
+    swarm::{NetworkBehaviour, Swarm, SwarmBuilder, SwarmEvent},
+    tcp, yamux, Multiaddr, PeerId, Transport,
+};
+use serde::Serialize;
+use std::error::Error;
+use std::fmt;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, info_span, warn};
+
+use super::loss::SequenceTracker;
+use super::protocol::{self, FrameSequencer, MidiCodec, MidiFrame};
+use crate::failure::Failure;
+use crate::output::{Report, Reporter};
+use crate::runtime;
+
+/// How often the soak test reports its numbers.
+const REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Settings of a `p2pmidi selftest` run.
+#[derive(Clone, Debug)]
+pub struct SelftestOptions {
+    pub duration: Duration,
+    /// MIDI events sent per second.
+    pub rate: u32,
+    /// Connect through memory instead of TCP on the loopback interface.
+    pub memory: bool,
+}
+
+/// Numbers of a soak test so far.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SoakReport {
+    pub elapsed_s: f64,
+    pub events_sent: u64,
+    pub events_received: u64,
+    pub lost: u64,
+    pub reconnects: u64,
+    /// Resident memory, where the platform tells.
+    pub memory_mb: Option<f64>,
+    pub memory_growth_mb: Option<f64>,
+    /// How fast the offset between the sender and receiver clocks changes.
+    pub drift_us_per_hour: f64,
+}
+
+impl fmt::Display for SoakReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Soak {:.0}s: {} sent, {} received, {} lost, {} reconnects, drift {:.1} µs/h",
+            self.elapsed_s,
+            self.events_sent,
+            self.events_received,
+            self.lost,
+            self.reconnects,
+            self.drift_us_per_hour
+        )?;
+        if let (Some(memory), Some(growth)) = (self.memory_mb, self.memory_growth_mb) {
+            write!(f, ", memory {:.1} MB ({:+.1} MB)", memory, growth)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(NetworkBehaviour)]
+struct SoakBehaviour {
+    midi: request_response::Behaviour<MidiCodec>,
+}
+
+/// Resident memory of this process in bytes, only known on Linux.
+fn resident_memory() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    Some(pages * 4096)
+}
+
+fn soak_swarm(memory: bool) -> Result<Swarm<SoakBehaviour>, Box<dyn Error>> {
+    let key = identity::Keypair::generate_ed25519();
+    let peer_id = PeerId::from(key.public());
+    let transport: Boxed<(PeerId, StreamMuxerBox)> = match memory {
+        true => MemoryTransport::default()
+            .upgrade(upgrade::Version::V1)
+            .authenticate(noise::Config::new(&key)?)
+            .multiplex(yamux::Config::default())
+            .boxed(),
+        false => tcp::tokio::Transport::new(tcp::Config::default())
+            .upgrade(upgrade::Version::V1)
+            .authenticate(noise::Config::new(&key)?)
+            .multiplex(yamux::Config::default())
+            .boxed(),
+    };
+    let behaviour = SoakBehaviour {
+        midi: request_response::Behaviour::new(
+            [(protocol::PROTOCOL, request_response::ProtocolSupport::Full)],
+            request_response::Config::default(),
+        ),
+    };
+    Ok(SwarmBuilder::with_tokio_executor(transport, behaviour, peer_id).build())
+}
+
+/// Tracks the clock offset between sender and receiver, keeping the smallest one of each report
+/// interval as the least delayed estimate.
+#[derive(Default)]
+struct DriftEstimator {
+    first: Option<(Instant, i64)>,
+    window_min: Option<i64>,
+    drift_us_per_hour: f64,
+}
+
+impl DriftEstimator {
+    fn observe(&mut self, offset_us: i64) {
+        self.window_min = Some(self.window_min.map_or(offset_us, |m| m.min(offset_us)));
+    }
+
+    fn end_window(&mut self) -> f64 {
+        if let Some(offset) = self.window_min.take() {
+            match self.first {
+                None => self.first = Some((Instant::now(), offset)),
+                Some((since, first)) => {
+                    let hours = since.elapsed().as_secs_f64() / 3600.0;
+                    if hours > 0.0 {
+                        self.drift_us_per_hour = (offset - first) as f64 / hours;
+                    }
+                }
+            }
+        }
+        self.drift_us_per_hour
+    }
+}
+
+/// Notes on and off over a few octaves, so a stuck note would be obvious on a synth.
+fn generated_message(n: u64) -> Vec<u8> {
+    let note = 36 + (n / 2 % 48) as u8;
+    match n % 2 {
+        0 => vec![0x90, note, 64 + (n % 64) as u8],
+        _ => vec![0x80, note, 0],
+    }
+}
+
+/// Stream generated MIDI between two swarms in this process for `duration`, reporting memory,
+/// reconnects, loss and clock drift along the way. Fails if any MIDI was lost.
+pub fn run_selftest(options: SelftestOptions, reporter: Reporter) -> Result<(), Box<dyn Error>> {
+    let _selftest = info_span!("selftest", memory = options.memory).entered();
+    let _runtime = runtime::enter();
+    let mut sender = soak_swarm(options.memory)?;
+    let mut receiver = soak_swarm(options.memory)?;
+    let receiver_id = *receiver.local_peer_id();
+    receiver.listen_on(match options.memory {
+        true => "/memory/0".parse()?,
+        false => "/ip4/127.0.0.1/tcp/0".parse()?,
+    })?;
+
+    let (stop_sender, mut stop) = futures::channel::mpsc::unbounded();
+    if let Err(e) = ctrlc::set_handler(move || {
+        let _ = stop_sender.unbounded_send(());
+    }) {
+        warn!("Could not handle Ctrl-C: {}", e);
+    }
+
+    let report = runtime::block_on(async {
+        let address: Multiaddr = loop {
+            if let SwarmEvent::NewListenAddr { address, .. } = receiver.select_next_some().await {
+                break address;
+            }
+        };
+        info!("Soak testing over {} for {:?}", address, options.duration);
+        sender.dial(address.clone())?;
+
+        let start = Instant::now();
+        let interval = Duration::from_secs(1) / options.rate.max(1);
+        let mut sequencer = FrameSequencer::default();
+        let mut tracker = SequenceTracker::default();
+        let mut drift = DriftEstimator::default();
+        let mut connected = false;
+        let mut reconnects = 0;
+        let mut sent = 0;
+        let baseline_memory = resident_memory();
+
+        let mut tick = futures_timer::Delay::new(interval).fuse();
+        let mut report_timer = futures_timer::Delay::new(REPORT_INTERVAL).fuse();
+        let mut end = futures_timer::Delay::new(options.duration).fuse();
+        let make_report = |sent, tracker: &SequenceTracker, drift: f64, reconnects| {
+            let memory = resident_memory();
+            SoakReport {
+                elapsed_s: start.elapsed().as_secs_f64(),
+                events_sent: sent,
+                events_received: tracker.stats().received,
+                lost: tracker.stats().lost,
+                reconnects,
+                memory_mb: memory.map(|m| m as f64 / 1e6),
+                memory_growth_mb: memory
+                    .zip(baseline_memory)
+                    .map(|(now, base)| (now as f64 - base as f64) / 1e6),
+                drift_us_per_hour: drift,
+            }
+        };
+
+        loop {
+            futures::select! {
+                event = sender.select_next_some() => match event {
+                    SwarmEvent::ConnectionEstablished { .. } => connected = true,
+                    SwarmEvent::ConnectionClosed { num_established: 0, cause, .. } => {
+                        warn!("Connection closed: {:?}, reconnecting", cause);
+                        connected = false;
+                        reconnects += 1;
+                        sender.dial(address.clone())?;
+                    }
+                    SwarmEvent::OutgoingConnectionError { error, .. } => {
+                        warn!("Could not reconnect: {}", error);
+                        sender.dial(address.clone())?;
+                    }
+                    SwarmEvent::Behaviour(SoakBehaviourEvent::Midi(
+                        request_response::Event::OutboundFailure { error, .. },
+                    )) => warn!("Error sending MIDI: {}", error),
+                    event => debug!("{:?}", event),
+                },
+                event = receiver.select_next_some() => match event {
+                    SwarmEvent::Behaviour(SoakBehaviourEvent::Midi(
+                        request_response::Event::Message {
+                            message: request_response::Message::Request { request, channel, .. },
+                            ..
+                        },
+                    )) => {
+                        let _ = receiver.behaviour_mut().midi.send_response(channel, ());
+                        let received_us = start.elapsed().as_micros() as i64;
+                        for frame in &request {
+                            tracker.observe(frame.seq);
+                            drift.observe(received_us - frame.timestamp_us as i64);
+                        }
+                    }
+                    event => debug!("{:?}", event),
+                },
+                _ = tick => {
+                    tick = futures_timer::Delay::new(interval).fuse();
+                    if connected {
+                        let frame: MidiFrame = sequencer.frame(generated_message(sent));
+                        sender.behaviour_mut().midi.send_request(&receiver_id, vec![frame]);
+                        sent += 1;
+                    }
+                },
+                _ = report_timer => {
+                    report_timer = futures_timer::Delay::new(REPORT_INTERVAL).fuse();
+                    let report = make_report(sent, &tracker, drift.end_window(), reconnects);
+                    reporter.report(Report::Soak(report));
+                },
+                _ = end => break,
+                _ = stop.select_next_some() => {
+                    info!("Stopping early");
+                    break;
+                }
+            }
+        }
+
+        // Let the last batches arrive before counting
+        let mut grace = futures_timer::Delay::new(Duration::from_secs(2)).fuse();
+        loop {
+            futures::select! {
+                event = receiver.select_next_some() => {
+                    if let SwarmEvent::Behaviour(SoakBehaviourEvent::Midi(
+                        request_response::Event::Message {
+                            message: request_response::Message::Request { request, channel, .. },
+                            ..
+                        },
+                    )) = event
+                    {
+                        let _ = receiver.behaviour_mut().midi.send_response(channel, ());
+                        for frame in &request {
+                            tracker.observe(frame.seq);
+                        }
+                    }
+                },
+                _ = sender.select_next_some() => {},
+                _ = grace => break,
+            }
+        }
+        Ok::<SoakReport, Box<dyn Error>>(make_report(
+            sent,
+            &tracker,
+            drift.end_window(),
+            reconnects,
+        ))
+    })?;
+
+    let lost = report.events_sent - report.events_received.min(report.events_sent);
+    reporter.report(Report::Soak(report));
+    match lost {
+        0 => Ok(()),
+        lost => Err(Failure::Runtime(format!("{} MIDI events were lost", lost)).into()),
+    }
+}
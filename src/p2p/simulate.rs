@@ -0,0 +1,154 @@
+use futures::channel::mpsc::UnboundedSender;
+use rand::Rng;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::trace;
+
+use super::protocol::MidiFrame;
+use crate::runtime;
+
+/// Network conditions to simulate on received MIDI, written like
+/// `latency=40ms,jitter=10ms,loss=2%,reorder=1%`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NetworkConditions {
+    /// Delay added to every batch.
+    pub latency: Duration,
+    /// Batches are delayed by up to this much more or less than `latency`.
+    pub jitter: Duration,
+    /// Share of frames dropped, from 0 to 1.
+    pub loss: f64,
+    /// Share of batches held back long enough to arrive after the next ones, from 0 to 1.
+    pub reorder: f64,
+}
+
+/// Parse durations like `40ms`, `1.5s` or `8h`. Plain numbers are milliseconds.
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let (number, unit) = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .map(|i| value.split_at(i))
+        .unwrap_or((value, "ms"));
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("Invalid duration '{}'", value))?;
+    let seconds = match unit {
+        "us" => number / 1_000_000.0,
+        "ms" => number / 1000.0,
+        "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 3600.0,
+        _ => {
+            return Err(format!(
+                "Unknown unit in '{}', use us, ms, s, m or h",
+                value
+            ))
+        }
+    };
+    Ok(Duration::from_secs_f64(seconds))
+}
+
+fn parse_share(value: &str) -> Result<f64, String> {
+    let share = match value.strip_suffix('%') {
+        Some(percent) => percent.parse::<f64>().map(|p| p / 100.0),
+        None => value.parse::<f64>(),
+    }
+    .map_err(|_| format!("Invalid percentage '{}'", value))?;
+    if !(0.0..=1.0).contains(&share) {
+        return Err(format!("'{}' is not between 0% and 100%", value));
+    }
+    Ok(share)
+}
+
+impl FromStr for NetworkConditions {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut conditions = NetworkConditions::default();
+        for setting in s.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let (key, value) = setting
+                .split_once('=')
+                .ok_or_else(|| format!("Expected key=value, got '{}'", setting))?;
+            match key.trim() {
+                "latency" => conditions.latency = parse_duration(value.trim())?,
+                "jitter" => conditions.jitter = parse_duration(value.trim())?,
+                "loss" => conditions.loss = parse_share(value.trim())?,
+                "reorder" => conditions.reorder = parse_share(value.trim())?,
+                key => {
+                    return Err(format!(
+                        "Unknown network condition '{}', expected latency, jitter, loss or reorder",
+                        key
+                    ))
+                }
+            }
+        }
+        Ok(conditions)
+    }
+}
+
+impl fmt::Display for NetworkConditions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "latency={}ms,jitter={}ms,loss={}%,reorder={}%",
+            self.latency.as_millis(),
+            self.jitter.as_millis(),
+            self.loss * 100.0,
+            self.reorder * 100.0
+        )
+    }
+}
+
+/// Hands received batches on to the session, through the simulated network conditions if any.
+pub struct NetworkSimulator<T> {
+    conditions: Option<NetworkConditions>,
+    deliver: UnboundedSender<(T, Vec<MidiFrame>)>,
+}
+
+impl<T: Send + 'static> NetworkSimulator<T> {
+    pub fn new(
+        conditions: Option<NetworkConditions>,
+        deliver: UnboundedSender<(T, Vec<MidiFrame>)>,
+    ) -> Self {
+        NetworkSimulator {
+            conditions,
+            deliver,
+        }
+    }
+
+    /// Pass a batch on right away without conditions, otherwise drop some of its frames and
+    /// deliver the rest later.
+    pub fn receive(&self, item: T, mut frames: Vec<MidiFrame>) {
+        let conditions = match &self.conditions {
+            Some(c) => c,
+            None => {
+                let _ = self.deliver.unbounded_send((item, frames));
+                return;
+            }
+        };
+        let mut rng = rand::thread_rng();
+        frames.retain(|frame| {
+            let kept = !rng.gen_bool(conditions.loss);
+            if !kept {
+                trace!(seq = frame.seq, "Simulated loss");
+            }
+            kept
+        });
+        if frames.is_empty() {
+            return;
+        }
+
+        let jitter = conditions.jitter.as_secs_f64();
+        let mut delay = conditions.latency.as_secs_f64() + rng.gen_range(-jitter..=jitter);
+        if rng.gen_bool(conditions.reorder) {
+            // Long enough to be overtaken by the batches sent right after
+            delay += conditions.latency.as_secs_f64() + 2.0 * jitter + 0.01;
+            trace!("Simulated reordering");
+        }
+        let delay = Duration::from_secs_f64(delay.max(0.0));
+        let deliver = self.deliver.clone();
+        runtime::spawn(async move {
+            tokio::time::sleep(delay).await;
+            let _ = deliver.unbounded_send((item, frames));
+        });
+    }
+}
@@ -0,0 +1,107 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::latency::Histogram;
+
+/// What happened during a session, reported when it ends.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SessionReport {
+    pub duration_s: f64,
+    pub peers: Vec<String>,
+    pub transports: Vec<String>,
+    pub events_sent: u64,
+    pub events_received: u64,
+    pub rtt_avg_ms: f64,
+    pub rtt_p50_ms: f64,
+    pub rtt_p99_ms: f64,
+    /// Connections to peers that had been connected before.
+    pub reconnects: u64,
+}
+
+impl fmt::Display for SessionReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Session report")?;
+        writeln!(f, "  Duration:   {:.0}s", self.duration_s)?;
+        writeln!(f, "  Peers:      {}", self.peers.join(", "))?;
+        writeln!(f, "  Transports: {}", self.transports.join(", "))?;
+        writeln!(
+            f,
+            "  MIDI:       {} sent, {} received",
+            self.events_sent, self.events_received
+        )?;
+        writeln!(
+            f,
+            "  RTT:        avg {:.1} ms, p50 {:.1} ms, p99 {:.1} ms",
+            self.rtt_avg_ms, self.rtt_p50_ms, self.rtt_p99_ms
+        )?;
+        write!(f, "  Reconnects: {}", self.reconnects)
+    }
+}
+
+/// Collects the numbers of a [`SessionReport`] while the session runs.
+pub struct SessionSummary {
+    start: Instant,
+    /// Display name of every peer seen, by PeerId.
+    peers: BTreeMap<String, String>,
+    transports: BTreeSet<&'static str>,
+    events_sent: u64,
+    events_received: u64,
+    rtt: Histogram,
+    reconnects: u64,
+}
+
+impl Default for SessionSummary {
+    fn default() -> Self {
+        SessionSummary {
+            start: Instant::now(),
+            peers: BTreeMap::new(),
+            transports: BTreeSet::new(),
+            events_sent: 0,
+            events_received: 0,
+            rtt: Histogram::default(),
+            reconnects: 0,
+        }
+    }
+}
+
+impl SessionSummary {
+    pub fn peer_connected(&mut self, peer_id: &str, name: &str, transport: &'static str) {
+        if self
+            .peers
+            .insert(peer_id.to_string(), name.to_string())
+            .is_some()
+        {
+            self.reconnects += 1;
+        }
+        self.transports.insert(transport);
+    }
+
+    pub fn rtt(&mut self, rtt: Duration) {
+        self.rtt.record(rtt);
+    }
+
+    pub fn sent(&mut self, events: usize) {
+        self.events_sent += events as u64;
+    }
+
+    pub fn received(&mut self, events: usize) {
+        self.events_received += events as u64;
+    }
+
+    pub fn report(&self) -> SessionReport {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        SessionReport {
+            duration_s: self.start.elapsed().as_secs_f64(),
+            peers: self.peers.values().cloned().collect(),
+            transports: self.transports.iter().map(|t| t.to_string()).collect(),
+            events_sent: self.events_sent,
+            events_received: self.events_received,
+            rtt_avg_ms: ms(self.rtt.mean()),
+            rtt_p50_ms: ms(self.rtt.quantile(0.5)),
+            rtt_p99_ms: ms(self.rtt.quantile(0.99)),
+            reconnects: self.reconnects,
+        }
+    }
+}
@@ -0,0 +1,47 @@
+//! Pre-shared swarm keys. Nodes only talk to nodes holding the same key, so a band can keep its
+//! clients and private relay out of reach of everyone else. Keys use the `swarm.key` format of
+//! other libp2p implementations.
+//!
+//! QUIC can't carry a pre-shared key, so private swarms only use TCP.
+
+use futures::future::{self, Either, FutureExt, TryFutureExt};
+use libp2p::core::transport::{Boxed, Transport};
+use libp2p::pnet::{PnetConfig, PnetOutput, PreSharedKey};
+use libp2p::tcp;
+use std::error::Error;
+use std::path::Path;
+use tracing::info;
+
+/// Read the swarm key at `path`.
+pub fn load(path: &Path) -> Result<PreSharedKey, Box<dyn Error>> {
+    let path = shellexpand::tilde(&path.display().to_string()).into_owned();
+    let contents = std::fs::read_to_string(&path)
+        .map_err(|e| format!("Could not read swarm key {}: {}", path, e))?;
+    let key: PreSharedKey = contents
+        .trim()
+        .parse()
+        .map_err(|e| format!("Invalid swarm key {}: {}", path, e))?;
+    info!("Private swarm {}", key.fingerprint());
+    Ok(key)
+}
+
+/// TCP, behind the swarm key when there is one.
+pub(crate) fn tcp_transport(
+    config: tcp::Config,
+    key: Option<PreSharedKey>,
+) -> Boxed<Either<PnetOutput<tcp::tokio::TcpStream>, tcp::tokio::TcpStream>> {
+    tcp::tokio::Transport::new(config)
+        .and_then(move |socket, _| match key {
+            Some(key) => PnetConfig::new(key)
+                .handshake(socket)
+                .map_ok(Either::Left)
+                .left_future(),
+            None => future::ok(Either::Right(socket)).right_future(),
+        })
+        .boxed()
+}
+
+/// A new random swarm key, in the format `load` reads.
+pub fn generate() -> String {
+    PreSharedKey::new(rand::random()).to_string()
+}
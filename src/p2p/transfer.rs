@@ -0,0 +1,191 @@
+//! Small files sent between peers during a session, like patch SysEx dumps and presets. The sender
+//! offers a file, and only once the other side accepts it does it fetch the file. Offered files
+//! stay in memory until fetched, declined or the peer leaves, so their size is bounded.
+
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use libp2p::{request_response, StreamProtocol};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::directory::{read_json, write_json};
+
+/// Protocol files are offered and fetched over.
+pub const TRANSFER_PROTOCOL: StreamProtocol = StreamProtocol::new("/p2pmidi/transfer/1.0.0");
+
+/// Largest file sent or taken.
+pub const MAX_FILE_SIZE: u64 = 1024 * 1024;
+
+/// Most offers waiting for an answer, each way.
+pub const MAX_PENDING_OFFERS: usize = 16;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "request", rename_all = "snake_case")]
+pub enum TransferRequest {
+    /// Ask the peer whether it wants the file, fetching it with the same `id` if it does.
+    Offer {
+        id: u64,
+        name: String,
+        size: u64,
+    },
+    Fetch {
+        id: u64,
+    },
+    Decline {
+        id: u64,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "response", rename_all = "snake_case")]
+pub enum TransferResponse {
+    Ack,
+    /// Followed on the wire by the `size` bytes of the file.
+    File {
+        name: String,
+        size: u64,
+        #[serde(skip)]
+        data: Vec<u8>,
+    },
+    Refused {
+        reason: String,
+    },
+}
+
+impl TransferResponse {
+    pub fn file(name: String, data: Vec<u8>) -> Self {
+        TransferResponse::File {
+            name,
+            size: data.len() as u64,
+            data,
+        }
+    }
+}
+
+/// A file a peer offered, waiting to be accepted or declined.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FileOffer {
+    pub peer_id: String,
+    pub id: u64,
+    pub name: String,
+    pub size: u64,
+}
+
+/// Read a file to offer, refusing those too large to send.
+pub fn read_file(path: &Path) -> Result<(String, Vec<u8>), String> {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .ok_or_else(|| format!("{} is not a file", path.display()))?;
+    let size = std::fs::metadata(path)
+        .map_err(|e| format!("Error reading {}: {}", path.display(), e))?
+        .len();
+    if size > MAX_FILE_SIZE {
+        return Err(format!(
+            "{} is {} bytes, files up to {} bytes can be sent",
+            path.display(),
+            size,
+            MAX_FILE_SIZE
+        ));
+    }
+    let data =
+        std::fs::read(path).map_err(|e| format!("Error reading {}: {}", path.display(), e))?;
+    Ok((name, data))
+}
+
+/// Save a received file in `dir` under the name it was sent with, never leaving `dir` and never
+/// overwriting another file. Returns where it was saved.
+pub fn save_file(dir: &Path, name: &str, data: &[u8]) -> io::Result<PathBuf> {
+    std::fs::create_dir_all(dir)?;
+    let name = Path::new(name)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .filter(|name| !name.starts_with('.'))
+        .unwrap_or_else(|| "received".to_string());
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) => (stem.to_string(), format!(".{}", extension)),
+        None => (name.clone(), String::new()),
+    };
+    let mut path = dir.join(&name);
+    let mut n = 1;
+    while path.exists() {
+        path = dir.join(format!("{} ({}){}", stem, n, extension));
+        n += 1;
+    }
+    std::fs::write(&path, data)?;
+    Ok(path)
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Length prefixed JSON, with the file itself raw after the response carrying it.
+#[derive(Debug, Clone, Default)]
+pub struct TransferCodec;
+
+#[async_trait]
+impl request_response::Codec for TransferCodec {
+    type Protocol = StreamProtocol;
+    type Request = TransferRequest;
+    type Response = TransferResponse;
+
+    async fn read_request<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+    ) -> io::Result<TransferRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_json(io).await
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+    ) -> io::Result<TransferResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut response = read_json(io).await?;
+        if let TransferResponse::File { size, data, .. } = &mut response {
+            if *size > MAX_FILE_SIZE {
+                return Err(invalid(format!("File of {} bytes is too large", size)));
+            }
+            data.resize(*size as usize, 0);
+            io.read_exact(data).await?;
+        }
+        Ok(response)
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        request: TransferRequest,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_json(io, &request).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        response: TransferResponse,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_json(io, &response).await?;
+        if let TransferResponse::File { data, .. } = &response {
+            io.write_all(data).await?;
+        }
+        Ok(())
+    }
+}
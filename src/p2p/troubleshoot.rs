@@ -0,0 +1,532 @@
+//! Checks of the way to the relay and on to other peers, run in order and stopping at the first one
+//! failing with advice on what to do about it, so "it doesn't connect" turns into which step
+//! doesn't and why.
+
+use futures::{future::FutureExt, stream::StreamExt};
+use libp2p::{
+    core::multiaddr::Protocol, dcutr, identity, ping, pnet::PreSharedKey, relay, swarm::SwarmEvent,
+    Multiaddr, PeerId, Swarm,
+};
+use serde::Serialize;
+use std::error::Error;
+use std::fmt;
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+use tracing::{info, info_span};
+
+use super::client::{
+    bootstrap, build_swarm, describe_transport, relay_multiaddr, Behaviour, Event,
+};
+use super::trust::agent_version;
+use crate::failure::Failure;
+use crate::output::{Report, Reporter};
+use crate::runtime;
+
+/// Settings of a `p2pmidi troubleshoot` run.
+#[derive(Clone, Debug)]
+pub struct TroubleshootOptions {
+    pub relay_address: String,
+    pub relay_port: u16,
+    pub relay_peer_id: Option<PeerId>,
+    pub use_ipv6: bool,
+    pub bind_address: Option<IpAddr>,
+    pub swarm_key: Option<PreSharedKey>,
+    /// A peer connected to the relay to hole punch to. The last check is skipped without one.
+    pub peer: Option<PeerId>,
+    /// How long each check waits for an answer.
+    pub timeout: Duration,
+}
+
+/// The checks, in the order they are run.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Check {
+    Dns,
+    Tcp,
+    Quic,
+    Reservation,
+    /// Whether we are behind NAT, from the address the relay sees us at.
+    Nat,
+    HolePunch,
+}
+
+impl Check {
+    pub const ALL: [Check; 6] = [
+        Check::Dns,
+        Check::Tcp,
+        Check::Quic,
+        Check::Reservation,
+        Check::Nat,
+        Check::HolePunch,
+    ];
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Check::Dns => write!(f, "Relay address"),
+            Check::Tcp => write!(f, "TCP to the relay"),
+            Check::Quic => write!(f, "QUIC to the relay"),
+            Check::Reservation => write!(f, "Relay reservation"),
+            Check::Nat => write!(f, "NAT"),
+            Check::HolePunch => write!(f, "Hole punch"),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum Outcome {
+    Passed {
+        detail: String,
+    },
+    /// Failed, but sessions do without it so the checks go on.
+    Warning {
+        error: String,
+        advice: String,
+    },
+    Failed {
+        error: String,
+        advice: String,
+    },
+    /// Not run, after an earlier check failed or for lack of what it needs.
+    Skipped {
+        reason: String,
+    },
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct CheckResult {
+    pub check: Check,
+    #[serde(flatten)]
+    pub outcome: Outcome,
+}
+
+impl fmt::Display for CheckResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.outcome {
+            Outcome::Passed { detail } => write!(f, "[ok]   {}: {}", self.check, detail),
+            Outcome::Warning { error, advice } => {
+                write!(f, "[warn] {}: {}\n       {}", self.check, error, advice)
+            }
+            Outcome::Failed { error, advice } => {
+                write!(f, "[fail] {}: {}\n       {}", self.check, error, advice)
+            }
+            Outcome::Skipped { reason } => write!(f, "[skip] {}: {}", self.check, reason),
+        }
+    }
+}
+
+fn failed(error: impl ToString, advice: impl ToString) -> Outcome {
+    Outcome::Failed {
+        error: error.to_string(),
+        advice: advice.to_string(),
+    }
+}
+
+/// Dial the relay over QUIC and hang up once connected.
+fn connect_quic(
+    swarm: &mut Swarm<Behaviour>,
+    address: Multiaddr,
+    timeout: Duration,
+) -> Result<(), String> {
+    swarm.dial(address).map_err(|e| e.to_string())?;
+    runtime::block_on(async {
+        let mut deadline = futures_timer::Delay::new(timeout).fuse();
+        loop {
+            futures::select! {
+                event = swarm.select_next_some() => match event {
+                    SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. }
+                        if describe_transport(endpoint.get_remote_address()) == "QUIC" =>
+                    {
+                        let _ = swarm.disconnect_peer_id(peer_id);
+                        return Ok(());
+                    }
+                    SwarmEvent::OutgoingConnectionError { error, .. } => {
+                        return Err(error.to_string());
+                    }
+                    _ => {}
+                },
+                _ = deadline => return Err(format!("No answer after {}s", timeout.as_secs())),
+            }
+        }
+    })
+}
+
+/// Listen on the relay circuit and wait for the relay to accept the reservation.
+fn reserve(
+    swarm: &mut Swarm<Behaviour>,
+    relay_address: &Multiaddr,
+    timeout: Duration,
+) -> Result<(), String> {
+    let listener = swarm
+        .listen_on(relay_address.clone().with(Protocol::P2pCircuit))
+        .map_err(|e| e.to_string())?;
+    runtime::block_on(async {
+        let mut deadline = futures_timer::Delay::new(timeout).fuse();
+        loop {
+            futures::select! {
+                event = swarm.select_next_some() => match event {
+                    SwarmEvent::Behaviour(Event::Relay(
+                        relay::client::Event::ReservationReqAccepted { .. },
+                    )) => return Ok(()),
+                    SwarmEvent::ListenerClosed { listener_id, reason: Err(e), .. }
+                        if listener_id == listener =>
+                    {
+                        return Err(e.to_string());
+                    }
+                    SwarmEvent::ListenerError { listener_id, error } if listener_id == listener => {
+                        return Err(error.to_string());
+                    }
+                    _ => {}
+                },
+                _ = deadline => return Err(format!("No answer after {}s", timeout.as_secs())),
+            }
+        }
+    })
+}
+
+/// Dial `peer` through the relay and wait for hole punching to connect to it directly. Fails
+/// with the error and whether the peer was reached through the relay.
+fn hole_punch(
+    swarm: &mut Swarm<Behaviour>,
+    relay_address: &Multiaddr,
+    peer: PeerId,
+    timeout: Duration,
+) -> Result<(), (String, bool)> {
+    swarm
+        .dial(
+            relay_address
+                .clone()
+                .with(Protocol::P2pCircuit)
+                .with(Protocol::P2p(peer)),
+        )
+        .map_err(|e| (e.to_string(), false))?;
+    runtime::block_on(async {
+        let mut deadline = futures_timer::Delay::new(timeout).fuse();
+        let mut relayed = false;
+        loop {
+            futures::select! {
+                event = swarm.select_next_some() => match event {
+                    SwarmEvent::ConnectionEstablished { peer_id, .. } if peer_id == peer => {
+                        relayed = true;
+                    }
+                    SwarmEvent::OutgoingConnectionError { peer_id: Some(peer_id), error, .. }
+                        if peer_id == peer && !relayed =>
+                    {
+                        return Err((error.to_string(), false));
+                    }
+                    SwarmEvent::Behaviour(Event::Dcutr(
+                        dcutr::Event::DirectConnectionUpgradeSucceeded { remote_peer_id },
+                    )) if remote_peer_id == peer => return Ok(()),
+                    SwarmEvent::Behaviour(Event::Dcutr(
+                        dcutr::Event::DirectConnectionUpgradeFailed { remote_peer_id, error },
+                    )) if remote_peer_id == peer => return Err((error.to_string(), true)),
+                    _ => {}
+                },
+                _ = deadline => {
+                    let error = format!("No direct connection after {}s", timeout.as_secs());
+                    return Err((error, relayed));
+                }
+            }
+        }
+    })
+}
+
+/// Run the checks, handing each result to `report` as it comes. Returns the check that failed.
+fn run_checks(
+    options: &TroubleshootOptions,
+    report: &mut dyn FnMut(Check, Outcome),
+) -> Result<Option<Check>, Box<dyn Error>> {
+    let host = options.relay_address.as_str();
+    let ip = match host.parse::<IpAddr>() {
+        Ok(ip) => {
+            report(
+                Check::Dns,
+                Outcome::Passed {
+                    detail: format!("{} is an IP address", ip),
+                },
+            );
+            ip
+        }
+        Err(_) => match (host, options.relay_port).to_socket_addrs() {
+            Ok(addresses) => {
+                let addresses: Vec<SocketAddr> = addresses.collect();
+                match addresses.iter().find(|a| a.is_ipv6() == options.use_ipv6) {
+                    // Sessions read relay_address as an IP address, the checks go on with it
+                    Some(address) => {
+                        report(
+                            Check::Dns,
+                            Outcome::Warning {
+                                error: format!("{} resolves to {}", host, address.ip()),
+                                advice: format!(
+                                    "Set relay_address to {}, sessions don't resolve hostnames",
+                                    address.ip()
+                                ),
+                            },
+                        );
+                        address.ip()
+                    }
+                    None => {
+                        report(
+                            Check::Dns,
+                            failed(
+                                format!("{} has no address of the IP version used", host),
+                                "Set relay_address to the IP address of the relay",
+                            ),
+                        );
+                        return Ok(Some(Check::Dns));
+                    }
+                }
+            }
+            Err(e) => {
+                report(
+                    Check::Dns,
+                    failed(
+                        format!("Could not resolve {}: {}", host, e),
+                        "Check the spelling of relay_address, and that this machine is online",
+                    ),
+                );
+                return Ok(Some(Check::Dns));
+            }
+        },
+    };
+
+    let relay = SocketAddr::new(ip, options.relay_port);
+    // The address the relay would see us at without NAT
+    let local_ip = match TcpStream::connect_timeout(&relay, options.timeout) {
+        Ok(stream) => {
+            report(
+                Check::Tcp,
+                Outcome::Passed {
+                    detail: format!("{} takes connections", relay),
+                },
+            );
+            stream.local_addr().ok().map(|address| address.ip())
+        }
+        Err(e) => {
+            let advice = match e.kind() {
+                ErrorKind::ConnectionRefused => format!(
+                    "Nothing listens on port {} of the relay: check relay_port, and that the \
+                     relay is running",
+                    options.relay_port
+                ),
+                ErrorKind::TimedOut | ErrorKind::WouldBlock => "A firewall on this network or \
+                    in front of the relay drops the connection, or the relay is down. Trying \
+                    from another network, like a phone hotspot, tells which"
+                    .to_string(),
+                _ => "Check that this machine is online".to_string(),
+            };
+            report(Check::Tcp, failed(e, advice));
+            return Ok(Some(Check::Tcp));
+        }
+    };
+
+    let relay_address = relay_multiaddr(
+        &ip.to_string(),
+        options.relay_port,
+        ip.is_ipv6(),
+        options.relay_peer_id,
+    )?;
+    // A random identity so the checks do not clash with a running session
+    let local_key = identity::Keypair::generate_ed25519();
+    let mut swarm = build_swarm(
+        &local_key,
+        ping::Config::new(),
+        agent_version(None),
+        options.swarm_key.clone(),
+    )?;
+
+    match options.swarm_key {
+        Some(_) => report(
+            Check::Quic,
+            Outcome::Skipped {
+                reason: "QUIC is off in private swarms".to_string(),
+            },
+        ),
+        None => {
+            let quic = Multiaddr::empty()
+                .with(match ip {
+                    IpAddr::V4(ip) => Protocol::Ip4(ip),
+                    IpAddr::V6(ip) => Protocol::Ip6(ip),
+                })
+                .with(Protocol::Udp(options.relay_port))
+                .with(Protocol::QuicV1);
+            match connect_quic(&mut swarm, quic, options.timeout) {
+                Ok(()) => report(
+                    Check::Quic,
+                    Outcome::Passed {
+                        detail: "The relay answers over QUIC".to_string(),
+                    },
+                ),
+                Err(e) => report(
+                    Check::Quic,
+                    Outcome::Warning {
+                        error: e,
+                        advice: format!(
+                            "UDP port {} of the relay is blocked. Sessions work over TCP, but \
+                             hole punching is less likely to succeed",
+                            options.relay_port
+                        ),
+                    },
+                ),
+            }
+        }
+    }
+
+    if let Err(e) = bootstrap(&mut swarm, &relay_address, options.bind_address) {
+        report(
+            Check::Reservation,
+            failed(
+                e,
+                "The relay takes TCP connections but not ours. Check relay_peer_id, and that \
+                 swarm_key is the one of the relay when it runs a private swarm",
+            ),
+        );
+        return Ok(Some(Check::Reservation));
+    }
+    if let Err(e) = reserve(&mut swarm, &relay_address, options.timeout) {
+        report(
+            Check::Reservation,
+            failed(
+                e,
+                "The relay does not let us wait for peers on it. It may be full or only let \
+                 known peers in: ask its operator, or try another relay",
+            ),
+        );
+        return Ok(Some(Check::Reservation));
+    }
+    report(
+        Check::Reservation,
+        Outcome::Passed {
+            detail: "Peers can reach us through the relay".to_string(),
+        },
+    );
+
+    let observed_ip = swarm
+        .external_addresses()
+        .flat_map(|address| address.iter())
+        .find_map(|protocol| match protocol {
+            Protocol::Ip4(ip) => Some(IpAddr::V4(ip)),
+            Protocol::Ip6(ip) => Some(IpAddr::V6(ip)),
+            _ => None,
+        });
+    let behind_nat = match (observed_ip, local_ip) {
+        (Some(observed), Some(local)) => {
+            report(
+                Check::Nat,
+                Outcome::Passed {
+                    detail: match observed == local {
+                        true => format!("Not behind NAT, peers see us at {}", observed),
+                        false => format!("Behind NAT, peers see {} at {}", local, observed),
+                    },
+                },
+            );
+            observed != local
+        }
+        _ => {
+            report(
+                Check::Nat,
+                Outcome::Warning {
+                    error: "The relay did not tell the address it sees us at".to_string(),
+                    advice: "Hole punching needs it, peers will likely stay relayed".to_string(),
+                },
+            );
+            true
+        }
+    };
+
+    let peer = match options.peer {
+        Some(peer) => peer,
+        None => {
+            report(
+                Check::HolePunch,
+                Outcome::Skipped {
+                    reason: "No peer to hole punch to".to_string(),
+                },
+            );
+            return Ok(None);
+        }
+    };
+    match hole_punch(&mut swarm, &relay_address, peer, options.timeout) {
+        Ok(()) => report(
+            Check::HolePunch,
+            Outcome::Passed {
+                detail: format!("Connected directly to {}", peer),
+            },
+        ),
+        Err((e, false)) => {
+            report(
+                Check::HolePunch,
+                failed(
+                    e,
+                    "The peer is not reachable through the relay. Check its PeerId, and that it \
+                     is running and connected to the same relay",
+                ),
+            );
+            return Ok(Some(Check::HolePunch));
+        }
+        Err((e, true)) => {
+            let advice = match behind_nat {
+                true => {
+                    "Both of you are likely behind NATs giving every destination another port. \
+                     Sessions still work through the relay, only later. Forwarding the port of \
+                     one of you on its router lets the other connect directly"
+                }
+                false => {
+                    "The peer is behind a NAT or firewall that lets nothing in. Sessions still \
+                     work through the relay, only later"
+                }
+            };
+            report(Check::HolePunch, failed(e, advice));
+            return Ok(Some(Check::HolePunch));
+        }
+    }
+    Ok(None)
+}
+
+/// Run the checks in order, handing each result to `report` as it comes. The ones after a failed
+/// check are skipped.
+pub fn troubleshoot(
+    options: &TroubleshootOptions,
+    report: &mut dyn FnMut(&CheckResult),
+) -> Result<Vec<CheckResult>, Box<dyn Error>> {
+    let _troubleshoot = info_span!("troubleshoot").entered();
+    let _runtime = runtime::enter();
+    let mut results: Vec<CheckResult> = Vec::new();
+    let mut push = |check, outcome| {
+        let result = CheckResult { check, outcome };
+        report(&result);
+        results.push(result);
+    };
+    if let Some(failed) = run_checks(options, &mut push)? {
+        info!("{} failed", failed);
+        for check in Check::ALL.into_iter().skip_while(|c| *c != failed).skip(1) {
+            push(
+                check,
+                Outcome::Skipped {
+                    reason: format!("{} failed", failed),
+                },
+            );
+        }
+    }
+    Ok(results)
+}
+
+/// Run the checks for `p2pmidi troubleshoot`, failing if one did.
+pub fn run_troubleshoot(
+    options: TroubleshootOptions,
+    reporter: Reporter,
+) -> Result<(), Box<dyn Error>> {
+    let results = troubleshoot(&options, &mut |result| {
+        reporter.report(Report::Check(result.clone()))
+    })?;
+    match results
+        .iter()
+        .find(|result| matches!(result.outcome, Outcome::Failed { .. }))
+    {
+        Some(result) => Err(Failure::Runtime(format!("{} failed", result.check)).into()),
+        None => Ok(()),
+    }
+}
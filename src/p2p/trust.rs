@@ -0,0 +1,131 @@
+//! Trust on first use. Peers are accepted by hand the first time they connect and remembered by
+//! PeerId, so someone showing up with a known name but another key stands out.
+
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::storage::{KnownPeer, KnownPeers, Storage};
+
+/// Prefix of the identify agent version, followed by the version and the name a peer goes by.
+const AGENT_PREFIX: &str = "p2pmidi/";
+
+/// What we know of a connecting peer.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Trust {
+    Known,
+    /// Never accepted before.
+    New,
+    /// Goes by a name that belongs to another accepted peer.
+    NameTaken {
+        known_peer_id: String,
+    },
+}
+
+/// Peers let in without asking when they connect.
+#[derive(clap::ValueEnum, Debug, Default, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AutoAccept {
+    /// Everyone who knows our address.
+    Everyone,
+    /// Peers accepted before.
+    #[default]
+    Known,
+    /// Peers accepted before whose emoji were compared.
+    Verified,
+    /// Nobody, always ask.
+    Nobody,
+}
+
+/// Agent version announced over identify, carrying our name.
+pub fn agent_version(name: Option<&str>) -> String {
+    format!(
+        "{}{} {}",
+        AGENT_PREFIX,
+        env!("CARGO_PKG_VERSION"),
+        name.unwrap_or_default()
+    )
+}
+
+/// The name a peer announced in its agent version.
+pub fn peer_name(agent_version: &str) -> Option<String> {
+    let (_, name) = agent_version.strip_prefix(AGENT_PREFIX)?.split_once(' ')?;
+    match name.trim() {
+        "" => None,
+        name => Some(name.to_string()),
+    }
+}
+
+/// Accepted peers, kept in the known peers file.
+pub struct TrustStore {
+    storage: Storage,
+    peers: KnownPeers,
+}
+
+impl TrustStore {
+    pub fn load(storage: &Storage) -> Result<Self, Box<dyn Error>> {
+        Ok(TrustStore {
+            storage: storage.clone(),
+            peers: storage.known_peers()?,
+        })
+    }
+
+    pub fn check(&self, peer_id: &str, name: Option<&str>) -> Trust {
+        if let Some(name) = name {
+            let owner = self
+                .peers
+                .iter()
+                .find(|(known, peer)| *known != peer_id && peer.name.as_deref() == Some(name));
+            if let Some((known_peer_id, _)) = owner {
+                return Trust::NameTaken {
+                    known_peer_id: known_peer_id.clone(),
+                };
+            }
+        }
+        match self.peers.contains_key(peer_id) {
+            true => Trust::Known,
+            false => Trust::New,
+        }
+    }
+
+    /// Remember `peer_id` as accepted, under the name it goes by now.
+    pub fn accept(&mut self, peer_id: &str, name: Option<&str>) -> Result<(), Box<dyn Error>> {
+        let peer = self
+            .peers
+            .entry(peer_id.to_string())
+            .or_insert_with(|| KnownPeer {
+                name: None,
+                verified: false,
+                first_seen: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or_default(),
+            });
+        if name.is_some() {
+            peer.name = name.map(|n| n.to_string());
+        }
+        self.storage.save_known_peers(&self.peers)
+    }
+
+    pub fn auto_accepts(&self, peer_id: &str, auto_accept: AutoAccept) -> bool {
+        match auto_accept {
+            AutoAccept::Everyone => true,
+            AutoAccept::Known => self.peers.contains_key(peer_id),
+            AutoAccept::Verified => self.is_verified(peer_id),
+            AutoAccept::Nobody => false,
+        }
+    }
+
+    pub fn is_verified(&self, peer_id: &str) -> bool {
+        self.peers.get(peer_id).map_or(false, |peer| peer.verified)
+    }
+
+    /// Mark an accepted peer as verified, after comparing short authentication strings.
+    pub fn verify(&mut self, peer_id: &str) -> Result<(), Box<dyn Error>> {
+        match self.peers.get_mut(peer_id) {
+            Some(peer) => peer.verified = true,
+            None => return Err(format!("{} was never accepted", peer_id).into()),
+        }
+        self.storage.save_known_peers(&self.peers)
+    }
+}
@@ -0,0 +1,141 @@
+//! Relay events POSTed as JSON to a webhook, for operators of community relays to follow them in
+//! their monitoring or chat. Only plain `http://` URLs are supported, put a local proxy in front
+//! of HTTPS endpoints.
+
+use futures::channel::mpsc::{UnboundedReceiver, UnboundedSender};
+use futures::StreamExt;
+use serde::Serialize;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tracing::{debug, info, info_span, warn, Instrument};
+
+use crate::runtime;
+
+/// How long a webhook has to answer before the event is given up on.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Something happening on the relay.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum RelayEvent {
+    ReservationCreated {
+        peer_id: String,
+        renewed: bool,
+    },
+    ReservationExpired {
+        peer_id: String,
+    },
+    CircuitOpened {
+        src_peer_id: String,
+        dst_peer_id: String,
+    },
+    /// The relay does not count the bytes of each circuit, the bytes it received and sent in all
+    /// since it started are given instead.
+    CircuitClosed {
+        src_peer_id: String,
+        dst_peer_id: String,
+        duration_secs: f64,
+        relay_bytes_in: u64,
+        relay_bytes_out: u64,
+        error: Option<String>,
+    },
+}
+
+/// What is POSTed: the event, which relay it happened on and when.
+#[derive(Serialize)]
+struct Payload<'a> {
+    relay: &'a str,
+    /// Seconds since the Unix epoch.
+    timestamp: u64,
+    #[serde(flatten)]
+    event: &'a RelayEvent,
+}
+
+/// Host, port and path of `http://host[:port][/path]`.
+pub fn parse_webhook_url(url: &str) -> Result<(String, u16, String), String> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| format!("Expected http://host[:port][/path], got {}", url))?;
+    let (address, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], rest[i..].to_string()),
+        None => (rest, "/".to_string()),
+    };
+    let (host, port) = match address.rsplit_once(':') {
+        Some((host, port)) => (
+            host,
+            port.parse()
+                .map_err(|_| format!("Invalid webhook port: {}", port))?,
+        ),
+        None => (address, 80),
+    };
+    if host.is_empty() {
+        return Err(format!("No webhook host in {}", url));
+    }
+    Ok((host.to_string(), port, path))
+}
+
+async fn post(host: &str, port: u16, path: &str, body: &[u8]) -> Result<(), String> {
+    let mut stream = TcpStream::connect((host, port))
+        .await
+        .map_err(|e| e.to_string())?;
+    let head = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        path,
+        host,
+        body.len()
+    );
+    stream
+        .write_all(head.as_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+    stream.write_all(body).await.map_err(|e| e.to_string())?;
+    let mut status_line = String::new();
+    BufReader::new(&mut stream)
+        .read_line(&mut status_line)
+        .await
+        .map_err(|e| e.to_string())?;
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(format!("Answered {}", status_line.trim())),
+    }
+}
+
+/// POST every event sent to the returned channel to `url`, one at a time in the order they
+/// happen. Events the webhook fails to take are logged and dropped.
+pub fn start(url: &str, relay: String) -> Result<UnboundedSender<RelayEvent>, String> {
+    let (host, port, path) = parse_webhook_url(url)?;
+    let (sender, mut events): (_, UnboundedReceiver<RelayEvent>) =
+        futures::channel::mpsc::unbounded();
+    info!("Posting relay events to {}", url);
+    let span = info_span!("webhook", %host);
+    runtime::spawn(
+        async move {
+            while let Some(event) = events.next().await {
+                let timestamp = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or_default();
+                let payload = Payload {
+                    relay: &relay,
+                    timestamp,
+                    event: &event,
+                };
+                let body = match serde_json::to_vec(&payload) {
+                    Ok(body) => body,
+                    Err(e) => {
+                        warn!("Error serializing relay event: {}", e);
+                        continue;
+                    }
+                };
+                match tokio::time::timeout(WEBHOOK_TIMEOUT, post(&host, port, &path, &body)).await {
+                    Ok(Ok(())) => debug!("Posted {:?}", event),
+                    Ok(Err(e)) => warn!("Webhook did not take {:?}: {}", event, e),
+                    Err(_) => warn!("Webhook timed out on {:?}", event),
+                }
+            }
+        }
+        .instrument(span),
+    );
+    Ok(sender)
+}
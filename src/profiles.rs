@@ -0,0 +1,117 @@
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use super::constants;
+use super::settings::{convert_config_file, ConfigAction, ConfigFormat};
+
+/// Path of the config file backing a profile. The default profile is the main config file.
+///
+/// An existing profile file in any supported format is preferred, new profiles are YAML.
+pub fn profile_path(name: &str) -> PathBuf {
+    if name == constants::DEFAULT_PROFILE {
+        let path = shellexpand::tilde(constants::DEFAULT_CONFIG_PATH).into_owned();
+        return PathBuf::from(path);
+    }
+    let dir = shellexpand::tilde(constants::PROFILES_DIR).into_owned();
+    ConfigFormat::EXTENSIONS
+        .iter()
+        .map(|ext| Path::new(&dir).join(format!("{}.{}", name, ext)))
+        .find(|path| path.exists())
+        .unwrap_or_else(|| Path::new(&dir).join(format!("{}.yml", name)))
+}
+
+/// Refuse names that would put the profile file outside the profiles directory.
+pub fn check_profile_name(name: &str) -> Result<(), Box<dyn Error>> {
+    if name.is_empty() || name.starts_with('.') || name.contains(std::path::is_separator) {
+        return Err(format!("Invalid profile name: {:?}", name).into());
+    }
+    Ok(())
+}
+
+/// Names of all existing profiles, always starting with the default one.
+pub fn list_profiles() -> Result<Vec<String>, Box<dyn Error>> {
+    let mut profiles = vec![constants::DEFAULT_PROFILE.to_string()];
+    let dir = shellexpand::tilde(constants::PROFILES_DIR).into_owned();
+    let entries = match std::fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(profiles),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut names = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+        if !ConfigFormat::EXTENSIONS.contains(&ext) {
+            continue;
+        }
+        if let Some(stem) = path.file_stem().and_then(|s| s.to_str()) {
+            if stem != constants::DEFAULT_PROFILE {
+                names.push(stem.to_string());
+            }
+        }
+    }
+    names.sort();
+    profiles.extend(names);
+    Ok(profiles)
+}
+
+/// Copy a profile into a new one. Refuses to overwrite an existing profile.
+pub fn copy_profile(from: &str, to: &str) -> Result<PathBuf, Box<dyn Error>> {
+    check_profile_name(from)?;
+    check_profile_name(to)?;
+    let source = profile_path(from);
+    let target = match source.extension() {
+        Some(ext) if to != constants::DEFAULT_PROFILE => profile_path(to).with_extension(ext),
+        _ => profile_path(to),
+    };
+    if !source.exists() {
+        return Err(format!("Profile {:?} does not exist", from).into());
+    }
+    if target.exists() {
+        return Err(format!("Profile {:?} already exists", to).into());
+    }
+    if let Some(parent) = target.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::copy(&source, &target)?;
+    Ok(target)
+}
+
+/// Delete a profile. The default profile can't be deleted.
+pub fn delete_profile(name: &str) -> Result<PathBuf, Box<dyn Error>> {
+    check_profile_name(name)?;
+    if name == constants::DEFAULT_PROFILE {
+        return Err("The default profile can't be deleted".into());
+    }
+    let path = profile_path(name);
+    if !path.exists() {
+        return Err(format!("Profile {:?} does not exist", name).into());
+    }
+    std::fs::remove_file(&path)?;
+    Ok(path)
+}
+
+/// Run a `p2pmidi config` subcommand.
+pub fn run_config_command(action: &ConfigAction) -> Result<(), Box<dyn Error>> {
+    match action {
+        ConfigAction::List => {
+            for name in list_profiles()? {
+                println!("{}\t{}", name, profile_path(&name).display());
+            }
+        }
+        ConfigAction::Copy { from, to } => {
+            let path = copy_profile(from, to)?;
+            println!("Copied profile {} to {}", from, path.display());
+        }
+        ConfigAction::Delete { name } => {
+            let path = delete_profile(name)?;
+            println!("Deleted profile {} ({})", name, path.display());
+        }
+        ConfigAction::Convert { from, to } => {
+            let path = convert_config_file(from, to)?;
+            println!("Converted {} to {}", from.display(), path);
+        }
+    }
+    Ok(())
+}
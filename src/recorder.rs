@@ -0,0 +1,141 @@
+use midly::num::{u15, u24, u28};
+use midly::{
+    live::LiveEvent, Arena, Format, Header, MetaMessage, Smf, Timing, TrackEvent, TrackEventKind,
+};
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+use std::time::Instant;
+
+use crate::clock::PeerClock;
+use crate::p2p::protocol::MidiFrame;
+
+/// Ticks per beat of recorded files, written at 120 bpm so one tick is about a millisecond.
+const TICKS_PER_BEAT: u16 = 480;
+const US_PER_BEAT: u32 = 500_000;
+
+struct RecordedTrack {
+    peer_id: String,
+    name: String,
+    /// Maps the peer's clock to ours.
+    clock: PeerClock,
+    events: Vec<(u64, Vec<u8>)>,
+}
+
+/// Collects MIDI received from peers, one track per peer, to save as a standard MIDI file.
+///
+/// Events are placed using the sender timestamps so network jitter doesn't end up in the file.
+pub struct SessionRecorder {
+    start: Instant,
+    tracks: Vec<RecordedTrack>,
+    track_of_peer: HashMap<String, usize>,
+}
+
+impl Default for SessionRecorder {
+    fn default() -> Self {
+        SessionRecorder {
+            start: Instant::now(),
+            tracks: Vec::new(),
+            track_of_peer: HashMap::new(),
+        }
+    }
+}
+
+impl SessionRecorder {
+    pub fn record(&mut self, peer_id: &str, name: &str, frame: &MidiFrame) {
+        let index = *self
+            .track_of_peer
+            .entry(peer_id.to_string())
+            .or_insert_with(|| {
+                self.tracks.push(RecordedTrack {
+                    peer_id: peer_id.to_string(),
+                    name: name.to_string(),
+                    clock: PeerClock::default(),
+                    events: Vec::new(),
+                });
+                self.tracks.len() - 1
+            });
+        let track = &mut self.tracks[index];
+        let now_us = self.start.elapsed().as_micros() as i64;
+        let remote_us = i64::try_from(frame.timestamp_us).unwrap_or(i64::MAX);
+        if !track.clock.observe(now_us, remote_us) {
+            return;
+        }
+        let at_us = track.clock.to_local(remote_us).max(0) as u64;
+        track.events.push((at_us, frame.message.to_vec()));
+    }
+
+    /// Number of recorded events.
+    pub fn len(&self) -> usize {
+        self.tracks.iter().map(|t| t.events.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Write everything recorded so far. Messages that are not valid MIDI are skipped.
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let bytes = self.to_smf(|_| true)?;
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() {
+                std::fs::create_dir_all(parent)?;
+            }
+        }
+        std::fs::write(path, bytes)?;
+        Ok(())
+    }
+
+    /// The tracks of the peers `include` picks by PeerId, as a standard MIDI file.
+    pub fn to_smf(&self, include: impl Fn(&str) -> bool) -> Result<Vec<u8>, Box<dyn Error>> {
+        let arena = Arena::new();
+        let mut smf = Smf::new(Header::new(
+            Format::Parallel,
+            Timing::Metrical(u15::new(TICKS_PER_BEAT)),
+        ));
+        smf.tracks.push(vec![
+            TrackEvent {
+                delta: u28::new(0),
+                kind: TrackEventKind::Meta(MetaMessage::Tempo(u24::new(US_PER_BEAT))),
+            },
+            TrackEvent {
+                delta: u28::new(0),
+                kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+            },
+        ]);
+
+        for recorded in self.tracks.iter().filter(|t| include(&t.peer_id)) {
+            let mut events = recorded.events.clone();
+            events.sort_by_key(|(at, _)| *at);
+
+            let mut track = vec![TrackEvent {
+                delta: u28::new(0),
+                kind: TrackEventKind::Meta(MetaMessage::TrackName(
+                    arena.add(recorded.name.as_bytes()),
+                )),
+            }];
+            let mut last_tick = 0;
+            for (at_us, message) in &events {
+                let live = match LiveEvent::parse(message) {
+                    Ok(live) => live,
+                    Err(_) => continue,
+                };
+                let tick = at_us * TICKS_PER_BEAT as u64 / US_PER_BEAT as u64;
+                track.push(TrackEvent {
+                    delta: u28::new((tick - last_tick).min(0x0FFF_FFFF) as u32),
+                    kind: live.as_track_event(&arena),
+                });
+                last_tick = tick;
+            }
+            track.push(TrackEvent {
+                delta: u28::new(0),
+                kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+            });
+            smf.tracks.push(track);
+        }
+
+        let mut bytes = Vec::new();
+        smf.write_std(&mut bytes)?;
+        Ok(bytes)
+    }
+}
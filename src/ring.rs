@@ -0,0 +1,354 @@
+//! Single producer, single consumer queue handing MIDI from the input callback to the network
+//! task. Pushing never allocates, locks or blocks, so it is safe from the realtime MIDI thread.
+//! Short messages go in fixed slots, SysEx in a byte ring of its own allocated up front.
+
+use futures::stream::{FusedStream, Stream};
+use futures::task::AtomicWaker;
+use std::cell::UnsafeCell;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+/// Longest message a slot holds. Longer ones, which can only be SysEx, go in the SysEx ring.
+pub const MAX_MESSAGE_LEN: usize = 16;
+
+/// Bytes of SysEx queued at most. Longer SysEx is dropped and counted as too long.
+pub const SYSEX_CAPACITY: usize = 64 * 1024;
+
+/// A queued message, its bytes in the slot or, longer than `MAX_MESSAGE_LEN`, the next `len`
+/// bytes of the SysEx ring.
+#[derive(Clone, Copy)]
+struct Slot {
+    at: Instant,
+    len: usize,
+    bytes: [u8; MAX_MESSAGE_LEN],
+}
+
+/// A MIDI message and when the input callback got it.
+#[derive(Debug, Clone)]
+pub struct RawEvent {
+    pub at: Instant,
+    len: u8,
+    bytes: [u8; MAX_MESSAGE_LEN],
+    /// SysEx too long for `bytes`.
+    sysex: Vec<u8>,
+}
+
+impl RawEvent {
+    pub fn message(&self) -> &[u8] {
+        match self.sysex.is_empty() {
+            true => &self.bytes[..self.len as usize],
+            false => &self.sysex,
+        }
+    }
+
+    pub fn message_mut(&mut self) -> &mut [u8] {
+        match self.sysex.is_empty() {
+            true => &mut self.bytes[..self.len as usize],
+            false => &mut self.sysex,
+        }
+    }
+}
+
+struct Shared {
+    slots: Box<[UnsafeCell<Option<Slot>>]>,
+    /// Count of events taken by the consumer.
+    head: AtomicUsize,
+    /// Count of events written by the producer.
+    tail: AtomicUsize,
+    sysex: Box<[UnsafeCell<u8>]>,
+    /// Count of SysEx bytes taken by the consumer.
+    sysex_head: AtomicUsize,
+    /// Count of SysEx bytes written by the producer.
+    sysex_tail: AtomicUsize,
+    /// Messages dropped with the queue full.
+    overflows: AtomicU64,
+    /// SysEx dropped for being longer than the SysEx ring.
+    too_long: AtomicU64,
+    waker: AtomicWaker,
+}
+
+// Each slot and SysEx byte is only accessed by the producer before `tail` moves past it and by
+// the consumer after, never both at once.
+unsafe impl Sync for Shared {}
+
+/// Create a queue holding up to `capacity` events.
+pub fn ring_buffer(capacity: usize) -> (Producer, Consumer) {
+    assert!(capacity > 0);
+    let shared = Arc::new(Shared {
+        slots: (0..capacity).map(|_| UnsafeCell::new(None)).collect(),
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+        sysex: (0..SYSEX_CAPACITY).map(|_| UnsafeCell::new(0)).collect(),
+        sysex_head: AtomicUsize::new(0),
+        sysex_tail: AtomicUsize::new(0),
+        overflows: AtomicU64::new(0),
+        too_long: AtomicU64::new(0),
+        waker: AtomicWaker::new(),
+    });
+    (
+        Producer {
+            shared: shared.clone(),
+        },
+        Consumer {
+            shared,
+            seen: 0,
+            seen_too_long: 0,
+        },
+    )
+}
+
+/// Writing end, owned by the MIDI input callback.
+pub struct Producer {
+    shared: Arc<Shared>,
+}
+
+impl Producer {
+    /// Queue a message, returns false when it is dropped. Messages dropped with the queue full
+    /// are counted as overflows, SysEx longer than `SYSEX_CAPACITY` as too long.
+    pub fn push(&mut self, message: &[u8]) -> bool {
+        let shared = &self.shared;
+        if message.len() > SYSEX_CAPACITY {
+            shared.too_long.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        let tail = shared.tail.load(Ordering::Relaxed);
+        let head = shared.head.load(Ordering::Acquire);
+        let sysex_tail = shared.sysex_tail.load(Ordering::Relaxed);
+        let sysex_head = shared.sysex_head.load(Ordering::Acquire);
+        let long = message.len() > MAX_MESSAGE_LEN;
+        let sysex_free = SYSEX_CAPACITY - sysex_tail.wrapping_sub(sysex_head);
+        if tail.wrapping_sub(head) == shared.slots.len() || (long && message.len() > sysex_free) {
+            shared.overflows.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        let mut bytes = [0u8; MAX_MESSAGE_LEN];
+        match long {
+            true => {
+                for (i, byte) in message.iter().enumerate() {
+                    let at = sysex_tail.wrapping_add(i) % SYSEX_CAPACITY;
+                    unsafe { *shared.sysex[at].get() = *byte };
+                }
+                shared
+                    .sysex_tail
+                    .store(sysex_tail.wrapping_add(message.len()), Ordering::Release);
+            }
+            false => bytes[..message.len()].copy_from_slice(message),
+        }
+        let slot = Slot {
+            at: Instant::now(),
+            len: message.len(),
+            bytes,
+        };
+        unsafe { *shared.slots[tail % shared.slots.len()].get() = Some(slot) };
+        shared.tail.store(tail.wrapping_add(1), Ordering::Release);
+        shared.waker.wake();
+        true
+    }
+}
+
+/// Reading end, owned by the network task. Yields events as a stream, use `pop` to drain the
+/// rest of a burst at once.
+pub struct Consumer {
+    shared: Arc<Shared>,
+    /// Overflows already reported by `new_overflows`.
+    seen: u64,
+    /// Too long SysEx already reported by `new_too_long`.
+    seen_too_long: u64,
+}
+
+impl Consumer {
+    /// Take the next message. Only SysEx longer than `MAX_MESSAGE_LEN` allocates.
+    pub fn pop(&mut self) -> Option<RawEvent> {
+        let shared = &self.shared;
+        let head = shared.head.load(Ordering::Relaxed);
+        if head == shared.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let slot = unsafe { (*shared.slots[head % shared.slots.len()].get()).take() };
+        let slot = match slot {
+            Some(slot) => slot,
+            None => {
+                shared.head.store(head.wrapping_add(1), Ordering::Release);
+                return None;
+            }
+        };
+        let mut sysex = Vec::new();
+        if slot.len > MAX_MESSAGE_LEN {
+            let sysex_head = shared.sysex_head.load(Ordering::Relaxed);
+            sysex = (0..slot.len)
+                .map(|i| unsafe {
+                    *shared.sysex[sysex_head.wrapping_add(i) % SYSEX_CAPACITY].get()
+                })
+                .collect();
+            shared
+                .sysex_head
+                .store(sysex_head.wrapping_add(slot.len), Ordering::Release);
+        }
+        shared.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(RawEvent {
+            at: slot.at,
+            len: slot.len.min(MAX_MESSAGE_LEN) as u8,
+            bytes: slot.bytes,
+            sysex,
+        })
+    }
+
+    /// Messages dropped with the queue full since the last call.
+    pub fn new_overflows(&mut self) -> u64 {
+        let total = self.shared.overflows.load(Ordering::Relaxed);
+        let new = total - self.seen;
+        self.seen = total;
+        new
+    }
+
+    /// SysEx dropped for being too long since the last call.
+    pub fn new_too_long(&mut self) -> u64 {
+        let total = self.shared.too_long.load(Ordering::Relaxed);
+        let new = total - self.seen_too_long;
+        self.seen_too_long = total;
+        new
+    }
+}
+
+impl Stream for Consumer {
+    type Item = RawEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<RawEvent>> {
+        if let Some(event) = self.pop() {
+            return Poll::Ready(Some(event));
+        }
+        self.shared.waker.register(cx.waker());
+        // An event pushed before the waker was registered would not wake us
+        match self.pop() {
+            Some(event) => Poll::Ready(Some(event)),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl FusedStream for Consumer {
+    fn is_terminated(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::{waker, ArcWake};
+    use std::sync::atomic::AtomicBool;
+
+    #[test]
+    fn slots_are_reused_past_capacity() {
+        let (mut producer, mut consumer) = ring_buffer(4);
+        for i in 0..40u8 {
+            assert!(producer.push(&[0x90, i, 100]));
+            assert_eq!(consumer.pop().unwrap().message(), [0x90, i, 100]);
+        }
+        assert!(consumer.pop().is_none());
+        assert_eq!(consumer.new_overflows(), 0);
+    }
+
+    #[test]
+    fn overflows_are_counted() {
+        let (mut producer, mut consumer) = ring_buffer(2);
+        assert!(producer.push(&[0xF8]));
+        assert!(producer.push(&[0xF8]));
+        // Full
+        assert!(!producer.push(&[0xFA]));
+        consumer.pop().unwrap();
+        assert!(producer.push(&[0xF0; MAX_MESSAGE_LEN]));
+        assert_eq!(consumer.new_overflows(), 1);
+
+        // Only the overflows since the last call
+        assert_eq!(consumer.new_overflows(), 0);
+        assert!(!producer.push(&[0xFC]));
+        assert_eq!(consumer.new_overflows(), 1);
+        assert_eq!(consumer.pop().unwrap().message(), [0xF8]);
+        assert_eq!(consumer.pop().unwrap().message(), [0xF0; MAX_MESSAGE_LEN]);
+        assert!(consumer.pop().is_none());
+    }
+
+    #[test]
+    fn sysex_goes_through_in_order() {
+        let (mut producer, mut consumer) = ring_buffer(4);
+        let mut sysex = vec![0xF0];
+        sysex.extend((0..200u8).map(|i| i & 0x7F));
+        sysex.push(0xF7);
+        for _ in 0..1000 {
+            assert!(producer.push(&[0x90, 60, 100]));
+            assert!(producer.push(&sysex));
+            assert_eq!(consumer.pop().unwrap().message(), [0x90, 60, 100]);
+            assert_eq!(consumer.pop().unwrap().message(), sysex.as_slice());
+        }
+        assert!(consumer.pop().is_none());
+        assert_eq!(consumer.new_overflows(), 0);
+    }
+
+    #[test]
+    fn too_long_sysex_is_counted_apart_from_overflows() {
+        let (mut producer, mut consumer) = ring_buffer(4);
+        assert!(!producer.push(&vec![0xF0; SYSEX_CAPACITY + 1]));
+        assert!(producer.push(&vec![0xF0; SYSEX_CAPACITY]));
+        // The SysEx ring is full
+        assert!(!producer.push(&[0xF0; MAX_MESSAGE_LEN + 1]));
+        assert!(producer.push(&[0xF8]));
+        assert_eq!(consumer.new_too_long(), 1);
+        assert_eq!(consumer.new_overflows(), 1);
+        assert_eq!(consumer.pop().unwrap().message().len(), SYSEX_CAPACITY);
+        assert_eq!(consumer.pop().unwrap().message(), [0xF8]);
+    }
+
+    #[test]
+    fn nothing_is_lost_or_reordered_across_threads() {
+        let count = 100_000u32;
+        let (mut producer, mut consumer) = ring_buffer(64);
+        let producing = std::thread::spawn(move || {
+            for i in 0..count {
+                // Keep below capacity by waiting for room instead of overflowing
+                while !producer.push(&i.to_be_bytes()) {
+                    std::thread::yield_now();
+                }
+            }
+        });
+        let mut next = 0;
+        while next < count {
+            match consumer.pop() {
+                Some(event) => {
+                    assert_eq!(event.message(), next.to_be_bytes());
+                    next += 1;
+                }
+                None => std::thread::yield_now(),
+            }
+        }
+        producing.join().unwrap();
+        assert!(consumer.pop().is_none());
+    }
+
+    #[test]
+    fn stream_wakes_up_after_a_push() {
+        struct Woken(AtomicBool);
+        impl ArcWake for Woken {
+            fn wake_by_ref(woken: &Arc<Self>) {
+                woken.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let (mut producer, mut consumer) = ring_buffer(4);
+        let woken = Arc::new(Woken(AtomicBool::new(false)));
+        let waker = waker(woken.clone());
+        let mut cx = Context::from_waker(&waker);
+        assert!(Pin::new(&mut consumer).poll_next(&mut cx).is_pending());
+        assert!(!woken.0.load(Ordering::SeqCst));
+
+        producer.push(&[0xF8]);
+        assert!(woken.0.load(Ordering::SeqCst));
+        match Pin::new(&mut consumer).poll_next(&mut cx) {
+            Poll::Ready(Some(event)) => assert_eq!(event.message(), [0xF8]),
+            _ => panic!("the pushed event was not ready"),
+        }
+    }
+}
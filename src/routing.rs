@@ -0,0 +1,371 @@
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::time::{Duration, Instant};
+use tracing::{trace, warn};
+
+use super::arpeggiator::ArpeggiatorConfig;
+use super::harmony::HarmonyZone;
+use super::midi::{self, MessageKind};
+use super::p2p::playout::{LatencyMode, Timing};
+
+/// Send MIDI from channel `from` to channel `to`. Channels are numbered 1 to 16.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ChannelMapping {
+    pub from: u8,
+    pub to: u8,
+}
+
+/// Transport to keep a peer on, for venues whose firewalls mangle UDP or block direct connections.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportPreference {
+    Quic,
+    Tcp,
+    /// Through the relay, never connecting directly.
+    Relayed,
+}
+
+impl TransportPreference {
+    /// Whether a connection described as `transport` goes over this one.
+    pub fn carries(&self, transport: &str) -> bool {
+        transport == self.to_string()
+    }
+}
+
+impl fmt::Display for TransportPreference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // As connections are described
+        match self {
+            TransportPreference::Quic => write!(f, "QUIC"),
+            TransportPreference::Tcp => write!(f, "TCP"),
+            TransportPreference::Relayed => write!(f, "relayed"),
+        }
+    }
+}
+
+/// What a peer may do, everything unless taken away.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct Permissions {
+    /// Play MIDI into this node.
+    pub send_midi: bool,
+    /// Hear the MIDI played on this node.
+    pub receive_midi: bool,
+    /// Send SysEx, which can rewrite the patches and settings of synths.
+    pub send_sysex: bool,
+    /// Start, stop and change the tempo of the session, and send MIDI clock.
+    pub control_transport: bool,
+}
+
+impl Default for Permissions {
+    fn default() -> Self {
+        Permissions {
+            send_midi: true,
+            receive_midi: true,
+            send_sysex: true,
+            control_transport: true,
+        }
+    }
+}
+
+/// Settings for a single peer, from the `peers:` section of the config file. Peers are looked up
+/// by PeerId or by their address book name.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct PeerConfig {
+    /// Name shown for this peer instead of its PeerId.
+    pub display_name: Option<String>,
+    /// Channel remapping applied to MIDI received from this peer.
+    pub channel_map: Vec<ChannelMapping>,
+    /// Semitones added to notes received from this peer.
+    pub transpose: i8,
+    /// Kinds of MIDI messages from this peer that are dropped. Note offs, sustain releases and all
+    /// sound or notes off still pass, so a filter cannot leave notes hanging.
+    pub filters: Vec<MessageKind>,
+    /// Local MIDI output to play this peer on, instead of its own virtual device.
+    pub output: Option<String>,
+    /// Drop all MIDI from this peer but note offs.
+    pub muted: bool,
+    /// Percent the velocity of the notes of this peer is scaled to, 100 by default.
+    pub volume: Option<u8>,
+    pub permissions: Permissions,
+    /// Most MIDI events a second taken from this peer, instead of the global limit.
+    pub max_rate: Option<u32>,
+    /// Play this peer live, buffered or bars late instead of picking by round trip time.
+    pub latency_mode: Option<LatencyMode>,
+    /// Bars this peer is late by in bar mode, instead of the global setting.
+    pub delay_bars: Option<u32>,
+    /// Play this peer exactly as timed, or with its micro-timing smoothed. Tight by default.
+    pub timing: Option<Timing>,
+    /// Keep connections to this peer on this transport once it is up, closing the others.
+    pub transport: Option<TransportPreference>,
+    /// Keep other direct transports while the preferred one is not up. True by default, without
+    /// it the peer is reached through the relay meanwhile.
+    pub transport_fallback: Option<bool>,
+    /// Chords sent to this peer for notes played here, instead of those of the `harmony:` section.
+    /// An empty list sends it the notes as played.
+    pub harmony: Option<Vec<HarmonyZone>>,
+    /// Play the notes this peer holds as an arpeggio on the session tempo.
+    pub arpeggiator: Option<ArpeggiatorConfig>,
+}
+
+/// How MIDI coming from a connected peer is transformed and where it goes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerRoute {
+    pub display_name: String,
+    pub output: Option<String>,
+    /// Zero based target channel for each zero based source channel.
+    channel_map: [u8; 16],
+    transpose: i8,
+    filters: Vec<MessageKind>,
+    muted: bool,
+    volume: u8,
+    pub permissions: Permissions,
+    pub max_rate: Option<u32>,
+    pub latency_mode: Option<LatencyMode>,
+    pub delay_bars: Option<u32>,
+    pub timing: Timing,
+    pub transport: Option<TransportPreference>,
+    pub transport_fallback: bool,
+    pub harmony: Option<Vec<HarmonyZone>>,
+    pub arpeggiator: Option<ArpeggiatorConfig>,
+}
+
+impl PeerRoute {
+    pub fn new(peer: &str, config: &PeerConfig) -> Self {
+        let mut channel_map = [0u8; 16];
+        for (i, channel) in channel_map.iter_mut().enumerate() {
+            *channel = i as u8;
+        }
+        for mapping in &config.channel_map {
+            if (1..=16).contains(&mapping.from) && (1..=16).contains(&mapping.to) {
+                channel_map[mapping.from as usize - 1] = mapping.to - 1;
+            } else {
+                warn!(
+                    "Ignoring channel mapping {} -> {} for {}: channels go from 1 to 16",
+                    mapping.from, mapping.to, peer
+                );
+            }
+        }
+        PeerRoute {
+            display_name: config
+                .display_name
+                .clone()
+                .unwrap_or_else(|| peer.to_string()),
+            output: config.output.clone(),
+            channel_map,
+            transpose: config.transpose,
+            filters: config.filters.clone(),
+            muted: config.muted,
+            volume: config.volume.unwrap_or(100),
+            permissions: config.permissions.clone(),
+            max_rate: config.max_rate,
+            latency_mode: config.latency_mode,
+            delay_bars: config.delay_bars,
+            timing: config.timing.unwrap_or_default(),
+            transport: config.transport,
+            transport_fallback: config.transport_fallback.unwrap_or(true),
+            harmony: config.harmony.clone(),
+            arpeggiator: config.arpeggiator.clone(),
+        }
+    }
+
+    /// Which of the connections to this peer, by how they are described, to close to keep it on
+    /// its preferred transport.
+    pub fn unwanted_connections<C: Copy>(&self, connections: &[(C, &str)]) -> Vec<C> {
+        let preferred = match self.transport {
+            Some(preferred) => preferred,
+            None => return Vec::new(),
+        };
+        let preferred_up = connections.iter().any(|(_, t)| preferred.carries(t));
+        connections
+            .iter()
+            .filter(|(_, t)| !preferred.carries(t))
+            .filter(|(_, t)| {
+                preferred_up
+                    || preferred == TransportPreference::Relayed
+                    || (!self.transport_fallback && *t != "relayed")
+            })
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// Transform a raw MIDI message. Returns `None` if the message must be dropped.
+    #[tracing::instrument(level = "trace", skip_all, fields(route = %self.display_name))]
+    pub fn apply(&self, message: &[u8]) -> Option<Vec<u8>> {
+        let kind = MessageKind::of(message)?;
+        let permitted = match kind {
+            MessageKind::SysEx => self.permissions.send_midi && self.permissions.send_sysex,
+            MessageKind::Clock => self.permissions.send_midi && self.permissions.control_transport,
+            _ => self.permissions.send_midi,
+        };
+        if !permitted {
+            trace!("{:?} message not permitted", kind);
+            return None;
+        }
+        if self.muted && !midi::is_silencing(message) {
+            trace!("Muted {:?} message", kind);
+            return None;
+        }
+        // Filters never drop note offs so they cannot leave notes hanging
+        if self.filters.contains(&kind) && !midi::is_silencing(message) {
+            trace!("Filtered {:?} message", kind);
+            return None;
+        }
+
+        let mut message = message.to_vec();
+        if let Some(channel) = midi::channel(&message) {
+            message[0] = (message[0] & 0xF0) | self.channel_map[channel as usize];
+        }
+
+        if self.transpose != 0
+            && matches!(
+                kind,
+                MessageKind::NoteOn | MessageKind::NoteOff | MessageKind::PolyAftertouch
+            )
+        {
+            let note = *message.get(1)? as i16 + self.transpose as i16;
+            // Notes transposed out of range are dropped rather than folded back
+            if !(0..=127).contains(&note) {
+                trace!("Dropped note {} transposed out of range", note);
+                return None;
+            }
+            message[1] = note as u8;
+        }
+
+        // Note ons keep a velocity of at least 1, a velocity of 0 being a note off
+        if self.volume != 100 && kind == MessageKind::NoteOn && !midi::is_silencing(&message) {
+            let velocity = *message.get(2)? as u32 * self.volume as u32 / 100;
+            message[2] = velocity.clamp(1, 127) as u8;
+        }
+        Some(message)
+    }
+}
+
+/// Names of the virtual ports MIDI goes to a peer through and comes from it on.
+pub fn port_names(name: &str) -> (String, String) {
+    (format!("To {}", name), format!("From {}", name))
+}
+
+/// Name of the virtual port MIDI a peer labeled with `track` comes from.
+pub fn track_port_name(name: &str, track: &str) -> String {
+    format!("From {} ({})", name, track)
+}
+
+/// Keeps the routes of the connected peers, built from their config when they connect.
+#[derive(Default)]
+pub struct MidiRouter {
+    configs: BTreeMap<String, PeerConfig>,
+    routes: HashMap<PeerId, PeerRoute>,
+    /// Names the connected peers are known as, kept to rebuild their routes.
+    names: HashMap<PeerId, Option<String>>,
+    /// Routes of peers that left, with their names and when they left, to resume if they are back
+    /// soon.
+    departed: HashMap<PeerId, (Instant, PeerRoute, Option<String>)>,
+}
+
+impl MidiRouter {
+    pub fn new(configs: BTreeMap<String, PeerConfig>) -> Self {
+        MidiRouter {
+            configs,
+            routes: HashMap::new(),
+            names: HashMap::new(),
+            departed: HashMap::new(),
+        }
+    }
+
+    /// Load the route of a newly connected peer. The config is looked up by PeerId first and then
+    /// by the name the peer is known as, falling back to an untouched route.
+    pub fn connect_peer(&mut self, peer_id: &PeerId, name: Option<&str>) -> &PeerRoute {
+        let id = peer_id.to_string();
+        let config = self
+            .configs
+            .get(&id)
+            .or_else(|| name.and_then(|n| self.configs.get(n)))
+            .cloned()
+            .unwrap_or_default();
+        let route = PeerRoute::new(name.unwrap_or(&id), &config);
+        self.routes.insert(*peer_id, route);
+        self.names.insert(*peer_id, name.map(|n| n.to_string()));
+        &self.routes[peer_id]
+    }
+
+    /// Load the route of a peer that connected, giving it back the one it had if it left less than
+    /// `grace` ago.
+    pub fn reconnect_peer(&mut self, peer_id: &PeerId, grace: Duration) -> &PeerRoute {
+        match self.departed.remove(peer_id) {
+            Some((left, route, name)) if left.elapsed() <= grace => {
+                self.routes.insert(*peer_id, route);
+                self.names.insert(*peer_id, name);
+                &self.routes[peer_id]
+            }
+            _ => self.connect_peer(peer_id, None),
+        }
+    }
+
+    /// Replace the peer configs, rebuilding the routes of the connected peers. Routes kept for
+    /// peers that left are dropped, they come back to the new configs.
+    pub fn set_configs(&mut self, configs: BTreeMap<String, PeerConfig>) {
+        self.configs = configs;
+        self.departed.clear();
+        let peers: Vec<(PeerId, Option<String>)> = self.names.clone().into_iter().collect();
+        for (peer_id, name) in peers {
+            self.connect_peer(&peer_id, name.as_deref());
+        }
+    }
+
+    pub fn configs(&self) -> &BTreeMap<String, PeerConfig> {
+        &self.configs
+    }
+
+    /// Drop the route of a peer that left, keeping it for `grace` in case it comes back.
+    pub fn disconnect_peer(&mut self, peer_id: &PeerId, grace: Duration) {
+        self.departed
+            .retain(|_, (left, _, _)| left.elapsed() <= grace);
+        let name = self.names.remove(peer_id).flatten();
+        if let Some(route) = self.routes.remove(peer_id) {
+            self.departed
+                .insert(*peer_id, (Instant::now(), route, name));
+        }
+    }
+
+    /// Change the config of a connected peer and rebuild its route. The config it was found by is
+    /// changed, or a new one keyed by its PeerId.
+    pub fn update_peer(&mut self, peer_id: &PeerId, update: impl FnOnce(&mut PeerConfig)) {
+        let name = self.names.get(peer_id).cloned().flatten();
+        let id = peer_id.to_string();
+        let key = match name
+            .as_ref()
+            .filter(|name| self.configs.contains_key(*name))
+        {
+            Some(name) if !self.configs.contains_key(&id) => name.clone(),
+            _ => id,
+        };
+        update(self.configs.entry(key).or_default());
+        self.connect_peer(peer_id, name.as_deref());
+    }
+
+    /// The PeerId of a connected peer given by PeerId or by the name it is shown as.
+    pub fn resolve(&self, peer: &str) -> Option<PeerId> {
+        match peer.parse::<PeerId>() {
+            Ok(peer_id) if self.routes.contains_key(&peer_id) => Some(peer_id),
+            _ => self
+                .routes
+                .iter()
+                .find(|(_, route)| route.display_name == peer)
+                .map(|(peer_id, _)| *peer_id),
+        }
+    }
+
+    pub fn route(&self, peer_id: &PeerId) -> Option<&PeerRoute> {
+        self.routes.get(peer_id)
+    }
+
+    /// Whether MIDI played here goes to the peer.
+    pub fn may_receive(&self, peer_id: &PeerId) -> bool {
+        self.route(peer_id)
+            .map_or(true, |route| route.permissions.receive_midi)
+    }
+}
@@ -0,0 +1,36 @@
+//! The tokio runtime everything async runs on: sessions, their sockets and background tasks.
+
+use std::future::Future;
+use std::sync::OnceLock;
+use tokio::runtime::{Builder, EnterGuard, Runtime};
+use tokio::task::JoinHandle;
+
+static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+pub fn get() -> &'static Runtime {
+    RUNTIME.get_or_init(|| {
+        Builder::new_multi_thread()
+            .enable_all()
+            .thread_name("p2pmidi")
+            .build()
+            .expect("Error starting the async runtime")
+    })
+}
+
+/// Make the runtime current on this thread, needed to create sockets outside of a task.
+pub fn enter() -> EnterGuard<'static> {
+    get().enter()
+}
+
+/// Run a future to completion, blocking the calling thread.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    get().block_on(future)
+}
+
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    get().spawn(future)
+}
@@ -0,0 +1,340 @@
+//! A session running in the background, for applications embedding p2pmidi instead of running the
+//! binary. It plays MIDI to the peers, and hands back what they play and when they come and go.
+
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures::channel::oneshot;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::Path;
+use std::thread::JoinHandle;
+
+use crate::bridge::{BridgeEvent, BridgeMidi};
+use crate::clock::ClockQuality;
+use crate::control::{ControlRequest, ControlResponse, PendingRequest};
+use crate::failure::Failure;
+use crate::p2p::client::{run_client, ClientOptions, Embedding};
+use crate::p2p::paths::ConnectionPath;
+use crate::p2p::playout::Timing;
+use crate::routing::MidiRouter;
+
+/// Peers joining and leaving, and the MIDI they play.
+pub type SessionEvent = BridgeEvent;
+
+/// A running session. Dropping it leaves the session running until the process ends, call
+/// [`Session::stop`] to end it.
+pub struct Session {
+    input: UnboundedSender<BridgeMidi>,
+    control: UnboundedSender<PendingRequest>,
+    shutdown: UnboundedSender<()>,
+    thread: JoinHandle<Result<(), String>>,
+}
+
+/// A connected peer of a [`Session`].
+#[derive(Clone, Debug)]
+pub struct PeerHandle {
+    peer_id: String,
+    input: UnboundedSender<BridgeMidi>,
+    control: UnboundedSender<PendingRequest>,
+}
+
+/// Send `request` to the session and wait for its answer.
+fn request(
+    control: &UnboundedSender<PendingRequest>,
+    request: ControlRequest,
+) -> Result<serde_json::Value, Box<dyn Error>> {
+    let (reply, response) = oneshot::channel();
+    control
+        .unbounded_send((request, reply))
+        .map_err(|_| Failure::Runtime("Session has ended".to_string()))?;
+    match futures::executor::block_on(response) {
+        Ok(ControlResponse {
+            ok: true, result, ..
+        }) => Ok(result),
+        Ok(ControlResponse { error, .. }) => {
+            Err(Failure::Runtime(error.unwrap_or_default()).into())
+        }
+        Err(_) => Err(Failure::Runtime("Session has ended".to_string()).into()),
+    }
+}
+
+impl Session {
+    /// Start a session on its own thread, routing MIDI with `router`. The receiver gets every
+    /// [`SessionEvent`] for as long as the session runs.
+    pub fn start(
+        router: MidiRouter,
+        options: ClientOptions,
+    ) -> Result<(Session, UnboundedReceiver<SessionEvent>), Box<dyn Error>> {
+        let (events, event_receiver) = mpsc::unbounded();
+        let (input, input_receiver) = mpsc::unbounded();
+        let (control, control_receiver) = mpsc::unbounded();
+        let (shutdown, shutdown_receiver) = mpsc::unbounded();
+        let embedding = Embedding {
+            events: Some(events),
+            input: Some(input_receiver),
+            control: Some(control_receiver),
+            shutdown: Some(shutdown_receiver),
+        };
+        let thread = std::thread::Builder::new()
+            .name("session".to_string())
+            .spawn(move || run_client(router, options, embedding).map_err(|e| e.to_string()))?;
+        Ok((
+            Session {
+                input,
+                control,
+                shutdown,
+                thread,
+            },
+            event_receiver,
+        ))
+    }
+
+    /// Play `message` to every connected peer.
+    pub fn send_midi(&self, message: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.input
+            .unbounded_send(BridgeMidi {
+                to: None,
+                message: message.to_vec(),
+            })
+            .map_err(|_| Failure::Runtime("Session has ended".to_string()).into())
+    }
+
+    /// Handle to the peer with this PeerId. It need not be connected yet.
+    pub fn peer(&self, peer_id: impl Into<String>) -> PeerHandle {
+        PeerHandle {
+            peer_id: peer_id.into(),
+            input: self.input.clone(),
+            control: self.control.clone(),
+        }
+    }
+
+    /// Dial a multiaddr, or a PeerId through the relay.
+    pub fn dial(&self, address: impl Into<String>) -> Result<(), Box<dyn Error>> {
+        request(
+            &self.control,
+            ControlRequest::Dial {
+                address: address.into(),
+            },
+        )
+        .map(|_| ())
+    }
+
+    /// The same status `p2pmidi ctl status` shows.
+    pub fn status(&self) -> Result<serde_json::Value, Box<dyn Error>> {
+        request(&self.control, ControlRequest::Status)
+    }
+
+    /// How each connected peer is reached, connection by connection, keyed by PeerId.
+    pub fn connections(&self) -> Result<BTreeMap<String, Vec<ConnectionPath>>, Box<dyn Error>> {
+        Ok(serde_json::from_value(
+            self.status()?["connections"].take(),
+        )?)
+    }
+
+    /// How well the clock of each connected peer is known, keyed by PeerId.
+    pub fn clocks(&self) -> Result<BTreeMap<String, ClockQuality>, Box<dyn Error>> {
+        Ok(serde_json::from_value(self.status()?["clocks"].take())?)
+    }
+
+    /// Turn every note off on every peer.
+    pub fn panic(&self) -> Result<(), Box<dyn Error>> {
+        request(&self.control, ControlRequest::Panic).map(|_| ())
+    }
+
+    /// Change the session tempo for every peer, from the next bar line on while playing.
+    pub fn set_tempo(&self, tempo: f64) -> Result<(), Box<dyn Error>> {
+        request(&self.control, ControlRequest::SetTempo { tempo }).map(|_| ())
+    }
+
+    /// Start the session on every peer at once, after counting in `count_in` beats.
+    pub fn start_together(&self, count_in: u32) -> Result<(), Box<dyn Error>> {
+        request(&self.control, ControlRequest::StartTogether { count_in }).map(|_| ())
+    }
+
+    /// Count a tap of tap tempo. Returns the tempo changed to, once tapped three times.
+    pub fn tap_tempo(&self) -> Result<Option<f64>, Box<dyn Error>> {
+        Ok(request(&self.control, ControlRequest::TapTempo)?["tempo"].as_f64())
+    }
+
+    /// Save the routing, transforms and mutes of every peer under `name`.
+    pub fn save_preset(&self, name: impl Into<String>) -> Result<(), Box<dyn Error>> {
+        request(
+            &self.control,
+            ControlRequest::PresetSave { name: name.into() },
+        )
+        .map(|_| ())
+    }
+
+    /// Switch every peer to the routing saved under `name`.
+    pub fn load_preset(&self, name: impl Into<String>) -> Result<(), Box<dyn Error>> {
+        request(
+            &self.control,
+            ControlRequest::PresetLoad { name: name.into() },
+        )
+        .map(|_| ())
+    }
+
+    /// End the session and wait for it to close its connections.
+    pub fn stop(self) -> Result<(), Box<dyn Error>> {
+        let _ = self.shutdown.unbounded_send(());
+        match self.thread.join() {
+            Ok(result) => result.map_err(|e| Failure::Runtime(e).into()),
+            Err(_) => Err(Failure::Runtime("Session thread panicked".to_string()).into()),
+        }
+    }
+}
+
+impl PeerHandle {
+    pub fn id(&self) -> &str {
+        &self.peer_id
+    }
+
+    /// Play `message` to this peer only.
+    pub fn send_midi(&self, message: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.input
+            .unbounded_send(BridgeMidi {
+                to: Some(self.peer_id.clone()),
+                message: message.to_vec(),
+            })
+            .map_err(|_| Failure::Runtime("Session has ended".to_string()).into())
+    }
+
+    /// Let the peer in while it waits to join, remembering it for the next times.
+    pub fn accept(&self) -> Result<(), Box<dyn Error>> {
+        request(
+            &self.control,
+            ControlRequest::Accept {
+                peer_id: self.peer_id.clone(),
+            },
+        )
+        .map(|_| ())
+    }
+
+    pub fn reject(&self) -> Result<(), Box<dyn Error>> {
+        request(
+            &self.control,
+            ControlRequest::Reject {
+                peer_id: self.peer_id.clone(),
+            },
+        )
+        .map(|_| ())
+    }
+
+    /// Emoji to compare with the peer over another channel, as returned by `p2pmidi ctl sas`.
+    pub fn short_auth_string(&self) -> Result<serde_json::Value, Box<dyn Error>> {
+        request(
+            &self.control,
+            ControlRequest::Sas {
+                peer_id: self.peer_id.clone(),
+            },
+        )
+    }
+
+    /// Remember the peer as verified once its emoji matched.
+    pub fn verify(&self) -> Result<(), Box<dyn Error>> {
+        request(
+            &self.control,
+            ControlRequest::Verify {
+                peer_id: self.peer_id.clone(),
+            },
+        )
+        .map(|_| ())
+    }
+
+    pub fn disconnect(&self) -> Result<(), Box<dyn Error>> {
+        request(
+            &self.control,
+            ControlRequest::Disconnect {
+                peer_id: self.peer_id.clone(),
+            },
+        )
+        .map(|_| ())
+    }
+
+    /// Drop the connections to the peer and dial it again, picking its transport anew.
+    pub fn renegotiate(&self) -> Result<(), Box<dyn Error>> {
+        request(
+            &self.control,
+            ControlRequest::Renegotiate {
+                peer_id: self.peer_id.clone(),
+            },
+        )
+        .map(|_| ())
+    }
+
+    /// Scale the velocity of the notes of the peer to `volume` percent.
+    pub fn set_volume(&self, volume: u8) -> Result<(), Box<dyn Error>> {
+        request(
+            &self.control,
+            ControlRequest::PeerSet {
+                peer_id: self.peer_id.clone(),
+                volume: Some(volume),
+                muted: None,
+                timing: None,
+            },
+        )
+        .map(|_| ())
+    }
+
+    pub fn set_muted(&self, muted: bool) -> Result<(), Box<dyn Error>> {
+        request(
+            &self.control,
+            ControlRequest::PeerSet {
+                peer_id: self.peer_id.clone(),
+                volume: None,
+                muted: Some(muted),
+                timing: None,
+            },
+        )
+        .map(|_| ())
+    }
+
+    /// Play the peer exactly as timed, or with its micro-timing smoothed.
+    pub fn set_timing(&self, timing: Timing) -> Result<(), Box<dyn Error>> {
+        request(
+            &self.control,
+            ControlRequest::PeerSet {
+                peer_id: self.peer_id.clone(),
+                volume: None,
+                muted: None,
+                timing: Some(timing),
+            },
+        )
+        .map(|_| ())
+    }
+
+    /// Offer the peer a small file, like a SysEx dump, returning the id of the offer. The peer
+    /// gets it once it accepts.
+    pub fn send_file(&self, path: &Path) -> Result<u64, Box<dyn Error>> {
+        let result = request(
+            &self.control,
+            ControlRequest::FileSend {
+                peer_id: self.peer_id.clone(),
+                path: path.to_path_buf(),
+            },
+        )?;
+        Ok(result["id"].as_u64().unwrap_or_default())
+    }
+
+    /// Take the file the peer offered with `id`, saved under `received` in the data directory.
+    pub fn accept_file(&self, id: u64) -> Result<(), Box<dyn Error>> {
+        request(
+            &self.control,
+            ControlRequest::FileAccept {
+                peer_id: self.peer_id.clone(),
+                id,
+            },
+        )
+        .map(|_| ())
+    }
+
+    pub fn decline_file(&self, id: u64) -> Result<(), Box<dyn Error>> {
+        request(
+            &self.control,
+            ControlRequest::FileDecline {
+                peer_id: self.peer_id.clone(),
+                id,
+            },
+        )
+        .map(|_| ())
+    }
+}
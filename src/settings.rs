@@ -1,16 +1,38 @@
 use rand::Rng;
 
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::env;
+use std::error::Error;
 use std::io::Cursor;
-use std::{fs::File, io::BufReader, path::Path};
+use std::path::PathBuf;
+use std::{fs::File, path::Path};
 
 use super::midi;
 
+use super::bridge::ipmidi::IpMidiMapping;
+use super::bridge::osc::OscMapping;
 use super::constants;
-use clap::Parser;
+use super::failure::{Failure, EXIT_CODES_HELP};
+use super::harmony::HarmonyZone;
+use super::jack_transport::JackTransportMode;
+use super::migration;
+use super::p2p::backpressure::{BackpressurePolicy, ClassPolicy};
+use super::p2p::playout::{LatencyMode, Thru, Timing};
+use super::p2p::protocol::EventClass;
+use super::p2p::ratelimit::RateLimitPolicy;
+use super::p2p::simulate::{parse_duration, NetworkConditions};
+use super::p2p::trust::AutoAccept;
+use super::profiles;
+use super::routing::PeerConfig;
+use super::storage::Storage;
+use super::velocity::VelocityCurve;
+use clap::{Parser, Subcommand};
 use clap_serde_derive::ClapSerde;
+#[cfg(feature = "tui")]
 use skim::prelude::{SkimItemReader, SkimOptionsBuilder};
+#[cfg(feature = "tui")]
 use skim::Skim;
 
 /// Connect to other nodes creating virtual MIDI output devices for each of them and streaming MIDI
@@ -18,12 +40,18 @@ use skim::Skim;
 ///
 /// Run without arguments will launch the GUI
 #[derive(Parser)]
-#[clap(author, version, about, long_about = None)]
+#[clap(author, version, about, long_about = None, after_help = EXIT_CODES_HELP)]
 pub struct Args {
-    /// Act as a relay listening on all devices and ignores all other arguments except relay_port.
+    /// Act as a relay listening on all devices. Alone it ignores all other arguments except
+    /// relay_port, with --cli or a session command it also joins the session through itself.
     #[clap(short, long = "as-relay", default_value = "false")]
     pub as_relay: bool,
 
+    /// Relay config file with its identity, listen addresses, limits, auth, metrics and log, read
+    /// with --as-relay. P2PMIDI_RELAY_* environment variables override it.
+    #[clap(long = "relay-config")]
+    pub relay_config: Option<std::path::PathBuf>,
+
     /// Config file
     #[clap(short, long = "config", default_value = constants::DEFAULT_CONFIG_PATH)]
     pub config_path: std::path::PathBuf,
@@ -36,32 +64,478 @@ pub struct Args {
     #[clap(long = "cli")]
     pub cli: bool,
 
-    /// Prompt for midi input device interactively.
-    #[clap(short = 'D', long = "prompt")]
-    pub prompt_for_midi_device: bool,
+    /// Directory for the identity key, address book and recordings index. Defaults to
+    /// ~/.local/share/p2pmidi.
+    #[clap(long = "data-dir")]
+    pub data_dir: Option<PathBuf>,
+
+    /// Use a named settings profile instead of the default config file.
+    #[clap(long = "profile", conflicts_with = "config_path")]
+    pub profile: Option<String>,
+
+    /// Pick something interactively: the input device (default), the monitoring output, a peer
+    /// from the address book or a profile. Can be supplied multiple times.
+    #[clap(
+        short = 'D',
+        long = "prompt",
+        value_enum,
+        num_args = 0..=1,
+        default_missing_value = "device"
+    )]
+    pub prompt: Vec<PromptKind>,
+
+    /// Peer to dial, picked with `--prompt peer`.
+    #[clap(skip)]
+    pub target: Option<String>,
+
+    /// Never prompt interactively or open the GUI on its own, for use from scripts.
+    #[clap(long = "no-prompt")]
+    pub no_prompt: bool,
+
+    /// Print only errors.
+    #[clap(short = 'q', long = "quiet")]
+    pub quiet: bool,
+
+    /// Print every report as it comes instead of keeping a status line of peers, tempo, RTT and
+    /// MIDI events a second.
+    #[clap(short = 'v', long = "verbose")]
+    pub verbose: bool,
+
+    /// Print status, peers and latency as JSON lines on stdout. Logs go to stderr.
+    #[clap(long = "json")]
+    pub json: bool,
+
+    /// Measure how long MIDI events take at each stage and report latency histograms.
+    #[clap(long = "measure-latency")]
+    pub measure_latency: bool,
+
+    /// Also write the report printed at the end of a session as JSON to this file.
+    #[clap(long = "session-report")]
+    pub session_report: Option<PathBuf>,
+
+    /// Add latency, jitter, loss and reordering to received MIDI, for development.
+    /// For example `latency=40ms,jitter=10ms,loss=2%,reorder=1%`.
+    #[clap(long = "simulate-network", hide = true)]
+    pub simulate_network: Option<NetworkConditions>,
+
+    /// Sync the session tempo and start and stop with Ableton Link on the local network.
+    #[clap(long = "link")]
+    pub link: bool,
+
+    /// Accept peers connecting for the first time without asking, same as `--auto-accept everyone`.
+    #[clap(long = "trust-new-peers")]
+    pub trust_new_peers: bool,
+
+    /// Make the invite printed at startup stop letting peers in without asking after this long,
+    /// like `30m`.
+    #[clap(long = "invite-expires", value_parser = parse_duration)]
+    pub invite_expires: Option<std::time::Duration>,
+
+    /// Make the invite printed at startup let only the first peer in without asking.
+    #[clap(long = "invite-once")]
+    pub invite_once: bool,
+
+    #[clap(subcommand)]
+    pub command: Option<Command>,
 
     /// Rest of arguments
     #[clap(flatten)]
     pub settings: <Settings as ClapSerde>::Opt,
 }
 
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Manage settings profiles.
+    Config {
+        #[clap(subcommand)]
+        action: ConfigAction,
+    },
+    /// Run headless, controlled through a local socket with `p2pmidi ctl`.
+    Daemon {
+        /// Control socket path.
+        #[clap(long = "socket")]
+        socket: Option<std::path::PathBuf>,
+    },
+    /// Check connectivity to a peer or the relay and measure the round trip time.
+    Ping {
+        /// `relay`, a PeerId reached through the relay, or a multiaddr ending in /p2p/<PeerId>.
+        target: String,
+        /// Number of latency probes.
+        #[clap(short = 'c', long = "count", default_value = "5")]
+        count: usize,
+        /// Try to hole punch a direct connection before measuring.
+        #[clap(long = "hole-punch")]
+        hole_punch: bool,
+        /// Give up after this many seconds.
+        #[clap(long = "timeout", default_value = "30")]
+        timeout: u64,
+    },
+    /// Check the way to the relay and to other peers step by step, with advice on the first
+    /// step failing.
+    Troubleshoot {
+        /// PeerId or address book name of a peer connected to the relay, to hole punch to.
+        #[clap(long = "peer")]
+        peer: Option<String>,
+        /// Give up on each check after this many seconds.
+        #[clap(long = "timeout", default_value = "10")]
+        timeout: u64,
+    },
+    /// Stream a MIDI file to a peer and exit when done.
+    Play {
+        /// Standard MIDI file to play.
+        file: std::path::PathBuf,
+        /// PeerId reached through the relay, or a multiaddr ending in /p2p/<PeerId>.
+        #[clap(long = "to")]
+        to: String,
+    },
+    /// Stream generated MIDI through a connection inside this process to check it holds up.
+    Selftest {
+        /// Keep going this long, like `30m` or `8h`, reporting memory, reconnects and drift.
+        #[clap(long = "soak", default_value = "10s", value_parser = parse_duration)]
+        soak: std::time::Duration,
+        /// MIDI events per second.
+        #[clap(long = "rate", default_value = "200")]
+        rate: u32,
+        /// Connect through memory instead of TCP on the loopback interface.
+        #[clap(long = "memory")]
+        memory: bool,
+    },
+    /// Connect to a peer and stream MIDI with it.
+    Connect {
+        /// Address book name, invite link, PeerId reached through the relay or a multiaddr.
+        #[clap(required_unless_present = "last")]
+        target: Option<String>,
+        /// Join the latest session again, with the settings it was joined with.
+        #[clap(long = "last", conflicts_with = "target")]
+        last: bool,
+    },
+    /// Record MIDI received from peers to a standard MIDI file, without any MIDI device.
+    Record {
+        /// Peer to dial and record, in any form `connect` accepts.
+        #[clap(conflicts_with = "listen")]
+        target: Option<String>,
+        /// Wait for peers to connect instead of dialing one.
+        #[clap(long = "listen")]
+        listen: bool,
+        /// Standard MIDI file to write, updated every few seconds.
+        #[clap(long = "out")]
+        out: std::path::PathBuf,
+    },
+    /// Download a session recorded on the relay with --archive, as a standard MIDI file.
+    Archive {
+        /// Session name given to --archive.
+        session: String,
+        /// Standard MIDI file to write.
+        #[clap(long = "out")]
+        out: std::path::PathBuf,
+    },
+    /// List the sessions published in the directory of the relay, with the peers in each.
+    Sessions,
+    /// Write a new swarm key for a private swarm, to share with the band and its relay.
+    SwarmKey {
+        /// Key file to create.
+        out: std::path::PathBuf,
+    },
+    /// List the peers of the address book with how connecting to them went.
+    Peers {
+        /// Show every connection to this peer, by address book name or PeerId.
+        name: Option<String>,
+    },
+    /// Describe the wire format.
+    Protocol {
+        #[clap(subcommand)]
+        action: ProtocolAction,
+    },
+    /// Protect the identity key with a passphrase.
+    Identity {
+        #[clap(subcommand)]
+        action: IdentityAction,
+    },
+    /// Record how hard you play the input device and propose a velocity curve balancing it with
+    /// the other players.
+    Calibrate {
+        /// Notes to record before proposing a curve.
+        #[clap(long = "notes", default_value = "200")]
+        notes: usize,
+        /// Save the curve to the config file without asking.
+        #[clap(long = "save")]
+        save: bool,
+    },
+    /// Send a command to a running daemon.
+    Ctl {
+        /// Control socket path.
+        #[clap(long = "socket")]
+        socket: Option<std::path::PathBuf>,
+        #[clap(subcommand)]
+        action: CtlAction,
+    },
+    /// Show the monitor of a running GUI in a window of its own, fed on stdin by the GUI.
+    #[clap(hide = true)]
+    MonitorWindow,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum ProtocolAction {
+    /// Print the schema of the wire format as JSON: versions, message types and encodings.
+    Dump,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum CtlAction {
+    /// Show connected peers and listen addresses.
+    Status,
+    /// Show lost and late MIDI counters per peer.
+    Stats,
+    /// Connect to a multiaddr, or to a PeerId through the relay.
+    Dial { address: String },
+    /// Disconnect a peer.
+    Disconnect { peer_id: String },
+    /// Reconnect to a peer through the relay, hole punching again for its preferred transport.
+    Renegotiate { peer_id: String },
+    /// Accept a peer waiting to join.
+    Accept { peer_id: String },
+    /// Turn away a peer waiting to join.
+    Reject { peer_id: String },
+    /// Show the emoji to compare with a peer over voice chat.
+    Sas { peer_id: String },
+    /// Mark a peer verified after its emoji matched.
+    Verify { peer_id: String },
+    /// Print an invite to this session.
+    Invite {
+        /// Stop letting peers in with the invite after this long, like `30m`.
+        #[clap(long = "expires", value_parser = parse_duration)]
+        expires: Option<std::time::Duration>,
+        /// Let only the first peer in with the invite.
+        #[clap(long = "once")]
+        once: bool,
+    },
+    /// Show the last connection attempts.
+    History {
+        /// Number of attempts to show.
+        #[clap(long = "limit", default_value = "50")]
+        limit: usize,
+    },
+    /// Send all notes off to every connected peer.
+    Panic,
+    /// Change the session tempo from the next bar line on, for every peer.
+    Tempo {
+        /// Beats per minute.
+        #[clap(conflicts_with = "tap", required_unless_present = "tap")]
+        bpm: Option<f64>,
+        /// Tap the tempo, changing it once tapped three times.
+        #[clap(long = "tap")]
+        tap: bool,
+    },
+    /// Start the session on every peer at once after a count-in, MIDI start going out on
+    /// `--clock-output`.
+    Start {
+        /// Beats counted in before the start.
+        #[clap(long = "count-in", default_value = "4", value_parser = clap::value_parser!(u32).range(0..=16))]
+        count_in: u32,
+    },
+    /// Control recording of the session.
+    Record {
+        #[clap(subcommand)]
+        action: RecordAction,
+    },
+    /// Save and switch between named routing presets.
+    Preset {
+        #[clap(subcommand)]
+        action: PresetAction,
+    },
+    /// Send peers small files like patch SysEx dumps, and take those they send.
+    File {
+        #[clap(subcommand)]
+        action: FileAction,
+    },
+    /// Change the volume, mute or timing of a connected peer, given by PeerId or name.
+    Peer {
+        peer_id: String,
+        /// Percent the velocity of its notes is scaled to.
+        #[clap(long = "volume", value_parser = clap::value_parser!(u8).range(0..=200))]
+        volume: Option<u8>,
+        #[clap(long = "mute", conflicts_with = "unmute")]
+        mute: bool,
+        #[clap(long = "unmute")]
+        unmute: bool,
+        /// Play it exactly as timed, or with its micro-timing smoothed.
+        #[clap(long = "timing", value_enum)]
+        timing: Option<Timing>,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum RecordAction {
+    /// Start recording the session to a MIDI file, saving the one recording until now.
+    Start { path: std::path::PathBuf },
+    /// Stop recording and save the MIDI file.
+    Stop,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum PresetAction {
+    /// Save the routing, transforms and mutes of every peer under a name.
+    Save { name: String },
+    /// Switch every peer to the routing saved under a name.
+    Load { name: String },
+    /// List saved presets.
+    List,
+    /// Delete a preset.
+    Delete { name: String },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum FileAction {
+    /// Offer a file of up to 1 MiB to a peer, sent once it accepts.
+    Send {
+        peer_id: String,
+        path: std::path::PathBuf,
+    },
+    /// Take a file a peer offered, saving it in the received directory of the data directory.
+    Accept {
+        peer_id: String,
+        id: u64,
+    },
+    Decline {
+        peer_id: String,
+        id: u64,
+    },
+    /// List the files peers offered.
+    List,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum IdentityAction {
+    /// Encrypt the identity key with a passphrase, asked for at startup. Daemons and relays can
+    /// take it from the P2PMIDI_PASSPHRASE environment variable.
+    Lock {
+        /// The relay identity instead.
+        #[clap(long = "relay")]
+        relay: bool,
+        /// Also keep the passphrase in the OS keyring so it isn't asked for.
+        #[clap(long = "keyring")]
+        keyring: bool,
+    },
+    /// Store the identity key unencrypted again.
+    Unlock {
+        /// The relay identity instead.
+        #[clap(long = "relay")]
+        relay: bool,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum ConfigAction {
+    /// List available profiles.
+    List,
+    /// Copy a profile into a new one.
+    Copy { from: String, to: String },
+    /// Delete a profile.
+    Delete { name: String },
+    /// Convert a config file to another format, detected by the file extension.
+    Convert {
+        from: std::path::PathBuf,
+        to: std::path::PathBuf,
+    },
+}
+
+/// Config file formats, detected by file extension.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ConfigFormat {
+    Yaml,
+    Toml,
+    Json,
+}
+
+impl ConfigFormat {
+    /// Extensions recognized as config files.
+    pub const EXTENSIONS: [&'static str; 4] = ["yml", "yaml", "toml", "json"];
+
+    /// Detect the format from the file extension. Files without extension are read as YAML.
+    pub fn from_path(path: &Path) -> Result<Self, String> {
+        match path.extension().and_then(|e| e.to_str()) {
+            None | Some("yml") | Some("yaml") => Ok(ConfigFormat::Yaml),
+            Some("toml") => Ok(ConfigFormat::Toml),
+            Some("json") => Ok(ConfigFormat::Json),
+            Some(ext) => Err(format!("Unsupported config file extension: {:?}", ext)),
+        }
+    }
+
+    pub fn serialize<T: Serialize>(&self, value: &T) -> Result<String, Box<dyn Error>> {
+        Ok(match self {
+            ConfigFormat::Yaml => serde_yaml::to_string(value)?,
+            ConfigFormat::Toml => toml::to_string_pretty(value)?,
+            ConfigFormat::Json => serde_json::to_string_pretty(value)?,
+        })
+    }
+
+    pub fn deserialize<T: DeserializeOwned + Default>(
+        &self,
+        contents: &str,
+    ) -> Result<T, Box<dyn Error>> {
+        // Freshly created config files are empty
+        if contents.trim().is_empty() {
+            return Ok(T::default());
+        }
+        Ok(match self {
+            ConfigFormat::Yaml => serde_yaml::from_str(contents)?,
+            ConfigFormat::Toml => toml::from_str(contents)?,
+            ConfigFormat::Json => serde_json::from_str(contents)?,
+        })
+    }
+}
+
 #[derive(clap::ValueEnum, Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
 pub enum ThemeType {
     Light,
     Dark,
 }
 
-#[derive(ClapSerde, Serialize, Clone, Debug)]
+/// What `--prompt` asks for.
+#[derive(clap::ValueEnum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PromptKind {
+    Device,
+    Output,
+    Peer,
+    Profile,
+}
+
+#[derive(clap::ValueEnum, Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+#[derive(clap::ValueEnum, Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(ClapSerde, Serialize, Clone, Debug, PartialEq)]
 pub struct Settings {
+    /// Version of the config file schema, used to migrate old config files.
+    #[default(migration::CONFIG_VERSION)]
+    #[clap(skip)]
+    pub config_version: u32,
+
     /// Give yourself a name. Defaults to your username.
     #[clap(short = 'n', long = "name")]
     pub name: Option<String>,
 
-    /// IP address of node to connect to. Can be suplied multiple times
+    /// Peer dialed when none is given, as a PeerId, multiaddr, IP address or hostname with an
+    /// optional :port, or address book name. Can be supplied multiple times. Without any, the
+    /// session waits for peers to join.
     #[clap(short = 'i', long = "address")]
     pub ip_addresses: Vec<String>,
 
-    /// Port to connect to. All nodes must use the same port.
+    /// Port to connect to on peers given as an IP address or hostname without one. All nodes must
+    /// use the same port.
     #[clap(short = 'p', long = "port")]
     pub port: Option<u16>,
 
@@ -69,6 +543,59 @@ pub struct Settings {
     #[clap(short = 'd', long = "device")]
     pub midi_device: Option<String>,
 
+    /// Label MIDI from the input device with this track name, like "keys", so peers can play it
+    /// on a port of its own.
+    #[clap(long = "track")]
+    pub track: Option<String>,
+
+    /// MIDI output device to monitor what peers play on.
+    #[clap(long = "output")]
+    pub midi_output: Option<String>,
+
+    /// MIDI input device of a controller operating the settings window, its knobs and pads bound
+    /// to it with MIDI learn.
+    #[clap(long = "control-device")]
+    pub control_device: Option<String>,
+
+    /// Also play the input device on the output device, right away or as late as the peers hear
+    /// it so playing along sounds the same here as there.
+    #[clap(long = "thru", value_enum)]
+    pub thru: Option<Thru>,
+
+    /// What to drop when a peer can't keep up. Defaults to drop-oldest.
+    #[clap(long = "backpressure", value_enum)]
+    pub backpressure: Option<BackpressurePolicy>,
+
+    /// Priority, room and drop policy of each class of MIDI waiting for a peer, keyed by `note`,
+    /// `sustain`, `pitch_bend`, `controller`, `sysex` or `clock`. Only read from the config file.
+    #[clap(skip)]
+    pub priority_classes: BTreeMap<EventClass, ClassPolicy>,
+
+    /// Most MIDI events a second taken from each peer, protecting local synths from floods.
+    #[clap(long = "max-inbound-rate")]
+    pub max_inbound_rate: Option<u32>,
+
+    /// What happens to MIDI over the inbound rate limit. Defaults to dropping it.
+    #[clap(long = "rate-limit-policy", value_enum)]
+    pub rate_limit_policy: Option<RateLimitPolicy>,
+
+    /// Play every peer live, buffered or bars late, instead of picking by round trip time.
+    #[clap(long = "latency-mode", value_enum)]
+    pub latency_mode: Option<LatencyMode>,
+
+    /// How many bars late MIDI from peers is played in bar latency mode, 1 by default.
+    #[clap(long = "delay-bars")]
+    pub delay_bars: Option<u32>,
+
+    /// Peers let in without asking when they connect. Defaults to peers accepted before.
+    #[clap(long = "auto-accept", value_enum)]
+    pub auto_accept: Option<AutoAccept>,
+
+    /// Local address to listen and dial from, to pick the network interface on machines with
+    /// several. Defaults to listening on all of them and letting the system pick.
+    #[clap(long = "bind-address")]
+    pub bind_address: Option<std::net::IpAddr>,
+
     /// Circuit relay address. Use a non default address to connect.
     #[clap(short = 'r', long = "relay-address")]
     pub relay_address: Option<String>,
@@ -78,32 +605,224 @@ pub struct Settings {
     #[clap(short = 'P', long = "relay-port")]
     pub relay_port: Option<u16>,
 
+    /// PeerId the relay must present. Connecting fails if it doesn't, so a hijacked relay
+    /// hostname can't stand in for it.
+    #[clap(long = "relay-peer-id")]
+    pub relay_peer_id: Option<String>,
+
+    /// More relays to hold reservations on, as host[:port] with relay_port by default. Peers
+    /// measure their round trip time to each and agree on the one closest to both of them. Can be
+    /// supplied multiple times.
+    #[clap(long = "relay")]
+    pub relays: Vec<String>,
+
+    /// Serve RTP-MIDI sessions for macOS Network MIDI and network MIDI interfaces, one on this
+    /// port carrying every peer and one per peer on the following ports.
+    #[clap(long = "rtp-midi-port")]
+    pub rtp_midi_port: Option<u16>,
+
+    /// Join an RTP-MIDI session at host:port. Can be supplied multiple times.
+    #[clap(long = "rtp-midi-invite")]
+    pub rtp_midi_invite: Vec<String>,
+
+    /// Listen for OSC messages on this port and play them into the session as MIDI.
+    #[clap(long = "osc-port")]
+    pub osc_port: Option<u16>,
+
+    /// Send MIDI played by peers as OSC to host:port. Can be supplied multiple times.
+    #[clap(long = "osc-send")]
+    pub osc_send: Vec<String>,
+
+    /// OSC addresses mapped to controllers, notes and pitch bends. Only read from the config file.
+    #[clap(skip)]
+    pub osc_map: Vec<OscMapping>,
+
+    /// Serve Prometheus metrics on this address, like 127.0.0.1:9100.
+    #[clap(long = "metrics-address")]
+    pub metrics_address: Option<std::net::SocketAddr>,
+
+    /// Serve a web page on this address, like 0.0.0.0:8080, letting browsers join the session
+    /// through WebMIDI. Its URL with the secret token to share is logged at start, and browsers
+    /// are held to the `browser` entry of the peer configs.
+    #[clap(long = "gateway-address")]
+    pub gateway_address: Option<std::net::SocketAddr>,
+
+    /// ipMIDI multicast ports bridged into the session. Only read from the config file.
+    #[clap(skip)]
+    pub ipmidi: Vec<IpMidiMapping>,
+
+    /// Serve a Network MIDI 2.0 endpoint on this UDP port, for UMP capable systems and devices.
+    #[clap(long = "network-midi2-port")]
+    pub network_midi2_port: Option<u16>,
+
+    /// Open a "To <name>" and a "From <name>" virtual MIDI port for each peer, to send to it and
+    /// hear it apart from the others. On by default on Linux and macOS.
+    #[clap(long = "virtual-ports")]
+    pub virtual_ports: Option<bool>,
+
+    /// Send a copy of what you play to the relay to record in this session, if it records
+    /// sessions. Nothing is sent to it otherwise. The first to record names the session, the
+    /// others get in after playing with one of them through the relay.
+    #[clap(long = "archive")]
+    pub archive: Option<String>,
+
+    /// Let the others in an archived session download what you played, not only you. On by
+    /// default.
+    #[clap(long = "archive-share")]
+    pub archive_share: Option<bool>,
+
+    /// Record every session to the recordings directory of the data directory, so no take is
+    /// lost for not pressing record.
+    #[clap(long = "auto-record")]
+    pub auto_record: Option<bool>,
+
+    /// Keep only this many of the latest automatic recordings.
+    #[clap(long = "auto-record-keep")]
+    pub auto_record_keep: Option<usize>,
+
+    /// Delete automatic recordings older than this many days.
+    #[clap(long = "auto-record-days")]
+    pub auto_record_days: Option<u64>,
+
+    /// When running as a relay, record the sessions peers send with --archive here, for them to
+    /// download later with `p2pmidi archive`.
+    #[clap(long = "archive-dir")]
+    pub archive_dir: Option<std::path::PathBuf>,
+
+    /// List the session under this name in the directory of the relay while connected, for others
+    /// to find with `p2pmidi sessions` or in the GUI.
+    #[clap(long = "publish")]
+    pub publish: Option<String>,
+
+    /// When running as a relay, keep a directory of the sessions peers publish.
+    #[clap(long = "directory")]
+    pub directory: Option<bool>,
+
+    /// Token the session directory of the relay asks for. Give the relay and the peers publishing
+    /// or browsing sessions on it the same one.
+    #[clap(long = "relay-token")]
+    pub relay_token: Option<String>,
+
+    /// Publish session events to an MQTT broker, as mqtt://host[:port][/prefix].
+    #[clap(long = "status-mqtt")]
+    pub status_mqtt: Option<String>,
+
+    /// Publish session events to WebSocket clients connecting to this address.
+    #[clap(long = "status-websocket")]
+    pub status_websocket: Option<std::net::SocketAddr>,
+
+    /// Publish a latency alarm when the round trip time to a peer goes over this many ms.
+    #[clap(long = "latency-alarm-ms")]
+    pub latency_alarm_ms: Option<f64>,
+
+    /// Follow or drive JACK transport from the session transport.
+    #[clap(long = "jack-transport", value_enum)]
+    pub jack_transport: Option<JackTransportMode>,
+
+    /// MIDI output device to send clock, start and stop on from the session transport.
+    #[clap(long = "clock-output")]
+    pub clock_output: Option<String>,
+
+    /// Swarm key file making a private swarm: only nodes with the same key can connect, relay
+    /// included. Create one with `p2pmidi swarm-key`.
+    #[clap(long = "swarm-key")]
+    pub swarm_key: Option<PathBuf>,
+
     /// GUI theme.
     #[clap(long = "theme", value_enum)]
     pub theme: Option<ThemeType>,
+
+    /// Redraw the meters of the GUI less often and keep its channel lights still, leaving the CPU
+    /// to softsynths on the same machine.
+    #[clap(long = "performance-mode")]
+    pub performance_mode: Option<bool>,
+
+    /// Log verbosity. Defaults to info.
+    #[clap(long = "log-level", value_enum)]
+    pub log_level: Option<LogLevel>,
+
+    /// Write logs to this file instead of the terminal.
+    #[clap(long = "log-file")]
+    pub log_file: Option<PathBuf>,
+
+    /// Log output format. Defaults to text.
+    #[clap(long = "log-format", value_enum)]
+    pub log_format: Option<LogFormat>,
+
+    /// Per peer settings keyed by PeerId or address book name. Only read from the config file.
+    #[clap(skip)]
+    pub peers: BTreeMap<String, PeerConfig>,
+
+    /// Zones of notes sent to the peers as chords, for one-finger pad parts. Only read from the
+    /// config file.
+    #[clap(skip)]
+    pub harmony: Vec<HarmonyZone>,
+
+    /// Velocities played on the input device mapped to those sent, proposed by `p2pmidi
+    /// calibrate`. Only read from the config file.
+    #[clap(skip)]
+    pub velocity_curve: Option<VelocityCurve>,
+
+    /// Shortcut overrides, mapping action names to keys like `ctrl+m`. Only read from the config
+    /// file.
+    #[clap(skip)]
+    pub keybindings: BTreeMap<String, String>,
+
+    /// Controls of the control device, like `cc:1:7` or `note:10:36`, mapped to what they
+    /// operate: shortcut action names, `volume:<peer>`, `mute:<peer>` or `preset:<name>`. Written
+    /// by MIDI learn in the settings window.
+    #[clap(skip)]
+    pub midi_bindings: BTreeMap<String, String>,
 }
 
 impl Settings {
-    /// Save settings to config file as serde serialized YAML
-    pub(crate) fn save(&self) -> Result<String, Box<dyn std::error::Error>> {
-        let contents = match serde_yaml::to_string(self) {
-            Ok(s) => s,
-            Err(err) => return Err(err.into()),
-        };
-
-        // Get config file
-        let path = shellexpand::tilde(constants::DEFAULT_CONFIG_PATH).into_owned();
+    /// Save settings to the given config file, serialized in the format matching its extension
+    pub(crate) fn save(&self, config_path: &Path) -> Result<String, Box<dyn std::error::Error>> {
+        let path = shellexpand::tilde(&config_path.display().to_string()).into_owned();
         let config_path = Path::new(&path);
+        let contents = ConfigFormat::from_path(config_path)?.serialize(self)?;
+
         if File::open(config_path).is_ok() {
             std::fs::write(config_path, contents)?;
         } else {
             // Create directory and empty config file
-            let parent = config_path.parent().unwrap();
-            std::fs::create_dir_all(parent)?;
+            if let Some(parent) = config_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
             std::fs::write(config_path, contents)?;
         }
         Ok(config_path.display().to_string())
     }
+
+    /// Load settings from a config file, without merging any command line arguments.
+    ///
+    /// Config files from older versions are migrated and rewritten, keeping a backup of the
+    /// original file next to it.
+    pub fn load(config_path: &Path) -> Result<Settings, Box<dyn Error>> {
+        let format = ConfigFormat::from_path(config_path)?;
+        let contents = std::fs::read_to_string(config_path)?;
+        let mut value = format.deserialize::<serde_json::Value>(&contents)?;
+
+        if let Some(old_version) = migration::migrate(&mut value)? {
+            let mut backup = config_path.as_os_str().to_owned();
+            backup.push(format!(".v{}.bak", old_version));
+            std::fs::copy(config_path, &backup)?;
+            std::fs::write(config_path, format.serialize(&value)?)?;
+            eprintln!(
+                "Migrated config file {} to version {}, backup saved to {}",
+                config_path.display(),
+                migration::CONFIG_VERSION,
+                Path::new(&backup).display()
+            );
+        }
+
+        if value.is_null() {
+            return Ok(Settings::default());
+        }
+        let config = serde_json::from_value::<<Settings as ClapSerde>::Opt>(value)?;
+        Ok(Settings::from(config))
+    }
+
     pub fn apply_default_values(&mut self) {
         // Use env username if name is not set
         if self.name.is_none() {
@@ -129,66 +848,161 @@ impl Settings {
     }
 }
 
-pub fn parse_config_file(args: &mut Args) -> Settings {
+pub fn parse_config_file(args: &mut Args) -> Result<Settings, Failure> {
     // Get config file
     let path = shellexpand::tilde(&args.config_path.display().to_string()).into_owned();
     args.config_path = Path::new(&path).to_path_buf();
-    if let Ok(f) = File::open(&args.config_path) {
+    if args.config_path.exists() {
         // Parse config with serde
-        match serde_yaml::from_reader::<_, <Settings as ClapSerde>::Opt>(BufReader::new(f)) {
+        match Settings::load(&args.config_path) {
             // merge config already parsed from clap
-            Ok(config) => Settings::from(config).merge(&mut args.settings),
-            Err(err) => panic!("Error in configuration file:\n{}", err),
+            Ok(config) => Ok(config.merge(&mut args.settings)),
+            Err(err) => Err(Failure::Config(format!(
+                "Error in configuration file {}:\n{}",
+                args.config_path.display(),
+                err
+            ))),
         }
     } else {
         // Create directory and empty config file
         let parent = args.config_path.parent().unwrap();
         match std::fs::create_dir_all(parent) {
             Ok(_) => (),
-            Err(err) => println!("Error creating config directory:\n{}", err),
+            Err(err) => eprintln!("Error creating config directory:\n{}", err),
         }
         match std::fs::write(&args.config_path, "") {
             Ok(_) => (),
-            Err(err) => println!("Error creating config file:\n{}", err),
+            Err(err) => eprintln!("Error creating config file:\n{}", err),
         }
 
         // If there is not config file return only config parsed from clap
-        Settings::from(&mut args.settings)
+        Ok(Settings::from(&mut args.settings))
     }
 }
 
-pub fn get_program_config() -> (Args, Settings) {
+/// Convert a config file to the format matching the extension of `to`.
+pub fn convert_config_file(from: &Path, to: &Path) -> Result<String, Box<dyn Error>> {
+    let from = shellexpand::tilde(&from.display().to_string()).into_owned();
+    Settings::load(Path::new(&from))?.save(to)
+}
+
+/// Let the user pick one of `items` with skim.
+#[cfg(feature = "tui")]
+fn fuzzy_select(items: &[String]) -> Option<String> {
+    let options = SkimOptionsBuilder::default()
+        .height(Some("50%"))
+        .multi(false)
+        .build()
+        .unwrap();
+
+    let item_reader = SkimItemReader::default();
+    let items = item_reader.of_bufread(Cursor::new(items.join("\n")));
+    let selected = Skim::run_with(&options, Some(items))
+        .filter(|out| !out.is_abort)
+        .and_then(|out| {
+            out.selected_items
+                .first()
+                .map(|item| item.output().to_string())
+        });
+    if let Some(item) = &selected {
+        println!("Selected item: {}", item);
+    }
+    selected
+}
+
+/// Never reached, prompts are refused up front without the fuzzy finder.
+#[cfg(not(feature = "tui"))]
+fn fuzzy_select(_items: &[String]) -> Option<String> {
+    None
+}
+
+pub fn get_program_config() -> Result<(Args, Settings), Failure> {
     let mut args = Args::parse();
-    let mut settings = parse_config_file(&mut args);
+    let prompt = if args.no_prompt {
+        Vec::new()
+    } else {
+        args.prompt.clone()
+    };
+    if !prompt.is_empty() && !cfg!(feature = "tui") {
+        return Err(Failure::Config(
+            "This build has no interactive prompts, build with --features tui".to_string(),
+        ));
+    }
 
-    // Prompt for chosing midi device
-    if args.prompt_for_midi_device {
+    if prompt.contains(&PromptKind::Profile) {
+        let profiles = match profiles::list_profiles() {
+            Ok(p) => p,
+            Err(e) => return Err(Failure::Config(format!("Error listing profiles: {}", e))),
+        };
+        if let Some(profile) = fuzzy_select(&profiles) {
+            args.profile = Some(profile);
+        }
+    }
+    if let Some(profile) = &args.profile {
+        profiles::check_profile_name(profile).map_err(|e| Failure::Config(e.to_string()))?;
+        let path = profiles::profile_path(profile);
+        if profile != constants::DEFAULT_PROFILE && !path.exists() {
+            return Err(Failure::Config(format!(
+                "Profile {:?} does not exist, create it with `p2pmidi config copy`",
+                profile
+            )));
+        }
+        args.config_path = path;
+    }
+    let mut settings = parse_config_file(&mut args)?;
+
+    if prompt.contains(&PromptKind::Device) {
         let inputs = match midi::get_midi_input() {
             Ok(i) => i,
-            Err(e) => panic!("Error creating midi input: {}", e),
+            Err(e) => {
+                return Err(Failure::Runtime(format!(
+                    "Error creating midi input: {}",
+                    e
+                )))
+            }
         };
-        let items = inputs.join("\n");
-        let options = SkimOptionsBuilder::default()
-            .height(Some("50%"))
-            .multi(false)
-            .build()
-            .unwrap();
-
-        let item_reader = SkimItemReader::default();
-        let items = item_reader.of_bufread(Cursor::new(items));
-        let selected_items = Skim::run_with(&options, Some(items))
-            .map(|out| out.selected_items)
-            .unwrap_or_else(Vec::new);
-
-        for item in selected_items {
-            println!("Selected item: {}", item.output());
-            settings.midi_device = Some(item.output().to_string());
+        if let Some(device) = fuzzy_select(&inputs) {
+            settings.midi_device = Some(device);
+        }
+    }
+    if prompt.contains(&PromptKind::Output) {
+        let outputs = match midi::get_midi_output() {
+            Ok(o) => o,
+            Err(e) => {
+                return Err(Failure::Runtime(format!(
+                    "Error creating midi output: {}",
+                    e
+                )))
+            }
+        };
+        if let Some(device) = fuzzy_select(&outputs) {
+            settings.midi_output = Some(device);
+        }
+    }
+    if prompt.contains(&PromptKind::Peer) {
+        let book = Storage::new(args.data_dir.as_deref())
+            .address_book()
+            .map_err(|e| Failure::Runtime(format!("Error reading the address book: {}", e)))?;
+        let mut peers: Vec<String> = book.into_keys().collect();
+        for name in settings.peers.keys() {
+            if !peers.contains(name) {
+                peers.push(name.clone());
+            }
+        }
+        if peers.is_empty() {
+            eprintln!("No peers saved in the address book");
+        } else {
+            args.target = fuzzy_select(&peers);
         }
     }
 
     let arglen = env::args().collect::<Vec<String>>().len();
-    if !atty::is(atty::Stream::Stdin) || arglen == 1 {
+    if cfg!(feature = "gui")
+        && args.command.is_none()
+        && !args.no_prompt
+        && (!atty::is(atty::Stream::Stdin) || arglen == 1)
+    {
         args.gui = true;
     }
-    (args, settings)
+    Ok((args, settings))
 }
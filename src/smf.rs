@@ -0,0 +1,139 @@
+use midly::{MetaMessage, Smf, Timing, TrackEventKind};
+use std::error::Error;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::midi;
+
+/// How often a file player waiting for its next message checks whether it was cancelled.
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A raw MIDI message and when it plays, relative to the start of the file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimedMessage {
+    pub at: Duration,
+    pub message: Vec<u8>,
+}
+
+/// Read a standard MIDI file, merging all tracks into one list of messages ordered by time.
+pub fn load_events(path: &Path) -> Result<Vec<TimedMessage>, Box<dyn Error>> {
+    let bytes = std::fs::read(path)?;
+    let smf = Smf::parse(&bytes)?;
+
+    // Absolute tick of every event, keeping the track order for events on the same tick
+    let mut events = Vec::new();
+    for track in &smf.tracks {
+        let mut tick: u64 = 0;
+        for event in track {
+            tick += event.delta.as_int() as u64;
+            events.push((tick, event.kind));
+        }
+    }
+    events.sort_by_key(|(tick, _)| *tick);
+
+    // Microseconds per tick, changed by tempo events for metrical files. 120 bpm by default.
+    let ticks_per_beat = match smf.header.timing {
+        Timing::Metrical(tpb) => Some(tpb.as_int() as f64),
+        Timing::Timecode(_, _) => None,
+    };
+    let mut us_per_tick = match smf.header.timing {
+        Timing::Metrical(tpb) => 500_000.0 / tpb.as_int() as f64,
+        Timing::Timecode(fps, subframes) => 1_000_000.0 / (fps.as_f32() as f64 * subframes as f64),
+    };
+
+    let mut messages = Vec::new();
+    let mut last_tick = 0;
+    let mut us = 0.0;
+    for (tick, kind) in events {
+        us += (tick - last_tick) as f64 * us_per_tick;
+        last_tick = tick;
+        match kind {
+            TrackEventKind::Meta(MetaMessage::Tempo(tempo)) => {
+                if let Some(tpb) = ticks_per_beat {
+                    us_per_tick = tempo.as_int() as f64 / tpb;
+                }
+            }
+            kind => {
+                if let Some(live) = kind.as_live_event() {
+                    let mut message = Vec::new();
+                    live.write_std(&mut message)?;
+                    messages.push(TimedMessage {
+                        at: Duration::from_micros(us as u64),
+                        message,
+                    });
+                }
+            }
+        }
+    }
+    Ok(messages)
+}
+
+/// Plays the messages of a file in real time, on a thread of its own.
+#[derive(Debug)]
+pub struct FilePlayer {
+    started: Instant,
+    length: Duration,
+    cancelled: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl FilePlayer {
+    /// Hand every message to `send` when it is due. Notes still sounding when cancelled are
+    /// ended with all notes off.
+    pub fn start(
+        messages: Vec<TimedMessage>,
+        mut send: impl FnMut(&[u8]) + Send + 'static,
+    ) -> Result<Self, Box<dyn Error>> {
+        let length = messages.last().map(|m| m.at).unwrap_or_default();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let started = Instant::now();
+        let thread = {
+            let cancelled = cancelled.clone();
+            std::thread::Builder::new()
+                .name("file player".to_string())
+                .spawn(move || {
+                    for timed in messages {
+                        loop {
+                            if cancelled.load(Ordering::Relaxed) {
+                                for message in midi::all_notes_off() {
+                                    send(&message);
+                                }
+                                return;
+                            }
+                            let elapsed = started.elapsed();
+                            if elapsed >= timed.at {
+                                break;
+                            }
+                            std::thread::sleep((timed.at - elapsed).min(CANCEL_POLL_INTERVAL));
+                        }
+                        send(&timed.message);
+                    }
+                })?
+        };
+        Ok(FilePlayer {
+            started,
+            length,
+            cancelled,
+            thread,
+        })
+    }
+
+    /// How much of the file was played, from 0 to 1.
+    pub fn progress(&self) -> f32 {
+        match self.length.is_zero() {
+            true => 1.0,
+            false => (self.started.elapsed().as_secs_f32() / self.length.as_secs_f32()).min(1.0),
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,363 @@
+//! Session events published to an MQTT broker or over a WebSocket, so lighting and show control
+//! systems can follow the music.
+
+use futures::channel::mpsc::{UnboundedReceiver, UnboundedSender};
+use futures::{SinkExt, StreamExt};
+use rumqttc::{AsyncClient, LastWill, MqttOptions, QoS};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{debug, info, info_span, warn, Instrument};
+
+use crate::failure::Failure;
+use crate::p2p::playout::LatencyMode;
+use crate::runtime;
+use crate::storage::{EventRecord, Storage};
+
+const DEFAULT_MQTT_PORT: u16 = 1883;
+const DEFAULT_TOPIC_PREFIX: &str = "p2pmidi";
+
+/// How long to wait before reconnecting to the broker.
+const MQTT_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Something show control might react to, also kept in the event log.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum StatusEvent {
+    PeerJoined {
+        peer_id: String,
+        name: String,
+    },
+    PeerLeft {
+        peer_id: String,
+    },
+    Transport {
+        tempo: f64,
+        playing: bool,
+    },
+    Recording {
+        recording: bool,
+        path: Option<PathBuf>,
+    },
+    /// A peer went over its inbound MIDI rate limit.
+    RateLimited {
+        peer_id: String,
+        rate: u32,
+    },
+    /// MIDI from a peer is now played live, buffered or bars late.
+    LatencyMode {
+        peer_id: String,
+        mode: LatencyMode,
+        delay_bars: Option<u32>,
+    },
+    /// Round trip time to a peer went over the alarm threshold, or back under it.
+    Latency {
+        peer_id: String,
+        rtt_ms: f64,
+        alarm: bool,
+    },
+    /// Hole punching to a relayed peer succeeded, or failed with `error`.
+    DirectConnection {
+        peer_id: String,
+        error: Option<String>,
+    },
+    /// Frames from a peer went missing on the way.
+    Dropout {
+        peer_id: String,
+        lost: u64,
+    },
+    /// All notes were turned off on every peer.
+    Panic,
+}
+
+impl StatusEvent {
+    /// Topic under the prefix the event is published to.
+    fn topic(&self) -> &'static str {
+        match self {
+            StatusEvent::PeerJoined { .. } | StatusEvent::PeerLeft { .. } => "peers",
+            StatusEvent::Transport { .. } => "transport",
+            StatusEvent::Recording { .. } => "recording",
+            StatusEvent::RateLimited { .. } => "rate_limit",
+            StatusEvent::LatencyMode { .. } => "latency_mode",
+            StatusEvent::Latency { .. } => "latency",
+            StatusEvent::DirectConnection { .. } => "connections",
+            StatusEvent::Dropout { .. } => "loss",
+            StatusEvent::Panic => "panic",
+        }
+    }
+
+    /// Whether the event is a state newcomers should be told, retained by the broker.
+    fn is_state(&self) -> bool {
+        matches!(
+            self,
+            StatusEvent::Transport { .. } | StatusEvent::Recording { .. }
+        )
+    }
+}
+
+/// Where to publish session events.
+#[derive(Clone, Debug, Default)]
+pub struct StatusOptions {
+    /// Broker as `mqtt://host[:port][/prefix]`.
+    pub mqtt: Option<String>,
+    /// Serve events to WebSocket clients on this address.
+    pub websocket: Option<SocketAddr>,
+    /// Raise a latency alarm when the round trip time to a peer goes over this.
+    pub latency_alarm_ms: Option<f64>,
+}
+
+/// Broker address and topic prefix of `mqtt://host[:port][/prefix]`.
+fn parse_mqtt_url(url: &str) -> Result<(String, u16, String), String> {
+    let rest = url
+        .strip_prefix("mqtt://")
+        .ok_or_else(|| format!("Expected mqtt://host[:port][/prefix], got {}", url))?;
+    let (address, prefix) = match rest.split_once('/') {
+        Some((address, prefix)) if !prefix.trim_matches('/').is_empty() => {
+            (address, prefix.trim_matches('/').to_string())
+        }
+        Some((address, _)) => (address, DEFAULT_TOPIC_PREFIX.to_string()),
+        None => (rest, DEFAULT_TOPIC_PREFIX.to_string()),
+    };
+    let (host, port) = match address.rsplit_once(':') {
+        Some((host, port)) => (
+            host,
+            port.parse()
+                .map_err(|_| format!("Invalid MQTT port: {}", port))?,
+        ),
+        None => (address, DEFAULT_MQTT_PORT),
+    };
+    if host.is_empty() {
+        return Err(format!("No MQTT broker host in {}", url));
+    }
+    Ok((host.to_string(), port, prefix))
+}
+
+/// Hands session events to the MQTT and WebSocket publishers.
+#[derive(Default)]
+pub struct StatusPublisher {
+    subscribers: Vec<UnboundedSender<StatusEvent>>,
+    latency_alarm_ms: Option<f64>,
+    /// Peers whose latency alarm is raised.
+    alarmed: HashSet<String>,
+    /// Where the event log is appended to.
+    storage: Option<Storage>,
+}
+
+impl StatusPublisher {
+    /// Connect to the broker and serve the WebSocket enabled in `options`. Events are also
+    /// appended to the event log in `storage`.
+    pub fn start(options: &StatusOptions, storage: &Storage) -> Result<Self, Box<dyn Error>> {
+        let mut publisher = StatusPublisher {
+            latency_alarm_ms: options.latency_alarm_ms,
+            storage: Some(storage.clone()),
+            ..Default::default()
+        };
+        if let Some(url) = &options.mqtt {
+            let (host, port, prefix) = parse_mqtt_url(url).map_err(Failure::Config)?;
+            let (sender, receiver) = futures::channel::mpsc::unbounded();
+            publish_mqtt(host, port, prefix, receiver);
+            publisher.subscribers.push(sender);
+        }
+        if let Some(address) = options.websocket {
+            let (sender, receiver) = futures::channel::mpsc::unbounded();
+            serve_websocket(address, receiver)?;
+            publisher.subscribers.push(sender);
+        }
+        Ok(publisher)
+    }
+
+    pub fn publish(&mut self, event: StatusEvent) {
+        if let Some(storage) = &self.storage {
+            if let Err(e) = storage.append_event(&EventRecord::now(event.clone())) {
+                warn!("Error writing the event log: {}", e);
+            }
+        }
+        self.subscribers
+            .retain(|subscriber| subscriber.unbounded_send(event.clone()).is_ok());
+    }
+
+    /// Check a round trip time against the alarm threshold, publishing when the alarm is raised
+    /// or cleared.
+    pub fn latency(&mut self, peer_id: &str, rtt_ms: f64) {
+        let threshold = match self.latency_alarm_ms {
+            Some(threshold) => threshold,
+            None => return,
+        };
+        let alarm = rtt_ms > threshold;
+        if alarm == self.alarmed.contains(peer_id) {
+            return;
+        }
+        match alarm {
+            true => {
+                warn!("Latency to {} is {:.1} ms", peer_id, rtt_ms);
+                self.alarmed.insert(peer_id.to_string());
+            }
+            false => {
+                self.alarmed.remove(peer_id);
+            }
+        }
+        self.publish(StatusEvent::Latency {
+            peer_id: peer_id.to_string(),
+            rtt_ms,
+            alarm,
+        });
+    }
+
+    pub fn peer_left(&mut self, peer_id: &str) {
+        self.alarmed.remove(peer_id);
+        self.publish(StatusEvent::PeerLeft {
+            peer_id: peer_id.to_string(),
+        });
+    }
+}
+
+/// Publish events under `prefix` on the broker, with `prefix/online` telling whether we are.
+fn publish_mqtt(
+    host: String,
+    port: u16,
+    prefix: String,
+    mut events: UnboundedReceiver<StatusEvent>,
+) {
+    let _runtime = runtime::enter();
+    let online = format!("{}/online", prefix);
+    let mut options = MqttOptions::new(
+        format!("p2pmidi-{:08x}", rand::random::<u32>()),
+        host.clone(),
+        port,
+    );
+    options.set_keep_alive(Duration::from_secs(30));
+    options.set_last_will(LastWill::new(&online, "false", QoS::AtLeastOnce, true));
+    let (client, mut eventloop) = AsyncClient::new(options, 64);
+    info!(
+        "Publishing status to MQTT broker {}:{} under {}",
+        host, port, prefix
+    );
+
+    // The event loop does the actual talking to the broker, reconnecting as needed
+    let span = info_span!("mqtt", %host);
+    runtime::spawn(
+        async move {
+            loop {
+                match eventloop.poll().await {
+                    Ok(rumqttc::Event::Incoming(rumqttc::Packet::ConnAck(_))) => {
+                        info!("Connected to MQTT broker");
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        warn!("MQTT broker unreachable: {}", e);
+                        tokio::time::sleep(MQTT_RETRY_INTERVAL).await;
+                    }
+                }
+            }
+        }
+        .instrument(span),
+    );
+
+    runtime::spawn(async move {
+        if let Err(e) = client
+            .publish(&online, QoS::AtLeastOnce, true, "true")
+            .await
+        {
+            debug!("Error publishing to MQTT: {}", e);
+        }
+        while let Some(event) = events.next().await {
+            let payload = match serde_json::to_vec(&event) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    warn!("Error serializing status event: {}", e);
+                    continue;
+                }
+            };
+            let topic = format!("{}/{}", prefix, event.topic());
+            if let Err(e) = client
+                .publish(topic, QoS::AtLeastOnce, event.is_state(), payload)
+                .await
+            {
+                debug!("Error publishing to MQTT: {}", e);
+            }
+        }
+        let _ = client
+            .publish(&online, QoS::AtLeastOnce, true, "false")
+            .await;
+        let _ = client.disconnect().await;
+    });
+}
+
+/// Serve events to every WebSocket client on `address`, starting with the current state.
+fn serve_websocket(
+    address: SocketAddr,
+    mut events: UnboundedReceiver<StatusEvent>,
+) -> Result<(), Box<dyn Error>> {
+    let listener = std::net::TcpListener::bind(address)?;
+    listener.set_nonblocking(true)?;
+    let _runtime = runtime::enter();
+    let listener = TcpListener::from_std(listener)?;
+    info!("Publishing status at ws://{}/", address);
+
+    let clients: Arc<Mutex<Vec<UnboundedSender<String>>>> = Default::default();
+    // Last state of each topic, for clients connecting later
+    let states: Arc<Mutex<BTreeMap<&'static str, String>>> = Default::default();
+
+    let (accepting, known) = (clients.clone(), states.clone());
+    runtime::spawn(
+        async move {
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        warn!("Error accepting status connection: {}", e);
+                        continue;
+                    }
+                };
+                let (clients, states) = (accepting.clone(), known.clone());
+                runtime::spawn(async move {
+                    let socket = match tokio_tungstenite::accept_async(stream).await {
+                        Ok(socket) => socket,
+                        Err(e) => {
+                            debug!("Invalid status connection: {}", e);
+                            return;
+                        }
+                    };
+                    let (sender, mut outgoing) = futures::channel::mpsc::unbounded();
+                    for state in states.lock().unwrap().values() {
+                        let _ = sender.unbounded_send(state.clone());
+                    }
+                    clients.lock().unwrap().push(sender);
+                    let (mut sink, _) = socket.split();
+                    while let Some(text) = outgoing.next().await {
+                        if sink.send(Message::Text(text)).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        }
+        .instrument(info_span!("status")),
+    );
+
+    runtime::spawn(async move {
+        while let Some(event) = events.next().await {
+            let text = match serde_json::to_string(&event) {
+                Ok(text) => text,
+                Err(e) => {
+                    warn!("Error serializing status event: {}", e);
+                    continue;
+                }
+            };
+            if event.is_state() {
+                states.lock().unwrap().insert(event.topic(), text.clone());
+            }
+            clients
+                .lock()
+                .unwrap()
+                .retain(|client| client.unbounded_send(text.clone()).is_ok());
+        }
+    });
+    Ok(())
+}
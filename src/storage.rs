@@ -0,0 +1,530 @@
+use libp2p::identity;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::info;
+
+use super::constants;
+use super::keystore;
+use super::p2p::playout::LatencyMode;
+use super::routing::PeerConfig;
+use super::settings::Settings;
+use super::status::StatusEvent;
+
+/// Peers saved by name, mapping to the PeerId, multiaddr or invite to dial them at.
+pub type AddressBook = BTreeMap<String, String>;
+
+/// A peer accepted into a session before, by PeerId in the known peers file.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct KnownPeer {
+    /// Name the peer went by when it was accepted.
+    pub name: Option<String>,
+    /// Seconds since the unix epoch.
+    pub first_seen: u64,
+    /// Its short authentication string was compared over another channel.
+    #[serde(default)]
+    pub verified: bool,
+}
+
+/// Accepted peers by PeerId.
+pub type KnownPeers = BTreeMap<String, KnownPeer>;
+
+/// Routing, transforms and mutes of every peer saved under a name, to switch between at once.
+pub type Presets = BTreeMap<String, BTreeMap<String, PeerConfig>>;
+
+/// Sessions kept in the recent sessions list.
+const MAX_RECENT_SESSIONS: usize = 10;
+
+/// Single use invite tokens already used, by nonce, with when they expire.
+pub type UsedInvites = BTreeMap<String, u64>;
+
+/// How much of the automatic recordings is kept. The latest one is kept either way.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Retention {
+    /// Keep only this many, the latest.
+    pub keep_last: Option<usize>,
+    /// Keep only the ones from this many days ago or later.
+    pub keep_days: Option<u64>,
+}
+
+/// A recording made by this node, listed in the recordings index.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RecordingEntry {
+    pub path: PathBuf,
+    /// Seconds since the unix epoch.
+    pub started_at: u64,
+}
+
+/// The settings a session was joined with, put back to join it again.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct SessionSettings {
+    pub relay_address: String,
+    pub relay_port: u16,
+    pub relay_peer_id: Option<String>,
+    pub midi_device: Option<String>,
+    pub midi_output: Option<String>,
+    pub track: Option<String>,
+    pub latency_mode: Option<LatencyMode>,
+    pub delay_bars: u32,
+}
+
+/// A session joined lately, listed to join it again.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RecentSession {
+    /// Seconds since the unix epoch it was last joined.
+    pub at: u64,
+    /// The peers dialed, none when waiting for peers to join.
+    pub targets: Vec<String>,
+    /// Names of the peers played with.
+    #[serde(default)]
+    pub peers: Vec<String>,
+    pub settings: SessionSettings,
+}
+
+impl RecentSession {
+    /// Set `settings` to join the session again, dialing the same peers.
+    pub fn apply_to(&self, settings: &mut Settings) {
+        let saved = self.settings.clone();
+        settings.ip_addresses = self.targets.clone();
+        settings.relay_address = Some(saved.relay_address);
+        settings.relay_port = Some(saved.relay_port);
+        settings.relay_peer_id = saved.relay_peer_id;
+        settings.midi_device = saved.midi_device;
+        settings.midi_output = saved.midi_output;
+        settings.track = saved.track;
+        settings.latency_mode = saved.latency_mode;
+        settings.delay_bars = Some(saved.delay_bars);
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum ConnectionOutcome {
+    Connected,
+    Failed { error: String },
+}
+
+/// A connection attempt, one line of the connection history.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ConnectionRecord {
+    /// Seconds since the unix epoch.
+    pub at: u64,
+    pub peer_id: Option<String>,
+    pub direction: Direction,
+    pub address: Option<String>,
+    pub transport: String,
+    #[serde(flatten)]
+    pub outcome: ConnectionOutcome,
+    /// How long the connection lasted.
+    pub duration_secs: Option<f64>,
+    /// Average round trip time over the connection.
+    #[serde(default)]
+    pub rtt_ms: Option<f64>,
+}
+
+/// Something that happened in a session, one line of the event log.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct EventRecord {
+    /// Seconds since the unix epoch.
+    pub at: u64,
+    #[serde(flatten)]
+    pub event: StatusEvent,
+}
+
+impl EventRecord {
+    pub fn now(event: StatusEvent) -> Self {
+        EventRecord {
+            at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            event,
+        }
+    }
+}
+
+/// Data the program changes on its own, kept apart from the config file the user edits.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Storage {
+    dir: PathBuf,
+}
+
+impl Storage {
+    /// Use `dir`, or `$XDG_DATA_HOME/p2pmidi` falling back to `~/.local/share/p2pmidi`.
+    pub fn new(dir: Option<&Path>) -> Self {
+        let dir = match dir {
+            Some(dir) => dir.to_path_buf(),
+            None => match std::env::var_os("XDG_DATA_HOME") {
+                Some(data_home) if !data_home.is_empty() => {
+                    PathBuf::from(data_home).join("p2pmidi")
+                }
+                _ => PathBuf::from(constants::DATA_DIR),
+            },
+        };
+        Storage {
+            dir: PathBuf::from(shellexpand::tilde(&dir.display().to_string()).into_owned()),
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn identity_path(&self) -> PathBuf {
+        self.dir.join("identity.key")
+    }
+
+    pub fn relay_identity_path(&self) -> PathBuf {
+        self.dir.join("relay_identity.key")
+    }
+
+    pub fn address_book_path(&self) -> PathBuf {
+        self.dir.join("address_book.json")
+    }
+
+    pub fn known_peers_path(&self) -> PathBuf {
+        self.dir.join("known_peers.json")
+    }
+
+    pub fn used_invites_path(&self) -> PathBuf {
+        self.dir.join("used_invites.json")
+    }
+
+    pub fn history_path(&self) -> PathBuf {
+        self.dir.join("history.jsonl")
+    }
+
+    pub fn events_path(&self) -> PathBuf {
+        self.dir.join("events.jsonl")
+    }
+
+    pub fn recent_sessions_path(&self) -> PathBuf {
+        self.dir.join("recent_sessions.json")
+    }
+
+    pub fn recordings_path(&self) -> PathBuf {
+        self.dir.join("recordings.json")
+    }
+
+    pub fn presets_path(&self) -> PathBuf {
+        self.dir.join("presets.json")
+    }
+
+    /// Where sessions are recorded to with --auto-record.
+    pub fn auto_recordings_dir(&self) -> PathBuf {
+        self.dir.join("recordings")
+    }
+
+    /// A new file in the automatic recordings directory, named after now in UTC so they sort by
+    /// when they were made.
+    pub fn auto_recording_path(&self) -> PathBuf {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        self.auto_recordings_dir()
+            .join(format!("session-{}.mid", utc_timestamp(now)))
+    }
+
+    /// Where files peers send are saved.
+    pub fn received_dir(&self) -> PathBuf {
+        self.dir.join("received")
+    }
+
+    /// Read the node identity, creating a new one the first time so the PeerId others dial stays
+    /// the same across restarts. Locked identities ask for their passphrase.
+    pub fn load_identity(&self, path: &Path) -> Result<identity::Keypair, Box<dyn Error>> {
+        match std::fs::read(path) {
+            Ok(bytes) if keystore::is_locked(&bytes) => {
+                let bytes = keystore::unlock(&bytes, &keystore::passphrase(path)?)?;
+                Ok(identity::Keypair::from_protobuf_encoding(&bytes)?)
+            }
+            Ok(bytes) => Ok(identity::Keypair::from_protobuf_encoding(&bytes)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let keypair = identity::Keypair::generate_ed25519();
+                self.write(path, &keypair.to_protobuf_encoding()?)?;
+                info!("Created a new identity at {:?}", path);
+                Ok(keypair)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Write `identity` to `path` locked with `passphrase`, or in the clear with `None`.
+    pub fn save_identity(
+        &self,
+        path: &Path,
+        identity: &identity::Keypair,
+        passphrase: Option<&str>,
+    ) -> Result<(), Box<dyn Error>> {
+        let key = identity.to_protobuf_encoding()?;
+        let contents = match passphrase {
+            Some(passphrase) => keystore::lock(&key, passphrase)?,
+            None => key,
+        };
+        self.write(path, &contents)
+    }
+
+    pub fn address_book(&self) -> Result<AddressBook, Box<dyn Error>> {
+        self.read_json(&self.address_book_path())
+    }
+
+    pub fn save_address_book(&self, book: &AddressBook) -> Result<(), Box<dyn Error>> {
+        self.write(
+            &self.address_book_path(),
+            serde_json::to_string_pretty(book)?.as_bytes(),
+        )
+    }
+
+    pub fn known_peers(&self) -> Result<KnownPeers, Box<dyn Error>> {
+        self.read_json(&self.known_peers_path())
+    }
+
+    pub fn save_known_peers(&self, peers: &KnownPeers) -> Result<(), Box<dyn Error>> {
+        self.write(
+            &self.known_peers_path(),
+            serde_json::to_string_pretty(peers)?.as_bytes(),
+        )
+    }
+
+    pub fn used_invites(&self) -> Result<UsedInvites, Box<dyn Error>> {
+        self.read_json(&self.used_invites_path())
+    }
+
+    pub fn save_used_invites(&self, used: &UsedInvites) -> Result<(), Box<dyn Error>> {
+        self.write(
+            &self.used_invites_path(),
+            serde_json::to_string_pretty(used)?.as_bytes(),
+        )
+    }
+
+    pub fn presets(&self) -> Result<Presets, Box<dyn Error>> {
+        self.read_json(&self.presets_path())
+    }
+
+    pub fn save_presets(&self, presets: &Presets) -> Result<(), Box<dyn Error>> {
+        self.write(
+            &self.presets_path(),
+            serde_json::to_string_pretty(presets)?.as_bytes(),
+        )
+    }
+
+    /// The connection history, oldest first. Lines that don't parse are skipped.
+    pub fn history(&self) -> Result<Vec<ConnectionRecord>, Box<dyn Error>> {
+        match std::fs::read_to_string(self.history_path()) {
+            Ok(contents) => Ok(contents
+                .lines()
+                .filter_map(|line| serde_json::from_str(line).ok())
+                .collect()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn append_history(&self, record: &ConnectionRecord) -> Result<(), Box<dyn Error>> {
+        std::fs::create_dir_all(&self.dir)?;
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.history_path())?;
+        writeln!(file, "{}", serde_json::to_string(record)?)?;
+        Ok(())
+    }
+
+    /// The event log, oldest first. Lines that don't parse are skipped.
+    pub fn events(&self) -> Result<Vec<EventRecord>, Box<dyn Error>> {
+        match std::fs::read_to_string(self.events_path()) {
+            Ok(contents) => Ok(contents
+                .lines()
+                .filter_map(|line| serde_json::from_str(line).ok())
+                .collect()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn append_event(&self, record: &EventRecord) -> Result<(), Box<dyn Error>> {
+        std::fs::create_dir_all(&self.dir)?;
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.events_path())?;
+        writeln!(file, "{}", serde_json::to_string(record)?)?;
+        Ok(())
+    }
+
+    /// The sessions joined lately, latest first.
+    pub fn recent_sessions(&self) -> Result<Vec<RecentSession>, Box<dyn Error>> {
+        self.read_json(&self.recent_sessions_path())
+    }
+
+    /// Put `session` first in the recent sessions, in place of the one dialing the same peers and
+    /// keeping the names of the peers played with there.
+    pub fn remember_session(&self, session: &RecentSession) -> Result<(), Box<dyn Error>> {
+        let mut sessions = self.recent_sessions()?;
+        let mut session = session.clone();
+        if let Some(i) = sessions.iter().position(|s| s.targets == session.targets) {
+            for peer in sessions.remove(i).peers {
+                if !session.peers.contains(&peer) {
+                    session.peers.push(peer);
+                }
+            }
+        }
+        sessions.insert(0, session);
+        sessions.truncate(MAX_RECENT_SESSIONS);
+        self.write(
+            &self.recent_sessions_path(),
+            serde_json::to_string_pretty(&sessions)?.as_bytes(),
+        )
+    }
+
+    pub fn recordings(&self) -> Result<Vec<RecordingEntry>, Box<dyn Error>> {
+        self.read_json(&self.recordings_path())
+    }
+
+    /// Add a recording that just started to the index.
+    pub fn add_recording(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let mut recordings = self.recordings()?;
+        recordings.push(RecordingEntry {
+            path: std::env::current_dir()
+                .map(|dir| dir.join(path))
+                .unwrap_or_else(|_| path.to_path_buf()),
+            started_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+        });
+        self.write(
+            &self.recordings_path(),
+            serde_json::to_string_pretty(&recordings)?.as_bytes(),
+        )
+    }
+
+    /// Delete the automatic recordings past `retention` and drop them from the recordings index.
+    /// Returns how many were deleted.
+    pub fn prune_auto_recordings(&self, retention: &Retention) -> Result<usize, Box<dyn Error>> {
+        let mut files: Vec<PathBuf> = match std::fs::read_dir(self.auto_recordings_dir()) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.extension().map_or(false, |ext| ext == "mid"))
+                .collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        // Newest first
+        files.sort();
+        files.reverse();
+        let max_age = retention
+            .keep_days
+            .map(|days| std::time::Duration::from_secs(days * 24 * 60 * 60));
+        let keep_last = retention.keep_last.unwrap_or(usize::MAX).max(1);
+        let mut deleted = Vec::new();
+        for (i, path) in files.iter().enumerate() {
+            let too_old = match (i, max_age) {
+                (0, _) | (_, None) => false,
+                (_, Some(max_age)) => std::fs::metadata(path)
+                    .and_then(|metadata| metadata.modified())
+                    .ok()
+                    .and_then(|modified| modified.elapsed().ok())
+                    .map_or(false, |age| age > max_age),
+            };
+            if i >= keep_last || too_old {
+                std::fs::remove_file(path)?;
+                deleted.push(path.clone());
+            }
+        }
+        if !deleted.is_empty() {
+            let mut recordings = self.recordings()?;
+            recordings.retain(|entry| !deleted.contains(&entry.path));
+            self.write(
+                &self.recordings_path(),
+                serde_json::to_string_pretty(&recordings)?.as_bytes(),
+            )?;
+            info!("Deleted {} old automatic recordings", deleted.len());
+        }
+        Ok(deleted.len())
+    }
+
+    /// Missing files read as empty.
+    fn read_json<T: DeserializeOwned + Default>(&self, path: &Path) -> Result<T, Box<dyn Error>> {
+        match std::fs::read_to_string(path) {
+            Ok(contents) if contents.trim().is_empty() => Ok(T::default()),
+            Ok(contents) => Ok(serde_json::from_str(&contents)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(T::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Replace the file at `path` at once, readable only by us. The new contents go to a file
+    /// next to it first, so a crash never leaves it half written.
+    fn write(&self, path: &Path, contents: &[u8]) -> Result<(), Box<dyn Error>> {
+        std::fs::create_dir_all(&self.dir)?;
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        let temp = PathBuf::from(temp);
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(&temp)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        std::fs::rename(&temp, path)?;
+        Ok(())
+    }
+}
+
+/// Seconds since the unix epoch as the year, month, day, hour, minute and second in UTC.
+fn utc(secs: u64) -> (i64, i64, i64, u64, u64, u64) {
+    let days = (secs / 86_400) as i64;
+    let time = secs % 86_400;
+    // Days to the civil calendar, from Howard Hinnant's date algorithms
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day, time / 3_600, time / 60 % 60, time % 60)
+}
+
+/// Seconds since the unix epoch as YYYY-MM-DD-HHMMSS in UTC, for file names.
+fn utc_timestamp(secs: u64) -> String {
+    let (year, month, day, hour, minute, second) = utc(secs);
+    format!(
+        "{:04}-{:02}-{:02}-{:02}{:02}{:02}",
+        year, month, day, hour, minute, second
+    )
+}
+
+/// Seconds since the unix epoch as a time of day in UTC, like `20:15:07`.
+pub fn format_utc_time(secs: u64) -> String {
+    let (_, _, _, hour, minute, second) = utc(secs);
+    format!("{:02}:{:02}:{:02}", hour, minute, second)
+}
+
+/// Seconds since the unix epoch as a date and time in UTC, like `2024-05-01 20:15`.
+pub fn format_utc(secs: u64) -> String {
+    let (year, month, day, hour, minute, _) = utc(secs);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year, month, day, hour, minute
+    )
+}
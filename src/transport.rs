@@ -0,0 +1,382 @@
+//! The session transport: tempo, whether the session is playing and where the beat is. Every node
+//! keeps its own copy, kept in step with Link, JACK and the other peers.
+
+use futures::channel::mpsc::{UnboundedReceiver, UnboundedSender};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::p2p::playout::BEATS_PER_BAR;
+
+/// Non-commercial SysEx ID followed by `PM`, marking transport messages between peers.
+pub(crate) const SYSEX_HEADER: [u8; 4] = [0xF0, 0x7D, b'P', b'M'];
+
+pub(crate) const SYSEX_VERSION: u8 = 1;
+
+/// Non-commercial SysEx ID followed by `PS`, marking starts scheduled by a peer.
+pub(crate) const START_SYSEX_HEADER: [u8; 4] = [0xF0, 0x7D, b'P', b'S'];
+
+/// MIDI clock ticks per beat.
+pub const CLOCK_PPQN: u32 = 24;
+
+pub const DEFAULT_TEMPO: f64 = 120.0;
+
+/// Beats counted in before a start scheduled with the peers, one bar.
+pub const DEFAULT_COUNT_IN: u32 = 4;
+
+/// Most beats counted in before a scheduled start.
+pub const MAX_COUNT_IN: u32 = 16;
+
+/// Time the peers get to hear about a scheduled start before the count-in.
+pub const START_LEAD: Duration = Duration::from_secs(1);
+
+/// Tempos the session can be set to.
+pub const TEMPO_RANGE: std::ops::RangeInclusive<f64> = 20.0..=300.0;
+
+/// Taps further apart than this start counting again.
+const TAP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Most taps the tempo is averaged over.
+const TAPS: usize = 5;
+
+/// Microseconds since the Unix epoch, the clock beats are placed on so peers agree on them.
+pub fn unix_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_micros() as u64)
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct TransportState {
+    /// Beats per minute.
+    pub tempo: f64,
+    pub playing: bool,
+    /// The beat at `at_us`.
+    pub beat: f64,
+    /// Microseconds since the Unix epoch.
+    pub at_us: u64,
+}
+
+impl Default for TransportState {
+    fn default() -> Self {
+        TransportState {
+            tempo: DEFAULT_TEMPO,
+            playing: false,
+            beat: 0.0,
+            at_us: unix_micros(),
+        }
+    }
+}
+
+impl TransportState {
+    /// The beat at `at_us`, standing still while stopped.
+    pub fn beat_at(&self, at_us: u64) -> f64 {
+        match self.playing {
+            true => self.beat + (at_us as f64 - self.at_us as f64) * self.tempo / 60_000_000.0,
+            false => self.beat,
+        }
+    }
+
+    /// When `beat` is reached at the current tempo.
+    pub fn time_of_beat(&self, beat: f64) -> u64 {
+        let offset = (beat - self.beat) * 60_000_000.0 / self.tempo;
+        (self.at_us as f64 + offset).max(0.0) as u64
+    }
+
+    /// Times of the MIDI clock ticks from `from_us` up to but not including `to_us`.
+    pub fn clock_ticks(&self, from_us: u64, to_us: u64) -> Vec<u64> {
+        if !self.playing || to_us <= from_us {
+            return Vec::new();
+        }
+        let ppqn = CLOCK_PPQN as f64;
+        let first = (self.beat_at(from_us) * ppqn).ceil() as i64;
+        let last = (self.beat_at(to_us) * ppqn).ceil() as i64;
+        (first..last)
+            .map(|tick| self.time_of_beat(tick as f64 / ppqn))
+            .collect()
+    }
+
+    /// The same beat grid at another tempo, without the beat jumping at `at_us`.
+    pub fn with_tempo(&self, tempo: f64, at_us: u64) -> Self {
+        TransportState {
+            tempo,
+            beat: self.beat_at(at_us),
+            at_us,
+            ..*self
+        }
+    }
+
+    /// The same beat grid, going on at `tempo` from the first bar line after `at_us`. The state is
+    /// anchored on that bar line, so peers taking it up late still agree on where the beat is.
+    pub fn with_tempo_from_next_bar(&self, tempo: f64, at_us: u64) -> Self {
+        let bar = ((self.beat_at(at_us) / BEATS_PER_BAR).floor() + 1.0) * BEATS_PER_BAR;
+        TransportState {
+            tempo,
+            beat: bar,
+            at_us: self.time_of_beat(bar),
+            ..*self
+        }
+    }
+
+    pub fn with_playing(&self, playing: bool, at_us: u64) -> Self {
+        TransportState {
+            playing,
+            beat: self.beat_at(at_us),
+            at_us,
+            ..*self
+        }
+    }
+
+    /// The SysEx message telling peers about this state.
+    pub fn to_sysex(&self) -> Vec<u8> {
+        let mut message = SYSEX_HEADER.to_vec();
+        message.push(SYSEX_VERSION);
+        push_7bit(&mut message, (self.tempo * 1000.0).round() as u64);
+        message.push(self.playing as u8);
+        push_7bit(&mut message, (self.beat * 1000.0).round() as i64 as u64);
+        push_7bit(&mut message, self.at_us);
+        message.push(0xF7);
+        message
+    }
+
+    /// Read a transport message from a peer, `None` for any other MIDI.
+    pub fn from_sysex(message: &[u8]) -> Option<Self> {
+        let body = message.strip_prefix(&SYSEX_HEADER)?.strip_suffix(&[0xF7])?;
+        if body.len() != 32 || body[0] != SYSEX_VERSION {
+            return None;
+        }
+        let tempo = read_7bit(&body[1..11]) as f64 / 1000.0;
+        if tempo <= 0.0 {
+            return None;
+        }
+        Some(TransportState {
+            tempo,
+            playing: body[11] != 0,
+            beat: read_7bit(&body[12..22]) as i64 as f64 / 1000.0,
+            at_us: read_7bit(&body[22..32]),
+        })
+    }
+}
+
+/// SysEx data bytes only carry 7 bits, so a u64 takes ten of them.
+fn push_7bit(message: &mut Vec<u8>, value: u64) {
+    for i in (0..10).rev() {
+        message.push((value >> (7 * i)) as u8 & 0x7F);
+    }
+}
+
+fn read_7bit(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(0, |value, byte| (value << 7) | (*byte & 0x7F) as u64)
+}
+
+/// Longest a start can be scheduled ahead: the lead, then the longest count-in at the slowest
+/// tempo.
+fn max_start_delay() -> Duration {
+    START_LEAD + Duration::from_secs_f64(MAX_COUNT_IN as f64 * 60.0 / TEMPO_RANGE.start())
+}
+
+/// A start of the session on every node at once, after counting in. It is sent as a delay from
+/// when it was sent rather than a time, so each node places it with its own estimate of the clock
+/// of the sender instead of trusting the clocks to agree.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScheduledStart {
+    /// Microseconds from when it was sent to the start.
+    pub delay_us: u64,
+    /// Beats counted in before the start.
+    pub count_in: u32,
+    pub tempo: f64,
+}
+
+impl ScheduledStart {
+    pub fn to_sysex(&self) -> Vec<u8> {
+        let mut message = START_SYSEX_HEADER.to_vec();
+        message.push(SYSEX_VERSION);
+        push_7bit(&mut message, self.delay_us);
+        push_7bit(&mut message, self.count_in as u64);
+        push_7bit(&mut message, (self.tempo * 1000.0).round() as u64);
+        message.push(0xF7);
+        message
+    }
+
+    /// Read a scheduled start from a peer, `None` for any other MIDI.
+    pub fn from_sysex(message: &[u8]) -> Option<Self> {
+        let body = message
+            .strip_prefix(&START_SYSEX_HEADER)?
+            .strip_suffix(&[0xF7])?;
+        if body.len() != 31 || body[0] != SYSEX_VERSION {
+            return None;
+        }
+        let tempo = read_7bit(&body[21..31]) as f64 / 1000.0;
+        if !TEMPO_RANGE.contains(&tempo) {
+            return None;
+        }
+        let delay_us = read_7bit(&body[1..11]);
+        if Duration::from_micros(delay_us) > max_start_delay() {
+            return None;
+        }
+        Some(ScheduledStart {
+            delay_us,
+            count_in: read_7bit(&body[11..21]) as u32,
+            tempo,
+        })
+    }
+}
+
+/// A start waiting for its time, counting in the beats before it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CountIn {
+    pub start: Instant,
+    pub beats: u32,
+    pub tempo: f64,
+}
+
+impl CountIn {
+    fn beat_secs(&self) -> f64 {
+        60.0 / self.tempo
+    }
+
+    /// Beats left to count at `now`, or `None` before the count-in.
+    pub fn beats_left(&self, now: Instant) -> Option<u32> {
+        let left = self.start.saturating_duration_since(now).as_secs_f64();
+        let beats = (left / self.beat_secs()).ceil() as u32;
+        (beats <= self.beats).then_some(beats)
+    }
+
+    /// When the next beat is counted, the start being the last of them.
+    pub fn next_beat(&self, now: Instant) -> Instant {
+        let left = self.start.saturating_duration_since(now).as_secs_f64();
+        let beats = ((left / self.beat_secs()).ceil() - 1.0).clamp(0.0, self.beats as f64);
+        self.start - Duration::from_secs_f64(beats * self.beat_secs())
+    }
+
+    /// The transport playing from the first beat at the start.
+    pub fn started(&self) -> TransportState {
+        let late = Instant::now().saturating_duration_since(self.start);
+        TransportState {
+            tempo: self.tempo,
+            playing: true,
+            beat: 0.0,
+            at_us: unix_micros().saturating_sub(late.as_micros() as u64),
+        }
+    }
+}
+
+/// A tempo from taps on a button, key or pad.
+#[derive(Debug, Clone, Default)]
+pub struct TapTempo {
+    taps: VecDeque<Instant>,
+}
+
+impl TapTempo {
+    /// Count a tap, returning the tempo of the last taps once there are at least three.
+    pub fn tap(&mut self, at: Instant) -> Option<f64> {
+        if let Some(last) = self.taps.back() {
+            if at.saturating_duration_since(*last) > TAP_TIMEOUT {
+                self.taps.clear();
+            }
+        }
+        self.taps.push_back(at);
+        if self.taps.len() > TAPS {
+            self.taps.pop_front();
+        }
+        let span = at.saturating_duration_since(self.taps[0]).as_secs_f64();
+        if self.taps.len() < 3 || span <= 0.0 {
+            return None;
+        }
+        let tempo = 60.0 * (self.taps.len() - 1) as f64 / span;
+        Some(tempo.clamp(*TEMPO_RANGE.start(), *TEMPO_RANGE.end()))
+    }
+}
+
+/// Where a transport change came from, so it is not sent back where it came from.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    Local,
+    Link,
+    Jack,
+    Peer,
+    /// A start scheduled with the peers, which each node makes on its own.
+    Scheduled,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TransportChange {
+    pub state: TransportState,
+    pub source: Source,
+}
+
+#[derive(Default)]
+struct Inner {
+    state: TransportState,
+    subscribers: Vec<UnboundedSender<TransportChange>>,
+}
+
+/// The transport of this node, shared by the session and whatever syncs with it.
+#[derive(Clone, Default)]
+pub struct Transport {
+    inner: Arc<Mutex<Inner>>,
+}
+
+/// Closes the transport when dropped, see [`Transport::close_on_drop`].
+pub(crate) struct CloseOnDrop(Transport);
+
+impl Drop for CloseOnDrop {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+impl Transport {
+    pub fn state(&self) -> TransportState {
+        self.inner.lock().unwrap().state
+    }
+
+    /// Hear about every change from now on.
+    pub fn subscribe(&self) -> UnboundedReceiver<TransportChange> {
+        let (sender, receiver) = futures::channel::mpsc::unbounded();
+        self.inner.lock().unwrap().subscribers.push(sender);
+        receiver
+    }
+
+    /// End every subscription, so what follows the transport on its own thread stops.
+    pub fn close(&self) {
+        self.inner.lock().unwrap().subscribers.clear();
+    }
+
+    /// Close the transport once the returned guard is dropped.
+    pub(crate) fn close_on_drop(&self) -> CloseOnDrop {
+        CloseOnDrop(self.clone())
+    }
+
+    /// Replace the state, telling the subscribers if anything changed.
+    pub fn set(&self, state: TransportState, source: Source) {
+        let mut inner = self.inner.lock().unwrap();
+        let old = inner.state;
+        let now = unix_micros();
+        let unchanged = old.tempo == state.tempo
+            && old.playing == state.playing
+            && (old.beat_at(now) - state.beat_at(now)).abs() < 0.01;
+        if unchanged {
+            return;
+        }
+        inner.state = state;
+        let change = TransportChange { state, source };
+        inner
+            .subscribers
+            .retain(|subscriber| subscriber.unbounded_send(change).is_ok());
+    }
+
+    pub fn set_tempo(&self, tempo: f64, source: Source) {
+        let state = self.state().with_tempo(tempo, unix_micros());
+        self.set(state, source);
+    }
+
+    pub fn set_playing(&self, playing: bool, source: Source) {
+        let state = self.state().with_playing(playing, unix_micros());
+        self.set(state, source);
+    }
+}
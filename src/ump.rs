@@ -0,0 +1,193 @@
+//! Universal MIDI Packets, the MIDI 2.0 container format. The session carries MIDI 1.0 byte
+//! streams, so MIDI 1.0 messages are wrapped as they are and MIDI 2.0 channel voice messages are
+//! scaled down to their MIDI 1.0 equivalents.
+
+use crate::midi::data_len;
+
+/// Words in a packet, told by the message type in the top nibble of its first word.
+pub fn packet_len(word: u32) -> usize {
+    match word >> 28 {
+        0x0..=0x2 | 0x6 | 0x7 => 1,
+        0x3 | 0x4 | 0x8..=0xA => 2,
+        0xB | 0xC => 3,
+        _ => 4,
+    }
+}
+
+/// Wrap a MIDI 1.0 message into packets on `group`.
+pub fn from_midi1(message: &[u8], group: u8) -> Vec<u32> {
+    let group = (group as u32 & 0x0F) << 24;
+    let status = match message.first() {
+        Some(status) => *status,
+        None => return Vec::new(),
+    };
+    if status == 0xF0 {
+        // SysEx without its start and end bytes, six bytes to a packet
+        let data = match message.last() {
+            Some(0xF7) => &message[1..message.len() - 1],
+            _ => &message[1..],
+        };
+        let chunks: Vec<&[u8]> = match data.is_empty() {
+            true => vec![&[]],
+            false => data.chunks(6).collect(),
+        };
+        let last = chunks.len() - 1;
+        let mut words = Vec::with_capacity(chunks.len() * 2);
+        for (i, chunk) in chunks.into_iter().enumerate() {
+            let kind: u32 = match (i, last) {
+                (0, 0) => 0x0,
+                (0, _) => 0x1,
+                (i, last) if i == last => 0x3,
+                _ => 0x2,
+            };
+            let mut bytes = [0u8; 6];
+            bytes[..chunk.len()].copy_from_slice(chunk);
+            words.push(
+                0x3000_0000
+                    | group
+                    | (kind << 20)
+                    | ((chunk.len() as u32) << 16)
+                    | ((bytes[0] as u32) << 8)
+                    | bytes[1] as u32,
+            );
+            words.push(u32::from_be_bytes([bytes[2], bytes[3], bytes[4], bytes[5]]));
+        }
+        return words;
+    }
+    let message_type = match status {
+        0x80..=0xEF => 0x2000_0000,
+        0xF1..=0xFF => 0x1000_0000,
+        _ => return Vec::new(),
+    };
+    let data = |i: usize| *message.get(i).unwrap_or(&0) as u32 & 0x7F;
+    let len = data_len(status);
+    vec![
+        message_type
+            | group
+            | ((status as u32) << 16)
+            | if len > 0 { data(1) << 8 } else { 0 }
+            | if len > 1 { data(2) } else { 0 },
+    ]
+}
+
+/// Scale a MIDI 2.0 value of `bits` bits down to 7 bits.
+fn to_7bit(value: u32, bits: u32) -> u8 {
+    (value >> (bits - 7)) as u8
+}
+
+/// Longest SysEx assembled, the most a frame of the session protocol carries. Longer ones are
+/// dropped as they come in.
+const MAX_SYSEX_LEN: usize = u16::MAX as usize;
+
+/// Turns packets back into MIDI 1.0 messages, keeping the SysEx still being assembled per group.
+#[derive(Debug, Default)]
+pub struct Decoder {
+    sysex: [Vec<u8>; 16],
+}
+
+impl Decoder {
+    /// The group a packet is on, if its message type has one.
+    pub fn group(word: u32) -> Option<u8> {
+        match word >> 28 {
+            0x1..=0x5 | 0x8..=0xD => Some((word >> 24) as u8 & 0x0F),
+            _ => None,
+        }
+    }
+
+    /// MIDI 1.0 messages carried by `packet`, the words of one packet. Utility, stream and data
+    /// messages have no MIDI 1.0 equivalent and are dropped.
+    pub fn decode(&mut self, packet: &[u32]) -> Vec<Vec<u8>> {
+        let word = match packet.first() {
+            Some(word) => *word,
+            None => return Vec::new(),
+        };
+        let byte = |shift: u32| (word >> shift) as u8;
+        match word >> 28 {
+            // System common and realtime
+            0x1 => {
+                let status = byte(16);
+                let mut message = vec![status];
+                message.extend_from_slice(&[byte(8) & 0x7F, byte(0) & 0x7F][..data_len(status)]);
+                vec![message]
+            }
+            // MIDI 1.0 channel voice
+            0x2 => {
+                let status = byte(16);
+                if status < 0x80 {
+                    return Vec::new();
+                }
+                let mut message = vec![status];
+                message.extend_from_slice(&[byte(8) & 0x7F, byte(0) & 0x7F][..data_len(status)]);
+                vec![message]
+            }
+            // SysEx7
+            0x3 if packet.len() >= 2 => {
+                let group = byte(24) as usize & 0x0F;
+                let len = (byte(16) & 0x0F).min(6) as usize;
+                let second = packet[1].to_be_bytes();
+                let bytes = [byte(8), byte(0), second[0], second[1], second[2], second[3]];
+                let sysex = &mut self.sysex[group];
+                match byte(20) & 0x0F {
+                    0x0 | 0x1 => {
+                        sysex.clear();
+                        sysex.push(0xF0);
+                    }
+                    _ if sysex.is_empty() => return Vec::new(),
+                    _ => {}
+                }
+                // Room for the end byte too, the rest of it is dropped until the next start
+                if sysex.len() + len + 1 > MAX_SYSEX_LEN {
+                    *sysex = Vec::new();
+                    return Vec::new();
+                }
+                sysex.extend(bytes[..len].iter().map(|b| b & 0x7F));
+                match byte(20) & 0x0F {
+                    0x0 | 0x3 => {
+                        let mut message = std::mem::take(sysex);
+                        message.push(0xF7);
+                        vec![message]
+                    }
+                    _ => Vec::new(),
+                }
+            }
+            // MIDI 2.0 channel voice
+            0x4 if packet.len() >= 2 => {
+                let status = byte(16) & 0xF0;
+                let channel = byte(16) & 0x0F;
+                let index = byte(8) & 0x7F;
+                let value = packet[1];
+                match status {
+                    0x80 => vec![vec![0x80 | channel, index, to_7bit(value >> 16, 16)]],
+                    0x90 => {
+                        // Velocity zero means note off in MIDI 1.0
+                        let velocity = to_7bit(value >> 16, 16).max(1);
+                        vec![vec![0x90 | channel, index, velocity]]
+                    }
+                    0xA0 => vec![vec![0xA0 | channel, index, to_7bit(value, 32)]],
+                    0xB0 => vec![vec![0xB0 | channel, index, to_7bit(value, 32)]],
+                    0xC0 => {
+                        let mut messages = Vec::new();
+                        if word & 1 != 0 {
+                            let bank = value.to_be_bytes();
+                            messages.push(vec![0xB0 | channel, 0, bank[2] & 0x7F]);
+                            messages.push(vec![0xB0 | channel, 32, bank[3] & 0x7F]);
+                        }
+                        messages.push(vec![0xC0 | channel, (value >> 24) as u8 & 0x7F]);
+                        messages
+                    }
+                    0xD0 => vec![vec![0xD0 | channel, to_7bit(value, 32)]],
+                    0xE0 => {
+                        let bend = value >> 18;
+                        vec![vec![
+                            0xE0 | channel,
+                            (bend & 0x7F) as u8,
+                            (bend >> 7) as u8 & 0x7F,
+                        ]]
+                    }
+                    _ => Vec::new(),
+                }
+            }
+            _ => Vec::new(),
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use libp2p::{Multiaddr, PeerId};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use super::p2p::archive::valid_session;
+use super::settings::{Args, Settings};
+
+/// A problem found in the merged settings, with enough context to tell the user how to fix it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SettingsError {
+    InvalidPort {
+        field: &'static str,
+        value: u16,
+    },
+    InvalidAddress {
+        field: &'static str,
+        value: String,
+    },
+    InvalidPeerId {
+        field: &'static str,
+        value: String,
+    },
+    Conflict {
+        first: &'static str,
+        second: &'static str,
+    },
+    InvalidSessionName {
+        field: &'static str,
+        value: String,
+    },
+    MissingSwarmKey {
+        path: PathBuf,
+    },
+    /// An identity key asked for by path, which would otherwise be replaced by a new one.
+    MissingIdentity {
+        field: &'static str,
+        path: PathBuf,
+    },
+}
+
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SettingsError::InvalidPort { field, value } => write!(
+                f,
+                "{}: {} is not a valid port, use a number between 1 and 65535",
+                field, value
+            ),
+            SettingsError::InvalidAddress { field, value } => write!(
+                f,
+                "{}: {:?} is not an IP address, hostname or multiaddr (e.g. /ip4/1.2.3.4/tcp/8040)",
+                field, value
+            ),
+            SettingsError::InvalidPeerId { field, value } => {
+                write!(f, "{}: {:?} is not a PeerId", field, value)
+            }
+            SettingsError::Conflict { first, second } => {
+                write!(f, "{} and {} can't be used together", first, second)
+            }
+            SettingsError::InvalidSessionName { field, value } => write!(
+                f,
+                "{}: {:?} is not a session name, use letters, digits, - and _",
+                field, value
+            ),
+            SettingsError::MissingSwarmKey { path } => write!(
+                f,
+                "swarm_key: {} does not exist, create one with `p2pmidi swarm-key`",
+                path.display()
+            ),
+            SettingsError::MissingIdentity { field, path } => write!(
+                f,
+                "{}: {} does not exist, a new identity there would change the PeerId peers know",
+                field,
+                path.display()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SettingsError {}
+
+/// Render a list of errors as one indented line each.
+pub fn describe_errors(errors: &[SettingsError]) -> String {
+    errors
+        .iter()
+        .map(|e| format!("  - {}", e))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+fn is_hostname(address: &str) -> bool {
+    !address.is_empty()
+        && address.len() <= 253
+        && address.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+fn is_valid_address(address: &str) -> bool {
+    if address.starts_with('/') {
+        return Multiaddr::from_str(address).is_ok();
+    }
+    IpAddr::from_str(address).is_ok() || is_hostname(address)
+}
+
+/// Like `is_valid_address`, also taking a port after an IP address or hostname.
+fn is_valid_peer_address(address: &str) -> bool {
+    is_valid_address(address)
+        || SocketAddr::from_str(address).is_ok()
+        || address.rsplit_once(':').map_or(false, |(host, port)| {
+            is_hostname(host) && u16::from_str(port).is_ok()
+        })
+}
+
+impl Settings {
+    /// Check the merged settings, collecting every problem instead of stopping at the first one.
+    pub fn validate(&self) -> Result<(), Vec<SettingsError>> {
+        let mut errors = Vec::new();
+
+        for (field, port) in [("port", self.port), ("relay_port", self.relay_port)] {
+            if port == Some(0) {
+                errors.push(SettingsError::InvalidPort { field, value: 0 });
+            }
+        }
+
+        for address in &self.ip_addresses {
+            if !is_valid_peer_address(address) {
+                errors.push(SettingsError::InvalidAddress {
+                    field: "ip_addresses",
+                    value: address.clone(),
+                });
+            }
+        }
+
+        if let Some(address) = &self.relay_address {
+            if !is_valid_address(address) {
+                errors.push(SettingsError::InvalidAddress {
+                    field: "relay_address",
+                    value: address.clone(),
+                });
+            }
+        }
+
+        for address in &self.relays {
+            if !is_valid_peer_address(address) || PeerId::from_str(address).is_ok() {
+                errors.push(SettingsError::InvalidAddress {
+                    field: "relays",
+                    value: address.clone(),
+                });
+            }
+        }
+
+        if let Some(peer_id) = &self.relay_peer_id {
+            if PeerId::from_str(peer_id).is_err() {
+                errors.push(SettingsError::InvalidPeerId {
+                    field: "relay_peer_id",
+                    value: peer_id.clone(),
+                });
+            }
+        }
+
+        for (field, session) in [("archive", &self.archive), ("publish", &self.publish)] {
+            if let Some(session) = session {
+                if !valid_session(session) {
+                    errors.push(SettingsError::InvalidSessionName {
+                        field,
+                        value: session.clone(),
+                    });
+                }
+            }
+        }
+
+        if let Some(path) = &self.swarm_key {
+            if !path.exists() {
+                errors.push(SettingsError::MissingSwarmKey { path: path.clone() });
+            }
+        }
+
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors),
+        }
+    }
+}
+
+impl Args {
+    /// Check for mutually exclusive command line options.
+    pub fn validate(&self) -> Result<(), Vec<SettingsError>> {
+        if self.gui && self.cli {
+            return Err(vec![SettingsError::Conflict {
+                first: "--gui",
+                second: "--cli",
+            }]);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,216 @@
+//! Velocity compensation for the local controller, so a light or heavy keyboard plays as loud to
+//! the peers as everyone else's. `p2pmidi calibrate` records the velocities of a while of normal
+//! playing and proposes a curve taking them to a balanced spread.
+
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+
+use super::midi::{self, MessageKind};
+use super::ring;
+use super::settings::Settings;
+
+/// Fewest notes a curve is proposed from.
+pub const MIN_CALIBRATION_NOTES: usize = 50;
+
+/// Velocity a balanced player reaches at each percentile of their notes.
+const REFERENCE: [(f64, u8); 5] = [(0.05, 24), (0.25, 48), (0.5, 64), (0.75, 84), (0.95, 110)];
+
+/// Velocities of the notes played mapped to the velocities sent, from the `velocity_curve:`
+/// section of the config file, like `[[40, 64], [90, 110]]`. Velocities between the points are
+/// interpolated, and 0 and 127 stay where they are unless given a point.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Default)]
+#[serde(from = "Vec<(u8, u8)>", into = "Vec<(u8, u8)>")]
+pub struct VelocityCurve {
+    /// Sorted by input velocity, each input once.
+    points: Vec<(u8, u8)>,
+}
+
+impl From<Vec<(u8, u8)>> for VelocityCurve {
+    fn from(mut points: Vec<(u8, u8)>) -> Self {
+        points.retain(|(input, _)| *input <= 127);
+        points.sort_by_key(|(input, _)| *input);
+        points.dedup_by_key(|(input, _)| *input);
+        VelocityCurve { points }
+    }
+}
+
+impl From<VelocityCurve> for Vec<(u8, u8)> {
+    fn from(curve: VelocityCurve) -> Self {
+        curve.points
+    }
+}
+
+impl VelocityCurve {
+    pub fn is_identity(&self) -> bool {
+        self.points.iter().all(|(input, output)| input == output)
+    }
+
+    /// The velocity sent for `velocity`, at least 1 so notes stay notes.
+    pub fn velocity(&self, velocity: u8) -> u8 {
+        let mut below = (0, 0);
+        for &(input, output) in self.points.iter().chain([(127, 127)].iter()) {
+            if input >= velocity {
+                if input == below.0 {
+                    return output.clamp(1, 127);
+                }
+                let t = (velocity - below.0) as f64 / (input - below.0) as f64;
+                let velocity = below.1 as f64 + t * (output as f64 - below.1 as f64);
+                return (velocity.round() as u8).clamp(1, 127);
+            }
+            below = (input, output);
+        }
+        below.1.clamp(1, 127)
+    }
+
+    /// Change the velocity of a note on in place, leaving anything else as it is.
+    pub fn apply(&self, message: &mut [u8]) {
+        if MessageKind::of(message) == Some(MessageKind::NoteOn) && !midi::is_silencing(message) {
+            message[2] = self.velocity(message[2]);
+        }
+    }
+}
+
+/// How many notes were played at each velocity.
+#[derive(Debug, Clone)]
+pub struct VelocityHistogram {
+    counts: [usize; 128],
+}
+
+impl Default for VelocityHistogram {
+    fn default() -> Self {
+        VelocityHistogram { counts: [0; 128] }
+    }
+}
+
+impl VelocityHistogram {
+    /// Count the velocity of a note on. Returns whether it was one.
+    pub fn record(&mut self, message: &[u8]) -> bool {
+        if MessageKind::of(message) != Some(MessageKind::NoteOn) || midi::is_silencing(message) {
+            return false;
+        }
+        self.counts[message[2] as usize] += 1;
+        true
+    }
+
+    pub fn notes(&self) -> usize {
+        self.counts.iter().sum()
+    }
+
+    /// Lowest velocity at least `fraction` of the notes were played at or under.
+    pub fn percentile(&self, fraction: f64) -> u8 {
+        let wanted = (self.notes() as f64 * fraction).ceil().max(1.0) as usize;
+        let mut seen = 0;
+        for (velocity, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= wanted {
+                return velocity as u8;
+            }
+        }
+        127
+    }
+
+    /// The curve taking the velocities played to the spread of a balanced player.
+    pub fn propose(&self) -> VelocityCurve {
+        let mut points: Vec<(u8, u8)> = Vec::new();
+        for (fraction, output) in REFERENCE {
+            let input = self.percentile(fraction);
+            // Velocities played at several percentiles keep the first, the curve has to rise
+            if points.last().map_or(true, |(last, _)| input > *last) {
+                points.push((input, output));
+            }
+        }
+        VelocityCurve::from(points)
+    }
+
+    /// Bars of how many notes were played in each range of 16 velocities.
+    pub fn describe(&self) -> String {
+        let ranges: Vec<usize> = self
+            .counts
+            .chunks(16)
+            .map(|range| range.iter().sum())
+            .collect();
+        let most = ranges.iter().copied().max().unwrap_or(0).max(1);
+        ranges
+            .iter()
+            .enumerate()
+            .map(|(i, count)| {
+                format!(
+                    "{:>3}-{:<3} {:<40} {}",
+                    i * 16,
+                    i * 16 + 15,
+                    "#".repeat(count * 40 / most),
+                    count
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+fn ask(question: &str) -> Result<bool, Box<dyn Error>> {
+    print!("{} [y/N] ", question);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Record `notes` notes played on the MIDI device of the settings, show how their velocities
+/// spread and propose a curve, saving it to the config file when told to or asked to.
+pub fn run_calibration(
+    settings: &Settings,
+    config_path: &Path,
+    notes: usize,
+    save: bool,
+    interactive: bool,
+) -> Result<(), Box<dyn Error>> {
+    let device = settings
+        .midi_device
+        .clone()
+        .ok_or("Pick the MIDI device to calibrate with --device")?;
+    let notes = notes.max(MIN_CALIBRATION_NOTES);
+    let (producer, mut consumer) = ring::ring_buffer(1024);
+    let _connection = midi::connect_input(&device, producer)?;
+    println!(
+        "Play {} as you would in a session, soft and loud parts alike, for {} notes.",
+        device, notes
+    );
+    let mut histogram = VelocityHistogram::default();
+    while histogram.notes() < notes {
+        match consumer.pop() {
+            Some(event) => {
+                if histogram.record(event.message()) {
+                    print!("\r{}/{} notes", histogram.notes(), notes);
+                    std::io::stdout().flush()?;
+                }
+            }
+            None => std::thread::sleep(Duration::from_millis(5)),
+        }
+    }
+    println!("\n\nVelocities played:\n{}\n", histogram.describe());
+
+    let curve = histogram.propose();
+    if curve.is_identity() {
+        println!("Your playing is already balanced, no curve needed.");
+        return Ok(());
+    }
+    println!("Proposed curve, velocity played -> velocity sent:");
+    for velocity in [16, 32, 48, 64, 80, 96, 112, 127] {
+        println!("{:>3} -> {}", velocity, curve.velocity(velocity));
+    }
+    let points: Vec<(u8, u8)> = curve.clone().into();
+    println!("\nvelocity_curve: {:?}", points);
+    if !save && !(interactive && ask(&format!("Save it to {}?", config_path.display()))?) {
+        return Ok(());
+    }
+    let mut saved = match config_path.exists() {
+        true => Settings::load(config_path)?,
+        false => Settings::default(),
+    };
+    saved.velocity_curve = Some(curve);
+    println!("Saved to {}", saved.save(config_path)?);
+    Ok(())
+}